@@ -0,0 +1,276 @@
+#![forbid(unsafe_code)]
+
+use babencoin::{
+    block_forest::BlockForest,
+    chain_params::{ChainParams, ChainPreset},
+    data::{Block, BlockAttributes, BlockHash, VerifiedBlock, WalletId},
+    storage::{ChainStorage, FileStorage},
+    util::{encode_wallet_id, serialize_base64},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Inspect and audit a babencoin chain offline
+#[derive(StructOpt, Debug)]
+#[structopt()]
+struct Opts {
+    /// JSON-lines file with one block per line
+    #[structopt(
+        long = "dump",
+        required_unless = "data-dir",
+        conflicts_with = "data-dir"
+    )]
+    dump_path: Option<PathBuf>,
+
+    /// Data directory of a node
+    #[structopt(long = "data-dir")]
+    data_dir: Option<PathBuf>,
+
+    /// Do not print blocks of the main chain
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Print balances of all wallets as of the head
+    #[structopt(long = "balances")]
+    print_balances: bool,
+
+    /// Chain the blocks belong to (mainnet or regtest)
+    #[structopt(long = "chain", default_value = "mainnet")]
+    chain: ChainPreset,
+}
+
+#[derive(Serialize)]
+struct BlockSummary<'a> {
+    #[serde(serialize_with = "serialize_base64")]
+    hash: &'a BlockHash,
+    #[serde(flatten)]
+    attrs: &'a BlockAttributes,
+    transaction_count: usize,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default)]
+struct Audit {
+    issues: Vec<String>,
+}
+
+impl Audit {
+    fn report(&mut self, issue: String) {
+        eprintln!("inconsistency: {}", issue);
+        self.issues.push(issue);
+    }
+}
+
+fn load_dump(path: &Path, params: &ChainParams, audit: &mut Audit) -> Result<Vec<VerifiedBlock>> {
+    let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let mut blocks = vec![];
+    for (line_index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("failed to read {:?}", path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = serde_json::from_str::<Block>(&line)
+            .context("failed to parse block")
+            .and_then(|block| block.verified_for(params));
+        match result {
+            Ok(block) => blocks.push(block),
+            Err(err) => audit.report(format!("line {}: {:#}", line_index + 1, err)),
+        }
+    }
+    Ok(blocks)
+}
+
+fn load_storage(dir: &Path, params: &ChainParams) -> Result<Vec<VerifiedBlock>> {
+    if !dir.is_dir() {
+        bail!("{:?} is not a directory", dir);
+    }
+    let mut storage = FileStorage::open(dir).context("failed to open chain storage")?;
+    storage.load_blocks(params)
+}
+
+/// Returns the main chain, from genesis to head.
+fn main_chain(forest: &BlockForest) -> Vec<Arc<VerifiedBlock>> {
+    let mut chain = vec![forest.head().clone()];
+    while chain.last().unwrap().index > 0 {
+        let prev_hash = chain.last().unwrap().prev_hash;
+        let prev = forest
+            .find_block(&prev_hash)
+            .expect("main chain is connected to genesis");
+        chain.push(prev.clone());
+    }
+    chain.reverse();
+    chain
+}
+
+/// Replays the main chain into a fresh forest, checking each block against the
+/// difficulty the chain expects at that point.
+fn verify_difficulty(chain: &[Arc<VerifiedBlock>], params: &ChainParams, audit: &mut Audit) {
+    let mut replay = BlockForest::with_params(params.clone());
+    let pow_hash = params.make_pow_hash();
+    for block in chain.iter().skip(1) {
+        let expected_max_hash = replay.next_max_hash();
+        if block.max_hash != expected_max_hash {
+            audit.report(format!(
+                "block {} has max_hash {}, expected {}",
+                block.index,
+                hex(&block.max_hash),
+                hex(&expected_max_hash)
+            ));
+        }
+        if pow_hash.compute(block.hash()) > block.max_hash {
+            audit.report(format!("block {} hash exceeds its max_hash", block.index));
+        }
+        if let Err(err) = replay.add_block(block.as_ref().clone()) {
+            audit.report(format!("block {} fails to replay: {:#}", block.index, err));
+            return;
+        }
+    }
+}
+
+/// Recomputes all balances from scratch and compares them with the forest.
+fn verify_balances(
+    forest: &BlockForest,
+    chain: &[Arc<VerifiedBlock>],
+    audit: &mut Audit,
+) -> HashMap<WalletId, u64> {
+    let mut balances: HashMap<WalletId, u64> = HashMap::new();
+    for block in chain.iter().skip(1) {
+        let reward = block
+            .transactions()
+            .iter()
+            .try_fold(block.reward, |sum, tx| sum.checked_add(tx.fee));
+        let issuer_balance = balances.entry(block.issuer.clone()).or_default();
+        match reward.and_then(|reward| issuer_balance.checked_add(reward)) {
+            Some(balance) => *issuer_balance = balance,
+            None => audit.report(format!("block {} reward overflows", block.index)),
+        }
+
+        for tx in block.transactions() {
+            if let Some(index) = tx.lock_until_index.filter(|index| *index > block.index) {
+                audit.report(format!(
+                    "block {} includes a transaction locked until block {}",
+                    block.index, index
+                ));
+            }
+            let sender_balance = balances.entry(tx.sender.clone()).or_default();
+            match sender_balance
+                .checked_sub(tx.amount)
+                .and_then(|balance| balance.checked_sub(tx.fee))
+            {
+                Some(balance) => *sender_balance = balance,
+                None => {
+                    audit.report(format!(
+                        "block {} spends more than the sender has",
+                        block.index
+                    ));
+                    continue;
+                }
+            }
+            let receiver_balance = balances.entry(tx.receiver.clone()).or_default();
+            match receiver_balance.checked_add(tx.amount) {
+                Some(balance) => *receiver_balance = balance,
+                None => audit.report(format!("block {} overflows a balance", block.index)),
+            }
+        }
+    }
+
+    for (wallet, balance) in balances.iter() {
+        let forest_balance = forest.balance(wallet);
+        if forest_balance != *balance {
+            audit.report(format!(
+                "wallet {} has balance {}, recomputed {}",
+                encode_wallet_id(wallet).unwrap_or_default(),
+                forest_balance,
+                balance
+            ));
+        }
+    }
+    balances
+}
+
+fn hex(hash: &BlockHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn do_main() -> Result<bool> {
+    let opts = Opts::from_args();
+    let params = ChainParams::from_preset(opts.chain);
+    let mut audit = Audit::default();
+
+    let blocks = match (opts.dump_path.as_ref(), opts.data_dir.as_ref()) {
+        (Some(path), _) => load_dump(path, &params, &mut audit)?,
+        (None, Some(dir)) => load_storage(dir, &params)?,
+        (None, None) => bail!("either --dump or --data-dir is required"),
+    };
+    let block_count = blocks.len();
+
+    let mut forest = BlockForest::with_params(params.clone());
+    for block in blocks {
+        let index = block.index;
+        let hash = *block.hash();
+        if let Err(err) = forest.add_block(block) {
+            audit.report(format!(
+                "block {} ({}) is rejected: {:#}",
+                index,
+                base64::encode(hash),
+                err
+            ));
+        }
+    }
+    for hash in forest.unknown_block_hashes() {
+        audit.report(format!("parent {} is missing", base64::encode(hash)));
+    }
+
+    let chain = main_chain(&forest);
+    if !opts.quiet {
+        for block in chain.iter().rev() {
+            let summary = BlockSummary {
+                hash: block.hash(),
+                attrs: block,
+                transaction_count: block.transactions().len(),
+            };
+            println!("{}", serde_json::to_string(&summary)?);
+        }
+    }
+
+    verify_difficulty(&chain, &params, &mut audit);
+    let balances = verify_balances(&forest, &chain, &mut audit);
+    if opts.print_balances {
+        for (wallet, balance) in balances.iter() {
+            println!("{} {}", encode_wallet_id(wallet)?, balance);
+        }
+    }
+
+    eprintln!(
+        "loaded {} blocks, head index is {}, {} wallets, {} inconsistencies",
+        block_count,
+        forest.head().index,
+        balances.len(),
+        audit.issues.len()
+    );
+    Ok(audit.issues.is_empty())
+}
+
+fn main() {
+    match do_main() {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(2),
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+#![forbid(unsafe_code)]
+
+use babencoin::{
+    chain_params::{ChainParams, ChainPreset},
+    data::{
+        Block, MultisigPolicy, PeerMessage, Transaction, VerifiedTransaction, WalletId,
+        PROTOCOL_VERSION,
+    },
+    node::wire::{self, FrameBuffer, WireFormat},
+    util::{
+        decode_wallet_id, encode_wallet_id, format_pkcs8_private, format_pkcs8_public,
+        parse_pkcs8_private,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use rand::thread_rng;
+use rsa::RSAPrivateKey;
+use structopt::StructOpt;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const SUBMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+enum Opts {
+    /// Generate a new RSA keypair
+    Generate {
+        /// Where to write the PKCS8 private key (the public key goes next to it, with .pub extension)
+        #[structopt(short = "o", long = "out")]
+        out_path: PathBuf,
+
+        /// Key size in bits
+        #[structopt(long = "bits", default_value = "2048")]
+        bits: usize,
+    },
+
+    /// Print the wallet id of a private key
+    WalletId {
+        /// Path to the PKCS8 private key
+        #[structopt(short = "k", long = "key")]
+        key_path: PathBuf,
+    },
+
+    /// Print the wallet id of a multisig escrow wallet
+    Escrow {
+        /// How many of the keys must sign to spend from the wallet
+        #[structopt(long = "threshold")]
+        threshold: u32,
+
+        /// Wallet ids of the keys
+        #[structopt(long = "key", required = true)]
+        keys: Vec<String>,
+    },
+
+    /// Build and sign a transaction
+    Transfer {
+        /// Path to the sender's PKCS8 private key
+        #[structopt(short = "k", long = "key")]
+        key_path: PathBuf,
+
+        /// Receiver's wallet id
+        #[structopt(long = "to")]
+        receiver: String,
+
+        #[structopt(long = "amount")]
+        amount: u64,
+
+        #[structopt(long = "fee", default_value = "0")]
+        fee: u64,
+
+        #[structopt(long = "comment", default_value = "")]
+        comment: String,
+
+        /// Don't let the transaction into blocks with a lower index
+        #[structopt(long = "lock-until-index")]
+        lock_until_index: Option<u64>,
+
+        /// Where to write the transaction json (stdout by default)
+        #[structopt(short = "o", long = "out")]
+        out_path: Option<PathBuf>,
+
+        /// Listen address of a node to submit the transaction to
+        #[structopt(long = "submit")]
+        node_address: Option<String>,
+
+        /// Chain the node runs (mainnet or regtest)
+        #[structopt(long = "chain", default_value = "mainnet")]
+        chain: ChainPreset,
+    },
+}
+
+fn read_private_key(path: &Path) -> Result<RSAPrivateKey> {
+    let raw = fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    parse_pkcs8_private(&raw).with_context(|| format!("failed to parse {:?}", path))
+}
+
+fn generate(out_path: &Path, bits: usize) -> Result<()> {
+    let key = RSAPrivateKey::new(&mut thread_rng(), bits).context("failed to generate key")?;
+
+    let pub_path = out_path.with_extension("pub");
+    fs::write(out_path, format_pkcs8_private(&key)?)
+        .with_context(|| format!("failed to write {:?}", out_path))?;
+    fs::write(&pub_path, format_pkcs8_public(&key.to_public_key())?)
+        .with_context(|| format!("failed to write {:?}", pub_path))?;
+
+    println!("{}", encode_wallet_id(&key.to_public_key().into())?);
+    Ok(())
+}
+
+fn recv_frame(conn: &mut TcpStream, buffer: &mut FrameBuffer) -> Result<Option<PeerMessage>> {
+    loop {
+        if let Some(frame) = buffer.next_frame()? {
+            return Ok(Some(frame?.message));
+        }
+        let mut chunk = [0u8; 4096];
+        let len = conn.read(&mut chunk).context("failed to read from node")?;
+        if len == 0 {
+            return Ok(None);
+        }
+        buffer.data_mut().extend_from_slice(&chunk[..len]);
+    }
+}
+
+fn submit(node_address: &str, params: &ChainParams, tx: VerifiedTransaction) -> Result<()> {
+    let mut conn = TcpStream::connect(node_address)
+        .with_context(|| format!("failed to connect to {}", node_address))?;
+    conn.set_read_timeout(Some(SUBMIT_TIMEOUT))?;
+
+    // NB: a wallet has no chain, so it claims to be at genesis and offers no features.
+    let genesis = Block::genesis_for(params);
+    let hello = PeerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        network_id: params.network_id(),
+        head_index: genesis.index,
+        head_hash: genesis.compute_hash(),
+        features: 0,
+    };
+    let message = PeerMessage::Transaction(Box::new(tx.into()));
+    for message in [hello, message] {
+        conn.write_all(&wire::encode_frame(&message, WireFormat::Json)?)?;
+    }
+    conn.flush()?;
+
+    let mut buffer = FrameBuffer::default();
+    match recv_frame(&mut conn, &mut buffer)? {
+        Some(PeerMessage::Hello { .. }) => (),
+        Some(_) => bail!("node has not started with a hello"),
+        None => bail!("node has dropped the connection, is it on another network?"),
+    }
+
+    // NB: wait for the node to hang up, leaving unread data would reset the connection
+    // and might lose the transaction.
+    conn.shutdown(Shutdown::Write)?;
+    while recv_frame(&mut conn, &mut buffer)?.is_some() {}
+    Ok(())
+}
+
+fn do_main() -> Result<()> {
+    match Opts::from_args() {
+        Opts::Generate { out_path, bits } => generate(&out_path, bits),
+        Opts::WalletId { key_path } => {
+            let key = read_private_key(&key_path)?;
+            let wallet: WalletId = key.to_public_key().into();
+            println!("{}", encode_wallet_id(&wallet)?);
+            Ok(())
+        }
+        Opts::Escrow { threshold, keys } => {
+            let keys = keys
+                .iter()
+                .map(|key| decode_wallet_id(key).context("invalid key wallet id"))
+                .collect::<Result<Vec<_>>>()?;
+            let policy = MultisigPolicy::new(threshold, keys)?;
+            println!("{}", encode_wallet_id(&policy.wallet_id())?);
+            Ok(())
+        }
+        Opts::Transfer {
+            key_path,
+            receiver,
+            amount,
+            fee,
+            comment,
+            lock_until_index,
+            out_path,
+            node_address,
+            chain,
+        } => {
+            let key = read_private_key(&key_path)?;
+            let receiver = decode_wallet_id(&receiver).context("invalid receiver wallet id")?;
+            let mut tx =
+                Transaction::new(key.to_public_key().into(), receiver, amount, fee, comment);
+            tx.lock_until_index = lock_until_index;
+            tx.add_signature(&key)
+                .context("failed to sign transaction")?;
+            let tx = tx.verified()?;
+
+            let json = serde_json::to_string_pretty(&tx as &Transaction)?;
+            match out_path {
+                Some(path) => fs::write(&path, json + "\n")
+                    .with_context(|| format!("failed to write {:?}", path))?,
+                None if node_address.is_none() => println!("{}", json),
+                None => (),
+            }
+
+            if let Some(node_address) = node_address {
+                submit(&node_address, &ChainParams::from_preset(chain), tx)?;
+                eprintln!("submitted transaction to {}", node_address);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn main() {
+    if let Err(err) = do_main() {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
+}
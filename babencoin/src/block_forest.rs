@@ -1,61 +1,199 @@
-use crate::data::{
-    BlockHash, TransactionHash, VerifiedBlock, VerifiedTransaction, WalletId, HASH_LEN,
+use crate::{
+    chain_params::ChainParams,
+    data::{
+        BlockAttributes, BlockHash, TransactionHash, VerifiedBlock, VerifiedTransaction, WalletId,
+        MAX_LOCATOR_LEN,
+    },
+    history::{ConfirmedEntry, HistoryIndex},
+    mempool::{transaction_size, Mempool, MempoolConfig},
+    pow::Retarget,
+    storage::ChainStorage,
 };
 
 use anyhow::{bail, Context, Result};
-use chrono::Duration;
-use log::debug;
-use num_bigint::BigUint;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet, VecDeque},
+    mem,
+    ops::AddAssign,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub const EPOCH_SIZE: usize = 16;
-pub const TARGET_BLOCK_MINING_TIME_SECONDS: u64 = 10;
+const LOCATOR_DENSE_PREFIX_LEN: usize = 10;
+const MEMPOOL_PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Describes how the main chain changed when the head was switched.
+#[derive(Clone, Debug)]
+pub struct Reorg {
+    pub common_ancestor: Arc<VerifiedBlock>,
+    /// Blocks that left the main chain, starting from the old head.
+    pub removed: Vec<Arc<VerifiedBlock>>,
+    /// Blocks that joined the main chain, ending with the new head.
+    pub added: Vec<Arc<VerifiedBlock>>,
+}
+
+impl Reorg {
+    pub fn old_head(&self) -> &Arc<VerifiedBlock> {
+        self.removed.first().unwrap_or(&self.common_ancestor)
+    }
+
+    pub fn new_head(&self) -> &Arc<VerifiedBlock> {
+        self.added.last().unwrap_or(&self.common_ancestor)
+    }
+
+    /// Returns whether the old head is still on the main chain.
+    pub fn is_extension(&self) -> bool {
+        self.removed.is_empty()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Bounds the memory of the forest on a long chain, see `BlockForest::set_pruning_config`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PruningConfig {
+    /// Balance snapshots are kept for blocks with an index divisible by this and for the
+    /// head. The others are recomputed from the closest checkpoint when needed.
+    pub checkpoint_interval: u64,
+    /// Blocks forking off the main chain at least this many blocks below the head are
+    /// dropped, and no new block may fork off there.
+    pub finality_depth: u64,
+}
+
+/// What pruning has reclaimed. Sizes are estimates that ignore the heap data of keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PruningStats {
+    pub blocks: usize,
+    pub snapshots: usize,
+    pub bytes: usize,
+}
+
+impl AddAssign for PruningStats {
+    fn add_assign(&mut self, other: Self) {
+        self.blocks += other.blocks;
+        self.snapshots += other.snapshots;
+        self.bytes += other.bytes;
+    }
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct BlockForest {
+    retarget: Box<dyn Retarget>,
+    genesis_hash: BlockHash,
     head: Arc<VerifiedBlock>,
     blocks: HashMap<BlockHash, Arc<VerifiedBlock>>,
     children_hashes: HashMap<BlockHash, Vec<BlockHash>>,
     bad_block_hashes: HashSet<BlockHash>,
     unknown_block_hashes: HashSet<BlockHash>,
+    /// Blocks whose transactions are known to apply on top of their parents.
+    validated_hashes: HashSet<BlockHash>,
+    /// Balances after a block. With pruning, only checkpoints and the head are kept.
     balance_snapshots: HashMap<BlockHash, HashMap<WalletId, u64>>,
-    pending_transactions: HashMap<TransactionHash, VerifiedTransaction>,
+    mempool: Mempool,
     pending_snapshot: HashMap<WalletId, u64>,
+    history: Option<HistoryIndex>,
+    pruning: Option<PruningConfig>,
+    /// Snapshots stored since the last pruning.
+    unpruned_hashes: Vec<BlockHash>,
+    /// Index of the last main chain block whose forks are dropped.
+    finalized_index: u64,
+    pruning_stats: PruningStats,
+    storage: Option<Box<dyn ChainStorage>>,
+    /// Whether the mempool changed since it was last persisted.
+    mempool_dirty: bool,
+    mempool_persisted_at: Instant,
 }
 
 impl Default for BlockForest {
     fn default() -> Self {
-        let genesis = Arc::new(VerifiedBlock::genesis());
+        Self::with_params(ChainParams::mainnet())
+    }
+}
+
+impl BlockForest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a forest for an explicitly given chain rather than the selected one.
+    pub fn with_params(params: ChainParams) -> Self {
+        let genesis = Arc::new(VerifiedBlock::genesis_for(&params));
 
         let mut blocks = HashMap::new();
         blocks.insert(*genesis.hash(), genesis.clone());
 
         let mut balance_snapshots = HashMap::new();
         balance_snapshots.insert(*genesis.hash(), HashMap::new());
+        let validated_hashes = HashSet::from([*genesis.hash()]);
 
         Self {
+            retarget: params.make_retarget(),
+            genesis_hash: *genesis.hash(),
             head: genesis,
             blocks,
             children_hashes: HashMap::new(),
             bad_block_hashes: HashSet::new(),
             unknown_block_hashes: HashSet::new(),
+            validated_hashes,
             balance_snapshots,
-            pending_transactions: HashMap::new(),
+            mempool: Mempool::new(MempoolConfig::default()),
             pending_snapshot: HashMap::new(),
+            history: None,
+            pruning: None,
+            unpruned_hashes: vec![],
+            finalized_index: 0,
+            pruning_stats: PruningStats::default(),
+            storage: None,
+            mempool_dirty: false,
+            mempool_persisted_at: Instant::now(),
         }
     }
-}
 
-impl BlockForest {
-    pub fn new() -> Self {
-        Self::default()
+    /// Restores the forest of the chain of `params` from `storage`, and persists all further
+    /// changes to it.
+    pub fn with_storage(params: ChainParams, mut storage: Box<dyn ChainStorage>) -> Result<Self> {
+        let blocks = storage
+            .load_blocks(&params)
+            .context("failed to load blocks")?;
+        let mut forest = Self::with_params(params);
+
+        let block_count = blocks.len();
+        for block in blocks {
+            let hash = *block.hash();
+            if let Err(err) = forest.add_block(block) {
+                debug!(
+                    "stored block {} is rejected: {:#}",
+                    base64::encode(hash),
+                    err
+                );
+            }
+        }
+
+        let transactions = storage
+            .load_pending_transactions()
+            .context("failed to load pending transactions")?;
+        for tx in transactions {
+            if let Err(err) = forest.add_transaction(tx) {
+                debug!("stored transaction is rejected: {:#}", err);
+            }
+        }
+
+        info!(
+            "restored {} blocks from storage, head index is {}",
+            block_count, forest.head.index
+        );
+
+        forest.storage = Some(storage);
+        Ok(forest)
     }
 
     pub fn head(&self) -> &Arc<VerifiedBlock> {
@@ -67,28 +205,167 @@ impl BlockForest {
     }
 
     pub fn pending_transactions(&self) -> &HashMap<TransactionHash, VerifiedTransaction> {
-        &self.pending_transactions
+        self.mempool.transactions()
+    }
+
+    /// Returns pending transactions ordered by fee rate, highest first. A transaction that
+    /// spends funds received in another pending transaction always goes after it.
+    pub fn pending_transactions_by_fee_rate(&self) -> Vec<VerifiedTransaction> {
+        let transactions = self.mempool.transactions().values().cloned().collect();
+        let mut snapshot = self.snapshot(self.head.hash()).into_owned();
+        let (ordered, _) = self.order_by_fee_rate(transactions, &mut snapshot);
+        ordered
+    }
+
+    /// Applies new limits, evicting pending transactions if needed.
+    pub fn set_mempool_config(&mut self, config: MempoolConfig) {
+        self.mempool.set_config(config);
+        if self.mempool.is_over_limits() {
+            self.enforce_mempool_limits();
+            self.persist_pending_transactions();
+        }
+    }
+
+    /// Enables pruning, the forest is pruned right away.
+    pub fn set_pruning_config(&mut self, config: PruningConfig) -> Result<()> {
+        if config.checkpoint_interval == 0 {
+            bail!("checkpoint_interval must be positive");
+        }
+        self.pruning = Some(config);
+        self.unpruned_hashes = self.balance_snapshots.keys().copied().collect();
+        self.prune();
+        Ok(())
+    }
+
+    /// Returns what pruning has reclaimed so far.
+    pub fn pruning_stats(&self) -> PruningStats {
+        self.pruning_stats
+    }
+
+    /// Drops pending transactions older than the mempool TTL, returns how many were dropped.
+    pub fn expire_pending_transactions(&mut self) -> usize {
+        let expired = self.mempool.expired();
+        if expired.is_empty() {
+            return 0;
+        }
+
+        for hash in expired.iter() {
+            self.mempool.remove(hash);
+        }
+        let transactions = self.mempool.transactions().values().cloned().collect();
+        self.rebuild_pending_transactions(transactions);
+        self.persist_pending_transactions();
+        expired.len()
+    }
+
+    /// Starts indexing main chain transactions by wallet, see `history`.
+    pub fn enable_history_index(&mut self) {
+        if self.history.is_some() {
+            return;
+        }
+
+        let genesis = self.blocks[&self.genesis_hash].clone();
+        let mut history = HistoryIndex::default();
+        for block in self.list_blocks(&self.head, &genesis).iter().rev() {
+            history.apply_block(block);
+        }
+        self.history = Some(history);
+    }
+
+    /// Returns balance changes of `wallet` on the main chain, oldest first.
+    pub fn history(&self, wallet: &WalletId) -> Result<Vec<ConfirmedEntry>> {
+        let Some(history) = self.history.as_ref() else {
+            bail!("history index is disabled");
+        };
+        Ok(history
+            .entries(wallet)
+            .iter()
+            .map(|entry| ConfirmedEntry {
+                entry: entry.clone(),
+                confirmations: self.head.index - entry.block_index + 1,
+            })
+            .collect())
     }
 
     pub fn find_block(&self, hash: &BlockHash) -> Option<&Arc<VerifiedBlock>> {
         self.blocks.get(hash)
     }
 
+    /// Returns whether the block is connected to genesis and its transactions apply.
+    pub fn is_validated(&self, hash: &BlockHash) -> bool {
+        self.validated_hashes.contains(hash)
+    }
+
+    /// Returns the balance of `wallet` as of the current head.
+    pub fn balance(&self, wallet: &WalletId) -> u64 {
+        self.snapshot(self.head.hash())
+            .get(wallet)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns hashes of main chain blocks, densely near the head and exponentially
+    /// sparser towards genesis. Genesis hash always comes last.
+    pub fn block_locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut block = &self.head;
+        loop {
+            locator.push(*block.hash());
+            if block.index == 0 || locator.len() == MAX_LOCATOR_LEN - 1 {
+                break;
+            }
+            if locator.len() >= LOCATOR_DENSE_PREFIX_LEN {
+                step *= 2;
+            }
+            for _ in 0..step {
+                if block.index == 0 {
+                    break;
+                }
+                block = &self.blocks[&block.prev_hash];
+            }
+        }
+
+        if locator.last() != Some(&self.genesis_hash) {
+            locator.push(self.genesis_hash);
+        }
+        locator
+    }
+
+    /// Returns up to `limit` main chain blocks following the most recent block among
+    /// `known_hashes`. If none of them is on the main chain, starts right after genesis.
+    pub fn main_chain_after(
+        &self,
+        known_hashes: &[BlockHash],
+        limit: usize,
+    ) -> Vec<Arc<VerifiedBlock>> {
+        let known_hashes: HashSet<_> = known_hashes.iter().collect();
+        let mut chain = vec![];
+        let mut block = &self.head;
+        while block.index > 0 && !known_hashes.contains(block.hash()) {
+            chain.push(block.clone());
+            block = &self.blocks[&block.prev_hash];
+        }
+
+        chain.reverse();
+        chain.truncate(limit);
+        chain
+    }
+
     pub fn next_max_hash(&self) -> BlockHash {
         let next_index = self.head.index + 1;
-        if next_index % EPOCH_SIZE as u64 > 0 {
-            return self.head.max_hash;
-        };
+        let count = self.retarget.ancestor_count(next_index);
+        let mut ancestors = self.get_ancestors(&self.head, count - 1);
+        ancestors.reverse();
+        ancestors.push(&self.head);
 
-        let mut prev_epoch = self.get_ancestors(&self.head, EPOCH_SIZE - 1);
-        prev_epoch.reverse();
-        prev_epoch.push(&self.head);
-
-        assert_eq!(prev_epoch.len(), EPOCH_SIZE);
-        self.compute_epoch_max_hash(&prev_epoch)
+        assert_eq!(ancestors.len(), count);
+        self.retarget.max_hash(next_index, &ancestors)
     }
 
-    pub fn add_block(&mut self, block: VerifiedBlock) -> Result<()> {
+    /// Adds a block to the forest. Returns the summary of the head switch, if the block
+    /// caused one.
+    pub fn add_block(&mut self, block: VerifiedBlock) -> Result<Option<Reorg>> {
         if self.bad_block_hashes.contains(block.hash()) {
             bail!("block {} is known to be bad", base64::encode(block.hash()));
         }
@@ -101,7 +378,15 @@ impl BlockForest {
         }
 
         if self.blocks.contains_key(block.hash()) {
-            return Ok(());
+            return Ok(None);
+        }
+        if let Some(config) = self.pruning.as_ref() {
+            if block.index + config.finality_depth <= self.head.index {
+                bail!(
+                    "block {} forks off below the finality depth",
+                    base64::encode(block.hash())
+                );
+            }
         }
 
         self.unknown_block_hashes.remove(block.hash());
@@ -119,29 +404,161 @@ impl BlockForest {
         self.validate_new_block(&block)?;
 
         if self.is_block_connected_to_genesis(block.hash()) {
-            self.validate_transaction_balances(block.hash())?;
+            let validation = self.validate_transaction_balances(block.hash());
+            let mut reorg = None;
+            if validation.is_ok() {
+                let head_candidate = self.find_head_candidate(&block_arc);
+                if head_candidate.index > self.head.index {
+                    let new_head = head_candidate.clone();
+                    reorg = Some(self.switch_head_to(new_head));
+                }
+            }
+            self.prune();
+            return validation.map(|()| reorg);
+        }
+
+        Ok(None)
+    }
 
-            let head_candidate = self.find_head_candidate(&block_arc);
-            if head_candidate.index > self.head.index {
-                let new_head = head_candidate.clone();
-                self.switch_head_to(new_head);
+    pub fn add_transaction(&mut self, tx: VerifiedTransaction) -> Result<()> {
+        if self.mempool.contains(tx.hash()) {
+            return Ok(());
+        }
+
+        if !self.mempool.has_room_for(&tx) {
+            if transaction_size(&tx) > self.mempool.config().max_bytes {
+                bail!("transaction is larger than the mempool");
+            }
+            let mut candidates = vec![tx.clone()];
+            candidates.extend(self.mempool.lowest_fee_rate().cloned());
+            self.mempool.sort_by_fee_rate(&mut candidates);
+            if candidates[0].hash() != tx.hash() {
+                bail!("mempool is full and transaction fee rate is too low");
             }
         }
 
+        Self::try_apply_tx_to_snapshot(&tx, self.head.index + 1, &mut self.pending_snapshot)?;
+        self.mempool.insert(tx);
+        self.enforce_mempool_limits();
+        self.persist_pending_transactions();
         Ok(())
     }
 
-    pub fn add_transaction(&mut self, tx: VerifiedTransaction) -> Result<()> {
-        if self.pending_transactions.contains_key(tx.hash()) {
+    fn enforce_mempool_limits(&mut self) {
+        if !self.mempool.is_over_limits() {
+            return;
+        }
+
+        while self.mempool.is_over_limits() {
+            let Some(hash) = self.mempool.lowest_fee_rate().map(|tx| *tx.hash()) else {
+                break;
+            };
+            debug!("evicting transaction {}", base64::encode(hash));
+            self.mempool.remove(&hash);
+        }
+
+        // NB: evicted transactions may have funded some of the remaining ones.
+        let transactions = self.mempool.transactions().values().cloned().collect();
+        self.rebuild_pending_transactions(transactions);
+    }
+
+    /// Replaces pending transactions with those of `transactions` that are valid on top
+    /// of the current head.
+    fn rebuild_pending_transactions(&mut self, transactions: Vec<VerifiedTransaction>) {
+        let mut snapshot = self.snapshot(self.head.hash()).into_owned();
+        let (valid, invalid) = self.order_by_fee_rate(transactions, &mut snapshot);
+        for tx in invalid {
+            debug!("discarding transaction {}", base64::encode(tx.hash()));
+        }
+        self.mempool.replace(valid);
+        self.pending_snapshot = snapshot;
+    }
+
+    /// Applies `transactions` to `snapshot` in fee rate order, retrying the failed ones while
+    /// there is progress. Returns applied transactions in order and the rest.
+    ///
+    /// `snapshot` is supposed to be the one of the head, transactions go to the next block.
+    fn order_by_fee_rate(
+        &self,
+        mut transactions: Vec<VerifiedTransaction>,
+        snapshot: &mut HashMap<WalletId, u64>,
+    ) -> (Vec<VerifiedTransaction>, Vec<VerifiedTransaction>) {
+        self.mempool.sort_by_fee_rate(&mut transactions);
+
+        let block_index = self.head.index + 1;
+        let mut ordered = vec![];
+        loop {
+            let mut deferred = vec![];
+            let applied_count = ordered.len();
+            for tx in transactions {
+                if Self::try_apply_tx_to_snapshot(&tx, block_index, snapshot).is_ok() {
+                    ordered.push(tx);
+                } else {
+                    deferred.push(tx);
+                }
+            }
+            if deferred.is_empty() || ordered.len() == applied_count {
+                return (ordered, deferred);
+            }
+            transactions = deferred;
+        }
+    }
+
+    /// Persists the mempool and syncs the storage, if any. Blocks are persisted as they come.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.storage.is_none() {
             return Ok(());
         }
+        self.store_pending_transactions()
+            .context("failed to persist pending transactions")?;
+        self.storage.as_mut().unwrap().flush()
+    }
+
+    /// Persists the mempool if it changed and the last write is older than
+    /// `MEMPOOL_PERSIST_INTERVAL`. The node calls this periodically.
+    pub fn persist_pending_transactions_if_due(&mut self) {
+        if !self.mempool_dirty || self.mempool_persisted_at.elapsed() < MEMPOOL_PERSIST_INTERVAL {
+            return;
+        }
+        if let Err(err) = self.store_pending_transactions() {
+            warn!("failed to persist pending transactions: {:#}", err);
+        }
+    }
+
+    /// Rewriting the whole mempool on every change would let a flood of transactions
+    /// turn into a flood of disk writes, so changes are batched.
+    fn persist_pending_transactions(&mut self) {
+        self.mempool_dirty = true;
+        self.persist_pending_transactions_if_due();
+    }
 
-        Self::try_apply_tx_to_snapshot(&tx, &mut self.pending_snapshot)?;
-        self.pending_transactions.insert(*tx.hash(), tx);
+    fn store_pending_transactions(&mut self) -> Result<()> {
+        let Some(storage) = self.storage.as_mut() else {
+            return Ok(());
+        };
+
+        let transactions = self
+            .mempool
+            .transactions()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        storage.store_pending_transactions(&transactions)?;
+        self.mempool_dirty = false;
+        self.mempool_persisted_at = Instant::now();
         Ok(())
     }
 
-    fn mark_bad_block(&mut self, root_hash: &BlockHash) {
+    /// Removes the block along with its descendants for good, returns what it reclaimed.
+    fn mark_bad_block(&mut self, root_hash: &BlockHash) -> PruningStats {
+        let (stats, removed_hashes) = self.remove_subtree(root_hash);
+        self.bad_block_hashes.extend(removed_hashes);
+        stats
+    }
+
+    /// Removes the block along with its descendants, returns what it reclaimed and the
+    /// hashes of the removed blocks.
+    fn remove_subtree(&mut self, root_hash: &BlockHash) -> (PruningStats, Vec<BlockHash>) {
         let root_block = &self.blocks[root_hash];
         if root_block.index > 0 {
             let parent_hash = self.blocks[root_hash].prev_hash;
@@ -151,14 +568,25 @@ impl BlockForest {
                 .retain(|hash| hash != root_hash);
         }
 
+        let mut stats = PruningStats::default();
+        let mut removed_hashes = vec![];
         let mut stack = vec![*root_hash];
         while let Some(hash) = stack.pop() {
-            self.blocks.remove(&hash);
-            self.bad_block_hashes.insert(hash);
+            if let Some(block) = self.blocks.remove(&hash) {
+                stats.blocks += 1;
+                stats.bytes += block_size(&block);
+            }
+            if let Some(snapshot) = self.balance_snapshots.remove(&hash) {
+                stats.snapshots += 1;
+                stats.bytes += snapshot_size(&snapshot);
+            }
+            self.validated_hashes.remove(&hash);
+            removed_hashes.push(hash);
             if let Some(children_hashes) = self.children_hashes.remove(&hash) {
                 stack.extend(children_hashes);
             }
         }
+        (stats, removed_hashes)
     }
 
     fn validate_new_block(&mut self, block: &VerifiedBlock) -> Result<()> {
@@ -174,7 +602,7 @@ impl BlockForest {
         let mut stack = vec![*block.hash()];
         let mut bad_children = vec![];
 
-        // Validate all descendants down to 2 * EPOCH_SIZE generations.
+        // Validate all descendants that the retargeting may depend on, with a margin.
         while let Some(hash) = stack.pop() {
             let children_hashes = match self.children_hashes.get(&hash) {
                 Some(h) => h,
@@ -185,7 +613,8 @@ impl BlockForest {
                 let child_block = &self.blocks[child_hash];
                 match self.validate_block(child_block) {
                     Ok(()) => {
-                        if child_block.index - block.index < (2 * EPOCH_SIZE) as u64 {
+                        let depth = 2 * self.retarget.max_ancestor_count();
+                        if child_block.index - block.index < depth as u64 {
                             stack.push(*child_hash);
                         }
                     }
@@ -226,14 +655,6 @@ impl BlockForest {
                     prev.timestamp,
                 );
             }
-
-            if block.index % EPOCH_SIZE as u64 > 0 && prev.max_hash != block.max_hash {
-                bail!(
-                    "wrong max_hash: expected {:?}, got {:?}",
-                    prev.max_hash,
-                    block.max_hash,
-                );
-            }
         }
 
         if let Some(expected_max_hash) = self.compute_max_hash(block) {
@@ -250,86 +671,31 @@ impl BlockForest {
     }
 
     fn compute_max_hash(&self, block: &VerifiedBlock) -> Option<BlockHash> {
-        if block.index % EPOCH_SIZE as u64 > 0 {
-            let parent = self.blocks.get(&block.prev_hash)?;
-            Some(parent.max_hash)
-        } else {
-            let mut prev_epoch = self.get_ancestors(block, EPOCH_SIZE);
-            if prev_epoch.len() != EPOCH_SIZE {
-                return None;
-            }
-            prev_epoch.reverse();
-            Some(self.compute_epoch_max_hash(&prev_epoch))
+        let count = self.retarget.ancestor_count(block.index);
+        let mut ancestors = self.get_ancestors(block, count);
+        if ancestors.len() != count {
+            return None;
         }
+        ancestors.reverse();
+        Some(self.retarget.max_hash(block.index, &ancestors))
     }
 
-    fn get_ancestors(&self, block: &VerifiedBlock, limit: usize) -> Vec<&VerifiedBlock> {
+    fn get_ancestors(&self, block: &VerifiedBlock, limit: usize) -> Vec<&BlockAttributes> {
         let mut ancestors = Vec::with_capacity(limit);
         let mut hash = block.prev_hash;
         while let Some(ancestor) = self.find_block(&hash) {
             if ancestors.len() == limit {
                 break;
             }
-            ancestors.push(ancestor as &VerifiedBlock);
+            ancestors.push(ancestor as &BlockAttributes);
             hash = ancestor.prev_hash;
         }
         ancestors
     }
 
-    fn compute_epoch_max_hash(&self, epoch: &[&VerifiedBlock]) -> BlockHash {
-        assert_eq!(epoch.len(), EPOCH_SIZE);
-        let epoch_id = epoch[0].index / 16;
-        assert_eq!(epoch[0].index, epoch_id * EPOCH_SIZE as u64);
-        assert_eq!(
-            epoch.last().unwrap().index,
-            (epoch_id + 1) * EPOCH_SIZE as u64 - 1
-        );
-
-        let avg_duration = {
-            let mut sum_duration = Duration::zero();
-            for (prev, cur) in epoch.iter().zip(epoch.iter().skip(1)) {
-                assert_eq!(prev.max_hash, cur.max_hash);
-
-                let delta = cur.timestamp - prev.timestamp;
-                assert!(delta > Duration::zero());
-
-                sum_duration = sum_duration
-                    .checked_add(&delta)
-                    .expect("duration add overflow");
-            }
-            sum_duration / (epoch.len() - 1) as i32
-        };
-
-        let old_max_hash = BigUint::from_bytes_be(&epoch[0].max_hash);
-        let factor = (avg_duration.num_seconds() as f64 / TARGET_BLOCK_MINING_TIME_SECONDS as f64)
-            .max(0.001)
-            .min(1000.);
-
-        let max_hash = if factor > 1. {
-            old_max_hash * factor.round() as u64
-        } else {
-            old_max_hash / (1. / factor).round() as u64
-        };
-
-        let bytes = max_hash.to_bytes_be();
-        let prefix_size = bytes.len().saturating_sub(HASH_LEN);
-        let leading_zeros = HASH_LEN.saturating_sub(bytes.len());
-
-        if bytes.iter().take(prefix_size).any(|b| *b > 0) {
-            [255u8; HASH_LEN]
-        } else {
-            let mut result = [0u8; HASH_LEN];
-            for (i, byte) in (leading_zeros..HASH_LEN).zip(bytes.into_iter().skip(prefix_size)) {
-                result[i] = byte;
-            }
-            result
-        }
-    }
-
     fn is_block_connected_to_genesis(&self, hash: &BlockHash) -> bool {
-        let genesis_hash = *VerifiedBlock::genesis().hash();
         let mut last_hash = *hash;
-        while last_hash != genesis_hash {
+        while last_hash != self.genesis_hash {
             if let Some(parent) = self.blocks.get(&last_hash) {
                 last_hash = parent.prev_hash;
             } else {
@@ -340,19 +706,23 @@ impl BlockForest {
     }
 
     fn validate_transaction_balances(&mut self, hash: &BlockHash) -> Result<()> {
-        if self.balance_snapshots.contains_key(hash) {
+        if self.validated_hashes.contains(hash) {
             return Ok(());
         }
 
         let mut root_block = &self.blocks[hash];
-        while !self.balance_snapshots.contains_key(&root_block.prev_hash) {
+        while !self.validated_hashes.contains(&root_block.prev_hash) {
             root_block = &self.blocks[&root_block.prev_hash];
         }
 
         let mut bad_block_hashes = vec![];
+        let mut new_validated_hashes = vec![];
         let mut queue: VecDeque<_> = vec![root_block].into();
         'next_block: while let Some(block) = queue.pop_back() {
-            let mut snapshot = self.balance_snapshots[&block.prev_hash].clone();
+            let mut snapshot = match self.balance_snapshots.get(&block.prev_hash) {
+                Some(snapshot) => snapshot.clone(),
+                None => self.snapshot(&block.prev_hash).into_owned(),
+            };
 
             if let Err(err) = Self::try_apply_issuer_reward_to_snapshot(block, &mut snapshot) {
                 debug!(
@@ -365,7 +735,7 @@ impl BlockForest {
             }
 
             for tx in block.transactions() {
-                if let Err(err) = Self::try_apply_tx_to_snapshot(tx, &mut snapshot) {
+                if let Err(err) = Self::try_apply_tx_to_snapshot(tx, block.index, &mut snapshot) {
                     debug!(
                         "failed to apply block transactions: {:#} (block {}, tx {})",
                         err,
@@ -378,6 +748,11 @@ impl BlockForest {
             }
 
             self.balance_snapshots.insert(*block.hash(), snapshot);
+            self.validated_hashes.insert(*block.hash());
+            new_validated_hashes.push(*block.hash());
+            if self.pruning.is_some() {
+                self.unpruned_hashes.push(*block.hash());
+            }
 
             if let Some(children_hashes) = self.children_hashes.get(block.hash()) {
                 for child_hash in children_hashes {
@@ -390,6 +765,16 @@ impl BlockForest {
             self.mark_bad_block(hash);
         }
 
+        // NB: only validated blocks are persisted, parents first, so that the log never
+        // has to be replayed through blocks that are known to be bad.
+        if let Some(storage) = self.storage.as_mut() {
+            for hash in new_validated_hashes.iter() {
+                storage
+                    .append_block(&self.blocks[hash])
+                    .context("failed to persist block")?;
+            }
+        }
+
         if bad_block_hashes.iter().any(|h| h == hash) {
             bail!("block transactions are invalid");
         }
@@ -420,8 +805,14 @@ impl BlockForest {
         best
     }
 
-    fn switch_head_to(&mut self, new_head: Arc<VerifiedBlock>) {
+    fn switch_head_to(&mut self, new_head: Arc<VerifiedBlock>) -> Reorg {
         let lca = self.find_lca(&self.head, &new_head);
+        let mut reorg = Reorg {
+            common_ancestor: lca.clone(),
+            removed: self.list_blocks(&self.head, lca),
+            added: self.list_blocks(&new_head, lca),
+        };
+        reorg.added.reverse();
 
         let new_branch_tx_hashes: HashSet<_> = self
             .list_transactions(&new_head, lca)
@@ -429,34 +820,158 @@ impl BlockForest {
             .map(|tx| *tx.hash())
             .collect();
 
-        let old_branch_txs = self.list_transactions(&self.head, lca);
+        let mut candidates = self.list_transactions(&self.head, lca);
+        candidates.extend(self.mempool.transactions().values().cloned());
+        let mut seen_hashes = HashSet::new();
+        candidates.retain(|tx| {
+            !new_branch_tx_hashes.contains(tx.hash()) && seen_hashes.insert(*tx.hash())
+        });
 
-        let mut new_pending_transactions = HashMap::new();
-        let mut new_snapshot = self.balance_snapshots.get(new_head.hash()).unwrap().clone();
-        for tx in old_branch_txs
-            .iter()
-            .chain(self.pending_transactions.values())
-        {
-            if new_branch_tx_hashes.contains(tx.hash())
-                || new_pending_transactions.contains_key(tx.hash())
-            {
+        if let Some(history) = self.history.as_mut() {
+            for block in reorg.removed.iter() {
+                history.revert_block(block);
+            }
+            for block in reorg.added.iter() {
+                history.apply_block(block);
+            }
+        }
+
+        if self.pruning.is_some() {
+            self.unpruned_hashes.push(*self.head.hash());
+        }
+        self.head = new_head;
+        self.rebuild_pending_transactions(candidates);
+        self.enforce_mempool_limits();
+        self.persist_pending_transactions();
+        reorg
+    }
+
+    /// Returns balances after the block, recomputing them from the closest stored snapshot
+    /// if needed. The block must be validated.
+    fn snapshot(&self, hash: &BlockHash) -> Cow<'_, HashMap<WalletId, u64>> {
+        if let Some(snapshot) = self.balance_snapshots.get(hash) {
+            return Cow::Borrowed(snapshot);
+        }
+
+        let mut blocks = vec![];
+        let mut block = &self.blocks[hash];
+        while !self.balance_snapshots.contains_key(block.hash()) {
+            blocks.push(block);
+            block = &self.blocks[&block.prev_hash];
+        }
+
+        let mut snapshot = self.balance_snapshots[block.hash()].clone();
+        for block in blocks.into_iter().rev() {
+            Self::try_apply_issuer_reward_to_snapshot(block, &mut snapshot)
+                .expect("validated block must apply");
+            for tx in block.transactions() {
+                Self::try_apply_tx_to_snapshot(tx, block.index, &mut snapshot)
+                    .expect("validated block must apply");
+            }
+        }
+        Cow::Owned(snapshot)
+    }
+
+    fn prune(&mut self) {
+        let Some(config) = self.pruning.clone() else {
+            return;
+        };
+
+        let mut stats = PruningStats::default();
+        for hash in mem::take(&mut self.unpruned_hashes) {
+            let is_checkpoint = self
+                .blocks
+                .get(&hash)
+                .is_some_and(|block| block.index.is_multiple_of(config.checkpoint_interval));
+            if is_checkpoint || hash == *self.head.hash() {
                 continue;
             }
+            if let Some(snapshot) = self.balance_snapshots.remove(&hash) {
+                stats.snapshots += 1;
+                stats.bytes += snapshot_size(&snapshot);
+            }
+        }
+        stats += self.finalize(&config);
 
-            if let Err(err) = Self::try_apply_tx_to_snapshot(tx, &mut new_snapshot) {
-                debug!(
-                    "discarding transaction {}: {:#}",
-                    base64::encode(tx.hash()),
-                    err,
+        if stats.blocks > 0 {
+            info!(
+                "pruned {} blocks and {} balance snapshots, reclaimed about {} bytes",
+                stats.blocks, stats.snapshots, stats.bytes
+            );
+        }
+        self.pruning_stats += stats;
+    }
+
+    /// Drops forks deeper than the finality depth along with snapshots of checkpoints
+    /// that can no longer be needed.
+    fn finalize(&mut self, config: &PruningConfig) -> PruningStats {
+        let final_index = self.head.index.saturating_sub(config.finality_depth);
+        if final_index <= self.finalized_index {
+            return PruningStats::default();
+        }
+        let final_checkpoint =
+            final_index / config.checkpoint_interval * config.checkpoint_interval;
+        let last_final_checkpoint =
+            self.finalized_index / config.checkpoint_interval * config.checkpoint_interval;
+
+        let mut stale_hashes = vec![];
+        let mut stale_snapshot_hashes = vec![];
+        let mut main_child_hash = None;
+        let mut block = &self.head;
+        loop {
+            if block.index < final_index && block.index >= self.finalized_index {
+                let children_hashes = self.children_hashes.get(block.hash());
+                stale_hashes.extend(
+                    children_hashes
+                        .into_iter()
+                        .flatten()
+                        .filter(|hash| Some(*hash) != main_child_hash),
                 );
-            } else {
-                new_pending_transactions.insert(*tx.hash(), tx.clone());
             }
+            if block.index < final_checkpoint {
+                stale_snapshot_hashes.push(*block.hash());
+            }
+            if block.index <= last_final_checkpoint {
+                break;
+            }
+            main_child_hash = Some(block.hash());
+            block = &self.blocks[&block.prev_hash];
+        }
+        let mut stale_hashes: Vec<BlockHash> = stale_hashes.into_iter().copied().collect();
+
+        // NB: orphans can't connect below the finality depth either.
+        for unknown_hash in self.unknown_block_hashes.iter() {
+            stale_hashes.extend(
+                self.children_hashes
+                    .get(unknown_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter(|hash| self.blocks[*hash].index <= final_index),
+            );
         }
 
-        self.head = new_head;
-        self.pending_transactions = new_pending_transactions;
-        self.pending_snapshot = new_snapshot;
+        // NB: stale forks are not bad, so they are simply dropped. Should they come back,
+        // `add_block` rejects them for being below the finality depth.
+        let mut stats = PruningStats::default();
+        for hash in stale_hashes {
+            stats += self.remove_subtree(&hash).0;
+        }
+        for hash in stale_snapshot_hashes {
+            if let Some(snapshot) = self.balance_snapshots.remove(&hash) {
+                stats.snapshots += 1;
+                stats.bytes += snapshot_size(&snapshot);
+            }
+        }
+        self.unknown_block_hashes.retain(|hash| {
+            self.children_hashes
+                .get(hash)
+                .is_some_and(|children| !children.is_empty())
+        });
+        self.children_hashes
+            .retain(|_, children| !children.is_empty());
+
+        self.finalized_index = final_index;
+        stats
     }
 
     fn find_lca<'a>(
@@ -501,10 +1016,18 @@ impl BlockForest {
         Ok(())
     }
 
+    /// Applies `tx` included in the block with index `block_index`.
     fn try_apply_tx_to_snapshot(
         tx: &VerifiedTransaction,
+        block_index: u64,
         snapshot: &mut HashMap<WalletId, u64>,
     ) -> Result<()> {
+        if let Some(lock_until_index) = tx.lock_until_index {
+            if block_index < lock_until_index {
+                bail!("transaction is locked until block {}", lock_until_index);
+            }
+        }
+
         let old_sender_balance = *snapshot.get(&tx.sender).unwrap_or(&0);
         let new_sender_balance = old_sender_balance
             .checked_sub(tx.amount)
@@ -530,6 +1053,21 @@ impl BlockForest {
         Ok(())
     }
 
+    /// Returns blocks from `inclusive_from` down to `exclusive_to`.
+    fn list_blocks(
+        &self,
+        inclusive_from: &Arc<VerifiedBlock>,
+        exclusive_to: &Arc<VerifiedBlock>,
+    ) -> Vec<Arc<VerifiedBlock>> {
+        let mut blocks = vec![];
+        let mut block = inclusive_from;
+        while block.hash() != exclusive_to.hash() {
+            blocks.push(block.clone());
+            block = &self.blocks[&block.prev_hash];
+        }
+        blocks
+    }
+
     fn list_transactions(
         &self,
         inclusive_from: &Arc<VerifiedBlock>,
@@ -544,3 +1082,274 @@ impl BlockForest {
         transactions
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn block_size(block: &VerifiedBlock) -> usize {
+    mem::size_of::<VerifiedBlock>()
+        + block
+            .transactions()
+            .iter()
+            .map(transaction_size)
+            .sum::<usize>()
+}
+
+fn snapshot_size(snapshot: &HashMap<WalletId, u64>) -> usize {
+    snapshot.capacity() * mem::size_of::<(WalletId, u64)>()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Block, MultisigPolicy, Transaction};
+
+    use chrono::Duration;
+    use rand::thread_rng;
+    use rsa::{algorithms::generate_multi_prime_key, RSAPrivateKey};
+
+    fn make_fork(prev: &VerifiedBlock, len: u64, nonce: u64) -> Vec<VerifiedBlock> {
+        let mut chain: Vec<VerifiedBlock> = vec![];
+        for _ in 0..len {
+            let prev = chain.last().unwrap_or(prev);
+            let mut block = Block::genesis();
+            block.attrs.index = prev.index + 1;
+            block.attrs.prev_hash = *prev.hash();
+            block.attrs.nonce = nonce;
+            block.attrs.timestamp = prev.timestamp + Duration::minutes(10);
+            chain.push(block.verified_for(&ChainParams::mainnet()).unwrap());
+        }
+        chain
+    }
+
+    fn make_block(
+        prev: &VerifiedBlock,
+        issuer: &RSAPrivateKey,
+        transactions: Vec<Transaction>,
+    ) -> Result<VerifiedBlock> {
+        let mut block = Block::genesis();
+        block.attrs.index = prev.index + 1;
+        block.attrs.prev_hash = *prev.hash();
+        block.attrs.timestamp = prev.timestamp + Duration::minutes(10);
+        block.attrs.reward = ChainParams::mainnet().max_reward;
+        block.attrs.issuer = issuer.to_public_key().into();
+        block.transactions = transactions;
+        block.update_merkle_root();
+        block.verified_for(&ChainParams::mainnet())
+    }
+
+    fn hashes(blocks: &[Arc<VerifiedBlock>]) -> Vec<BlockHash> {
+        blocks.iter().map(|block| *block.hash()).collect()
+    }
+
+    #[test]
+    fn test_reorg() {
+        let genesis = VerifiedBlock::genesis();
+        let fork_a = make_fork(&genesis, 2, 1);
+        let fork_b = make_fork(&genesis, 3, 2);
+
+        let mut forest = BlockForest::new();
+        let reorg = forest.add_block(fork_a[0].clone()).unwrap().unwrap();
+        assert!(reorg.is_extension());
+        assert_eq!(reorg.common_ancestor.hash(), genesis.hash());
+        assert_eq!(reorg.new_head().hash(), fork_a[0].hash());
+
+        let reorg = forest.add_block(fork_a[1].clone()).unwrap().unwrap();
+        assert_eq!(reorg.common_ancestor.hash(), fork_a[0].hash());
+        assert_eq!(hashes(&reorg.added), vec![*fork_a[1].hash()]);
+
+        assert!(forest.add_block(fork_b[0].clone()).unwrap().is_none());
+        assert!(forest.add_block(fork_b[1].clone()).unwrap().is_none());
+        assert!(forest.add_block(fork_a[1].clone()).unwrap().is_none());
+
+        let reorg = forest.add_block(fork_b[2].clone()).unwrap().unwrap();
+        assert!(!reorg.is_extension());
+        assert_eq!(reorg.common_ancestor.hash(), genesis.hash());
+        assert_eq!(reorg.old_head().hash(), fork_a[1].hash());
+        assert_eq!(
+            hashes(&reorg.removed),
+            vec![*fork_a[1].hash(), *fork_a[0].hash()]
+        );
+        assert_eq!(
+            hashes(&reorg.added),
+            fork_b.iter().map(|block| *block.hash()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_multisig_and_lock() {
+        let keys: Vec<_> = (0..4)
+            .map(|_| generate_multi_prime_key(&mut thread_rng(), 32, 1024).unwrap())
+            .collect();
+        let miner = &keys[3];
+        let miner_wallet: WalletId = miner.to_public_key().into();
+        let policy = MultisigPolicy::new(
+            2,
+            keys[..3]
+                .iter()
+                .map(|key| key.to_public_key().into())
+                .collect(),
+        )
+        .unwrap();
+        let escrow = policy.wallet_id();
+
+        let mut forest = BlockForest::new();
+        let block = make_block(&VerifiedBlock::genesis(), miner, vec![]).unwrap();
+        forest.add_block(block.clone()).unwrap();
+
+        let mut funding = Transaction::new(miner_wallet.clone(), escrow.clone(), 500, 0, "".into());
+        funding.add_signature(miner).unwrap();
+        let mut locked = Transaction::new(
+            miner_wallet.clone(),
+            ChainParams::mainnet().genesis_issuer,
+            1,
+            0,
+            "".into(),
+        );
+        locked.lock_until_index = Some(5);
+        locked.add_signature(miner).unwrap();
+
+        let early = make_block(&block, miner, vec![funding.clone(), locked.clone()]).unwrap();
+        assert!(forest.add_block(early).is_err());
+        let block = make_block(&block, miner, vec![funding]).unwrap();
+        forest.add_block(block.clone()).unwrap();
+        assert_eq!(forest.balance(&escrow), 500);
+
+        let mut spending = Transaction::new_multisig(policy, miner_wallet, 200, 10, "".into());
+        spending.add_signature(&keys[0]).unwrap();
+        spending.add_signature(&keys[1]).unwrap();
+        let block = make_block(&block, miner, vec![spending]).unwrap();
+        forest.add_block(block.clone()).unwrap();
+        assert_eq!(forest.balance(&escrow), 290);
+
+        let locked = locked.verified().unwrap();
+        assert!(forest.add_transaction(locked.clone()).is_err());
+        forest
+            .add_block(make_block(&block, miner, vec![]).unwrap())
+            .unwrap();
+        forest.add_transaction(locked).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let keys: Vec<_> = (0..3)
+            .map(|_| generate_multi_prime_key(&mut thread_rng(), 32, 1024).unwrap())
+            .collect();
+        let wallets: Vec<WalletId> = keys.iter().map(|key| key.to_public_key().into()).collect();
+        let max_reward = ChainParams::mainnet().max_reward as i128;
+
+        let mut forest = BlockForest::new();
+        assert!(forest.history(&wallets[0]).is_err());
+
+        let genesis = VerifiedBlock::genesis();
+        let first = make_block(&genesis, &keys[0], vec![]).unwrap();
+        forest.add_block(first.clone()).unwrap();
+        forest.enable_history_index();
+
+        let mut tx = Transaction::new(wallets[0].clone(), wallets[2].clone(), 100, 5, "".into());
+        tx.add_signature(&keys[0]).unwrap();
+        let second = make_block(&first, &keys[0], vec![tx.clone()]).unwrap();
+        forest.add_block(second.clone()).unwrap();
+
+        let history = forest.history(&wallets[0]).unwrap();
+        let deltas: Vec<_> = history.iter().map(|item| item.entry.delta).collect();
+        assert_eq!(deltas, vec![max_reward, -105, max_reward + 5]);
+        let confirmations: Vec<_> = history.iter().map(|item| item.confirmations).collect();
+        assert_eq!(confirmations, vec![2, 1, 1]);
+        assert_eq!(history[1].entry.transaction_hash, Some(tx.compute_hash()));
+        assert_eq!(history[2].entry.transaction_hash, None);
+        assert_eq!(
+            deltas.iter().sum::<i128>(),
+            forest.balance(&wallets[0]) as i128
+        );
+        assert_eq!(forest.history(&wallets[2]).unwrap().len(), 1);
+
+        let mut prev = genesis;
+        for _ in 0..3 {
+            let block = make_block(&prev, &keys[1], vec![]).unwrap();
+            forest.add_block(block.clone()).unwrap();
+            prev = block;
+        }
+        assert_eq!(forest.head().hash(), prev.hash());
+        assert!(forest.history(&wallets[0]).unwrap().is_empty());
+        assert!(forest.history(&wallets[2]).unwrap().is_empty());
+        let confirmations: Vec<_> = forest
+            .history(&wallets[1])
+            .unwrap()
+            .iter()
+            .map(|item| item.confirmations)
+            .collect();
+        assert_eq!(confirmations, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_pruning() {
+        let keys: Vec<_> = (0..2)
+            .map(|_| generate_multi_prime_key(&mut thread_rng(), 32, 1024).unwrap())
+            .collect();
+        let wallets: Vec<WalletId> = keys.iter().map(|key| key.to_public_key().into()).collect();
+
+        let mut forest = BlockForest::new();
+        forest
+            .set_pruning_config(PruningConfig {
+                checkpoint_interval: 4,
+                finality_depth: 3,
+            })
+            .unwrap();
+
+        let mut main_chain = vec![VerifiedBlock::genesis()];
+        let mut stale = None;
+        for index in 1..=9 {
+            let transactions = if index == 3 {
+                let mut tx =
+                    Transaction::new(wallets[0].clone(), wallets[1].clone(), 100, 0, "".into());
+                tx.add_signature(&keys[0]).unwrap();
+                vec![tx]
+            } else {
+                vec![]
+            };
+            let block = make_block(main_chain.last().unwrap(), &keys[0], transactions).unwrap();
+            forest.add_block(block.clone()).unwrap();
+            main_chain.push(block);
+
+            if index == 3 {
+                stale = make_fork(&main_chain[2], 1, 7).pop();
+                let stale = stale.as_ref().unwrap();
+                assert!(forest.add_block(stale.clone()).unwrap().is_none());
+                assert!(forest.find_block(stale.hash()).is_some());
+            }
+        }
+        let stale = stale.unwrap();
+        assert!(forest.find_block(stale.hash()).is_none());
+        assert!(!forest.bad_block_hashes.contains(stale.hash()));
+        assert!(forest.add_block(stale).is_err());
+
+        // NB: the fork at index 3 is below the finality depth now.
+        let stats = forest.pruning_stats();
+        assert_eq!(stats.blocks, 1);
+        assert!(stats.snapshots > 0);
+        assert!(stats.bytes > 0);
+        let mut kept: Vec<_> = forest
+            .balance_snapshots
+            .keys()
+            .map(|hash| forest.blocks[hash].index)
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![4, 8, 9]);
+        assert!(forest
+            .add_block(make_fork(&main_chain[5], 1, 7).pop().unwrap())
+            .is_err());
+
+        // NB: the fork point has no snapshot, so it's recomputed from the checkpoint.
+        let mut prev = main_chain[7].clone();
+        for _ in 0..3 {
+            let block = make_block(&prev, &keys[1], vec![]).unwrap();
+            forest.add_block(block.clone()).unwrap();
+            prev = block;
+        }
+        assert_eq!(forest.head().hash(), prev.hash());
+        assert_eq!(forest.balance(&wallets[0]), 6900);
+        assert_eq!(forest.balance(&wallets[1]), 3100);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{
+    data::{Block, BlockHash, WalletId, HASH_LEN},
+    pow::{
+        EpochRetarget, MovingWindowRetarget, PowHash, PowHashKind, Retarget, RetargetKind, Sha3,
+        Sha3MemoryHard,
+    },
+    util::{decode_wallet_id, parse_pkcs8_public},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+use std::str::FromStr;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Consensus parameters of a chain. Nodes with different parameters can't agree on
+/// blocks, so every preset has its own genesis block and hence its own network id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    /// Difficulty is adjusted once per this many blocks.
+    pub epoch_size: usize,
+    pub target_block_mining_time_seconds: u64,
+    pub max_reward: u64,
+    pub genesis_timestamp: i64,
+    pub genesis_issuer: WalletId,
+    /// Nodes disagreeing on these can't share a chain, so they are part of the network id.
+    pub pow_hash: PowHashKind,
+    pub retarget: RetargetKind,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        Self {
+            epoch_size: 16,
+            target_block_mining_time_seconds: 10,
+            max_reward: 1000,
+            genesis_timestamp: 1626002428,
+            genesis_issuer: parse_pkcs8_public(include_str!("../data/genesis.crt"))
+                .unwrap()
+                .into(),
+            pow_hash: PowHashKind::Sha3,
+            retarget: RetargetKind::Epoch,
+        }
+    }
+
+    /// A chain for local testing. Blocks can't be timestamped faster than the 1s target,
+    /// so the difficulty never rises above the genesis one.
+    pub fn regtest() -> Self {
+        Self {
+            epoch_size: 4,
+            target_block_mining_time_seconds: 1,
+            genesis_timestamp: 1700000000,
+            ..Self::mainnet()
+        }
+    }
+
+    pub fn from_preset(preset: ChainPreset) -> Self {
+        match preset {
+            ChainPreset::Mainnet => Self::mainnet(),
+            ChainPreset::Regtest => Self::regtest(),
+        }
+    }
+
+    /// Identifies the chain, nodes with different ids don't talk to each other.
+    ///
+    /// It is the genesis hash, mixed with `pow_hash` and `retarget` unless both are the
+    /// original ones, so the mainnet keeps the id older nodes announce.
+    pub fn network_id(&self) -> BlockHash {
+        let genesis_hash = Block::genesis_for(self).compute_hash();
+        if self.pow_hash == PowHashKind::default() && self.retarget == RetargetKind::default() {
+            return genesis_hash;
+        }
+
+        let mut hasher = Sha3_512::new();
+        hasher.update(genesis_hash);
+        hasher.update(serde_json::to_vec(&self.pow_hash).expect("pow hash kind is serializable"));
+        hasher.update(serde_json::to_vec(&self.retarget).expect("retarget kind is serializable"));
+        let mut network_id = [0u8; HASH_LEN];
+        network_id.copy_from_slice(&hasher.finalize());
+        network_id
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.epoch_size < 2 {
+            bail!("epoch_size must be at least 2, got {}", self.epoch_size);
+        }
+        if self.target_block_mining_time_seconds == 0 {
+            bail!("target_block_mining_time_seconds must be positive");
+        }
+        self.pow_hash.validate()?;
+        self.retarget.validate()
+    }
+
+    pub fn make_pow_hash(&self) -> Box<dyn PowHash> {
+        match self.pow_hash {
+            PowHashKind::Sha3 => Box::new(Sha3),
+            PowHashKind::Sha3MemoryHard { memory_kib } => Box::new(Sha3MemoryHard::new(memory_kib)),
+        }
+    }
+
+    pub fn make_retarget(&self) -> Box<dyn Retarget> {
+        match self.retarget {
+            RetargetKind::Epoch => Box::new(EpochRetarget {
+                epoch_size: self.epoch_size,
+                target_block_mining_time_seconds: self.target_block_mining_time_seconds,
+            }),
+            RetargetKind::MovingWindow { window } => Box::new(MovingWindowRetarget {
+                window,
+                target_block_mining_time_seconds: self.target_block_mining_time_seconds,
+            }),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainPreset {
+    #[default]
+    Mainnet,
+    Regtest,
+}
+
+impl FromStr for ChainPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mainnet" => Ok(Self::Mainnet),
+            "regtest" => Ok(Self::Regtest),
+            _ => bail!("unknown chain preset {:?}", s),
+        }
+    }
+}
+
+/// A preset with optional overrides, as it appears in the node config.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainConfig {
+    pub preset: ChainPreset,
+    pub epoch_size: Option<usize>,
+    pub target_block_mining_time_seconds: Option<u64>,
+    pub max_reward: Option<u64>,
+    pub genesis_timestamp: Option<i64>,
+    /// Wallet id in the same format as the miner's `public_key`.
+    pub genesis_issuer: Option<String>,
+    pub pow_hash: Option<PowHashKind>,
+    pub retarget: Option<RetargetKind>,
+}
+
+impl ChainConfig {
+    pub fn params(&self) -> Result<ChainParams> {
+        let mut params = ChainParams::from_preset(self.preset);
+        if let Some(epoch_size) = self.epoch_size {
+            params.epoch_size = epoch_size;
+        }
+        if let Some(seconds) = self.target_block_mining_time_seconds {
+            params.target_block_mining_time_seconds = seconds;
+        }
+        if let Some(max_reward) = self.max_reward {
+            params.max_reward = max_reward;
+        }
+        if let Some(timestamp) = self.genesis_timestamp {
+            params.genesis_timestamp = timestamp;
+        }
+        if let Some(issuer) = self.genesis_issuer.as_ref() {
+            params.genesis_issuer =
+                decode_wallet_id(issuer).context("failed to decode genesis_issuer")?;
+        }
+        if let Some(pow_hash) = self.pow_hash {
+            params.pow_hash = pow_hash;
+        }
+        if let Some(retarget) = self.retarget {
+            params.retarget = retarget;
+        }
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_forest::BlockForest,
+        data::{Block, VerifiedBlock},
+    };
+
+    use chrono::Duration;
+
+    #[test]
+    fn test_config() {
+        let config: ChainConfig = serde_yaml::from_str("preset: regtest\nmax_reward: 5\n").unwrap();
+        let params = config.params().unwrap();
+        assert_eq!(params.epoch_size, ChainParams::regtest().epoch_size);
+        assert_eq!(params.max_reward, 5);
+
+        let config: ChainConfig = serde_yaml::from_str(
+            "retarget:\n  kind: moving_window\n  window: 8\npow_hash:\n  kind: sha3_memory_hard\n  memory_kib: 64\n",
+        )
+        .unwrap();
+        let params = config.params().unwrap();
+        assert_eq!(params.retarget, RetargetKind::MovingWindow { window: 8 });
+        assert_eq!(
+            params.pow_hash,
+            PowHashKind::Sha3MemoryHard { memory_kib: 64 }
+        );
+
+        let config: ChainConfig = serde_yaml::from_str("epoch_size: 1\n").unwrap();
+        assert!(config.params().is_err());
+        let config: ChainConfig =
+            serde_yaml::from_str("retarget:\n  kind: moving_window\n  window: 1\n").unwrap();
+        assert!(config.params().is_err());
+        assert_eq!(
+            ChainConfig::default().params().unwrap(),
+            ChainParams::mainnet()
+        );
+    }
+
+    #[test]
+    fn test_separate_networks() {
+        let mainnet = ChainParams::mainnet();
+        let regtest = ChainParams::regtest();
+        assert_ne!(
+            Block::genesis_for(&mainnet).compute_hash(),
+            Block::genesis_for(&regtest).compute_hash()
+        );
+
+        let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+        block.clone().verified_for(&mainnet).unwrap();
+        assert!(block.verified_for(&regtest).is_err());
+
+        assert_eq!(
+            mainnet.network_id(),
+            Block::genesis_for(&mainnet).compute_hash()
+        );
+        assert_ne!(mainnet.network_id(), regtest.network_id());
+        let memory_hard = ChainParams {
+            pow_hash: PowHashKind::Sha3MemoryHard { memory_kib: 64 },
+            ..mainnet.clone()
+        };
+        let moving_window = ChainParams {
+            retarget: RetargetKind::MovingWindow { window: 8 },
+            ..mainnet.clone()
+        };
+        assert_ne!(mainnet.network_id(), memory_hard.network_id());
+        assert_ne!(mainnet.network_id(), moving_window.network_id());
+        assert_ne!(memory_hard.network_id(), moving_window.network_id());
+    }
+
+    #[test]
+    fn test_epoch_size() {
+        let params = ChainParams {
+            target_block_mining_time_seconds: 2,
+            ..ChainParams::regtest()
+        };
+        let mut forest = BlockForest::with_params(params.clone());
+
+        let mut prev = VerifiedBlock::genesis_for(&params);
+        for index in 1..params.epoch_size as u64 {
+            let mut block = Block::genesis_for(&params);
+            block.attrs.index = index;
+            block.attrs.prev_hash = *prev.hash();
+            block.attrs.timestamp = prev.timestamp + Duration::seconds(1);
+            let block = block.verified_for(&params).unwrap();
+            forest.add_block(block.clone()).unwrap();
+            prev = block;
+        }
+
+        // NB: blocks come twice as fast as the target, so the difficulty doubles.
+        let max_hash = forest.next_max_hash();
+        assert_eq!(max_hash[0], 0x7f);
+        assert!(max_hash[1..].iter().all(|byte| *byte == 0xff));
+    }
+}
@@ -1,6 +1,12 @@
-use crate::util::{
-    deserialize_base64, deserialize_base64_fixed, deserialize_utc, deserialize_wallet_id,
-    parse_pkcs8_public, serialize_base64, serialize_utc, serialize_wallet_id,
+use crate::{
+    chain_params::ChainParams,
+    merkle::{self, InclusionProof, MerkleBranch},
+    util::{
+        deserialize_base64, deserialize_base64_blobs, deserialize_base64_fixed,
+        deserialize_base64_vec, deserialize_utc, deserialize_wallet_id, deserialize_wallet_id_vec,
+        serialize_base64, serialize_base64_vec, serialize_utc, serialize_wallet_id,
+        serialize_wallet_id_vec,
+    },
 };
 
 use anyhow::{bail, Context, Result};
@@ -17,9 +23,27 @@ use std::{
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub const GENESIS_TIMESTAMP: i64 = 1626002428;
-pub const MAX_REWARD: u64 = 1000;
 pub const HASH_LEN: usize = 64;
+pub const MAX_LOCATOR_LEN: usize = 64;
+pub const MAX_HEADERS_PER_MESSAGE: usize = 32;
+pub const MAX_PEERS_PER_MESSAGE: usize = 64;
+pub const MAX_PEER_ADDRESS_LEN: usize = 256;
+
+/// Version 2 introduced multisig and time-locked transactions, version 3 introduced merkle
+/// roots in block headers. Version 3 is a hard fork: a block hash covers only the header now,
+/// so every block hash changed, the genesis one included.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Peers speaking an older protocol are dropped during the handshake, before version 3 they
+/// are on another chain anyway.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// The peer answers `GetPeers`.
+pub const FEATURE_PEER_EXCHANGE: u64 = 1 << 0;
+/// The peer understands `Goodbye`.
+pub const FEATURE_GOODBYE: u64 = 1 << 1;
+pub const SUPPORTED_FEATURES: u64 = FEATURE_PEER_EXCHANGE | FEATURE_GOODBYE;
 
 pub type BlockHash = [u8; HASH_LEN];
 pub type TransactionHash = [u8; HASH_LEN];
@@ -27,34 +51,172 @@ pub type TransactionHash = [u8; HASH_LEN];
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Clone, Debug, Eq)]
-pub struct WalletId {
-    pub public_key: RSAPublicKey,
+pub enum WalletId {
+    /// Owned by a single RSA key.
+    Key(RSAPublicKey),
+    /// Escrow wallet, identified by the hash of its `MultisigPolicy`.
+    Multisig(WalletHash),
 }
 
+pub type WalletHash = [u8; HASH_LEN];
+
 impl WalletId {
-    pub fn genesis() -> Self {
-        parse_pkcs8_public(include_str!("../data/genesis.crt"))
-            .unwrap()
-            .into()
+    pub fn public_key(&self) -> Option<&RSAPublicKey> {
+        match self {
+            Self::Key(public_key) => Some(public_key),
+            Self::Multisig(_) => None,
+        }
+    }
+
+    fn update_hasher(&self, hasher: &mut Sha3_512) {
+        match self {
+            Self::Key(public_key) => {
+                hasher.update(public_key.n().to_bytes_le());
+                hasher.update(public_key.e().to_bytes_le());
+            }
+            Self::Multisig(hash) => hasher.update(hash),
+        }
     }
 }
 
 impl Hash for WalletId {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.public_key.n().hash(state);
-        self.public_key.e().hash(state);
+        match self {
+            Self::Key(public_key) => {
+                public_key.n().hash(state);
+                public_key.e().hash(state);
+            }
+            Self::Multisig(hash) => hash.hash(state),
+        }
     }
 }
 
 impl PartialEq for WalletId {
     fn eq(&self, other: &Self) -> bool {
-        self.public_key.n() == other.public_key.n() && self.public_key.e() == other.public_key.e()
+        match (self, other) {
+            (Self::Key(lhs), Self::Key(rhs)) => lhs.n() == rhs.n() && lhs.e() == rhs.e(),
+            (Self::Multisig(lhs), Self::Multisig(rhs)) => lhs == rhs,
+            _ => false,
+        }
     }
 }
 
 impl From<RSAPublicKey> for WalletId {
     fn from(public_key: RSAPublicKey) -> Self {
-        Self { public_key }
+        Self::Key(public_key)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Funds of an escrow wallet can be spent by any `threshold` of its `keys`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub threshold: u32,
+
+    #[serde(
+        serialize_with = "serialize_wallet_id_vec",
+        deserialize_with = "deserialize_wallet_id_vec"
+    )]
+    pub keys: Vec<WalletId>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u32, keys: Vec<WalletId>) -> Result<Self> {
+        let policy = Self { threshold, keys };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn wallet_id(&self) -> WalletId {
+        WalletId::Multisig(self.compute_hash())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.keys.len() > MAX_MULTISIG_KEYS {
+            bail!("multisig policy has more than {} keys", MAX_MULTISIG_KEYS);
+        }
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            bail!(
+                "multisig threshold must be between 1 and {}, got {}",
+                self.keys.len(),
+                self.threshold
+            );
+        }
+        for (i, key) in self.keys.iter().enumerate() {
+            if key.public_key().is_none() {
+                bail!("multisig policy keys can't be multisig wallets");
+            }
+            if self.keys[..i].contains(key) {
+                bail!("multisig policy has duplicate keys");
+            }
+        }
+        Ok(())
+    }
+
+    fn compute_hash(&self) -> WalletHash {
+        let mut hasher = Sha3_512::new();
+        hasher.write_u32::<LittleEndian>(self.threshold).unwrap();
+        for key in self.keys.iter() {
+            let public_key = key.public_key().expect("policy is validated");
+            for part in [public_key.n(), public_key.e()] {
+                let bytes = part.to_bytes_le();
+                hasher
+                    .write_u64::<LittleEndian>(bytes.len() as u64)
+                    .unwrap();
+                hasher.update(bytes);
+            }
+        }
+
+        let mut hash = [0u8; HASH_LEN];
+        hash.copy_from_slice(&hasher.finalize());
+        hash
+    }
+}
+
+/// Reveals the policy of a multisig sender along with the signatures of its keys.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MultisigSpend {
+    pub policy: MultisigPolicy,
+
+    /// One entry per policy key, empty for the keys that didn't sign.
+    #[serde(
+        serialize_with = "serialize_base64_vec",
+        deserialize_with = "deserialize_base64_blobs"
+    )]
+    pub signatures: Vec<Vec<u8>>,
+}
+
+impl MultisigSpend {
+    fn verify(&self, sender: &WalletHash, hash: &TransactionHash) -> Result<()> {
+        self.policy.validate()?;
+        if self.policy.compute_hash() != *sender {
+            bail!("multisig policy doesn't match the sender");
+        }
+        if self.signatures.len() != self.policy.keys.len() {
+            bail!("expected one signature slot per multisig key");
+        }
+
+        let mut signature_count = 0;
+        for (key, signature) in self.policy.keys.iter().zip(self.signatures.iter()) {
+            if signature.is_empty() {
+                continue;
+            }
+            key.public_key().expect("policy is validated").verify(
+                PaddingScheme::PKCS1v15Sign { hash: None },
+                hash,
+                signature,
+            )?;
+            signature_count += 1;
+        }
+        if signature_count < self.policy.threshold {
+            bail!(
+                "transaction has {} of {} required signatures",
+                signature_count,
+                self.policy.threshold
+            );
+        }
+        Ok(())
     }
 }
 
@@ -64,6 +226,23 @@ impl From<RSAPublicKey> for WalletId {
 #[serde(tag = "kind")]
 #[serde(rename_all = "lowercase")]
 pub enum PeerMessage {
+    /// The first message each side sends, nothing else is accepted before it.
+    Hello {
+        protocol_version: u32,
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+        )]
+        network_id: BlockHash,
+        head_index: u64,
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+        )]
+        head_hash: BlockHash,
+        /// Bitmask of `FEATURE_*` flags, unknown bits are ignored.
+        features: u64,
+    },
     Block(Box<Block>),
     Transaction(Box<Transaction>),
     Request {
@@ -73,14 +252,113 @@ pub enum PeerMessage {
         )]
         block_hash: BlockHash,
     },
+    GetHeaders {
+        #[serde(
+            serialize_with = "serialize_base64_vec",
+            deserialize_with = "deserialize_base64_vec::<'_, _, HASH_LEN>"
+        )]
+        locator: Vec<BlockHash>,
+    },
+    Headers {
+        headers: Vec<BlockAttributes>,
+    },
+    GetBlocks {
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+        )]
+        from_hash: BlockHash,
+        count: u64,
+    },
+    GetPeers,
+    Peers {
+        peers: Vec<PeerAddress>,
+    },
+    /// Asks for an `InclusionProof` of a transaction in a block, answered only if the peer
+    /// has the block and the transaction is in it.
+    GetProof {
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+        )]
+        block_hash: BlockHash,
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+        )]
+        transaction_hash: TransactionHash,
+    },
+    Proof(Box<InclusionProof>),
+    /// The sender is shutting down and closes the connection, it's not a failure.
+    Goodbye,
 }
 
 impl PeerMessage {
-    pub fn verified(self) -> Result<VerifiedPeerMessage> {
+    /// Verifies the message, blocks are checked against the chain of `params`.
+    pub fn verified_for(self, params: &ChainParams) -> Result<VerifiedPeerMessage> {
         match self {
-            Self::Block(block) => Ok(VerifiedPeerMessage::Block(Box::new(block.verified()?))),
+            Self::Hello {
+                protocol_version,
+                network_id,
+                head_index,
+                head_hash,
+                features,
+            } => Ok(VerifiedPeerMessage::Hello {
+                protocol_version,
+                network_id,
+                head_index,
+                head_hash,
+                features,
+            }),
+            Self::Block(block) => Ok(VerifiedPeerMessage::Block(Box::new(
+                block.verified_for(params)?,
+            ))),
             Self::Transaction(tx) => Ok(VerifiedPeerMessage::Transaction(Box::new(tx.verified()?))),
             Self::Request { block_hash } => Ok(VerifiedPeerMessage::Request { block_hash }),
+            Self::GetHeaders { locator } => {
+                if locator.is_empty() || locator.len() > MAX_LOCATOR_LEN {
+                    bail!("block locator has {} hashes", locator.len());
+                }
+                Ok(VerifiedPeerMessage::GetHeaders { locator })
+            }
+            Self::Headers { headers } => {
+                if headers.len() > MAX_HEADERS_PER_MESSAGE {
+                    bail!("too many headers in a message: {}", headers.len());
+                }
+                for (prev, cur) in headers.iter().zip(headers.iter().skip(1)) {
+                    if prev.index.checked_add(1) != Some(cur.index) {
+                        bail!("headers are not consecutive");
+                    }
+                }
+                Ok(VerifiedPeerMessage::Headers { headers })
+            }
+            Self::GetBlocks { from_hash, count } => {
+                Ok(VerifiedPeerMessage::GetBlocks { from_hash, count })
+            }
+            Self::GetPeers => Ok(VerifiedPeerMessage::GetPeers),
+            Self::Peers { peers } => {
+                if peers.len() > MAX_PEERS_PER_MESSAGE {
+                    bail!("too many peers in a message: {}", peers.len());
+                }
+                for peer in peers.iter() {
+                    if peer.address.is_empty() || peer.address.len() > MAX_PEER_ADDRESS_LEN {
+                        bail!("peer address has invalid length: {}", peer.address.len());
+                    }
+                }
+                Ok(VerifiedPeerMessage::Peers { peers })
+            }
+            Self::GetProof {
+                block_hash,
+                transaction_hash,
+            } => Ok(VerifiedPeerMessage::GetProof {
+                block_hash,
+                transaction_hash,
+            }),
+            Self::Proof(proof) => {
+                proof.verify(&proof.header.compute_hash())?;
+                Ok(VerifiedPeerMessage::Proof(proof))
+            }
+            Self::Goodbye => Ok(VerifiedPeerMessage::Goodbye),
         }
     }
 }
@@ -88,11 +366,40 @@ impl PeerMessage {
 impl From<VerifiedPeerMessage> for PeerMessage {
     fn from(other: VerifiedPeerMessage) -> Self {
         match other {
+            VerifiedPeerMessage::Hello {
+                protocol_version,
+                network_id,
+                head_index,
+                head_hash,
+                features,
+            } => PeerMessage::Hello {
+                protocol_version,
+                network_id,
+                head_index,
+                head_hash,
+                features,
+            },
             VerifiedPeerMessage::Block(block) => PeerMessage::Block(Box::new((*block).into())),
             VerifiedPeerMessage::Transaction(tx) => {
                 PeerMessage::Transaction(Box::new((*tx).into()))
             }
             VerifiedPeerMessage::Request { block_hash } => PeerMessage::Request { block_hash },
+            VerifiedPeerMessage::GetHeaders { locator } => PeerMessage::GetHeaders { locator },
+            VerifiedPeerMessage::Headers { headers } => PeerMessage::Headers { headers },
+            VerifiedPeerMessage::GetBlocks { from_hash, count } => {
+                PeerMessage::GetBlocks { from_hash, count }
+            }
+            VerifiedPeerMessage::GetPeers => PeerMessage::GetPeers,
+            VerifiedPeerMessage::Peers { peers } => PeerMessage::Peers { peers },
+            VerifiedPeerMessage::GetProof {
+                block_hash,
+                transaction_hash,
+            } => PeerMessage::GetProof {
+                block_hash,
+                transaction_hash,
+            },
+            VerifiedPeerMessage::Proof(proof) => PeerMessage::Proof(proof),
+            VerifiedPeerMessage::Goodbye => PeerMessage::Goodbye,
         }
     }
 }
@@ -101,9 +408,47 @@ impl From<VerifiedPeerMessage> for PeerMessage {
 
 #[derive(Clone, Debug)]
 pub enum VerifiedPeerMessage {
+    Hello {
+        protocol_version: u32,
+        network_id: BlockHash,
+        head_index: u64,
+        head_hash: BlockHash,
+        features: u64,
+    },
     Block(Box<VerifiedBlock>),
     Transaction(Box<VerifiedTransaction>),
-    Request { block_hash: BlockHash },
+    Request {
+        block_hash: BlockHash,
+    },
+    GetHeaders {
+        locator: Vec<BlockHash>,
+    },
+    Headers {
+        headers: Vec<BlockAttributes>,
+    },
+    GetBlocks {
+        from_hash: BlockHash,
+        count: u64,
+    },
+    GetPeers,
+    Peers {
+        peers: Vec<PeerAddress>,
+    },
+    GetProof {
+        block_hash: BlockHash,
+        transaction_hash: TransactionHash,
+    },
+    /// Consistent with its own header, it's up to the receiver to check the block hash.
+    Proof(Box<InclusionProof>),
+    Goodbye,
+}
+
+/// A listen address of some peer along with the last time it was known to be alive.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeerAddress {
+    pub address: String,
+    #[serde(serialize_with = "serialize_utc", deserialize_with = "deserialize_utc")]
+    pub last_seen: DateTime<Utc>,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -134,6 +479,37 @@ pub struct BlockAttributes {
         deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
     )]
     pub prev_hash: BlockHash,
+
+    /// Root of the merkle tree over the hashes of block transactions, see `merkle`.
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub merkle_root: TransactionHash,
+}
+
+impl BlockAttributes {
+    /// Block hash. Transactions are covered by the merkle root, so headers are enough.
+    pub fn compute_hash(&self) -> BlockHash {
+        let mut hasher = Sha3_512::new();
+        hasher.write_u64::<LittleEndian>(self.index).unwrap();
+        hasher
+            .write_i64::<LittleEndian>(self.timestamp.timestamp())
+            .unwrap();
+        hasher.write_u64::<LittleEndian>(self.reward).unwrap();
+        hasher.write_u64::<LittleEndian>(self.nonce).unwrap();
+        self.issuer.update_hasher(&mut hasher);
+        hasher.update(self.max_hash);
+        hasher.update(self.prev_hash);
+        hasher.update(self.merkle_root);
+
+        let digest = hasher.finalize();
+        assert_eq!(digest.len(), HASH_LEN);
+
+        let mut hash = [0u8; HASH_LEN];
+        hash.copy_from_slice(&digest);
+        hash
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -160,88 +536,89 @@ impl DerefMut for Block {
 }
 
 impl Block {
+    /// Genesis block of the mainnet.
     pub fn genesis() -> Block {
+        Self::genesis_for(&ChainParams::mainnet())
+    }
+
+    pub fn genesis_for(params: &ChainParams) -> Block {
         Block {
             attrs: BlockAttributes {
                 index: 0,
-                timestamp: Utc.timestamp_opt(GENESIS_TIMESTAMP, 0).unwrap(),
+                timestamp: Utc.timestamp_opt(params.genesis_timestamp, 0).unwrap(),
                 reward: 0,
                 nonce: 0,
-                issuer: WalletId::genesis(),
+                issuer: params.genesis_issuer.clone(),
                 max_hash: [255u8; HASH_LEN],
                 prev_hash: [0u8; HASH_LEN],
+                merkle_root: merkle::compute_root(&[]),
             },
             transactions: vec![],
         }
     }
 
     pub fn compute_hash(&self) -> BlockHash {
-        Self::compute_hash_inner(
-            &self.attrs,
-            self.transactions.iter().map(|tx| tx.compute_hash()),
-        )
+        self.attrs.compute_hash()
+    }
+
+    /// Must be called whenever transactions change, the root is a part of the block hash.
+    pub fn update_merkle_root(&mut self) {
+        let hashes: Vec<_> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.compute_hash())
+            .collect();
+        self.attrs.merkle_root = merkle::compute_root(&hashes);
     }
 
-    pub fn verified(self) -> Result<VerifiedBlock> {
-        if self.timestamp.timestamp() < GENESIS_TIMESTAMP {
+    pub fn verified_for(self, params: &ChainParams) -> Result<VerifiedBlock> {
+        if self.timestamp.timestamp() < params.genesis_timestamp {
             bail!("block timestamp is less than genesis timestamp");
         }
         if self.timestamp > Utc::now() {
             bail!("block timestamp is greater than now");
         }
-        if self.reward > MAX_REWARD {
+        if self.reward > params.max_reward {
             bail!("block reward is greater than max reward");
         }
-        if self.index == 0 && self != Self::genesis() {
+        if self.issuer.public_key().is_none() {
+            bail!("block issuer must be a single-key wallet");
+        }
+        let genesis = Self::genesis_for(params);
+        if self.index == 0 && self != genesis {
             bail!("block index is 0, but not the genesis block");
         }
-        if self.index == 1 && self.prev_hash != VerifiedBlock::genesis().hash {
+        if self.index == 1 && self.prev_hash != genesis.compute_hash() {
             bail!("block index is 1, but prev_hash != genesis");
         }
 
+        // NB: cheap checks go first, blocks are verified before anything is known about
+        // their sender.
+        let hashes: Vec<_> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.compute_hash())
+            .collect();
+        if merkle::compute_root(&hashes) != self.attrs.merkle_root {
+            bail!("merkle root doesn't match block transactions");
+        }
+
+        let hash = self.attrs.compute_hash();
+        if params.make_pow_hash().compute(&hash) > self.attrs.max_hash {
+            bail!("proof of work hash is greater than max_hash");
+        }
+
         let mut transactions = Vec::with_capacity(self.transactions.len());
         for tx in self.transactions.into_iter() {
             transactions.push(tx.verified().context("transaction verification failed")?);
         }
 
-        let hash = Self::compute_hash_inner(&self.attrs, transactions.iter().map(|tx| *tx.hash()));
-        if hash > self.attrs.max_hash {
-            bail!("block hash is greater than max_hash");
-        }
-
         Ok(VerifiedBlock {
             attrs: self.attrs,
             transactions,
             hash,
         })
     }
-
-    fn compute_hash_inner(
-        attrs: &BlockAttributes,
-        transaction_hashes: impl IntoIterator<Item = TransactionHash>,
-    ) -> BlockHash {
-        let mut hasher = Sha3_512::new();
-        hasher.write_u64::<LittleEndian>(attrs.index).unwrap();
-        hasher
-            .write_i64::<LittleEndian>(attrs.timestamp.timestamp())
-            .unwrap();
-        hasher.write_u64::<LittleEndian>(attrs.reward).unwrap();
-        hasher.write_u64::<LittleEndian>(attrs.nonce).unwrap();
-        hasher.update(attrs.issuer.public_key.n().to_bytes_le());
-        hasher.update(attrs.issuer.public_key.e().to_bytes_le());
-        hasher.update(attrs.max_hash);
-        hasher.update(attrs.prev_hash);
-        for tx_hash in transaction_hashes.into_iter() {
-            hasher.update(tx_hash);
-        }
-
-        let digest = hasher.finalize();
-        assert_eq!(digest.len(), HASH_LEN);
-
-        let mut hash = [0u8; HASH_LEN];
-        hash.copy_from_slice(&digest);
-        hash
-    }
 }
 
 impl From<VerifiedBlock> for Block {
@@ -271,8 +648,13 @@ impl Deref for VerifiedBlock {
 }
 
 impl VerifiedBlock {
+    /// Genesis block of the mainnet.
     pub fn genesis() -> VerifiedBlock {
-        Block::genesis().verified().unwrap()
+        Self::genesis_for(&ChainParams::mainnet())
+    }
+
+    pub fn genesis_for(params: &ChainParams) -> VerifiedBlock {
+        Block::genesis_for(params).verified_for(params).unwrap()
     }
 
     pub fn hash(&self) -> &BlockHash {
@@ -283,6 +665,16 @@ impl VerifiedBlock {
         &self.transactions
     }
 
+    pub fn inclusion_proof(&self, transaction_hash: &TransactionHash) -> Option<InclusionProof> {
+        let hashes: Vec<_> = self.transactions.iter().map(|tx| *tx.hash()).collect();
+        let index = hashes.iter().position(|hash| hash == transaction_hash)?;
+        Some(InclusionProof {
+            header: self.attrs.clone(),
+            transaction_hash: *transaction_hash,
+            branch: MerkleBranch::new(&hashes, index),
+        })
+    }
+
     pub fn to_block(&self) -> Block {
         Block {
             attrs: self.attrs.clone(),
@@ -315,35 +707,114 @@ pub struct Transaction {
     )]
     pub receiver: WalletId,
 
+    /// Signature of a single-key sender, empty for multisig ones.
     #[serde(
         serialize_with = "serialize_base64",
         deserialize_with = "deserialize_base64"
     )]
     pub signature: Vec<u8>,
+
+    /// Set iff the sender is a multisig wallet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigSpend>,
+
+    /// The transaction can't get into blocks with a lower index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_until_index: Option<u64>,
 }
 
 impl Transaction {
+    /// Builds an unsigned transaction, see `add_signature`.
+    pub fn new(
+        sender: WalletId,
+        receiver: WalletId,
+        amount: u64,
+        fee: u64,
+        comment: String,
+    ) -> Transaction {
+        Transaction {
+            amount,
+            fee,
+            comment,
+            sender,
+            receiver,
+            signature: vec![],
+            multisig: None,
+            lock_until_index: None,
+        }
+    }
+
+    /// Builds an unsigned transaction spending from the escrow wallet of `policy`.
+    pub fn new_multisig(
+        policy: MultisigPolicy,
+        receiver: WalletId,
+        amount: u64,
+        fee: u64,
+        comment: String,
+    ) -> Transaction {
+        let mut transaction = Self::new(policy.wallet_id(), receiver, amount, fee, comment);
+        transaction.multisig = Some(MultisigSpend {
+            signatures: vec![vec![]; policy.keys.len()],
+            policy,
+        });
+        transaction
+    }
+
+    /// Signs the transaction with one of the sender's keys. All the other fields must be
+    /// final by then, since they are covered by the signature.
+    pub fn add_signature(&mut self, key: &RSAPrivateKey) -> Result<()> {
+        let hash = self.compute_hash();
+        let signature = key.sign(PaddingScheme::PKCS1v15Sign { hash: None }, &hash)?;
+
+        let wallet: WalletId = key.to_public_key().into();
+        if let Some(spend) = self.multisig.as_mut() {
+            let Some(index) = spend.policy.keys.iter().position(|key| *key == wallet) else {
+                bail!("key is not in the multisig policy");
+            };
+            spend.signatures[index] = signature;
+        } else if self.sender == wallet {
+            self.signature = signature;
+        } else {
+            bail!("key doesn't belong to the sender");
+        }
+        Ok(())
+    }
+
     pub fn verified(self) -> Result<VerifiedTransaction> {
         let hash = self.compute_hash();
 
-        self.sender.public_key.verify(
-            PaddingScheme::PKCS1v15Sign { hash: None },
-            &hash,
-            &self.signature,
-        )?;
+        match (&self.sender, self.multisig.as_ref()) {
+            (WalletId::Key(public_key), None) => public_key.verify(
+                PaddingScheme::PKCS1v15Sign { hash: None },
+                &hash,
+                &self.signature,
+            )?,
+            (WalletId::Multisig(sender), Some(spend)) => {
+                if !self.signature.is_empty() {
+                    bail!("multisig transaction has a single-key signature");
+                }
+                spend.verify(sender, &hash)?;
+            }
+            (WalletId::Key(_), Some(_)) => bail!("single-key sender has multisig signatures"),
+            (WalletId::Multisig(_), None) => bail!("multisig sender has no multisig signatures"),
+        }
 
         Ok(VerifiedTransaction { inner: self, hash })
     }
 
+    /// Signatures are not covered, the rest is. Plain transactions hash the same way they
+    /// did before multisig and time locks were introduced.
     pub fn compute_hash(&self) -> TransactionHash {
         let mut hasher = Sha3_512::new();
         hasher.write_u64::<LittleEndian>(self.amount).unwrap();
         hasher.write_u64::<LittleEndian>(self.fee).unwrap();
         hasher.update(self.comment.as_bytes());
-        hasher.update(self.sender.public_key.n().to_bytes_le());
-        hasher.update(self.sender.public_key.e().to_bytes_le());
-        hasher.update(self.receiver.public_key.n().to_bytes_le());
-        hasher.update(self.receiver.public_key.e().to_bytes_le());
+        self.sender.update_hasher(&mut hasher);
+        self.receiver.update_hasher(&mut hasher);
+        if let Some(index) = self.lock_until_index {
+            hasher.update(b"lock_until_index");
+            hasher.write_u64::<LittleEndian>(index).unwrap();
+        }
 
         let digest = hasher.finalize();
         assert_eq!(digest.len(), HASH_LEN);
@@ -391,18 +862,16 @@ impl VerifiedTransaction {
         fee: u64,
         comment: String,
     ) -> Result<VerifiedTransaction> {
-        let mut transaction = Transaction {
-            sender: sender.to_public_key().into(),
-            signature: vec![],
+        let mut transaction = Transaction::new(
+            sender.to_public_key().into(),
             receiver,
             amount,
             fee,
             comment,
-        };
+        );
+        transaction.add_signature(sender)?;
 
         let hash = transaction.compute_hash();
-        transaction.signature = sender.sign(PaddingScheme::PKCS1v15Sign { hash: None }, &hash)?;
-
         Ok(VerifiedTransaction {
             inner: transaction,
             hash,
@@ -417,10 +886,15 @@ mod tests {
     use super::*;
     use crate::util::parse_pkcs8_private;
 
+    use rand::thread_rng;
+    use rsa::algorithms::generate_multi_prime_key;
+
     #[test]
     fn test_genesis() {
         VerifiedBlock::genesis();
-        Block::genesis().verified().unwrap();
+        Block::genesis()
+            .verified_for(&ChainParams::mainnet())
+            .unwrap();
     }
 
     #[test]
@@ -431,38 +905,99 @@ mod tests {
         (&tx as &Transaction).clone().verified().unwrap();
     }
 
+    #[test]
+    fn test_multisig() {
+        let keys: Vec<_> = (0..3)
+            .map(|_| generate_multi_prime_key(&mut thread_rng(), 32, 1024).unwrap())
+            .collect();
+        let policy = MultisigPolicy::new(
+            2,
+            keys.iter().map(|key| key.to_public_key().into()).collect(),
+        )
+        .unwrap();
+        assert!(MultisigPolicy::new(4, policy.keys.clone()).is_err());
+        assert!(MultisigPolicy::new(1, vec![policy.wallet_id()]).is_err());
+
+        let mut tx =
+            Transaction::new_multisig(policy, Block::genesis().issuer.clone(), 10, 1, "".into());
+        tx.add_signature(&keys[2]).unwrap();
+        assert!(tx.clone().verified().is_err());
+        tx.add_signature(&keys[0]).unwrap();
+        tx.clone().verified().unwrap();
+
+        let json = serde_json::to_string(&tx).unwrap();
+        assert_eq!(serde_json::from_str::<Transaction>(&json).unwrap(), tx);
+
+        // NB: the sender commits to the policy, it can't be swapped for another one.
+        let mut forged = tx.clone();
+        forged.multisig.as_mut().unwrap().policy.threshold = 1;
+        assert!(forged.verified().is_err());
+
+        let mut locked = tx;
+        locked.lock_until_index = Some(10);
+        assert!(locked.verified().is_err());
+    }
+
+    #[test]
+    fn test_headers() {
+        let params = ChainParams::mainnet();
+        let genesis = Block::genesis().attrs.clone();
+        let mut next = genesis.clone();
+        next.index += 1;
+        let headers = |headers: &[&BlockAttributes]| PeerMessage::Headers {
+            headers: headers.iter().map(|&header| header.clone()).collect(),
+        };
+
+        headers(&[&genesis, &next]).verified_for(&params).unwrap();
+        assert!(headers(&[&next, &genesis]).verified_for(&params).is_err());
+
+        let mut last = genesis.clone();
+        last.index = u64::MAX;
+        headers(&[&last]).verified_for(&params).unwrap();
+        assert!(headers(&[&last, &genesis]).verified_for(&params).is_err());
+    }
+
     #[test]
     fn test_block_json() {
+        let params = ChainParams::mainnet();
         let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
-        let verified = block.verified().unwrap();
+        let verified = block.verified_for(&params).unwrap();
 
         let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
         let genesis = VerifiedBlock::genesis();
 
-        assert_eq!(
-            verified,
-            Block {
-                attrs: BlockAttributes {
-                    index: 1,
-                    reward: MAX_REWARD,
-                    nonce: 27532,
-                    timestamp: Utc.timestamp_opt(1626003028, 0).unwrap(),
-                    issuer: priv_key.to_public_key().into(),
-                    max_hash: [255u8; HASH_LEN],
-                    prev_hash: *genesis.hash(),
-                },
-                transactions: vec![VerifiedTransaction::sign(
-                    &priv_key,
-                    genesis.issuer.clone(),
-                    500,
-                    30,
-                    "hi".into()
-                )
-                .unwrap()
-                .into(),],
-            }
-            .verified()
+        let mut expected = Block {
+            attrs: BlockAttributes {
+                index: 1,
+                reward: params.max_reward,
+                nonce: 27532,
+                timestamp: Utc.timestamp_opt(1626003028, 0).unwrap(),
+                issuer: priv_key.to_public_key().into(),
+                max_hash: [255u8; HASH_LEN],
+                prev_hash: *genesis.hash(),
+                merkle_root: [0u8; HASH_LEN],
+            },
+            transactions: vec![VerifiedTransaction::sign(
+                &priv_key,
+                genesis.issuer.clone(),
+                500,
+                30,
+                "hi".into(),
+            )
             .unwrap()
-        );
+            .into()],
+        };
+        expected.update_merkle_root();
+        assert_eq!(verified, expected.verified_for(&params).unwrap());
+
+        let tx_hash = *verified.transactions()[0].hash();
+        let proof = verified.inclusion_proof(&tx_hash).unwrap();
+        proof.verify(verified.hash()).unwrap();
+        assert!(proof.verify(genesis.hash()).is_err());
+        assert!(verified.inclusion_proof(&[0u8; HASH_LEN]).is_none());
+
+        let mut tampered = verified.to_block();
+        tampered.transactions.clear();
+        assert!(tampered.verified_for(&params).is_err());
     }
 }
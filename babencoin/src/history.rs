@@ -0,0 +1,102 @@
+//! Optional index of main chain balance changes by wallet, see
+//! `BlockForest::enable_history_index`.
+
+use crate::{
+    data::{BlockHash, TransactionHash, VerifiedBlock, WalletId},
+    util::{serialize_base64, serialize_base64_option},
+};
+
+use serde::Serialize;
+
+use std::collections::HashMap;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    #[serde(serialize_with = "serialize_base64")]
+    pub block_hash: BlockHash,
+    pub block_index: u64,
+    /// None for the block reward along with the fees of its transactions.
+    #[serde(serialize_with = "serialize_base64_option")]
+    pub transaction_hash: Option<TransactionHash>,
+    /// Balance change. A sender pays the amount and the fee, a receiver gets the amount.
+    pub delta: i128,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConfirmedEntry {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    /// One for an entry in the head block.
+    pub confirmations: u64,
+}
+
+/// Entries of every wallet in main chain order. Blocks leave the main chain from the head,
+/// so their entries are always at the tails of the lists.
+#[derive(Default)]
+pub struct HistoryIndex {
+    entries: HashMap<WalletId, Vec<HistoryEntry>>,
+}
+
+impl HistoryIndex {
+    pub fn entries(&self, wallet: &WalletId) -> &[HistoryEntry] {
+        self.entries
+            .get(wallet)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Records a block that joined the main chain as the new head.
+    pub fn apply_block(&mut self, block: &VerifiedBlock) {
+        let mut push = |wallet: &WalletId, transaction_hash, delta| {
+            self.entries
+                .entry(wallet.clone())
+                .or_default()
+                .push(HistoryEntry {
+                    block_hash: *block.hash(),
+                    block_index: block.index,
+                    transaction_hash,
+                    delta,
+                });
+        };
+
+        let mut reward = block.reward as i128;
+        for tx in block.transactions() {
+            let hash = Some(*tx.hash());
+            reward += tx.fee as i128;
+            if tx.sender == tx.receiver {
+                push(&tx.sender, hash, -(tx.fee as i128));
+            } else {
+                push(&tx.sender, hash, -(tx.amount as i128) - tx.fee as i128);
+                push(&tx.receiver, hash, tx.amount as i128);
+            }
+        }
+        if reward > 0 {
+            push(&block.issuer, None, reward);
+        }
+    }
+
+    /// Forgets the head block when it leaves the main chain.
+    pub fn revert_block(&mut self, block: &VerifiedBlock) {
+        let wallets = block
+            .transactions()
+            .iter()
+            .flat_map(|tx| [&tx.sender, &tx.receiver])
+            .chain([&block.issuer]);
+        for wallet in wallets {
+            let Some(entries) = self.entries.get_mut(wallet) else {
+                continue;
+            };
+            while entries
+                .last()
+                .is_some_and(|entry| entry.block_hash == *block.hash())
+            {
+                entries.pop();
+            }
+            if entries.is_empty() {
+                self.entries.remove(wallet);
+            }
+        }
+    }
+}
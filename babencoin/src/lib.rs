@@ -1,6 +1,12 @@
 #![forbid(unsafe_code)]
 
 pub mod block_forest;
+pub mod chain_params;
 pub mod data;
+pub mod history;
+pub mod mempool;
+pub mod merkle;
 pub mod node;
+pub mod pow;
+pub mod storage;
 pub mod util;
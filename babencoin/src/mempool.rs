@@ -0,0 +1,291 @@
+use crate::data::{Transaction, TransactionHash, VerifiedTransaction};
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MempoolConfig {
+    pub max_transactions: usize,
+    pub max_bytes: usize,
+    /// Pending transactions older than this are dropped. The age of transactions restored
+    /// from storage starts anew.
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_transactions: 10000,
+            max_bytes: 16 << 20,
+            ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Entry {
+    size: usize,
+    received_at: Instant,
+}
+
+/// Pending transactions along with the bookkeeping needed to bound them.
+///
+/// The mempool does not check balances, `BlockForest` decides which transactions get in.
+pub struct Mempool {
+    config: MempoolConfig,
+    transactions: HashMap<TransactionHash, VerifiedTransaction>,
+    entries: HashMap<TransactionHash, Entry>,
+    total_bytes: usize,
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            transactions: HashMap::new(),
+            entries: HashMap::new(),
+            total_bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &MempoolConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: MempoolConfig) {
+        self.config = config;
+    }
+
+    pub fn transactions(&self) -> &HashMap<TransactionHash, VerifiedTransaction> {
+        &self.transactions
+    }
+
+    pub fn contains(&self, hash: &TransactionHash) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn is_over_limits(&self) -> bool {
+        self.transactions.len() > self.config.max_transactions
+            || self.total_bytes > self.config.max_bytes
+    }
+
+    /// Returns whether `tx` could get in without evicting anything.
+    pub fn has_room_for(&self, tx: &VerifiedTransaction) -> bool {
+        self.transactions.len() < self.config.max_transactions
+            && self.total_bytes + transaction_size(tx) <= self.config.max_bytes
+    }
+
+    pub fn insert(&mut self, tx: VerifiedTransaction) {
+        self.insert_received_at(tx, Instant::now());
+    }
+
+    pub fn remove(&mut self, hash: &TransactionHash) -> Option<VerifiedTransaction> {
+        let entry = self.entries.remove(hash)?;
+        self.total_bytes -= entry.size;
+        self.transactions.remove(hash)
+    }
+
+    /// Replaces the contents with `transactions`, keeping the age of those already known.
+    pub fn replace(&mut self, transactions: Vec<VerifiedTransaction>) {
+        let now = Instant::now();
+        let mut old_entries = std::mem::take(&mut self.entries);
+        self.transactions.clear();
+        self.total_bytes = 0;
+        for tx in transactions {
+            let received_at = old_entries
+                .remove(tx.hash())
+                .map(|entry| entry.received_at)
+                .unwrap_or(now);
+            self.insert_received_at(tx, received_at);
+        }
+    }
+
+    /// Returns the transaction that should be evicted first.
+    pub fn lowest_fee_rate(&self) -> Option<&VerifiedTransaction> {
+        self.transactions
+            .values()
+            .min_by(|a, b| self.compare_fee_rate(a, b))
+    }
+
+    pub fn expired(&self) -> Vec<TransactionHash> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(|(_, entry)| now.duration_since(entry.received_at) >= self.config.ttl)
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    /// Orders transactions by fee per byte, highest first.
+    pub fn sort_by_fee_rate(&self, transactions: &mut [VerifiedTransaction]) {
+        transactions.sort_by(|a, b| self.compare_fee_rate(b, a));
+    }
+
+    fn compare_fee_rate(&self, a: &VerifiedTransaction, b: &VerifiedTransaction) -> Ordering {
+        let size_of = |tx: &VerifiedTransaction| match self.entries.get(tx.hash()) {
+            Some(entry) => entry.size,
+            None => transaction_size(tx),
+        };
+        // NB: compare fee / size without losing precision.
+        let lhs = a.fee as u128 * size_of(b) as u128;
+        let rhs = b.fee as u128 * size_of(a) as u128;
+        lhs.cmp(&rhs).then_with(|| a.hash().cmp(b.hash()))
+    }
+
+    fn insert_received_at(&mut self, tx: VerifiedTransaction, received_at: Instant) {
+        if self.contains(tx.hash()) {
+            return;
+        }
+        let size = transaction_size(&tx);
+        self.total_bytes += size;
+        self.entries.insert(*tx.hash(), Entry { size, received_at });
+        self.transactions.insert(*tx.hash(), tx);
+    }
+}
+
+/// Size of the transaction as it's sent over the wire.
+pub fn transaction_size(tx: &VerifiedTransaction) -> usize {
+    serde_json::to_vec(tx as &Transaction)
+        .map(|data| data.len())
+        .unwrap_or(usize::MAX)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_forest::BlockForest, chain_params::ChainParams, data::Block,
+        util::parse_pkcs8_private,
+    };
+
+    use rand::thread_rng;
+    use rsa::RSAPrivateKey;
+
+    fn make_tx(fee: u64, comment: &str) -> VerifiedTransaction {
+        let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
+        let receiver = priv_key.to_public_key().into();
+        VerifiedTransaction::sign(&priv_key, receiver, 1, fee, comment.into()).unwrap()
+    }
+
+    #[test]
+    fn test_limits() {
+        let cheap = make_tx(1, "a");
+        let expensive = make_tx(10, "b");
+
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_transactions: 1,
+            ..Default::default()
+        });
+        assert!(mempool.has_room_for(&cheap));
+        mempool.insert(cheap.clone());
+        assert!(!mempool.has_room_for(&expensive));
+        assert_eq!(mempool.total_bytes(), transaction_size(&cheap));
+
+        mempool.insert(expensive.clone());
+        assert!(mempool.is_over_limits());
+        assert_eq!(mempool.lowest_fee_rate(), Some(&cheap));
+
+        mempool.remove(cheap.hash());
+        assert!(!mempool.is_over_limits());
+        assert_eq!(mempool.total_bytes(), transaction_size(&expensive));
+    }
+
+    #[test]
+    fn test_fee_rate_order() {
+        let short = make_tx(10, "");
+        let long = make_tx(10, &"x".repeat(1000));
+        let cheap = make_tx(1, "");
+
+        let mempool = Mempool::new(MempoolConfig::default());
+        let mut transactions = vec![cheap.clone(), long.clone(), short.clone()];
+        mempool.sort_by_fee_rate(&mut transactions);
+        assert_eq!(transactions, vec![short, long, cheap]);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let tx = make_tx(1, "a");
+        let mut mempool = Mempool::new(MempoolConfig {
+            ttl: Duration::from_millis(50),
+            ..Default::default()
+        });
+        mempool.insert(tx.clone());
+        assert!(mempool.expired().is_empty());
+
+        std::thread::sleep(Duration::from_millis(60));
+        mempool.replace(vec![tx.clone()]);
+        assert_eq!(mempool.expired(), vec![*tx.hash()]);
+    }
+
+    fn funded_forest() -> BlockForest {
+        let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+        let mut forest = BlockForest::new();
+        forest
+            .add_block(block.verified_for(&ChainParams::mainnet()).unwrap())
+            .unwrap();
+        forest
+    }
+
+    #[test]
+    fn test_block_forest_eviction() {
+        let mut forest = funded_forest();
+        forest.set_mempool_config(MempoolConfig {
+            max_transactions: 2,
+            ..Default::default()
+        });
+
+        let low = make_tx(1, "a");
+        let high = make_tx(5, "b");
+        let mid = make_tx(3, "c");
+        forest.add_transaction(low.clone()).unwrap();
+        forest.add_transaction(high.clone()).unwrap();
+        forest.add_transaction(mid.clone()).unwrap();
+        assert!(forest.add_transaction(make_tx(0, "d")).is_err());
+
+        assert_eq!(forest.pending_transactions_by_fee_rate(), vec![high, mid]);
+    }
+
+    #[test]
+    fn test_block_forest_dependent_order() {
+        let mut forest = funded_forest();
+
+        let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
+        let other_key = RSAPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let fund = VerifiedTransaction::sign(
+            &priv_key,
+            other_key.to_public_key().into(),
+            100,
+            1,
+            "".into(),
+        )
+        .unwrap();
+        let spend = VerifiedTransaction::sign(
+            &other_key,
+            priv_key.to_public_key().into(),
+            50,
+            40,
+            "".into(),
+        )
+        .unwrap();
+
+        forest.add_transaction(fund.clone()).unwrap();
+        forest.add_transaction(spend.clone()).unwrap();
+        assert_eq!(forest.pending_transactions_by_fee_rate(), vec![fund, spend]);
+    }
+}
@@ -0,0 +1,230 @@
+//! Merkle tree over transaction hashes of a block.
+//!
+//! A leaf is the hash of a marker byte and a transaction hash, an inner node is the hash of
+//! another marker byte and its two children, so an inner node can't be passed off as a
+//! transaction in a proof. A node without a sibling is promoted to the next level unchanged rather
+//! than paired with itself, so duplicating the last transaction doesn't keep the root.
+//! The root of an empty list is all zeros.
+
+use crate::{
+    data::{BlockAttributes, BlockHash, TransactionHash, HASH_LEN},
+    util::{
+        deserialize_base64_fixed, deserialize_base64_vec, serialize_base64, serialize_base64_vec,
+    },
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const LEAF_MARKER: u8 = 0;
+const INNER_NODE_MARKER: u8 = 1;
+
+/// Proofs longer than this can't belong to a block that fits into a message.
+pub const MAX_MERKLE_DEPTH: usize = 32;
+
+fn hash_leaf(leaf: &TransactionHash) -> TransactionHash {
+    let mut hasher = Sha3_512::new();
+    hasher.update([LEAF_MARKER]);
+    hasher.update(leaf);
+
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+fn hash_pair(left: &TransactionHash, right: &TransactionHash) -> TransactionHash {
+    let mut hasher = Sha3_512::new();
+    hasher.update([INNER_NODE_MARKER]);
+    hasher.update(left);
+    hasher.update(right);
+
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+fn next_level(level: &[TransactionHash]) -> Vec<TransactionHash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn compute_root(leaves: &[TransactionHash]) -> TransactionHash {
+    if leaves.is_empty() {
+        return [0u8; HASH_LEN];
+    }
+
+    let mut level: Vec<_> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Path from a leaf to the root: the siblings met on the way up, bottom first.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MerkleBranch {
+    pub index: u64,
+    pub leaf_count: u64,
+
+    #[serde(
+        serialize_with = "serialize_base64_vec",
+        deserialize_with = "deserialize_base64_vec::<'_, _, HASH_LEN>"
+    )]
+    pub siblings: Vec<TransactionHash>,
+}
+
+impl MerkleBranch {
+    pub fn new(leaves: &[TransactionHash], index: usize) -> Self {
+        assert!(index < leaves.len());
+
+        let mut siblings = vec![];
+        let mut level: Vec<_> = leaves.iter().map(hash_leaf).collect();
+        let mut position = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            level = next_level(&level);
+            position /= 2;
+        }
+
+        Self {
+            index: index as u64,
+            leaf_count: leaves.len() as u64,
+            siblings,
+        }
+    }
+
+    pub fn compute_root(&self, leaf: &TransactionHash) -> Result<TransactionHash> {
+        if self.index >= self.leaf_count {
+            bail!("leaf index is out of range");
+        }
+        if self.siblings.len() > MAX_MERKLE_DEPTH {
+            bail!("merkle branch is too long");
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut hash = hash_leaf(leaf);
+        let mut position = self.index;
+        let mut level_len = self.leaf_count;
+        while level_len > 1 {
+            let sibling_position = position ^ 1;
+            if sibling_position < level_len {
+                let Some(sibling) = siblings.next() else {
+                    bail!("merkle branch is too short");
+                };
+                hash = if position.is_multiple_of(2) {
+                    hash_pair(&hash, sibling)
+                } else {
+                    hash_pair(sibling, &hash)
+                };
+            }
+            position /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+        if siblings.next().is_some() {
+            bail!("merkle branch is too long");
+        }
+        Ok(hash)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Proves that a transaction is in a block to someone who only knows the block hash.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub header: BlockAttributes,
+
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub transaction_hash: TransactionHash,
+
+    pub branch: MerkleBranch,
+}
+
+impl InclusionProof {
+    pub fn verify(&self, block_hash: &BlockHash) -> Result<()> {
+        if self.header.compute_hash() != *block_hash {
+            bail!("header doesn't match the block hash");
+        }
+        if self.branch.compute_root(&self.transaction_hash)? != self.header.merkle_root {
+            bail!("merkle branch doesn't lead to the merkle root");
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<TransactionHash> {
+        (1..=count).map(|i| [i; HASH_LEN]).collect()
+    }
+
+    #[test]
+    fn test_root() {
+        assert_eq!(compute_root(&[]), [0u8; HASH_LEN]);
+        assert_eq!(compute_root(&leaves(1)), hash_leaf(&leaves(1)[0]));
+
+        let three = leaves(3);
+        let [a, b, c] = [0, 1, 2].map(|i| hash_leaf(&three[i]));
+        let expected = hash_pair(&hash_pair(&a, &b), &c);
+        assert_eq!(compute_root(&three), expected);
+        assert_ne!(compute_root(&three), compute_root(&leaves(4)));
+    }
+
+    #[test]
+    fn test_branch() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = compute_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let branch = MerkleBranch::new(&leaves, index);
+                assert_eq!(branch.compute_root(leaf).unwrap(), root);
+                assert_ne!(branch.compute_root(&[42u8; HASH_LEN]).unwrap(), root);
+
+                let mut truncated = branch.clone();
+                if truncated.siblings.pop().is_some() {
+                    assert!(truncated.compute_root(leaf).is_err());
+                }
+                let mut out_of_range = branch.clone();
+                out_of_range.index = count.into();
+                assert!(out_of_range.compute_root(leaf).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_inner_node() {
+        let leaves = leaves(4);
+        let root = compute_root(&leaves);
+        let left = hash_pair(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+        let right = hash_pair(&hash_leaf(&leaves[2]), &hash_leaf(&leaves[3]));
+        assert_eq!(hash_pair(&left, &right), root);
+
+        // NB: the header doesn't commit to the leaf count, so a branch may claim a shorter tree.
+        let forged = MerkleBranch {
+            index: 0,
+            leaf_count: 2,
+            siblings: vec![right],
+        };
+        assert_ne!(forged.compute_root(&left).unwrap(), root);
+    }
+}
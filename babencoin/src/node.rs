@@ -1,21 +1,43 @@
-mod gossip_service;
-mod mining_service;
-mod peer_service;
+pub mod address_book;
+pub mod gossip_service;
+pub mod metrics_service;
+pub mod mining_service;
+pub mod peer_service;
+pub mod rpc_service;
+pub mod supervisor;
+pub mod wire;
 
+use crate::{
+    block_forest::{BlockForest, PruningConfig, Reorg},
+    chain_params::ChainConfig,
+    mempool::MempoolConfig,
+    storage::FileStorage,
+};
+
+use futures::future;
 use gossip_service::{GossipService, GossipServiceConfig};
-use log::error;
+use log::*;
+use metrics_service::{Metrics, MetricsService, MetricsServiceConfig};
 use mining_service::{MiningService, MiningServiceConfig};
-use peer_service::{PeerService, PeerServiceConfig};
+use peer_service::{ChainHead, PeerService, PeerServiceConfig};
+use rpc_service::{RpcService, RpcServiceConfig};
+use supervisor::{RestartPolicy, ShutdownToken, Supervisor};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use tokio::{
     select,
-    sync::{mpsc::channel, oneshot},
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc::channel, oneshot, watch},
     task::JoinHandle,
 };
 
-use std::future::Future;
+use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub const CHAIN_EVENT_QUEUE_SIZE: usize = 1000;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -24,6 +46,34 @@ pub struct Config {
     pub peer_app: AppConfig<PeerServiceConfig>,
     pub gossip_app: AppConfig<GossipServiceConfig>,
     pub mining_app: AppConfig<MiningServiceConfig>,
+
+    #[serde(default = "default_rpc_app")]
+    pub rpc_app: AppConfig<RpcServiceConfig>,
+
+    #[serde(default = "default_metrics_app")]
+    pub metrics_app: AppConfig<MetricsServiceConfig>,
+
+    /// Directory to persist the chain in. If not set, the chain is kept in memory only.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    pub mempool: MempoolConfig,
+
+    #[serde(default)]
+    pub chain: ChainConfig,
+
+    /// Whether to index transactions by wallet for the `get_history` rpc method.
+    #[serde(default)]
+    pub history_index: bool,
+
+    /// If not set, every balance snapshot and every fork is kept forever.
+    #[serde(default)]
+    pub pruning: Option<PruningConfig>,
+
+    /// Applies to every service separately.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
 }
 
 impl Default for Config {
@@ -41,70 +91,234 @@ impl Default for Config {
                 thread_count: 1,
                 service: Default::default(),
             },
+            rpc_app: default_rpc_app(),
+            metrics_app: default_metrics_app(),
+            data_dir: None,
+            mempool: Default::default(),
+            chain: Default::default(),
+            history_index: false,
+            pruning: None,
+            restart_policy: Default::default(),
         }
     }
 }
 
+fn default_rpc_app() -> AppConfig<RpcServiceConfig> {
+    AppConfig::<RpcServiceConfig> {
+        thread_count: 1,
+        service: Default::default(),
+    }
+}
+
+fn default_metrics_app() -> AppConfig<MetricsServiceConfig> {
+    AppConfig::<MetricsServiceConfig> {
+        thread_count: 1,
+        service: Default::default(),
+    }
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct AppConfig<T> {
     pub thread_count: usize,
     pub service: T,
 }
 
+/// Runs the node until SIGINT or SIGTERM.
 pub async fn run(config: Config) -> Result<()> {
+    let (chain_event_sender, _) = broadcast::channel(CHAIN_EVENT_QUEUE_SIZE);
+    run_with_chain_events(config, chain_event_sender).await
+}
+
+/// Same as `run`, but publishes every head switch to `chain_event_sender`.
+///
+/// Subscribe with `chain_event_sender.subscribe()` before the node starts to see all of them.
+pub async fn run_with_chain_events(
+    config: Config,
+    chain_event_sender: broadcast::Sender<Arc<Reorg>>,
+) -> Result<()> {
+    let shutdown = ShutdownToken::new();
+    shutdown_on_signals(shutdown.clone())?;
+    run_until(config, chain_event_sender, shutdown).await
+}
+
+/// Runs the node until `shutdown` is triggered, then waits for the services to wind down.
+///
+/// A failed service is restarted as `config.restart_policy` allows. Once it gives up, the
+/// whole node shuts down and an error is returned.
+pub async fn run_until(
+    config: Config,
+    chain_event_sender: broadcast::Sender<Arc<Reorg>>,
+    shutdown: ShutdownToken,
+) -> Result<()> {
+    let params = config.chain.params().context("invalid chain config")?;
+    let mut block_forest = match &config.data_dir {
+        Some(dir) => {
+            let storage = FileStorage::open(dir).context("failed to open chain storage")?;
+            BlockForest::with_storage(params.clone(), Box::new(storage))?
+        }
+        None => BlockForest::with_params(params.clone()),
+    };
+    let params = Arc::new(params);
+    block_forest.set_mempool_config(config.mempool);
+    if config.history_index {
+        block_forest.enable_history_index();
+    }
+    if let Some(pruning) = config.pruning {
+        block_forest
+            .set_pruning_config(pruning)
+            .context("invalid pruning config")?;
+    }
+
     let (peer_event_sender, peer_event_receiver) = channel(1000);
     let (command_sender, command_receiver) = channel(1000);
     let (block_sender, block_receiver) = channel(1000);
     let (mining_info_sender, mining_info_receiver) = channel(1000);
+    let (rpc_request_sender, rpc_request_receiver) = channel(1000);
+    let (head_sender, head_receiver) =
+        watch::channel(ChainHead::from(block_forest.head().as_ref()));
+    let metrics = Arc::new(Metrics::default());
+    let supervisor = |name| Supervisor::new(name, config.restart_policy.clone(), shutdown.clone());
 
-    let mut peer_service =
-        PeerService::new(config.peer_app.service, peer_event_sender, command_receiver);
-    let mut peer_service_handle = start_runtime(config.peer_app.thread_count, async move {
-        peer_service.run().await
+    // NB: the peer, gossip and mining services observe the shutdown to wind down cleanly,
+    // the others are simply dropped.
+    let mut peer_service = PeerService::new(
+        config.peer_app.service,
+        peer_event_sender,
+        command_receiver,
+        head_receiver,
+    )
+    .with_chain_params(params.clone())
+    .with_metrics(metrics.clone())
+    .with_shutdown(shutdown.clone());
+    let mut peer_supervisor = supervisor("peer service");
+    let peer_service_handle = start_runtime(config.peer_app.thread_count, async move {
+        while peer_supervisor
+            .should_restart(peer_service.run().await)
+            .await?
+        {}
+        anyhow::Ok(())
     });
 
     let mut gossip_service = GossipService::new(
         config.gossip_app.service,
+        block_forest,
         peer_event_receiver,
         command_sender,
         block_receiver,
         mining_info_sender,
-    );
-    let mut gossip_service_handle = start_runtime(config.gossip_app.thread_count, async move {
-        gossip_service.run().await
+        rpc_request_receiver,
+    )
+    .with_chain_event_sender(chain_event_sender)
+    .with_head_sender(head_sender)
+    .with_metrics(metrics.clone())
+    .with_shutdown(shutdown.clone());
+    let mut gossip_supervisor = supervisor("gossip service");
+    let gossip_service_handle = start_runtime(config.gossip_app.thread_count, async move {
+        while gossip_supervisor
+            .should_restart(gossip_service.run().await)
+            .await?
+        {}
+        anyhow::Ok(())
     });
 
     let mut mining_service = MiningService::new(
         config.mining_app.service,
         mining_info_receiver,
         block_sender,
-    );
-    let mut mining_service_handle = start_runtime(config.mining_app.thread_count, async move {
-        mining_service.run().await
+    )
+    .with_chain_params(params)
+    .with_metrics(metrics.clone())
+    .with_shutdown(shutdown.clone());
+    let mut mining_supervisor = supervisor("mining service");
+    let mining_service_handle = start_runtime(config.mining_app.thread_count, async move {
+        while mining_supervisor
+            .should_restart(mining_service.run().await)
+            .await?
+        {}
+        anyhow::Ok(())
     });
 
-    select! {
-        result = &mut peer_service_handle => {
-            error!("peer service terminated: {:?}", result);
-        }
-        result = &mut gossip_service_handle => {
-            error!("gossip service terminated: {:?}", result);
-        }
-        result = &mut mining_service_handle => {
-            error!("mining service terminated: {:?}", result);
+    let mut rpc_service = RpcService::new(config.rpc_app.service, rpc_request_sender);
+    let mut rpc_supervisor = supervisor("rpc service");
+    let rpc_shutdown = shutdown.clone();
+    let rpc_service_handle = start_runtime(config.rpc_app.thread_count, async move {
+        while rpc_supervisor
+            .should_restart(rpc_shutdown.run_until(rpc_service.run()).await)
+            .await?
+        {}
+        anyhow::Ok(())
+    });
+
+    let mut metrics_service = MetricsService::new(config.metrics_app.service, metrics);
+    let mut metrics_supervisor = supervisor("metrics service");
+    let metrics_shutdown = shutdown.clone();
+    let metrics_service_handle = start_runtime(config.metrics_app.thread_count, async move {
+        while metrics_supervisor
+            .should_restart(metrics_shutdown.run_until(metrics_service.run()).await)
+            .await?
+        {}
+        anyhow::Ok(())
+    });
+
+    let mut services = vec![
+        ("peer service", peer_service_handle),
+        ("gossip service", gossip_service_handle),
+        ("mining service", mining_service_handle),
+        ("rpc service", rpc_service_handle),
+        ("metrics service", metrics_service_handle),
+    ];
+    let terminated = {
+        let any_service = future::select_all(services.iter_mut().map(|(_, handle)| handle));
+        // NB: services stop on their own once the shutdown is triggered, that's not a failure.
+        select! {
+            biased;
+            _ = shutdown.triggered() => None,
+            (result, index, _) = any_service => Some((index, result)),
         }
+    };
+    if let Some((index, result)) = terminated.as_ref() {
+        let (name, _) = services.remove(*index);
+        error!("{} terminated: {:?}", name, result);
     }
 
-    let handles = [
-        peer_service_handle,
-        gossip_service_handle,
-        mining_service_handle,
-    ];
-    for handle in handles.iter() {
+    shutdown.trigger();
+    let wind_down = async {
+        for (name, handle) in services.iter_mut() {
+            match handle.await {
+                Ok(Ok(())) => debug!("{} has stopped", name),
+                result => warn!("{} has stopped: {:?}", name, result),
+            }
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, wind_down)
+        .await
+        .is_err()
+    {
+        warn!("services haven't stopped in time");
+    }
+    for (_, handle) in services.iter() {
         handle.abort();
     }
 
-    bail!("node terminated");
+    if terminated.is_some() {
+        bail!("node terminated");
+    }
+    info!("node has shut down");
+    Ok(())
+}
+
+/// Triggers `shutdown` on the first SIGINT or SIGTERM.
+fn shutdown_on_signals(shutdown: ShutdownToken) -> Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt()).context("failed to handle SIGINT")?;
+    let mut terminate = signal(SignalKind::terminate()).context("failed to handle SIGTERM")?;
+    tokio::spawn(async move {
+        select! {
+            _ = interrupt.recv() => info!("got SIGINT, shutting down"),
+            _ = terminate.recv() => info!("got SIGTERM, shutting down"),
+        }
+        shutdown.trigger();
+    });
+    Ok(())
 }
 
 fn start_runtime<F>(thread_count: usize, future: F) -> JoinHandle<F::Output>
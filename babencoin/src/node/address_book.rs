@@ -0,0 +1,207 @@
+use crate::data::PeerAddress;
+
+use chrono::{DateTime, Utc};
+
+use std::{cmp::Reverse, collections::HashMap};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_DIAL_FAILURES: u32 = 3;
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Entry {
+    last_seen: DateTime<Utc>,
+    failed_dials: u32,
+    pinned: bool,
+}
+
+/// Listen addresses of peers known to this node.
+///
+/// Pinned addresses come from the config and are never forgotten. Learned addresses are
+/// dropped after several failed dials, or evicted (oldest first) once the book is full.
+pub struct AddressBook {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+}
+
+impl AddressBook {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.entries.contains_key(address)
+    }
+
+    pub fn pin(&mut self, address: String) {
+        self.entries
+            .entry(address)
+            .or_insert_with(|| Entry {
+                last_seen: DateTime::<Utc>::MIN_UTC,
+                failed_dials: 0,
+                pinned: true,
+            })
+            .pinned = true;
+    }
+
+    /// Adds an address learned from a peer, or refreshes its last-seen time.
+    pub fn insert(&mut self, peer: PeerAddress) {
+        // NB: peers may lie about the future, never let them outrank live addresses.
+        let last_seen = peer.last_seen.min(Utc::now());
+        if let Some(entry) = self.entries.get_mut(&peer.address) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return;
+        }
+
+        if self.entries.len() >= self.capacity && !self.evict_oldest(last_seen) {
+            return;
+        }
+        self.entries.insert(
+            peer.address,
+            Entry {
+                last_seen,
+                failed_dials: 0,
+                pinned: false,
+            },
+        );
+    }
+
+    pub fn mark_seen(&mut self, address: &str) {
+        if let Some(entry) = self.entries.get_mut(address) {
+            entry.last_seen = Utc::now();
+            entry.failed_dials = 0;
+        }
+    }
+
+    pub fn mark_failed(&mut self, address: &str) {
+        let Some(entry) = self.entries.get_mut(address) else {
+            return;
+        };
+        entry.failed_dials += 1;
+        if !entry.pinned && entry.failed_dials >= MAX_DIAL_FAILURES {
+            self.entries.remove(address);
+        }
+    }
+
+    /// Returns up to `limit` most recently seen addresses.
+    pub fn recent(&self, limit: usize) -> Vec<PeerAddress> {
+        let mut peers = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.last_seen > DateTime::<Utc>::MIN_UTC)
+            .map(|(address, entry)| PeerAddress {
+                address: address.clone(),
+                last_seen: entry.last_seen,
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| Reverse(peer.last_seen));
+        peers.truncate(limit);
+        peers
+    }
+
+    /// Returns learned addresses for which `filter` holds, most recently seen first.
+    pub fn dial_candidates(&self, filter: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|(address, entry)| !entry.pinned && filter(address))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, entry)| Reverse(entry.last_seen));
+        candidates
+            .into_iter()
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    fn evict_oldest(&mut self, newer_than: DateTime<Utc>) -> bool {
+        let oldest = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.pinned && entry.last_seen < newer_than)
+            .min_by_key(|(_, entry)| entry.last_seen)
+            .map(|(address, _)| address.clone());
+        match oldest {
+            Some(address) => {
+                self.entries.remove(&address);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    fn peer(address: &str, secs_ago: i64) -> PeerAddress {
+        PeerAddress {
+            address: address.into(),
+            last_seen: Utc::now() - Duration::seconds(secs_ago),
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut book = AddressBook::new(2);
+        book.pin("seed:1".into());
+        book.insert(peer("a:1", 10));
+        assert_eq!(book.len(), 2);
+
+        book.insert(peer("b:1", 5));
+        assert!(book.contains("seed:1"));
+        assert!(!book.contains("a:1"));
+        assert!(book.contains("b:1"));
+
+        book.insert(peer("c:1", 100));
+        assert!(!book.contains("c:1"));
+    }
+
+    #[test]
+    fn test_dial_failures() {
+        let mut book = AddressBook::new(10);
+        book.pin("seed:1".into());
+        book.insert(peer("a:1", 0));
+        for _ in 0..MAX_DIAL_FAILURES {
+            book.mark_failed("seed:1");
+            book.mark_failed("a:1");
+        }
+        assert!(book.contains("seed:1"));
+        assert!(!book.contains("a:1"));
+    }
+
+    #[test]
+    fn test_ordering() {
+        let mut book = AddressBook::new(10);
+        book.pin("seed:1".into());
+        book.insert(peer("a:1", 30));
+        book.insert(peer("b:1", 10));
+        book.insert(peer("c:1", -1000));
+        book.mark_seen("a:1");
+
+        let recent = book.recent(10);
+        assert_eq!(recent[0].address, "a:1");
+        assert!(recent.iter().all(|peer| peer.last_seen <= Utc::now()));
+        assert!(recent.iter().all(|peer| peer.address != "seed:1"));
+
+        let candidates = book.dial_candidates(|address| address != "b:1");
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0], "a:1");
+    }
+}
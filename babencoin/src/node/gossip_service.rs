@@ -1,24 +1,38 @@
 use crate::{
-    block_forest::BlockForest,
-    data::{BlockHash, TransactionHash, VerifiedBlock, VerifiedPeerMessage, VerifiedTransaction},
+    block_forest::{BlockForest, Reorg},
+    data::{
+        BlockAttributes, BlockHash, Transaction, VerifiedBlock, VerifiedPeerMessage,
+        VerifiedTransaction, MAX_HEADERS_PER_MESSAGE, MAX_LOCATOR_LEN,
+    },
+    node::metrics_service::Metrics,
     node::mining_service::MiningInfo,
-    node::peer_service::{PeerCommand, PeerCommandKind, PeerEvent, PeerEventKind, SessionId},
+    node::peer_service::{
+        ChainHead, PeerCommand, PeerCommandKind, PeerEvent, PeerEventKind, SessionId,
+    },
+    node::rpc_service::{RpcRequest, RpcRequestKind, RpcResponse},
+    node::supervisor::ShutdownToken,
 };
 
 use anyhow::{Context, Result};
-use futures::{future::pending, stream, Stream, StreamExt};
+use futures::{stream, Stream, StreamExt};
 use log::*;
 use rand::{seq::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
 use tokio::{
     pin, select,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        broadcast,
+        mpsc::{Receiver, Sender},
+        watch,
+    },
 };
 
-use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
-};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_BLOCKS_PER_REQUEST: u64 = 64;
+const MEMPOOL_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -29,25 +43,589 @@ pub struct GossipServiceConfig {
 }
 
 pub struct GossipService {
-    // TODO: your code here.
+    config: GossipServiceConfig,
+    event_receiver: Receiver<PeerEvent>,
+    command_sender: Sender<PeerCommand>,
+    block_receiver: Receiver<VerifiedBlock>,
+    mining_info_sender: Sender<MiningInfo>,
+    rpc_receiver: Receiver<RpcRequest>,
+    chain_event_sender: Option<broadcast::Sender<Arc<Reorg>>>,
+    head_sender: Option<watch::Sender<ChainHead>>,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownToken,
+    block_forest: BlockForest,
+    sessions: HashMap<SessionId, SessionState>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    /// Latest peer score, see `PeerScoringConfig`.
+    score: i64,
+    /// The head announced by the peer on connect.
+    head: Option<ChainHead>,
+    /// Blocks below this index were requested from the peer by the sync, see `handle_block`.
+    sync_until_index: u64,
 }
 
 impl GossipService {
     pub fn new(
         config: GossipServiceConfig,
+        block_forest: BlockForest,
         event_receiver: Receiver<PeerEvent>,
         command_sender: Sender<PeerCommand>,
         block_receiver: Receiver<VerifiedBlock>,
         mining_info_sender: Sender<MiningInfo>,
+        rpc_receiver: Receiver<RpcRequest>,
     ) -> Self {
-        // TODO: your code here.
-        unimplemented!()
+        Self {
+            config,
+            event_receiver,
+            command_sender,
+            block_receiver,
+            mining_info_sender,
+            rpc_receiver,
+            chain_event_sender: None,
+            head_sender: None,
+            metrics: Default::default(),
+            shutdown: Default::default(),
+            block_forest,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Publishes every head switch to `sender`.
+    pub fn with_chain_event_sender(mut self, sender: broadcast::Sender<Arc<Reorg>>) -> Self {
+        self.chain_event_sender = Some(sender);
+        self
     }
 
+    /// Keeps `sender` updated with the current head, so that the peer service can
+    /// announce it.
+    pub fn with_head_sender(mut self, sender: watch::Sender<ChainHead>) -> Self {
+        sender.send_replace(self.block_forest.head().as_ref().into());
+        self.head_sender = Some(sender);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Once `shutdown` is triggered, `run` returns.
+    pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Flushes the chain storage on return, whatever the reason.
     pub async fn run(&mut self) -> Result<()> {
-        // TODO: your code here.
-        unimplemented!()
+        let result = self.serve().await;
+        if let Err(err) = self.block_forest.flush() {
+            error!("failed to flush chain storage: {:#}", err);
+        }
+        result
+    }
+
+    async fn serve(&mut self) -> Result<()> {
+        self.metrics
+            .gossip
+            .head_index
+            .set(self.block_forest.head().index as i64);
+        self.update_mining_info().await?;
+
+        let eager_requests = Self::make_ticker(self.config.eager_requests_interval);
+        pin!(eager_requests);
+        let mempool_expiry = Self::make_ticker(MEMPOOL_EXPIRY_INTERVAL);
+        pin!(mempool_expiry);
+
+        loop {
+            select! {
+                event = self.event_receiver.recv() => {
+                    let event = event.context("peer event channel is closed")?;
+                    self.handle_peer_event(event).await?;
+                }
+                block = self.block_receiver.recv() => {
+                    let block = block.context("block channel is closed")?;
+                    self.handle_mined_block(block).await?;
+                }
+                Some(()) = eager_requests.next() => {
+                    self.send_eager_requests().await?;
+                }
+                Some(()) = mempool_expiry.next() => {
+                    if self.block_forest.expire_pending_transactions() > 0 {
+                        self.update_mining_info().await?;
+                    }
+                    self.block_forest.persist_pending_transactions_if_due();
+                }
+                Some(request) = self.rpc_receiver.recv() => {
+                    let response = self.handle_rpc_request(request.request_kind).await;
+                    let _ = request.response_sender.send(response);
+                }
+                _ = self.shutdown.triggered() => {
+                    info!("gossip service is shutting down");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn make_ticker(interval: Duration) -> impl Stream<Item = ()> {
+        if interval.is_zero() {
+            return stream::pending().left_stream();
+        }
+        stream::unfold(tokio::time::interval(interval), |mut interval| async {
+            interval.tick().await;
+            Some(((), interval))
+        })
+        .right_stream()
+    }
+
+    async fn handle_peer_event(&mut self, event: PeerEvent) -> Result<()> {
+        let session_id = event.session_id;
+        match event.event_kind {
+            PeerEventKind::Connected => {
+                self.sessions.insert(session_id, SessionState::default());
+                self.greet_session(session_id).await?;
+            }
+            PeerEventKind::Head(head) => {
+                if let Some(session) = self.sessions.get_mut(&session_id) {
+                    session.head = Some(head);
+                }
+            }
+            PeerEventKind::Disconnected => {
+                self.sessions.remove(&session_id);
+            }
+            PeerEventKind::NewMessage(message) => {
+                self.handle_message(session_id, message).await?;
+            }
+            PeerEventKind::ScoreChanged {
+                score,
+                misbehaviour,
+            } => {
+                debug!(
+                    "session {} misbehaved ({:?}), score is now {}",
+                    session_id, misbehaviour, score
+                );
+                if let Some(session) = self.sessions.get_mut(&session_id) {
+                    session.score = score;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn greet_session(&mut self, session_id: SessionId) -> Result<()> {
+        let head = self.block_forest.head().as_ref().clone();
+        self.send_message(session_id, VerifiedPeerMessage::Block(Box::new(head)))
+            .await?;
+
+        let transactions = self
+            .block_forest
+            .pending_transactions()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for tx in transactions {
+            self.send_message(session_id, VerifiedPeerMessage::Transaction(Box::new(tx)))
+                .await?;
+        }
+
+        let locator = self.block_forest.block_locator();
+        self.send_message(session_id, VerifiedPeerMessage::GetHeaders { locator })
+            .await
+    }
+
+    async fn handle_message(
+        &mut self,
+        session_id: SessionId,
+        message: VerifiedPeerMessage,
+    ) -> Result<()> {
+        match message {
+            VerifiedPeerMessage::Block(block) => self.handle_block(session_id, *block).await,
+            VerifiedPeerMessage::Transaction(tx) => self.handle_transaction(session_id, *tx).await,
+            VerifiedPeerMessage::Request { block_hash } => {
+                if let Some(block) = self.block_forest.find_block(&block_hash) {
+                    let block = block.as_ref().clone();
+                    self.send_message(session_id, VerifiedPeerMessage::Block(Box::new(block)))
+                        .await?;
+                }
+                Ok(())
+            }
+            VerifiedPeerMessage::GetHeaders { locator } => {
+                let headers = self
+                    .block_forest
+                    .main_chain_after(&locator, MAX_HEADERS_PER_MESSAGE)
+                    .iter()
+                    .map(|block| (block as &BlockAttributes).clone())
+                    .collect::<Vec<_>>();
+                if headers.is_empty() {
+                    return Ok(());
+                }
+                self.send_message(session_id, VerifiedPeerMessage::Headers { headers })
+                    .await
+            }
+            VerifiedPeerMessage::Headers { headers } => {
+                self.handle_headers(session_id, headers).await
+            }
+            VerifiedPeerMessage::GetBlocks { from_hash, count } => {
+                let count = count.min(MAX_BLOCKS_PER_REQUEST) as usize;
+                let blocks = self.block_forest.main_chain_after(&[from_hash], count);
+                for block in blocks {
+                    let block = block.as_ref().clone();
+                    self.send_message(session_id, VerifiedPeerMessage::Block(Box::new(block)))
+                        .await?;
+                }
+                Ok(())
+            }
+            VerifiedPeerMessage::GetProof {
+                block_hash,
+                transaction_hash,
+            } => {
+                let proof = self
+                    .block_forest
+                    .find_block(&block_hash)
+                    .and_then(|block| block.inclusion_proof(&transaction_hash));
+                if let Some(proof) = proof {
+                    self.send_message(session_id, VerifiedPeerMessage::Proof(Box::new(proof)))
+                        .await?;
+                }
+                Ok(())
+            }
+            // NB: handshake, address exchange and goodbyes are handled by the peer service
+            // itself, proofs are only of interest to light clients.
+            VerifiedPeerMessage::Hello { .. }
+            | VerifiedPeerMessage::Goodbye
+            | VerifiedPeerMessage::GetPeers
+            | VerifiedPeerMessage::Peers { .. }
+            | VerifiedPeerMessage::Proof(_) => Ok(()),
+        }
+    }
+
+    async fn handle_headers(
+        &mut self,
+        session_id: SessionId,
+        headers: Vec<BlockAttributes>,
+    ) -> Result<()> {
+        let Some(last) = headers.last() else {
+            return Ok(());
+        };
+
+        // NB: a header hash is unknown until the block body arrives, but every header
+        // names its parent, so known blocks are detected via the next header's prev hash.
+        let known_count = headers
+            .iter()
+            .skip(1)
+            .take_while(|header| self.block_forest.find_block(&header.prev_hash).is_some())
+            .count();
+        if known_count + 1 < headers.len() || last.index > self.block_forest.head().index {
+            if let Some(session) = self.sessions.get_mut(&session_id) {
+                session.sync_until_index = session.sync_until_index.max(last.index);
+            }
+            self.send_message(
+                session_id,
+                VerifiedPeerMessage::GetBlocks {
+                    from_hash: headers[known_count].prev_hash,
+                    count: (headers.len() - known_count) as u64,
+                },
+            )
+            .await?;
+        }
+
+        if headers.len() == MAX_HEADERS_PER_MESSAGE {
+            let mut locator = vec![last.prev_hash];
+            locator.extend(self.block_forest.block_locator());
+            locator.truncate(MAX_LOCATOR_LEN);
+            self.send_message(session_id, VerifiedPeerMessage::GetHeaders { locator })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_block(&mut self, session_id: SessionId, block: VerifiedBlock) -> Result<()> {
+        if self.block_forest.find_block(block.hash()).is_some() {
+            return Ok(());
+        }
+
+        let hash = *block.hash();
+        let prev_hash = block.prev_hash;
+        let reorg = match self.block_forest.add_block(block.clone()) {
+            Ok(reorg) => reorg,
+            Err(err) => {
+                debug!("rejected block {}: {:#}", base64::encode(hash), err);
+                self.metrics.gossip.blocks_rejected.inc();
+                return Ok(());
+            }
+        };
+        self.metrics.gossip.blocks_accepted.inc();
+
+        if self
+            .block_forest
+            .unknown_block_hashes()
+            .contains(&prev_hash)
+        {
+            self.send_message(
+                session_id,
+                VerifiedPeerMessage::Request {
+                    block_hash: prev_hash,
+                },
+            )
+            .await?;
+        }
+
+        // NB: orphans are not relayed, peers would only have to request their parents
+        // in turn. Once an orphan connects, the new head is relayed instead. Neither are
+        // the blocks the sync is catching up on, only the tip it catches up to.
+        let is_synced = self
+            .sessions
+            .get(&session_id)
+            .is_some_and(|session| block.index < session.sync_until_index);
+        if self.block_forest.is_validated(&hash) && !is_synced {
+            self.broadcast(
+                VerifiedPeerMessage::Block(Box::new(block)),
+                Some(session_id),
+            )
+            .await?;
+            if let Some(new_head) = reorg.as_ref().map(|reorg| reorg.new_head()) {
+                if new_head.hash() != &hash {
+                    let new_head = new_head.as_ref().clone();
+                    self.broadcast(VerifiedPeerMessage::Block(Box::new(new_head)), None)
+                        .await?;
+                }
+            }
+        }
+
+        if let Some(reorg) = reorg {
+            self.publish_reorg(reorg);
+            self.update_mining_info().await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_transaction(
+        &mut self,
+        session_id: SessionId,
+        tx: VerifiedTransaction,
+    ) -> Result<()> {
+        if self
+            .block_forest
+            .pending_transactions()
+            .contains_key(tx.hash())
+        {
+            return Ok(());
+        }
+
+        if let Err(err) = self.block_forest.add_transaction(tx.clone()) {
+            debug!(
+                "rejected transaction {}: {:#}",
+                base64::encode(tx.hash()),
+                err
+            );
+            self.metrics.gossip.transactions_rejected.inc();
+            return Ok(());
+        }
+        self.metrics.gossip.transactions_accepted.inc();
+
+        self.announce_transaction(tx, Some(session_id)).await
+    }
+
+    async fn announce_transaction(
+        &mut self,
+        tx: VerifiedTransaction,
+        except: Option<SessionId>,
+    ) -> Result<()> {
+        self.broadcast(VerifiedPeerMessage::Transaction(Box::new(tx)), except)
+            .await?;
+        self.update_mining_info().await
+    }
+
+    async fn handle_rpc_request(&mut self, request_kind: RpcRequestKind) -> Result<RpcResponse> {
+        match request_kind {
+            RpcRequestKind::GetHead => {
+                let head = self.block_forest.head();
+                Ok(RpcResponse::Head {
+                    hash: *head.hash(),
+                    attrs: Box::new((head as &BlockAttributes).clone()),
+                })
+            }
+            RpcRequestKind::GetBlock(hash) => Ok(RpcResponse::Block(
+                self.block_forest
+                    .find_block(&hash)
+                    .map(|block| Box::new(block.to_block())),
+            )),
+            RpcRequestKind::GetBalance(wallet) => Ok(RpcResponse::Balance {
+                balance: self.block_forest.balance(&wallet),
+            }),
+            RpcRequestKind::GetHistory(wallet) => {
+                Ok(RpcResponse::History(self.block_forest.history(&wallet)?))
+            }
+            RpcRequestKind::GetPendingTransactions => Ok(RpcResponse::Transactions(
+                self.block_forest
+                    .pending_transactions()
+                    .values()
+                    .map(|tx| (tx as &Transaction).clone())
+                    .collect(),
+            )),
+            RpcRequestKind::SubmitTransaction(tx) => {
+                let hash = *tx.hash();
+                if !self.block_forest.pending_transactions().contains_key(&hash) {
+                    if let Err(err) = self.block_forest.add_transaction((*tx).clone()) {
+                        self.metrics.gossip.transactions_rejected.inc();
+                        return Err(err);
+                    }
+                    self.metrics.gossip.transactions_accepted.inc();
+                    self.announce_transaction(*tx, None).await?;
+                }
+                Ok(RpcResponse::Submitted { hash })
+            }
+        }
     }
 
-    // TODO: your code here.
+    async fn handle_mined_block(&mut self, block: VerifiedBlock) -> Result<()> {
+        let hash = *block.hash();
+        let reorg = match self.block_forest.add_block(block.clone()) {
+            Ok(reorg) => reorg,
+            Err(err) => {
+                warn!("rejected mined block {}: {:#}", base64::encode(hash), err);
+                self.metrics.gossip.blocks_rejected.inc();
+                return Ok(());
+            }
+        };
+        self.metrics.gossip.blocks_accepted.inc();
+        if let Some(reorg) = reorg {
+            self.publish_reorg(reorg);
+        }
+
+        info!(
+            "mined block {} (index {})",
+            base64::encode(hash),
+            block.index
+        );
+        self.broadcast(VerifiedPeerMessage::Block(Box::new(block)), None)
+            .await?;
+        self.update_mining_info().await
+    }
+
+    async fn send_eager_requests(&mut self) -> Result<()> {
+        // NB: prefer peers that never misbehaved and were ahead of us on connect, as they
+        // are the likeliest to have the missing blocks. Fall back to anyone otherwise.
+        let head_index = self.block_forest.head().index;
+        let well_behaved = |session: &SessionState| session.score >= 0;
+        let ahead = |session: &SessionState| {
+            session.score >= 0 && session.head.is_some_and(|head| head.index > head_index)
+        };
+        let mut sessions = self.sessions_where(ahead);
+        if sessions.is_empty() {
+            sessions = self.sessions_where(well_behaved);
+        }
+        if sessions.is_empty() {
+            sessions = self.sessions.keys().copied().collect();
+        }
+        let block_hashes = self
+            .block_forest
+            .unknown_block_hashes()
+            .iter()
+            .copied()
+            .collect::<Vec<BlockHash>>();
+
+        for block_hash in block_hashes {
+            let Some(&session_id) = sessions.choose(&mut thread_rng()) else {
+                break;
+            };
+            self.send_message(session_id, VerifiedPeerMessage::Request { block_hash })
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn sessions_where(&self, pred: impl Fn(&SessionState) -> bool) -> Vec<SessionId> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| pred(session))
+            .map(|(session_id, _)| *session_id)
+            .collect()
+    }
+
+    fn publish_reorg(&self, reorg: Reorg) {
+        if !reorg.is_extension() {
+            info!(
+                "chain reorganization: {} blocks removed, {} added, common ancestor {}",
+                reorg.removed.len(),
+                reorg.added.len(),
+                reorg.common_ancestor.index
+            );
+            self.metrics.gossip.reorgs.inc();
+        }
+        self.metrics
+            .gossip
+            .head_index
+            .set(reorg.new_head().index as i64);
+        let pruning_stats = self.block_forest.pruning_stats();
+        self.metrics
+            .gossip
+            .pruned_blocks
+            .set(pruning_stats.blocks as i64);
+        self.metrics
+            .gossip
+            .pruned_bytes
+            .set(pruning_stats.bytes as i64);
+        if let Some(sender) = self.head_sender.as_ref() {
+            sender.send_replace(reorg.new_head().as_ref().into());
+        }
+        if let Some(sender) = self.chain_event_sender.as_ref() {
+            // NB: it's fine to have no subscribers.
+            let _ = sender.send(Arc::new(reorg));
+        }
+    }
+
+    async fn update_mining_info(&mut self) -> Result<()> {
+        // NB: this follows every change of the mempool, so it's the place to sample it.
+        self.metrics
+            .gossip
+            .pending_transactions
+            .set(self.block_forest.pending_transactions().len() as i64);
+
+        let head = self.block_forest.head();
+        let info = MiningInfo {
+            block_index: head.index + 1,
+            prev_hash: *head.hash(),
+            max_hash: self.block_forest.next_max_hash(),
+            transactions: self.block_forest.pending_transactions_by_fee_rate(),
+        };
+        self.mining_info_sender
+            .send(info)
+            .await
+            .context("mining info channel is closed")
+    }
+
+    async fn broadcast(
+        &mut self,
+        message: VerifiedPeerMessage,
+        except: Option<SessionId>,
+    ) -> Result<()> {
+        let sessions = self
+            .sessions
+            .keys()
+            .copied()
+            .filter(|session_id| Some(*session_id) != except)
+            .collect::<Vec<_>>();
+        for session_id in sessions {
+            self.send_message(session_id, message.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        &mut self,
+        session_id: SessionId,
+        message: VerifiedPeerMessage,
+    ) -> Result<()> {
+        self.command_sender
+            .send(PeerCommand {
+                session_id,
+                command_kind: PeerCommandKind::SendMessage(message),
+            })
+            .await
+            .context("peer command channel is closed")
+    }
 }
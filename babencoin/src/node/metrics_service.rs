@@ -0,0 +1,459 @@
+use anyhow::{bail, Context, Result};
+use futures::future;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_HEADER_SIZE: usize = 8192;
+const METRICS_PATH: &str = "/metrics";
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A count that only goes up.
+#[derive(Default, Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.
+#[derive(Default, Debug)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, value: i64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Number and total length of observed intervals, exported as a summary without quantiles.
+#[derive(Default, Debug)]
+pub struct DurationSummary {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+}
+
+impl DurationSummary {
+    pub fn observe(&self, duration: Duration) {
+        self.total_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_micros(self.total_micros.load(Ordering::Relaxed))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug)]
+pub struct MiningMetrics {
+    pub threads: Gauge,
+    pub hashes: Counter,
+    /// Hashes per second over the last few seconds, updated by the mining service.
+    pub hash_rate: Gauge,
+    pub blocks_mined: Counter,
+    /// Time from receiving the work to finding a block for it.
+    pub block_time: DurationSummary,
+    pub work_updates: Counter,
+    /// Work abandoned after some hashing because a new `MiningInfo` arrived.
+    pub wasted_work: Counter,
+}
+
+#[derive(Default, Debug)]
+pub struct GossipMetrics {
+    pub blocks_accepted: Counter,
+    pub blocks_rejected: Counter,
+    pub transactions_accepted: Counter,
+    pub transactions_rejected: Counter,
+    pub reorgs: Counter,
+    pub head_index: Gauge,
+    pub pending_transactions: Gauge,
+    pub pruned_blocks: Gauge,
+    /// Estimated, see `PruningStats`.
+    pub pruned_bytes: Gauge,
+}
+
+#[derive(Default, Debug)]
+pub struct PeerMetrics {
+    pub sessions: Gauge,
+    pub messages_received: Counter,
+    pub messages_sent: Counter,
+    pub bytes_sent: Counter,
+    pub misbehaviours: Counter,
+    pub bans: Counter,
+}
+
+/// Counters and gauges of all node services. Every service gets a shared reference and
+/// updates its own section, the metrics service only reads them.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub mining: MiningMetrics,
+    pub gossip: GossipMetrics,
+    pub peer: PeerMetrics,
+}
+
+impl Metrics {
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = TextWriter::default();
+
+        let mining = &self.mining;
+        out.gauge(
+            "mining_threads",
+            "Number of mining threads.",
+            &mining.threads,
+        );
+        out.counter("mining_hashes_total", "Nonces tried.", &mining.hashes);
+        out.gauge(
+            "mining_hash_rate",
+            "Hashes per second over the last few seconds.",
+            &mining.hash_rate,
+        );
+        out.counter(
+            "mining_blocks_mined_total",
+            "Blocks found by this node.",
+            &mining.blocks_mined,
+        );
+        out.summary(
+            "mining_block_time_seconds",
+            "Time from receiving the work to finding a block.",
+            &mining.block_time,
+        );
+        out.counter(
+            "mining_work_updates_total",
+            "Mining infos received from the gossip service.",
+            &mining.work_updates,
+        );
+        out.counter(
+            "mining_wasted_work_total",
+            "Work abandoned by a mining thread because a new mining info arrived.",
+            &mining.wasted_work,
+        );
+
+        let gossip = &self.gossip;
+        out.counter(
+            "gossip_blocks_accepted_total",
+            "Blocks added to the block forest.",
+            &gossip.blocks_accepted,
+        );
+        out.counter(
+            "gossip_blocks_rejected_total",
+            "Blocks rejected by the block forest.",
+            &gossip.blocks_rejected,
+        );
+        out.counter(
+            "gossip_transactions_accepted_total",
+            "Transactions added to the mempool.",
+            &gossip.transactions_accepted,
+        );
+        out.counter(
+            "gossip_transactions_rejected_total",
+            "Transactions rejected by the mempool.",
+            &gossip.transactions_rejected,
+        );
+        out.counter(
+            "gossip_reorgs_total",
+            "Head switches that removed blocks from the main chain.",
+            &gossip.reorgs,
+        );
+        out.gauge(
+            "gossip_head_index",
+            "Index of the head block.",
+            &gossip.head_index,
+        );
+        out.gauge(
+            "gossip_pending_transactions",
+            "Transactions in the mempool.",
+            &gossip.pending_transactions,
+        );
+        out.gauge(
+            "gossip_pruned_blocks",
+            "Blocks dropped below the finality depth.",
+            &gossip.pruned_blocks,
+        );
+        out.gauge(
+            "gossip_pruned_bytes",
+            "Memory reclaimed by pruning, estimated.",
+            &gossip.pruned_bytes,
+        );
+
+        let peer = &self.peer;
+        out.gauge(
+            "peer_sessions",
+            "Sessions that completed the handshake.",
+            &peer.sessions,
+        );
+        out.counter(
+            "peer_messages_received_total",
+            "Verified messages received from peers.",
+            &peer.messages_received,
+        );
+        out.counter(
+            "peer_messages_sent_total",
+            "Messages sent to peers.",
+            &peer.messages_sent,
+        );
+        out.counter(
+            "peer_bytes_sent_total",
+            "Bytes of framed messages sent to peers.",
+            &peer.bytes_sent,
+        );
+        out.counter(
+            "peer_misbehaviours_total",
+            "Penalized peer misbehaviours.",
+            &peer.misbehaviours,
+        );
+        out.counter("peer_bans_total", "Banned peer addresses.", &peer.bans);
+
+        out.0
+    }
+
+    fn summary_line(&self) -> String {
+        format!(
+            "{} H/s, {} blocks mined, {} wasted work, head index {}, {} pending transactions, \
+             {} peers, {} messages received, {} sent",
+            self.mining.hash_rate.get(),
+            self.mining.blocks_mined.get(),
+            self.mining.wasted_work.get(),
+            self.gossip.head_index.get(),
+            self.gossip.pending_transactions.get(),
+            self.peer.sessions.get(),
+            self.peer.messages_received.get(),
+            self.peer.messages_sent.get(),
+        )
+    }
+}
+
+#[derive(Default)]
+struct TextWriter(String);
+
+impl TextWriter {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP babencoin_{} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE babencoin_{} {}", name, kind);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.0, "babencoin_{} {}", name, counter.get());
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, gauge: &Gauge) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.0, "babencoin_{} {}", name, gauge.get());
+    }
+
+    fn summary(&mut self, name: &str, help: &str, summary: &DurationSummary) {
+        self.header(name, help, "summary");
+        let _ = writeln!(
+            self.0,
+            "babencoin_{}_sum {}",
+            name,
+            summary.total().as_secs_f64()
+        );
+        let _ = writeln!(self.0, "babencoin_{}_count {}", name, summary.count());
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct MetricsServiceConfig {
+    /// Address to serve metrics in the Prometheus text format on, at `/metrics`.
+    pub listen_address: Option<String>,
+    /// Logs a summary of the metrics this often. Zero disables logging.
+    #[serde(default, with = "humantime_serde")]
+    pub log_interval: Duration,
+}
+
+/// Exposes node metrics over HTTP and/or logs them periodically.
+pub struct MetricsService {
+    config: MetricsServiceConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsService {
+    pub fn new(config: MetricsServiceConfig, metrics: Arc<Metrics>) -> Self {
+        Self { config, metrics }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let serve_future = Self::serve_loop(self.config.listen_address.clone(), &self.metrics);
+        let log_future = Self::log_loop(self.config.log_interval, &self.metrics);
+        future::try_join(serve_future, log_future).await?;
+        Ok(())
+    }
+
+    async fn log_loop(interval: Duration, metrics: &Metrics) -> Result<()> {
+        if interval.is_zero() {
+            return future::pending().await;
+        }
+
+        let mut interval = tokio::time::interval(interval);
+        // NB: the first tick completes immediately, when there's nothing to report yet.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            info!("metrics: {}", metrics.summary_line());
+        }
+    }
+
+    async fn serve_loop(listen_address: Option<String>, metrics: &Arc<Metrics>) -> Result<()> {
+        let Some(address) = listen_address else {
+            return future::pending().await;
+        };
+
+        let listener = TcpListener::bind(&address)
+            .await
+            .with_context(|| format!("failed to listen on {}", address))?;
+        info!("serving metrics on {}", address);
+
+        loop {
+            let (stream, peer_address) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(err) => {
+                    warn!("failed to accept metrics connection: {}", err);
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(err) = Self::serve_connection(stream, &metrics).await {
+                    debug!("metrics connection from {} failed: {:#}", peer_address, err);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+        let (read_half, mut write_half) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        let response = match Self::read_http_request(&mut reader).await {
+            Ok(path) if path == METRICS_PATH => {
+                Self::make_http_response("200 OK", &metrics.render())
+            }
+            Ok(path) => {
+                Self::make_http_response("404 Not Found", &format!("no such path {}\n", path))
+            }
+            Err(err) => Self::make_http_response("400 Bad Request", &format!("{:#}\n", err)),
+        };
+        write_half.write_all(response.as_bytes()).await?;
+        write_half.flush().await?;
+        Ok(())
+    }
+
+    /// Reads a GET request and returns its path.
+    async fn read_http_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<String> {
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let Some(path) = request_line
+            .strip_prefix("GET ")
+            .and_then(|rest| rest.split_whitespace().next())
+        else {
+            bail!("only GET requests are supported");
+        };
+
+        let mut header_size = request_line.len();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                bail!("connection closed before the end of headers");
+            }
+            header_size += line.len();
+            if header_size > MAX_HEADER_SIZE {
+                bail!("headers are larger than {} bytes", MAX_HEADER_SIZE);
+            }
+            if line.trim_end().is_empty() {
+                break;
+            }
+        }
+        Ok(path.to_string())
+    }
+
+    fn make_http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.mining.hashes.add(3000);
+        metrics
+            .mining
+            .block_time
+            .observe(Duration::from_millis(1500));
+        metrics
+            .mining
+            .block_time
+            .observe(Duration::from_millis(500));
+        metrics.peer.sessions.add(2);
+        metrics.peer.sessions.add(-1);
+
+        let text = metrics.render();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"# TYPE babencoin_mining_hashes_total counter"));
+        assert!(lines.contains(&"babencoin_mining_hashes_total 3000"));
+        assert!(lines.contains(&"babencoin_mining_block_time_seconds_sum 2"));
+        assert!(lines.contains(&"babencoin_mining_block_time_seconds_count 2"));
+        assert!(lines.contains(&"babencoin_peer_sessions 1"));
+
+        for line in lines.iter().filter(|line| !line.starts_with('#')) {
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(name.starts_with("babencoin_"));
+            value.parse::<f64>().unwrap();
+        }
+    }
+}
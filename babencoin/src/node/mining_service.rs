@@ -1,13 +1,16 @@
 use crate::{
+    chain_params::ChainParams,
     data::{
         Block, BlockAttributes, BlockHash, Transaction, VerifiedBlock, VerifiedTransaction,
-        WalletId, MAX_REWARD,
+        WalletId, HASH_LEN,
     },
+    node::{metrics_service::Metrics, supervisor::ShutdownToken},
+    pow::PowHash,
     util::{deserialize_wallet_id, serialize_wallet_id},
 };
 
-use anyhow::{Context, Result};
-use chrono::Utc;
+use anyhow::{bail, Context, Result};
+use chrono::{TimeZone, Utc};
 use futures::{stream, Stream, StreamExt};
 use log::*;
 use rand::{thread_rng, Rng};
@@ -20,13 +23,20 @@ use tokio::{
 use std::{
     sync::{
         mpsc::{self, SyncSender},
-        Arc, RwLock,
+        Arc,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
+const NONCES_PER_ATTEMPT: usize = 1000;
+const MINING_IDLE_INTERVAL: Duration = Duration::from_millis(50);
+const HASH_RATE_INTERVAL: Duration = Duration::from_secs(5);
+
+////////////////////////////////////////////////////////////////////////////////
+
 #[derive(Serialize, Deserialize)]
 pub struct MiningServiceConfig {
     pub mining_thread_count: usize,
@@ -44,7 +54,7 @@ impl Default for MiningServiceConfig {
         Self {
             mining_thread_count: 0,
             max_tx_per_block: 0,
-            public_key: WalletId::genesis(),
+            public_key: ChainParams::mainnet().genesis_issuer,
         }
     }
 }
@@ -56,11 +66,18 @@ pub struct MiningInfo {
     pub block_index: u64,
     pub prev_hash: BlockHash,
     pub max_hash: BlockHash,
+    /// Pending transactions in fee rate order, so any prefix of them makes a valid block.
     pub transactions: Vec<VerifiedTransaction>,
 }
 
 pub struct MiningService {
-    // TODO: your code here.
+    config: MiningServiceConfig,
+    info_receiver: Receiver<MiningInfo>,
+    block_sender: Sender<VerifiedBlock>,
+    thread_info_senders: Vec<mpsc::Sender<Arc<MiningInfo>>>,
+    params: Arc<ChainParams>,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownToken,
 }
 
 impl MiningService {
@@ -69,13 +86,120 @@ impl MiningService {
         info_receiver: Receiver<MiningInfo>,
         block_sender: Sender<VerifiedBlock>,
     ) -> Self {
-        // TODO: your code here.
-        unimplemented!()
+        Self {
+            config,
+            info_receiver,
+            block_sender,
+            thread_info_senders: vec![],
+            params: Arc::new(ChainParams::mainnet()),
+            metrics: Default::default(),
+            shutdown: Default::default(),
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Blocks are mined for the chain of `params`, mainnet by default.
+    pub fn with_chain_params(mut self, params: Arc<ChainParams>) -> Self {
+        self.params = params;
+        self
     }
 
+    /// Once `shutdown` is triggered, `run` returns.
+    pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Stops and joins the mining threads on return, whatever the reason.
     pub async fn run(&mut self) -> Result<()> {
-        // TODO: your code here.
-        unimplemented!()
+        if self.config.public_key.public_key().is_none() {
+            bail!("mining rewards can't go to a multisig wallet");
+        }
+
+        let (thread_block_sender, thread_block_receiver) =
+            mpsc::sync_channel(self.config.mining_thread_count);
+        let mut threads = vec![];
+        for _ in 0..self.config.mining_thread_count {
+            let (info_sender, info_receiver) = mpsc::channel();
+            let public_key = self.config.public_key.clone();
+            let block_sender = thread_block_sender.clone();
+            let params = self.params.clone();
+            let metrics = self.metrics.clone();
+            threads.push(thread::spawn(move || {
+                Self::mining_thread(public_key, &params, info_receiver, block_sender, &metrics)
+            }));
+            self.thread_info_senders.push(info_sender);
+        }
+        drop(thread_block_sender);
+        self.metrics
+            .mining
+            .threads
+            .set(self.config.mining_thread_count as i64);
+
+        let result = self.serve(thread_block_receiver).await;
+
+        // NB: a mining thread returns as soon as it sees its work channel closed.
+        self.thread_info_senders.clear();
+        let joined = tokio::task::spawn_blocking(move || {
+            threads
+                .into_iter()
+                .map(|thread| thread.join())
+                .filter(Result::is_err)
+                .count()
+        })
+        .await;
+        match joined {
+            Ok(0) => debug!("mining threads have stopped"),
+            Ok(panicked) => warn!("{} mining threads have panicked", panicked),
+            Err(err) => warn!("failed to join mining threads: {}", err),
+        }
+        self.metrics.mining.threads.set(0);
+        result
+    }
+
+    async fn serve(&mut self, thread_block_receiver: mpsc::Receiver<VerifiedBlock>) -> Result<()> {
+        let blocks = Self::make_block_stream(thread_block_receiver).fuse();
+        pin!(blocks);
+
+        let mut hash_rate_interval = tokio::time::interval(HASH_RATE_INTERVAL);
+        let mut last_hashes = (Instant::now(), self.metrics.mining.hashes.get());
+
+        loop {
+            select! {
+                info = self.info_receiver.recv() => {
+                    let mut info = info.context("mining info channel is closed")?;
+                    info.transactions.truncate(self.config.max_tx_per_block);
+                    self.metrics.mining.work_updates.inc();
+                    let info = Arc::new(info);
+                    for sender in self.thread_info_senders.iter() {
+                        sender.send(info.clone()).context("mining thread has terminated")?;
+                    }
+                }
+                Some(block) = blocks.next() => {
+                    self.block_sender
+                        .send(block)
+                        .await
+                        .context("block channel is closed")?;
+                }
+                _ = hash_rate_interval.tick() => {
+                    let now = (Instant::now(), self.metrics.mining.hashes.get());
+                    let elapsed = now.0.duration_since(last_hashes.0).as_secs_f64();
+                    if elapsed > 0.0 {
+                        let rate = (now.1 - last_hashes.1) as f64 / elapsed;
+                        self.metrics.mining.hash_rate.set(rate as i64);
+                    }
+                    last_hashes = now;
+                }
+                _ = self.shutdown.triggered() => {
+                    info!("mining service is shutting down");
+                    return Ok(());
+                }
+            }
+        }
     }
 
     fn make_block_stream(
@@ -91,6 +215,105 @@ impl MiningService {
         })
     }
 
-    // TODO: your code here.
-}
+    fn mining_thread(
+        public_key: WalletId,
+        params: &ChainParams,
+        info_receiver: mpsc::Receiver<Arc<MiningInfo>>,
+        block_sender: SyncSender<VerifiedBlock>,
+        metrics: &Metrics,
+    ) {
+        let mut rng = thread_rng();
+        let pow_hash = params.make_pow_hash();
+        let mut next_info = None;
+        loop {
+            let info = match next_info.take() {
+                Some(info) => info,
+                None => match info_receiver.recv() {
+                    Ok(info) => info,
+                    Err(_) => return,
+                },
+            };
+
+            // NB: the parent block cannot be newer than the moment we learned about it,
+            // so waiting for the next second guarantees a strictly greater timestamp.
+            let min_timestamp = Utc::now().timestamp() + 1;
+            let mut block = Block {
+                attrs: BlockAttributes {
+                    index: info.block_index,
+                    reward: params.max_reward,
+                    nonce: 0,
+                    timestamp: Utc::now(),
+                    issuer: public_key.clone(),
+                    max_hash: info.max_hash,
+                    prev_hash: info.prev_hash,
+                    merkle_root: [0; HASH_LEN],
+                },
+                transactions: info
+                    .transactions
+                    .iter()
+                    .map(|tx| (tx as &Transaction).clone())
+                    .collect(),
+            };
+            block.update_merkle_root();
 
+            let started_at = Instant::now();
+            let mut hashed = false;
+            loop {
+                match info_receiver.try_recv() {
+                    Ok(info) => {
+                        if hashed {
+                            metrics.mining.wasted_work.inc();
+                        }
+                        next_info = Some(info);
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => (),
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+
+                let now = Utc::now().timestamp();
+                if now < min_timestamp {
+                    thread::sleep(MINING_IDLE_INTERVAL);
+                    continue;
+                }
+                block.timestamp = Utc.timestamp_opt(now, 0).unwrap();
+
+                hashed = true;
+                if let Some(verified) =
+                    Self::try_mine(&mut block, params, pow_hash.as_ref(), &mut rng, metrics)
+                {
+                    metrics.mining.blocks_mined.inc();
+                    metrics.mining.block_time.observe(started_at.elapsed());
+                    if block_sender.send(verified).is_err() {
+                        return;
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn try_mine(
+        block: &mut Block,
+        params: &ChainParams,
+        pow_hash: &dyn PowHash,
+        rng: &mut impl Rng,
+        metrics: &Metrics,
+    ) -> Option<VerifiedBlock> {
+        for attempt in 1..=NONCES_PER_ATTEMPT {
+            block.nonce = rng.gen();
+            if pow_hash.compute(&block.compute_hash()) <= block.max_hash {
+                metrics.mining.hashes.add(attempt as u64);
+                match block.clone().verified_for(params) {
+                    Ok(verified) => return Some(verified),
+                    Err(err) => {
+                        warn!("mined an invalid block: {:#}", err);
+                        return None;
+                    }
+                }
+            }
+        }
+        metrics.mining.hashes.add(NONCES_PER_ATTEMPT as u64);
+        None
+    }
+}
@@ -1,11 +1,27 @@
-use crate::data::{PeerMessage, VerifiedPeerMessage};
+use super::{
+    address_book::AddressBook,
+    metrics_service::Metrics,
+    supervisor::ShutdownToken,
+    wire::{self, FrameBuffer, WireFormat},
+};
+use crate::{
+    chain_params::ChainParams,
+    data::{
+        BlockHash, PeerAddress, PeerMessage, VerifiedBlock, VerifiedPeerMessage, FEATURE_GOODBYE,
+        FEATURE_PEER_EXCHANGE, MAX_PEERS_PER_MESSAGE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        SUPPORTED_FEATURES,
+    },
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
 use futures::{
+    future,
     stream::{self, FuturesUnordered},
     Stream, StreamExt,
 };
 use log::*;
+use rand::{seq::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
@@ -14,22 +30,35 @@ use tokio::{
         TcpListener, TcpStream,
     },
     pin, select,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        watch,
+    },
     task::JoinHandle,
 };
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Mutex,
+        Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
-const BUF_SIZE: usize = 65536;
+const READ_CHUNK_SIZE: usize = 65536;
+const SESSION_COMMAND_QUEUE_SIZE: usize = 1000;
+const FLOOD_WINDOW: Duration = Duration::from_secs(1);
+const ADDRESS_BOOK_CAPACITY: usize = 1024;
+const MIN_DISCOVERY_INTERVAL: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(5);
+const GOODBYE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub type SessionId = u64;
 
@@ -41,6 +70,59 @@ pub struct PeerServiceConfig {
     pub dial_cooldown: Duration,
     pub dial_addresses: Vec<String>,
     pub listen_address: Option<String>,
+    /// Address to advertise to other peers, if this node is reachable from outside.
+    #[serde(default)]
+    pub advertised_address: Option<String>,
+    /// Number of outbound connections to maintain by dialing addresses learned from peers.
+    /// Static `dial_addresses` count towards it, but are dialed regardless.
+    #[serde(default = "default_target_outbound_count")]
+    pub target_outbound_count: usize,
+    #[serde(default)]
+    pub scoring: PeerScoringConfig,
+}
+
+fn default_target_outbound_count() -> usize {
+    8
+}
+
+/// Every session starts with a zero score, which is lowered on each misbehaviour.
+/// Once the score drops below `ban_threshold`, the session is dropped and its IP is
+/// banned for `ban_duration`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PeerScoringConfig {
+    pub ban_threshold: i64,
+    #[serde(with = "humantime_serde")]
+    pub ban_duration: Duration,
+    pub invalid_message_penalty: i64,
+    pub malformed_message_penalty: i64,
+    pub oversized_message_penalty: i64,
+    pub flood_penalty: i64,
+    pub max_messages_per_second: u32,
+}
+
+impl Default for PeerScoringConfig {
+    fn default() -> Self {
+        Self {
+            ban_threshold: -100,
+            ban_duration: Duration::from_secs(600),
+            invalid_message_penalty: 20,
+            malformed_message_penalty: 50,
+            oversized_message_penalty: 50,
+            flood_penalty: 1,
+            max_messages_per_second: 1000,
+        }
+    }
+}
+
+impl PeerScoringConfig {
+    fn penalty(&self, misbehaviour: Misbehaviour) -> i64 {
+        match misbehaviour {
+            Misbehaviour::InvalidMessage => self.invalid_message_penalty,
+            Misbehaviour::MalformedMessage => self.malformed_message_penalty,
+            Misbehaviour::OversizedMessage => self.oversized_message_penalty,
+            Misbehaviour::Flood => self.flood_penalty,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -49,11 +131,47 @@ pub struct PeerEvent {
     pub event_kind: PeerEventKind,
 }
 
+/// Index and hash of the block a node considers its head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainHead {
+    pub index: u64,
+    pub hash: BlockHash,
+}
+
+impl From<&VerifiedBlock> for ChainHead {
+    fn from(block: &VerifiedBlock) -> Self {
+        Self {
+            index: block.index,
+            hash: *block.hash(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum PeerEventKind {
+    /// The peer has completed the handshake. Nothing is reported about sessions that
+    /// fail it.
     Connected,
     Disconnected,
+    /// The head the peer announced in its hello, sent right after `Connected`.
+    Head(ChainHead),
     NewMessage(VerifiedPeerMessage),
+    ScoreChanged {
+        score: i64,
+        misbehaviour: Misbehaviour,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// The message is well-formed, but fails verification or is out of place.
+    InvalidMessage,
+    /// The message is not a valid json of a known kind.
+    MalformedMessage,
+    /// The message exceeds the frame size limit.
+    OversizedMessage,
+    /// The peer sends more messages than allowed.
+    Flood,
 }
 
 #[derive(Debug, Clone)]
@@ -71,7 +189,77 @@ pub enum PeerCommandKind {
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct PeerService {
-    // TODO: your code here.
+    config: PeerServiceConfig,
+    command_receiver: Receiver<PeerCommand>,
+    shared: Shared,
+}
+
+/// State shared by the service and all of its session tasks.
+#[derive(Clone)]
+struct Shared {
+    peer_event_sender: Sender<PeerEvent>,
+    scoring: Arc<PeerScoringConfig>,
+    sessions: Arc<Mutex<HashMap<SessionId, Sender<PeerCommandKind>>>>,
+    bans: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    address_book: Arc<Mutex<AddressBook>>,
+    /// Addresses that are being dialed or have a live outbound session.
+    outbound: Arc<Mutex<HashSet<String>>>,
+    advertised_address: Option<String>,
+    target_outbound_count: usize,
+    next_session_id: Arc<AtomicU64>,
+    params: Arc<ChainParams>,
+    network_id: BlockHash,
+    head_receiver: watch::Receiver<ChainHead>,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownToken,
+}
+
+impl Shared {
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn ban(&self, ip: IpAddr) {
+        let until = Instant::now() + self.scoring.ban_duration;
+        self.bans.lock().unwrap().insert(ip, until);
+        self.metrics.peer.bans.inc();
+    }
+
+    fn needs_peers(&self) -> bool {
+        self.outbound.lock().unwrap().len() < self.target_outbound_count
+    }
+
+    fn hello(&self) -> VerifiedPeerMessage {
+        let head = *self.head_receiver.borrow();
+        VerifiedPeerMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            network_id: self.network_id,
+            head_index: head.index,
+            head_hash: head.hash,
+            features: SUPPORTED_FEATURES,
+        }
+    }
+
+    fn known_peers(&self) -> Vec<PeerAddress> {
+        let mut peers = vec![];
+        if let Some(address) = self.advertised_address.as_ref() {
+            peers.push(PeerAddress {
+                address: address.clone(),
+                last_seen: Utc::now(),
+            });
+        }
+        let limit = MAX_PEERS_PER_MESSAGE - peers.len();
+        peers.extend(self.address_book.lock().unwrap().recent(limit));
+        peers
+    }
 }
 
 impl PeerService {
@@ -79,37 +267,591 @@ impl PeerService {
         config: PeerServiceConfig,
         peer_event_sender: Sender<PeerEvent>,
         command_receiver: Receiver<PeerCommand>,
+        head_receiver: watch::Receiver<ChainHead>,
     ) -> Self {
-        // TODO: your code here.
-        unimplemented!()
+        let mut address_book = AddressBook::new(ADDRESS_BOOK_CAPACITY);
+        for address in config.dial_addresses.iter() {
+            address_book.pin(address.clone());
+        }
+
+        let shared = Shared {
+            peer_event_sender,
+            scoring: Arc::new(config.scoring.clone()),
+            sessions: Default::default(),
+            bans: Default::default(),
+            address_book: Arc::new(Mutex::new(address_book)),
+            outbound: Default::default(),
+            advertised_address: config.advertised_address.clone(),
+            target_outbound_count: config.target_outbound_count,
+            next_session_id: Default::default(),
+            params: Arc::new(ChainParams::mainnet()),
+            network_id: ChainParams::mainnet().network_id(),
+            head_receiver,
+            metrics: Default::default(),
+            shutdown: Default::default(),
+        };
+        Self {
+            config,
+            command_receiver,
+            shared,
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.shared.metrics = metrics;
+        self
+    }
+
+    /// Peers are expected to be on the chain of `params`, mainnet by default.
+    pub fn with_chain_params(mut self, params: Arc<ChainParams>) -> Self {
+        self.shared.network_id = params.network_id();
+        self.shared.params = params;
+        self
+    }
+
+    /// Once `shutdown` is triggered, every session says goodbye to its peer, and `run`
+    /// returns when they are done.
+    pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+        self.shared.shutdown = shutdown;
+        self
     }
 
     pub async fn run(&mut self) -> Result<()> {
-        // TODO: your code here.
-        unimplemented!()
+        let listen_future =
+            Self::listen_loop(self.config.listen_address.clone(), self.shared.clone());
+        pin!(listen_future);
+
+        let mut dial_futures = self
+            .config
+            .dial_addresses
+            .iter()
+            .map(|address| {
+                Self::dial_loop(
+                    address.clone(),
+                    self.config.dial_cooldown,
+                    self.shared.clone(),
+                )
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let discovery_future = self.discovery_loop();
+        pin!(discovery_future);
+
+        loop {
+            select! {
+                result = &mut listen_future => {
+                    return result.context("listen loop terminated");
+                }
+                result = &mut discovery_future => {
+                    return result.context("discovery loop terminated");
+                }
+                Some(result) = dial_futures.next() => {
+                    result.context("dial loop terminated")?;
+                }
+                command = self.command_receiver.recv() => {
+                    let Some(command) = command else {
+                        bail!("command channel is closed");
+                    };
+                    self.handle_command(command);
+                }
+                _ = self.shared.shutdown.triggered() => {
+                    info!("peer service is shutting down");
+                    self.wait_for_sessions().await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Gives sessions a chance to say goodbye before they are dropped along with the runtime.
+    async fn wait_for_sessions(&self) {
+        let deadline = Instant::now() + GOODBYE_TIMEOUT;
+        while !self.shared.sessions.lock().unwrap().is_empty() {
+            if Instant::now() >= deadline {
+                warn!("some sessions haven't closed in time");
+                return;
+            }
+            tokio::time::sleep(GOODBYE_POLL_INTERVAL).await;
+        }
+    }
+
+    fn handle_command(&self, command: PeerCommand) {
+        let sender = self
+            .shared
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&command.session_id)
+            .cloned();
+        let Some(sender) = sender else {
+            debug!(
+                "dropping command for unknown session {}",
+                command.session_id
+            );
+            return;
+        };
+        // NB: never block here, otherwise a slow peer may stall the gossip service.
+        if let Err(err) = sender.try_send(command.command_kind) {
+            warn!(
+                "failed to pass command to session {}: {}",
+                command.session_id, err
+            );
+        }
+    }
+
+    async fn listen_loop(listen_address: Option<String>, shared: Shared) -> Result<()> {
+        let Some(address) = listen_address else {
+            return future::pending().await;
+        };
+
+        let listener = TcpListener::bind(&address)
+            .await
+            .with_context(|| format!("failed to listen on {}", address))?;
+        info!("listening on {}", address);
+
+        loop {
+            let (stream, peer_address) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(err) => {
+                    warn!("failed to accept connection: {}", err);
+                    continue;
+                }
+            };
+            if shared.is_banned(peer_address.ip()) {
+                debug!("rejected connection from banned {}", peer_address);
+                continue;
+            }
+            info!("accepted connection from {}", peer_address);
+            Self::start_session(stream, peer_address, shared.clone());
+        }
+    }
+
+    async fn dial_loop(address: String, dial_cooldown: Duration, shared: Shared) -> Result<()> {
+        loop {
+            shared.outbound.lock().unwrap().insert(address.clone());
+            Self::run_outbound(&address, &shared).await;
+            tokio::time::sleep(dial_cooldown).await;
+        }
+    }
+
+    /// Keeps the number of outbound connections at the target by dialing learned addresses.
+    fn discovery_loop(&self) -> impl Future<Output = Result<()>> {
+        let shared = self.shared.clone();
+        let target = shared.target_outbound_count;
+        let interval = self.config.dial_cooldown.max(MIN_DISCOVERY_INTERVAL);
+        let own_addresses = self
+            .config
+            .listen_address
+            .iter()
+            .chain(self.config.advertised_address.iter())
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        async move {
+            if target == 0 {
+                return future::pending().await;
+            }
+            loop {
+                tokio::time::sleep(interval).await;
+                Self::discover(&shared, target, &own_addresses);
+            }
+        }
+    }
+
+    fn discover(shared: &Shared, target: usize, own_addresses: &HashSet<String>) {
+        let mut outbound = shared.outbound.lock().unwrap();
+        if outbound.len() >= target {
+            return;
+        }
+
+        let mut candidates = shared
+            .address_book
+            .lock()
+            .unwrap()
+            .dial_candidates(|address| {
+                !outbound.contains(address) && !own_addresses.contains(address)
+            });
+        if candidates.is_empty() {
+            // NB: ask everyone for more addresses, they will show up by the next round.
+            for sender in shared.sessions.lock().unwrap().values() {
+                let _ =
+                    sender.try_send(PeerCommandKind::SendMessage(VerifiedPeerMessage::GetPeers));
+            }
+            return;
+        }
+
+        candidates.shuffle(&mut thread_rng());
+        candidates.truncate(target - outbound.len());
+        for address in candidates {
+            debug!("dialing discovered peer {}", address);
+            outbound.insert(address.clone());
+            let shared = shared.clone();
+            tokio::spawn(async move { Self::run_outbound(&address, &shared).await });
+        }
+    }
+
+    /// Dials `address` and waits for the session to end. The address must already be
+    /// registered in `shared.outbound`, it's removed once done.
+    async fn run_outbound(address: &str, shared: &Shared) {
+        match Self::dial(address, shared).await {
+            Ok(handle) => {
+                shared.address_book.lock().unwrap().mark_seen(address);
+                if let Err(err) = handle.await {
+                    warn!("session with {} panicked: {}", address, err);
+                }
+            }
+            Err(err) => {
+                shared.address_book.lock().unwrap().mark_failed(address);
+                debug!("failed to connect to {}: {:#}", address, err);
+            }
+        }
+        shared.outbound.lock().unwrap().remove(address);
+    }
+
+    async fn dial(address: &str, shared: &Shared) -> Result<JoinHandle<()>> {
+        let stream = TcpStream::connect(address).await?;
+        let peer_address = stream.peer_addr()?;
+        if shared.is_banned(peer_address.ip()) {
+            bail!("{} is banned", peer_address);
+        }
+        info!("connected to {}", address);
+        Ok(Self::start_session(stream, peer_address, shared.clone()))
     }
 
-    // TODO: your code here.
+    fn start_session(
+        stream: TcpStream,
+        peer_address: SocketAddr,
+        shared: Shared,
+    ) -> JoinHandle<()> {
+        let session_id = shared.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let (command_sender, command_receiver) = channel(SESSION_COMMAND_QUEUE_SIZE);
+        shared
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(session_id, command_sender);
+
+        tokio::spawn(async move {
+            let mut session = Session {
+                id: session_id,
+                peer_address,
+                shared: shared.clone(),
+                score: 0,
+                window_start: Instant::now(),
+                window_message_count: 0,
+                wire_format: WireFormat::Json,
+                peer_features: 0,
+                established: false,
+            };
+            if let Err(err) = session.run(stream, command_receiver).await {
+                info!("session {} terminated: {:#}", session_id, err);
+            }
+
+            shared.sessions.lock().unwrap().remove(&session_id);
+            if session.established {
+                shared.metrics.peer.sessions.add(-1);
+                shared
+                    .send_event(session_id, PeerEventKind::Disconnected)
+                    .await;
+            }
+        })
+    }
+}
+
+impl Shared {
+    async fn send_event(&self, session_id: SessionId, event_kind: PeerEventKind) {
+        let _ = self
+            .peer_event_sender
+            .send(PeerEvent {
+                session_id,
+                event_kind,
+            })
+            .await;
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+struct Session {
+    id: SessionId,
+    peer_address: SocketAddr,
+    shared: Shared,
+    score: i64,
+    window_start: Instant,
+    window_message_count: u32,
+    /// Format of outgoing messages, json until the peer advertises a binary version.
+    wire_format: WireFormat,
+    peer_features: u64,
+    /// Whether the handshake is done and the gossip service knows about the session.
+    established: bool,
+}
+
+impl Session {
+    async fn run(
+        &mut self,
+        mut stream: TcpStream,
+        mut command_receiver: Receiver<PeerCommandKind>,
+    ) -> Result<()> {
+        let (read_half, write_half) = stream.split();
+        let messages = MessageReader::new(read_half, self.shared.params.clone()).into_stream();
+        pin!(messages);
+        let mut writer = BufWriter::new(write_half);
+
+        self.write_message(&mut writer, self.shared.hello()).await?;
+        let head = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.receive_hello(messages.as_mut()))
+            .await
+            .context("handshake timed out")??;
+        self.established = true;
+        self.shared.metrics.peer.sessions.add(1);
+        self.shared
+            .send_event(self.id, PeerEventKind::Connected)
+            .await;
+        self.shared
+            .send_event(self.id, PeerEventKind::Head(head))
+            .await;
+
+        if self.peer_features & FEATURE_PEER_EXCHANGE != 0 && self.shared.needs_peers() {
+            self.write_message(&mut writer, VerifiedPeerMessage::GetPeers)
+                .await?;
+        }
+
+        let shutdown = self.shared.shutdown.clone();
+        loop {
+            select! {
+                Some(message) = messages.next() => {
+                    match message {
+                        Ok(received) => {
+                            if matches!(received.message, VerifiedPeerMessage::Goodbye) {
+                                info!("session {} peer has shut down", self.id);
+                                return Ok(());
+                            }
+                            self.negotiate_wire_format(&received.wire_versions);
+                            self.handle_message(received.message, &mut writer).await?;
+                        }
+                        Err(err) => self.handle_read_error(err).await?,
+                    }
+                }
+                command = command_receiver.recv() => {
+                    match command {
+                        Some(PeerCommandKind::SendMessage(message)) => {
+                            self.write_message(&mut writer, message).await?;
+                        }
+                        Some(PeerCommandKind::Drop) | None => {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = shutdown.triggered() => {
+                    if self.peer_features & FEATURE_GOODBYE != 0 {
+                        self.write_message(&mut writer, VerifiedPeerMessage::Goodbye)
+                            .await?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Waits for the peer's hello and checks that both sides can talk to each other.
+    async fn receive_hello(
+        &mut self,
+        mut messages: Pin<&mut impl Stream<Item = Result<ReceivedMessage, ReadError>>>,
+    ) -> Result<ChainHead> {
+        let received = loop {
+            match messages.next().await.context("message stream has ended")? {
+                Ok(received) => break received,
+                Err(err) => self.handle_read_error(err).await?,
+            }
+        };
+        self.negotiate_wire_format(&received.wire_versions);
+
+        let VerifiedPeerMessage::Hello {
+            protocol_version,
+            network_id,
+            head_index,
+            head_hash,
+            features,
+        } = received.message
+        else {
+            bail!("peer has not started with a hello");
+        };
+        if protocol_version < MIN_PROTOCOL_VERSION {
+            bail!(
+                "peer speaks protocol version {}, at least {} is required",
+                protocol_version,
+                MIN_PROTOCOL_VERSION
+            );
+        }
+        if network_id != self.shared.network_id {
+            bail!("peer is on another network {}", base64::encode(network_id));
+        }
+
+        debug!(
+            "session {} completed handshake (protocol version {}, features {:#x}, head index {})",
+            self.id, protocol_version, features, head_index
+        );
+        self.peer_features = features;
+        Ok(ChainHead {
+            index: head_index,
+            hash: head_hash,
+        })
+    }
+
+    async fn handle_read_error(&mut self, err: ReadError) -> Result<()> {
+        match err {
+            ReadError::Closed(err) => Err(err),
+            ReadError::Fatal(misbehaviour, err) => {
+                self.penalize(misbehaviour).await?;
+                Err(err)
+            }
+            ReadError::Rejected(misbehaviour, err) => {
+                debug!("session {} sent a bad message: {:#}", self.id, err);
+                self.penalize(misbehaviour).await
+            }
+        }
+    }
+
+    async fn handle_message(
+        &mut self,
+        message: VerifiedPeerMessage,
+        writer: &mut BufWriter<WriteHalf<'_>>,
+    ) -> Result<()> {
+        self.shared.metrics.peer.messages_received.inc();
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= FLOOD_WINDOW {
+            self.window_start = now;
+            self.window_message_count = 0;
+        }
+        self.window_message_count += 1;
+        if self.window_message_count > self.shared.scoring.max_messages_per_second {
+            return self.penalize(Misbehaviour::Flood).await;
+        }
+
+        // NB: handshake and address exchange are handled here, the gossip service never
+        // sees them.
+        match message {
+            VerifiedPeerMessage::Hello { .. } => {
+                debug!("session {} sent a second hello", self.id);
+                return self.penalize(Misbehaviour::InvalidMessage).await;
+            }
+            VerifiedPeerMessage::GetPeers => {
+                let peers = self.shared.known_peers();
+                return self
+                    .write_message(writer, VerifiedPeerMessage::Peers { peers })
+                    .await;
+            }
+            VerifiedPeerMessage::Peers { peers } => {
+                let mut address_book = self.shared.address_book.lock().unwrap();
+                for peer in peers {
+                    address_book.insert(peer);
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        self.shared
+            .peer_event_sender
+            .send(PeerEvent {
+                session_id: self.id,
+                event_kind: PeerEventKind::NewMessage(message),
+            })
+            .await
+            .context("peer event channel is closed")
+    }
+
+    async fn penalize(&mut self, misbehaviour: Misbehaviour) -> Result<()> {
+        self.shared.metrics.peer.misbehaviours.inc();
+        self.score = self
+            .score
+            .saturating_sub(self.shared.scoring.penalty(misbehaviour));
+        self.shared
+            .send_event(
+                self.id,
+                PeerEventKind::ScoreChanged {
+                    score: self.score,
+                    misbehaviour,
+                },
+            )
+            .await;
+
+        if self.score < self.shared.scoring.ban_threshold {
+            self.shared.ban(self.peer_address.ip());
+            bail!(
+                "banned {} (score {}, last misbehaviour: {:?})",
+                self.peer_address.ip(),
+                self.score,
+                misbehaviour
+            );
+        }
+        Ok(())
+    }
+
+    fn negotiate_wire_format(&mut self, peer_versions: &[u8]) {
+        if self.wire_format != WireFormat::Json || peer_versions.is_empty() {
+            return;
+        }
+        self.wire_format = WireFormat::negotiate(peer_versions);
+        debug!(
+            "session {} negotiated {:?} wire format (peer supports {:?})",
+            self.id, self.wire_format, peer_versions
+        );
+    }
+
+    async fn write_message(
+        &self,
+        writer: &mut BufWriter<WriteHalf<'_>>,
+        message: VerifiedPeerMessage,
+    ) -> Result<()> {
+        let data = match wire::encode_frame(&PeerMessage::from(message), self.wire_format) {
+            Ok(data) => data,
+            Err(err) => {
+                // NB: e.g. a block too large for a json-only peer, it's not worth the session.
+                warn!("session {} dropped an outgoing message: {:#}", self.id, err);
+                return Ok(());
+            }
+        };
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        self.shared.metrics.peer.messages_sent.inc();
+        self.shared.metrics.peer.bytes_sent.add(data.len() as u64);
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum ReadError {
+    /// The connection is closed or broken.
+    Closed(anyhow::Error),
+    /// The stream can not be read any further.
+    Fatal(Misbehaviour, anyhow::Error),
+    /// A single message is rejected, but the stream is still usable.
+    Rejected(Misbehaviour, anyhow::Error),
+}
+
+/// A verified message along with the binary wire versions its sender has advertised.
+struct ReceivedMessage {
+    message: VerifiedPeerMessage,
+    wire_versions: Vec<u8>,
+}
+
 struct MessageReader<'a> {
     inner: ReadHalf<'a>,
-    buffer: Box<[u8; BUF_SIZE]>,
-    len: usize,
+    buffer: FrameBuffer,
+    params: Arc<ChainParams>,
 }
 
 impl<'a> MessageReader<'a> {
-    fn new(inner: ReadHalf<'a>) -> Self {
+    fn new(inner: ReadHalf<'a>, params: Arc<ChainParams>) -> Self {
         Self {
             inner,
-            buffer: Box::new([0u8; BUF_SIZE]),
-            len: 0,
+            buffer: FrameBuffer::default(),
+            params,
         }
     }
 
-    fn into_stream(self) -> impl Stream<Item = Result<VerifiedPeerMessage>> + 'a {
+    fn into_stream(self) -> impl Stream<Item = Result<ReceivedMessage, ReadError>> + 'a {
         stream::unfold(self, |mut reader| async {
             match reader.next_message().await {
                 Ok(msg) => Some((Ok(msg), reader)),
@@ -118,36 +860,40 @@ impl<'a> MessageReader<'a> {
         })
     }
 
-    async fn next_message(&mut self) -> Result<VerifiedPeerMessage> {
-        if let Some(msg) = self.try_parse_message()? {
-            return Ok(msg);
-        }
-        while self.len < BUF_SIZE {
-            let bytes_read = self.inner.read(&mut self.buffer[self.len..]).await?;
-            if bytes_read == 0 {
-                bail!("peer has disconnected");
-            }
-            self.len += bytes_read;
+    async fn next_message(&mut self) -> Result<ReceivedMessage, ReadError> {
+        loop {
             if let Some(msg) = self.try_parse_message()? {
                 return Ok(msg);
             }
+            let data = self.buffer.data_mut();
+            data.reserve(READ_CHUNK_SIZE);
+            let bytes_read = self
+                .inner
+                .read_buf(data)
+                .await
+                .map_err(|err| ReadError::Closed(err.into()))?;
+            if bytes_read == 0 {
+                return Err(ReadError::Closed(anyhow!("peer has disconnected")));
+            }
         }
-        bail!("message is larger than {} bytes", BUF_SIZE);
     }
 
-    fn try_parse_message(&mut self) -> Result<Option<VerifiedPeerMessage>> {
-        let Some(zero_pos) = self.buffer[..self.len].iter().position(|b| *b == 0) else {
-            return Ok(None);
+    fn try_parse_message(&mut self) -> Result<Option<ReceivedMessage>, ReadError> {
+        let frame = match self.buffer.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(None),
+            Err(err) => return Err(ReadError::Fatal(Misbehaviour::OversizedMessage, err)),
         };
-        let data_str = std::str::from_utf8(&self.buffer[..zero_pos])
-            .context("message is not a valid utf-8")?;
-        let msg: PeerMessage =
-            serde_json::from_str(data_str).context("failed to deserialize message")?;
-        let verified_msg = msg.verified().context("message verification failed")?;
-
-        self.buffer[..self.len].rotate_left(zero_pos + 1);
-        self.len -= zero_pos + 1;
 
-        Ok(Some(verified_msg))
+        let frame = frame.map_err(|err| ReadError::Fatal(Misbehaviour::MalformedMessage, err))?;
+        let message = frame
+            .message
+            .verified_for(&self.params)
+            .context("message verification failed")
+            .map_err(|err| ReadError::Rejected(Misbehaviour::InvalidMessage, err))?;
+        Ok(Some(ReceivedMessage {
+            message,
+            wire_versions: frame.wire_versions,
+        }))
     }
 }
@@ -0,0 +1,287 @@
+use crate::{
+    data::{
+        Block, BlockAttributes, BlockHash, Transaction, TransactionHash, VerifiedTransaction,
+        WalletId, HASH_LEN,
+    },
+    history::ConfirmedEntry,
+    util::{deserialize_base64_fixed, deserialize_wallet_id, serialize_base64},
+};
+
+use anyhow::{bail, Context, Result};
+use futures::future;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+    time::timeout,
+};
+
+use std::time::Duration;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_HEADER_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 1 << 20;
+/// A client has this long to send the whole request, so that slow ones can't pile up.
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const SERVER_ERROR: i64 = -32000;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct RpcServiceConfig {
+    pub listen_address: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RpcRequest {
+    pub request_kind: RpcRequestKind,
+    pub response_sender: oneshot::Sender<Result<RpcResponse>>,
+}
+
+#[derive(Debug)]
+pub enum RpcRequestKind {
+    GetHead,
+    GetBlock(BlockHash),
+    GetBalance(WalletId),
+    GetHistory(WalletId),
+    GetPendingTransactions,
+    SubmitTransaction(Box<VerifiedTransaction>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum RpcResponse {
+    Head {
+        #[serde(serialize_with = "serialize_base64")]
+        hash: BlockHash,
+        #[serde(flatten)]
+        attrs: Box<BlockAttributes>,
+    },
+    Block(Option<Box<Block>>),
+    Balance {
+        balance: u64,
+    },
+    History(Vec<ConfirmedEntry>),
+    Transactions(Vec<Transaction>),
+    Submitted {
+        #[serde(serialize_with = "serialize_base64")]
+        hash: TransactionHash,
+    },
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+enum RpcMethod {
+    GetHead,
+    GetBlock {
+        #[serde(deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>")]
+        hash: BlockHash,
+    },
+    GetBalance {
+        #[serde(deserialize_with = "deserialize_wallet_id")]
+        wallet: WalletId,
+    },
+    GetHistory {
+        #[serde(deserialize_with = "deserialize_wallet_id")]
+        wallet: WalletId,
+    },
+    GetPendingTransactions,
+    SubmitTransaction(Box<Transaction>),
+}
+
+#[derive(Deserialize)]
+struct RpcCall {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    method: RpcMethod,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Serves JSON-RPC 2.0 requests over HTTP on a local address.
+///
+/// Every request is forwarded to the gossip service, which owns the chain state.
+pub struct RpcService {
+    config: RpcServiceConfig,
+    request_sender: Sender<RpcRequest>,
+}
+
+impl RpcService {
+    pub fn new(config: RpcServiceConfig, request_sender: Sender<RpcRequest>) -> Self {
+        Self {
+            config,
+            request_sender,
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let Some(address) = self.config.listen_address.as_ref() else {
+            return future::pending().await;
+        };
+
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("failed to listen on {}", address))?;
+        info!("serving rpc on {}", address);
+
+        loop {
+            let (stream, peer_address) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(err) => {
+                    warn!("failed to accept rpc connection: {}", err);
+                    continue;
+                }
+            };
+
+            let request_sender = self.request_sender.clone();
+            tokio::spawn(async move {
+                if let Err(err) = Self::serve_connection(stream, request_sender).await {
+                    debug!("rpc connection from {} failed: {:#}", peer_address, err);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(
+        mut stream: TcpStream,
+        request_sender: Sender<RpcRequest>,
+    ) -> Result<()> {
+        let (read_half, mut write_half) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        let request = timeout(REQUEST_READ_TIMEOUT, Self::read_http_request(&mut reader));
+        let body = match request.await.context("timed out reading the request") {
+            Ok(Ok(body)) => body,
+            Ok(Err(err)) | Err(err) => {
+                let message = Value::from(format!("{:#}", err)).to_string();
+                let response = Self::make_http_response("400 Bad Request", &message);
+                write_half.write_all(response.as_bytes()).await?;
+                return Err(err);
+            }
+        };
+
+        let reply = Self::handle_call(&body, &request_sender).await;
+        let response = Self::make_http_response("200 OK", &reply.to_string());
+        write_half.write_all(response.as_bytes()).await?;
+        write_half.flush().await?;
+        Ok(())
+    }
+
+    async fn read_http_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+        // NB: `read_line` buffers until a newline, so a line is never allowed to grow past
+        // what is left of the header limit.
+        let mut request_line = String::new();
+        (&mut *reader)
+            .take(MAX_HEADER_SIZE as u64)
+            .read_line(&mut request_line)
+            .await?;
+        if !request_line.starts_with("POST ") {
+            bail!("only POST requests are supported");
+        }
+
+        let mut header_size = request_line.len();
+        let mut content_length = None;
+        loop {
+            let mut line = String::new();
+            let limit = (MAX_HEADER_SIZE + 1).saturating_sub(header_size) as u64;
+            if (&mut *reader).take(limit).read_line(&mut line).await? == 0 {
+                bail!("connection closed before the end of headers");
+            }
+            header_size += line.len();
+            if header_size > MAX_HEADER_SIZE {
+                bail!("headers are larger than {} bytes", MAX_HEADER_SIZE);
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = Some(value.trim().parse::<usize>()?);
+                }
+            }
+        }
+
+        let content_length = content_length.context("missing Content-Length header")?;
+        if content_length > MAX_BODY_SIZE {
+            bail!("body is larger than {} bytes", MAX_BODY_SIZE);
+        }
+
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).await?;
+        Ok(body)
+    }
+
+    fn make_http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    async fn handle_call(body: &[u8], request_sender: &Sender<RpcRequest>) -> Value {
+        let raw: Value = match serde_json::from_slice(body) {
+            Ok(raw) => raw,
+            Err(err) => return Self::make_error(Value::Null, PARSE_ERROR, err.to_string()),
+        };
+        let id = raw.get("id").cloned().unwrap_or(Value::Null);
+
+        let call: RpcCall = match serde_json::from_value(raw) {
+            Ok(call) => call,
+            Err(err) => return Self::make_error(id, INVALID_REQUEST, err.to_string()),
+        };
+
+        match Self::execute(call.method, request_sender).await {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": call.id, "result": result}),
+            Err(err) => Self::make_error(call.id, SERVER_ERROR, format!("{:#}", err)),
+        }
+    }
+
+    async fn execute(method: RpcMethod, request_sender: &Sender<RpcRequest>) -> Result<Value> {
+        let request_kind = match method {
+            RpcMethod::GetHead => RpcRequestKind::GetHead,
+            RpcMethod::GetBlock { hash } => RpcRequestKind::GetBlock(hash),
+            RpcMethod::GetBalance { wallet } => RpcRequestKind::GetBalance(wallet),
+            RpcMethod::GetHistory { wallet } => RpcRequestKind::GetHistory(wallet),
+            RpcMethod::GetPendingTransactions => RpcRequestKind::GetPendingTransactions,
+            RpcMethod::SubmitTransaction(tx) => RpcRequestKind::SubmitTransaction(Box::new(
+                tx.verified().context("transaction verification failed")?,
+            )),
+        };
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        request_sender
+            .send(RpcRequest {
+                request_kind,
+                response_sender,
+            })
+            .await
+            .context("rpc request channel is closed")?;
+
+        let response = response_receiver
+            .await
+            .context("gossip service dropped the request")??;
+        Ok(serde_json::to_value(response)?)
+    }
+
+    fn make_error(id: Value, code: i64, message: String) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": code, "message": message},
+        })
+    }
+}
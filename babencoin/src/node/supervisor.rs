@@ -0,0 +1,174 @@
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{select, sync::watch};
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Tells services to stop. All clones observe the same trigger, which can't be undone.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownToken {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Completes once the token is triggered, right away if it already is.
+    pub async fn triggered(&self) {
+        let mut receiver = self.sender.subscribe();
+        // NB: the sender lives as long as `self`, so this can't fail.
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Runs `future` until it completes or the token is triggered, whichever is first.
+    pub async fn run_until<F>(&self, future: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        select! {
+            result = future => result,
+            _ = self.triggered() => Ok(()),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// How the node treats a service that has failed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RestartPolicy {
+    /// Restarts of a single service before the whole node gives up, zero to never restart.
+    pub max_restarts: u32,
+    /// Delay before the first restart, doubled on every next one.
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Restarts a single service according to a `RestartPolicy`, see `should_restart`.
+pub struct Supervisor {
+    name: &'static str,
+    policy: RestartPolicy,
+    shutdown: ShutdownToken,
+    restart_count: u32,
+}
+
+impl Supervisor {
+    pub fn new(name: &'static str, policy: RestartPolicy, shutdown: ShutdownToken) -> Self {
+        Self {
+            name,
+            policy,
+            shutdown,
+            restart_count: 0,
+        }
+    }
+
+    /// Takes the outcome of a service run and tells whether to run it once more, waiting
+    /// for the backoff first. Fails once the service has used up all of its restarts.
+    ///
+    /// Nothing is restarted after the shutdown is triggered.
+    pub async fn should_restart(&mut self, result: Result<()>) -> Result<bool> {
+        let err = match result {
+            Ok(()) => return Ok(false),
+            Err(err) if self.shutdown.is_triggered() => {
+                warn!("{} failed during shutdown: {:#}", self.name, err);
+                return Ok(false);
+            }
+            Err(err) => err,
+        };
+        if self.restart_count >= self.policy.max_restarts {
+            return Err(err.context(format!(
+                "{} failed after {} restarts",
+                self.name, self.restart_count
+            )));
+        }
+
+        let backoff = self
+            .policy
+            .backoff
+            .saturating_mul(1 << self.restart_count.min(MAX_BACKOFF_DOUBLINGS));
+        self.restart_count += 1;
+        error!(
+            "{} failed, restart {} of {} in {:?}: {:#}",
+            self.name, self.restart_count, self.policy.max_restarts, backoff, err
+        );
+        select! {
+            _ = tokio::time::sleep(backoff) => Ok(true),
+            _ = self.shutdown.triggered() => Ok(false),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_supervisor() {
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let shutdown = ShutdownToken::new();
+        let mut supervisor = Supervisor::new("test service", policy.clone(), shutdown.clone());
+        assert!(supervisor
+            .should_restart(Err(anyhow!("boom")))
+            .await
+            .unwrap());
+        assert!(supervisor
+            .should_restart(Err(anyhow!("boom")))
+            .await
+            .unwrap());
+        assert!(supervisor
+            .should_restart(Err(anyhow!("boom")))
+            .await
+            .is_err());
+
+        let mut supervisor = Supervisor::new("test service", policy, shutdown.clone());
+        assert!(!supervisor.should_restart(Ok(())).await.unwrap());
+        shutdown.trigger();
+        shutdown.triggered().await;
+        assert!(!supervisor
+            .should_restart(Err(anyhow!("boom")))
+            .await
+            .unwrap());
+        assert!(shutdown.run_until(std::future::pending()).await.is_ok());
+    }
+}
@@ -0,0 +1,702 @@
+//! Framing and encoding of peer messages.
+//!
+//! Two formats share a connection:
+//!
+//! * json: a `PeerMessage` serialized as json and terminated by a zero byte. Every node
+//!   understands it, but a frame may not exceed `MAX_JSON_FRAME_SIZE`.
+//! * binary: `BINARY_FRAME_MAGIC`, a version byte and a little-endian `u32` payload length,
+//!   followed by the payload. Hashes, keys and signatures are sent as raw bytes.
+//!
+//! The formats are told apart by the first byte of a frame, which is never
+//! `BINARY_FRAME_MAGIC` in valid utf-8. Json frames carry the binary versions their sender
+//! can read in an extra `wire_versions` field, which older nodes ignore. A node only sends
+//! binary frames once the peer has advertised a common version, so older peers keep
+//! getting json.
+
+use crate::{
+    data::{
+        Block, BlockAttributes, BlockHash, MultisigPolicy, MultisigSpend, PeerAddress, PeerMessage,
+        Transaction, WalletId, HASH_LEN,
+    },
+    merkle::{InclusionProof, MerkleBranch},
+};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, LocalResult, TimeZone, Utc};
+use rsa::{PublicKeyEncoding, RSAPublicKey};
+use serde::{Deserialize, Serialize};
+
+use std::io::Read;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Limit of the json framing, including the terminating zero byte.
+pub const MAX_JSON_FRAME_SIZE: usize = 65536;
+pub const MAX_BINARY_PAYLOAD_SIZE: usize = 32 << 20;
+
+pub const BINARY_FRAME_MAGIC: u8 = 0xbc;
+pub const BINARY_HEADER_SIZE: usize = 6;
+
+/// Binary wire versions this node can read and write, oldest first.
+pub const SUPPORTED_WIRE_VERSIONS: &[u8] = &[1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Binary(u8),
+}
+
+impl WireFormat {
+    /// Picks the newest binary version both sides support, falling back to json.
+    pub fn negotiate(peer_versions: &[u8]) -> Self {
+        SUPPORTED_WIRE_VERSIONS
+            .iter()
+            .rev()
+            .find(|version| peer_versions.contains(version))
+            .map_or(WireFormat::Json, |version| WireFormat::Binary(*version))
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub message: PeerMessage,
+    pub format: WireFormat,
+    /// Binary versions the sender has advertised along with this frame.
+    pub wire_versions: Vec<u8>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize)]
+struct JsonFrameRef<'a> {
+    #[serde(flatten)]
+    message: &'a PeerMessage,
+    wire_versions: &'a [u8],
+}
+
+#[derive(Deserialize)]
+struct JsonFrame {
+    #[serde(flatten)]
+    message: PeerMessage,
+    #[serde(default)]
+    wire_versions: Vec<u8>,
+}
+
+/// Encodes `message` as a complete frame of the given format.
+pub fn encode_frame(message: &PeerMessage, format: WireFormat) -> Result<Vec<u8>> {
+    match format {
+        WireFormat::Json => {
+            let frame = JsonFrameRef {
+                message,
+                wire_versions: SUPPORTED_WIRE_VERSIONS,
+            };
+            let mut data = serde_json::to_vec(&frame).context("failed to serialize message")?;
+            data.push(0);
+            if data.len() > MAX_JSON_FRAME_SIZE {
+                bail!(
+                    "message of {} bytes does not fit into a json frame",
+                    data.len()
+                );
+            }
+            Ok(data)
+        }
+        WireFormat::Binary(version) => {
+            if !SUPPORTED_WIRE_VERSIONS.contains(&version) {
+                bail!("unsupported wire version {}", version);
+            }
+            let mut data = vec![BINARY_FRAME_MAGIC, version, 0, 0, 0, 0];
+            encode_message(&mut data, message)?;
+            let payload_len = data.len() - BINARY_HEADER_SIZE;
+            if payload_len > MAX_BINARY_PAYLOAD_SIZE {
+                bail!("message of {} bytes is too large", payload_len);
+            }
+            (&mut data[2..BINARY_HEADER_SIZE]).write_u32::<LittleEndian>(payload_len as u32)?;
+            Ok(data)
+        }
+    }
+}
+
+/// Accumulates bytes read from a peer and splits them into frames.
+#[derive(Default)]
+pub struct FrameBuffer {
+    data: Vec<u8>,
+    /// Prefix of a pending json frame that is known to contain no zero byte.
+    scanned: usize,
+}
+
+impl FrameBuffer {
+    /// Buffer to append freshly read bytes to.
+    pub fn data_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+
+    /// Returns the next complete frame, if any.
+    ///
+    /// The outer error means the stream can't be split any further. The inner one rejects
+    /// a single frame, which is already consumed.
+    pub fn next_frame(&mut self) -> Result<Option<Result<Frame>>> {
+        let Some(frame_len) = self.frame_len()? else {
+            return Ok(None);
+        };
+        let result = decode_frame(&self.data[..frame_len]);
+        self.data.drain(..frame_len);
+        self.scanned = 0;
+        if self.data.is_empty() {
+            // NB: don't hold on to the memory of a huge frame for the rest of the session.
+            self.data.shrink_to(MAX_JSON_FRAME_SIZE);
+        }
+        Ok(Some(result))
+    }
+
+    fn frame_len(&mut self) -> Result<Option<usize>> {
+        if self.data.first() == Some(&BINARY_FRAME_MAGIC) {
+            if self.data.len() < BINARY_HEADER_SIZE {
+                return Ok(None);
+            }
+            let payload_len = (&self.data[2..BINARY_HEADER_SIZE]).read_u32::<LittleEndian>()?;
+            let payload_len = payload_len as usize;
+            if payload_len > MAX_BINARY_PAYLOAD_SIZE {
+                bail!("message is larger than {} bytes", MAX_BINARY_PAYLOAD_SIZE);
+            }
+            let frame_len = BINARY_HEADER_SIZE + payload_len;
+            return Ok((self.data.len() >= frame_len).then_some(frame_len));
+        }
+
+        let end = self.data.len().min(MAX_JSON_FRAME_SIZE);
+        match self.data[self.scanned..end].iter().position(|b| *b == 0) {
+            Some(pos) => Ok(Some(self.scanned + pos + 1)),
+            None if end == MAX_JSON_FRAME_SIZE => {
+                bail!("message is larger than {} bytes", MAX_JSON_FRAME_SIZE)
+            }
+            None => {
+                self.scanned = end;
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn decode_frame(frame: &[u8]) -> Result<Frame> {
+    if frame[0] == BINARY_FRAME_MAGIC {
+        let version = frame[1];
+        if !SUPPORTED_WIRE_VERSIONS.contains(&version) {
+            bail!("unsupported wire version {}", version);
+        }
+        let mut payload = &frame[BINARY_HEADER_SIZE..];
+        let message = decode_message(&mut payload).context("failed to decode message")?;
+        if !payload.is_empty() {
+            bail!("{} trailing bytes after message", payload.len());
+        }
+        return Ok(Frame {
+            message,
+            format: WireFormat::Binary(version),
+            wire_versions: vec![version],
+        });
+    }
+
+    let data = &frame[..frame.len() - 1];
+    let data_str = std::str::from_utf8(data).context("message is not a valid utf-8")?;
+    let frame: JsonFrame =
+        serde_json::from_str(data_str).context("failed to deserialize message")?;
+    Ok(Frame {
+        message: frame.message,
+        format: WireFormat::Json,
+        wire_versions: frame.wire_versions,
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+const TAG_BLOCK: u8 = 1;
+const TAG_TRANSACTION: u8 = 2;
+const TAG_REQUEST: u8 = 3;
+const TAG_GET_HEADERS: u8 = 4;
+const TAG_HEADERS: u8 = 5;
+const TAG_GET_BLOCKS: u8 = 6;
+const TAG_GET_PEERS: u8 = 7;
+const TAG_PEERS: u8 = 8;
+const TAG_HELLO: u8 = 9;
+const TAG_GET_PROOF: u8 = 10;
+const TAG_PROOF: u8 = 11;
+const TAG_GOODBYE: u8 = 12;
+
+const MULTISIG_WALLET_MARKER: u8 = 0;
+
+fn encode_message(out: &mut Vec<u8>, message: &PeerMessage) -> Result<()> {
+    match message {
+        PeerMessage::Hello {
+            protocol_version,
+            network_id,
+            head_index,
+            head_hash,
+            features,
+        } => {
+            out.push(TAG_HELLO);
+            out.write_u32::<LittleEndian>(*protocol_version)?;
+            out.extend_from_slice(network_id);
+            out.write_u64::<LittleEndian>(*head_index)?;
+            out.extend_from_slice(head_hash);
+            out.write_u64::<LittleEndian>(*features)?;
+        }
+        PeerMessage::Block(block) => {
+            out.push(TAG_BLOCK);
+            encode_attrs(out, &block.attrs)?;
+            encode_len(out, block.transactions.len())?;
+            for tx in block.transactions.iter() {
+                encode_transaction(out, tx)?;
+            }
+        }
+        PeerMessage::Transaction(tx) => {
+            out.push(TAG_TRANSACTION);
+            encode_transaction(out, tx)?;
+        }
+        PeerMessage::Request { block_hash } => {
+            out.push(TAG_REQUEST);
+            out.extend_from_slice(block_hash);
+        }
+        PeerMessage::GetHeaders { locator } => {
+            out.push(TAG_GET_HEADERS);
+            encode_len(out, locator.len())?;
+            for hash in locator.iter() {
+                out.extend_from_slice(hash);
+            }
+        }
+        PeerMessage::Headers { headers } => {
+            out.push(TAG_HEADERS);
+            encode_len(out, headers.len())?;
+            for attrs in headers.iter() {
+                encode_attrs(out, attrs)?;
+            }
+        }
+        PeerMessage::GetBlocks { from_hash, count } => {
+            out.push(TAG_GET_BLOCKS);
+            out.extend_from_slice(from_hash);
+            out.write_u64::<LittleEndian>(*count)?;
+        }
+        PeerMessage::GetPeers => out.push(TAG_GET_PEERS),
+        PeerMessage::Goodbye => out.push(TAG_GOODBYE),
+        PeerMessage::Peers { peers } => {
+            out.push(TAG_PEERS);
+            encode_len(out, peers.len())?;
+            for peer in peers.iter() {
+                encode_bytes(out, peer.address.as_bytes())?;
+                out.write_i64::<LittleEndian>(peer.last_seen.timestamp())?;
+            }
+        }
+        PeerMessage::GetProof {
+            block_hash,
+            transaction_hash,
+        } => {
+            out.push(TAG_GET_PROOF);
+            out.extend_from_slice(block_hash);
+            out.extend_from_slice(transaction_hash);
+        }
+        PeerMessage::Proof(proof) => {
+            out.push(TAG_PROOF);
+            encode_attrs(out, &proof.header)?;
+            out.extend_from_slice(&proof.transaction_hash);
+            out.write_u64::<LittleEndian>(proof.branch.index)?;
+            out.write_u64::<LittleEndian>(proof.branch.leaf_count)?;
+            encode_len(out, proof.branch.siblings.len())?;
+            for hash in proof.branch.siblings.iter() {
+                out.extend_from_slice(hash);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_message(input: &mut &[u8]) -> Result<PeerMessage> {
+    let message = match input.read_u8()? {
+        TAG_BLOCK => {
+            let attrs = decode_attrs(input)?;
+            let transactions = decode_vec(input, decode_transaction)?;
+            PeerMessage::Block(Box::new(Block {
+                attrs,
+                transactions,
+            }))
+        }
+        TAG_TRANSACTION => PeerMessage::Transaction(Box::new(decode_transaction(input)?)),
+        TAG_REQUEST => PeerMessage::Request {
+            block_hash: decode_hash(input)?,
+        },
+        TAG_GET_HEADERS => PeerMessage::GetHeaders {
+            locator: decode_vec(input, decode_hash)?,
+        },
+        TAG_HEADERS => PeerMessage::Headers {
+            headers: decode_vec(input, decode_attrs)?,
+        },
+        TAG_GET_BLOCKS => PeerMessage::GetBlocks {
+            from_hash: decode_hash(input)?,
+            count: input.read_u64::<LittleEndian>()?,
+        },
+        TAG_GET_PEERS => PeerMessage::GetPeers,
+        TAG_GOODBYE => PeerMessage::Goodbye,
+        TAG_PEERS => PeerMessage::Peers {
+            peers: decode_vec(input, |input| {
+                Ok(PeerAddress {
+                    address: decode_string(input)?,
+                    last_seen: decode_timestamp(input)?,
+                })
+            })?,
+        },
+        TAG_HELLO => PeerMessage::Hello {
+            protocol_version: input.read_u32::<LittleEndian>()?,
+            network_id: decode_hash(input)?,
+            head_index: input.read_u64::<LittleEndian>()?,
+            head_hash: decode_hash(input)?,
+            features: input.read_u64::<LittleEndian>()?,
+        },
+        TAG_GET_PROOF => PeerMessage::GetProof {
+            block_hash: decode_hash(input)?,
+            transaction_hash: decode_hash(input)?,
+        },
+        TAG_PROOF => PeerMessage::Proof(Box::new(InclusionProof {
+            header: decode_attrs(input)?,
+            transaction_hash: decode_hash(input)?,
+            branch: MerkleBranch {
+                index: input.read_u64::<LittleEndian>()?,
+                leaf_count: input.read_u64::<LittleEndian>()?,
+                siblings: decode_vec(input, decode_hash)?,
+            },
+        })),
+        tag => bail!("unknown message tag {}", tag),
+    };
+    Ok(message)
+}
+
+fn encode_attrs(out: &mut Vec<u8>, attrs: &BlockAttributes) -> Result<()> {
+    out.write_u64::<LittleEndian>(attrs.index)?;
+    out.write_u64::<LittleEndian>(attrs.reward)?;
+    out.write_u64::<LittleEndian>(attrs.nonce)?;
+    out.write_i64::<LittleEndian>(attrs.timestamp.timestamp())?;
+    encode_wallet(out, &attrs.issuer)?;
+    out.extend_from_slice(&attrs.max_hash);
+    out.extend_from_slice(&attrs.prev_hash);
+    out.extend_from_slice(&attrs.merkle_root);
+    Ok(())
+}
+
+fn decode_attrs(input: &mut &[u8]) -> Result<BlockAttributes> {
+    Ok(BlockAttributes {
+        index: input.read_u64::<LittleEndian>()?,
+        reward: input.read_u64::<LittleEndian>()?,
+        nonce: input.read_u64::<LittleEndian>()?,
+        timestamp: decode_timestamp(input)?,
+        issuer: decode_wallet(input)?,
+        max_hash: decode_hash(input)?,
+        prev_hash: decode_hash(input)?,
+        merkle_root: decode_hash(input)?,
+    })
+}
+
+fn encode_transaction(out: &mut Vec<u8>, tx: &Transaction) -> Result<()> {
+    out.write_u64::<LittleEndian>(tx.amount)?;
+    out.write_u64::<LittleEndian>(tx.fee)?;
+    encode_bytes(out, tx.comment.as_bytes())?;
+    encode_wallet(out, &tx.sender)?;
+    encode_wallet(out, &tx.receiver)?;
+    encode_bytes(out, &tx.signature)?;
+
+    match tx.multisig.as_ref() {
+        Some(spend) => {
+            out.push(1);
+            out.write_u32::<LittleEndian>(spend.policy.threshold)?;
+            encode_len(out, spend.policy.keys.len())?;
+            for key in spend.policy.keys.iter() {
+                encode_wallet(out, key)?;
+            }
+            encode_len(out, spend.signatures.len())?;
+            for signature in spend.signatures.iter() {
+                encode_bytes(out, signature)?;
+            }
+        }
+        None => out.push(0),
+    }
+    match tx.lock_until_index {
+        Some(index) => {
+            out.push(1);
+            out.write_u64::<LittleEndian>(index)?;
+        }
+        None => out.push(0),
+    }
+    Ok(())
+}
+
+fn decode_transaction(input: &mut &[u8]) -> Result<Transaction> {
+    Ok(Transaction {
+        amount: input.read_u64::<LittleEndian>()?,
+        fee: input.read_u64::<LittleEndian>()?,
+        comment: decode_string(input)?,
+        sender: decode_wallet(input)?,
+        receiver: decode_wallet(input)?,
+        signature: decode_bytes(input)?,
+        multisig: decode_option(input, |input| {
+            Ok(MultisigSpend {
+                policy: MultisigPolicy {
+                    threshold: input.read_u32::<LittleEndian>()?,
+                    keys: decode_vec(input, decode_wallet)?,
+                },
+                signatures: decode_vec(input, decode_bytes)?,
+            })
+        })?,
+        lock_until_index: decode_option(input, |input| Ok(input.read_u64::<LittleEndian>()?))?,
+    })
+}
+
+/// Wallets are sent as pkcs8 bytes, same as in json but without base64. Multisig wallets
+/// are sent as a zero byte followed by the policy hash, pkcs8 never starts with one.
+fn encode_wallet(out: &mut Vec<u8>, wallet: &WalletId) -> Result<()> {
+    match wallet {
+        WalletId::Key(public_key) => {
+            let bytes = public_key
+                .to_pkcs8()
+                .context("failed to encode key as pkcs8")?;
+            encode_bytes(out, &bytes)
+        }
+        WalletId::Multisig(hash) => {
+            encode_len(out, 1 + hash.len())?;
+            out.push(MULTISIG_WALLET_MARKER);
+            out.extend_from_slice(hash);
+            Ok(())
+        }
+    }
+}
+
+fn decode_wallet(input: &mut &[u8]) -> Result<WalletId> {
+    let bytes = decode_bytes(input)?;
+    if let Some((&MULTISIG_WALLET_MARKER, hash)) = bytes.split_first() {
+        let hash = hash.try_into().context("invalid multisig wallet hash")?;
+        return Ok(WalletId::Multisig(hash));
+    }
+    let public_key = RSAPublicKey::from_pkcs8(&bytes).context("invalid pkcs8")?;
+    Ok(WalletId::Key(public_key))
+}
+
+fn decode_timestamp(input: &mut &[u8]) -> Result<DateTime<Utc>> {
+    match Utc.timestamp_opt(input.read_i64::<LittleEndian>()?, 0) {
+        LocalResult::Single(dt) => Ok(dt),
+        _ => bail!("invalid timestamp"),
+    }
+}
+
+fn decode_hash(input: &mut &[u8]) -> Result<BlockHash> {
+    let mut hash = [0u8; HASH_LEN];
+    input.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
+fn encode_len(out: &mut Vec<u8>, len: usize) -> Result<()> {
+    let len = u32::try_from(len).context("sequence is too long")?;
+    out.write_u32::<LittleEndian>(len)?;
+    Ok(())
+}
+
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    encode_len(out, bytes.len())?;
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn decode_bytes(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    if len > input.len() {
+        bail!("byte string of {} bytes is truncated", len);
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes.to_vec())
+}
+
+fn decode_string(input: &mut &[u8]) -> Result<String> {
+    String::from_utf8(decode_bytes(input)?).context("string is not a valid utf-8")
+}
+
+fn decode_option<T>(
+    input: &mut &[u8],
+    decode_value: impl FnOnce(&mut &[u8]) -> Result<T>,
+) -> Result<Option<T>> {
+    match input.read_u8()? {
+        0 => Ok(None),
+        1 => decode_value(input).map(Some),
+        flag => bail!("invalid option flag {}", flag),
+    }
+}
+
+fn decode_vec<T>(
+    input: &mut &[u8],
+    mut decode_item: impl FnMut(&mut &[u8]) -> Result<T>,
+) -> Result<Vec<T>> {
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    // NB: every item takes at least a byte, don't let the peer make us preallocate more.
+    if len > input.len() {
+        bail!("sequence of {} items is truncated", len);
+    }
+    (0..len).map(|_| decode_item(input)).collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chain_params::ChainParams, data::VerifiedTransaction, util::parse_pkcs8_private};
+
+    fn test_block() -> Block {
+        serde_json::from_str(include_str!("../../data/test_block.json")).unwrap()
+    }
+
+    fn test_messages() -> Vec<PeerMessage> {
+        let params = ChainParams::mainnet();
+        let priv_key = parse_pkcs8_private(include_str!("../../data/test.pem")).unwrap();
+        let tx = VerifiedTransaction::sign(
+            &priv_key,
+            params.genesis_issuer.clone(),
+            1,
+            2,
+            "тест".into(),
+        )
+        .unwrap();
+        let policy = MultisigPolicy::new(1, vec![priv_key.to_public_key().into()]).unwrap();
+        let mut multisig_tx =
+            Transaction::new_multisig(policy.clone(), policy.wallet_id(), 3, 4, "".into());
+        multisig_tx.lock_until_index = Some(5);
+        multisig_tx.add_signature(&priv_key).unwrap();
+        let block = test_block();
+        vec![
+            PeerMessage::Hello {
+                protocol_version: 1,
+                network_id: params.network_id(),
+                head_index: block.index,
+                head_hash: block.compute_hash(),
+                features: u64::MAX,
+            },
+            PeerMessage::Block(Box::new(block.clone())),
+            PeerMessage::Transaction(Box::new(tx.into())),
+            PeerMessage::Transaction(Box::new(multisig_tx)),
+            PeerMessage::Request {
+                block_hash: block.compute_hash(),
+            },
+            PeerMessage::GetHeaders {
+                locator: vec![block.compute_hash(), block.prev_hash],
+            },
+            PeerMessage::Headers {
+                headers: vec![block.attrs.clone()],
+            },
+            PeerMessage::GetBlocks {
+                from_hash: block.prev_hash,
+                count: 7,
+            },
+            PeerMessage::GetPeers,
+            PeerMessage::Goodbye,
+            PeerMessage::GetProof {
+                block_hash: block.compute_hash(),
+                transaction_hash: block.transactions[0].compute_hash(),
+            },
+            PeerMessage::Proof(Box::new(
+                block
+                    .clone()
+                    .verified_for(&params)
+                    .unwrap()
+                    .inclusion_proof(&block.transactions[0].compute_hash())
+                    .unwrap(),
+            )),
+            PeerMessage::Peers {
+                peers: vec![PeerAddress {
+                    address: "localhost:9090".into(),
+                    last_seen: block.timestamp,
+                }],
+            },
+        ]
+    }
+
+    fn assert_same(lhs: &PeerMessage, rhs: &PeerMessage) {
+        assert_eq!(
+            serde_json::to_value(lhs).unwrap(),
+            serde_json::to_value(rhs).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for format in [WireFormat::Json, WireFormat::Binary(1)] {
+            let mut buffer = FrameBuffer::default();
+            for message in test_messages() {
+                let data = encode_frame(&message, format).unwrap();
+                // NB: feed byte by byte to check that partial frames are awaited.
+                for byte in data {
+                    buffer.data_mut().push(byte);
+                    if let Some(frame) = buffer.next_frame().unwrap() {
+                        let frame = frame.unwrap();
+                        assert_eq!(frame.format, format);
+                        assert_eq!(frame.wire_versions, SUPPORTED_WIRE_VERSIONS);
+                        assert_same(&frame.message, &message);
+                    }
+                }
+                assert!(buffer.data_mut().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_legacy_json() {
+        let message = PeerMessage::Block(Box::new(test_block()));
+        let data = encode_frame(&message, WireFormat::Json).unwrap();
+        let legacy: PeerMessage = serde_json::from_slice(&data[..data.len() - 1]).unwrap();
+        assert_same(&legacy, &message);
+
+        let mut buffer = FrameBuffer::default();
+        buffer
+            .data_mut()
+            .extend(serde_json::to_vec(&message).unwrap());
+        buffer.data_mut().push(0);
+        let frame = buffer.next_frame().unwrap().unwrap().unwrap();
+        assert!(frame.wire_versions.is_empty());
+        assert_eq!(
+            WireFormat::negotiate(&frame.wire_versions),
+            WireFormat::Json
+        );
+        assert_eq!(WireFormat::negotiate(&[1, 200]), WireFormat::Binary(1));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut block = test_block();
+        block.transactions = vec![block.transactions[0].clone(); 200];
+        let message = PeerMessage::Block(Box::new(block));
+        assert!(encode_frame(&message, WireFormat::Json).is_err());
+
+        let mut buffer = FrameBuffer::default();
+        let data = encode_frame(&message, WireFormat::Binary(1)).unwrap();
+        assert!(data.len() > MAX_JSON_FRAME_SIZE);
+        buffer.data_mut().extend(data);
+        assert!(buffer.next_frame().unwrap().unwrap().is_ok());
+
+        buffer.data_mut().extend(vec![b'{'; MAX_JSON_FRAME_SIZE]);
+        assert!(buffer.next_frame().is_err());
+
+        let mut buffer = FrameBuffer::default();
+        buffer
+            .data_mut()
+            .extend([BINARY_FRAME_MAGIC, 1, 0xff, 0xff, 0xff, 0xff]);
+        assert!(buffer.next_frame().is_err());
+
+        let mut buffer = FrameBuffer::default();
+        buffer.data_mut().extend([
+            BINARY_FRAME_MAGIC,
+            1,
+            5,
+            0,
+            0,
+            0,
+            TAG_TRANSACTION,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+        ]);
+        assert!(buffer.next_frame().unwrap().unwrap().is_err());
+        assert!(buffer.data_mut().is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+//! Proof of work: what a block hash must satisfy and how `max_hash` follows the block rate.
+//!
+//! Both are consensus rules, so the implementations are selected per chain, see
+//! `ChainParams::make_pow_hash` and `ChainParams::make_retarget`.
+
+use crate::data::{BlockAttributes, BlockHash, HASH_LEN};
+
+use anyhow::{bail, Result};
+use chrono::Duration;
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+use std::cell::RefCell;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Blocks from any peer are hashed before the peer is known to be honest, so the memory
+/// hard hash is capped at 1 MiB, i.e. 32768 SHA3 rounds per block.
+pub const MAX_POW_MEMORY_KIB: u32 = 1 << 10;
+
+/// Maps a block hash to the value that must not exceed the block's `max_hash`.
+pub trait PowHash: Send + Sync {
+    fn compute(&self, block_hash: &BlockHash) -> BlockHash;
+}
+
+/// Decides `max_hash` of a block from its ancestors.
+pub trait Retarget: Send + Sync {
+    /// Number of ancestors needed for the block at `index`, at least one.
+    fn ancestor_count(&self, index: u64) -> usize;
+
+    /// Upper bound of `ancestor_count`. A block can affect the max hash of descendants
+    /// this deep at most.
+    fn max_ancestor_count(&self) -> usize;
+
+    /// `ancestors` are exactly `ancestor_count(index)` blocks, from the oldest to the parent.
+    fn max_hash(&self, index: u64, ancestors: &[&BlockAttributes]) -> BlockHash;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PowHashKind {
+    #[default]
+    Sha3,
+    Sha3MemoryHard {
+        memory_kib: u32,
+    },
+}
+
+impl PowHashKind {
+    pub fn validate(&self) -> Result<()> {
+        if let Self::Sha3MemoryHard { memory_kib } = self {
+            if *memory_kib == 0 || *memory_kib > MAX_POW_MEMORY_KIB {
+                bail!(
+                    "memory_kib must be in 1..={}, got {}",
+                    MAX_POW_MEMORY_KIB,
+                    memory_kib
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RetargetKind {
+    #[default]
+    Epoch,
+    MovingWindow {
+        window: usize,
+    },
+}
+
+impl RetargetKind {
+    pub fn validate(&self) -> Result<()> {
+        if let Self::MovingWindow { window } = self {
+            if *window < 2 {
+                bail!("window must be at least 2, got {}", window);
+            }
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The block hash itself, i.e. SHA3-512 of the block header.
+pub struct Sha3;
+
+impl PowHash for Sha3 {
+    fn compute(&self, block_hash: &BlockHash) -> BlockHash {
+        *block_hash
+    }
+}
+
+/// Scrypt's ROMix with SHA3-512 as the mixing function: fills a buffer by hashing the block
+/// hash over and over, then walks it in data dependent order. Every nonce needs the whole
+/// buffer, so mining is bound by memory rather than by hashing speed.
+///
+/// The buffer is kept per thread and reused by all instances, so verifying blocks one by
+/// one doesn't allocate it over and over.
+pub struct Sha3MemoryHard {
+    cell_count: usize,
+}
+
+impl Sha3MemoryHard {
+    pub fn new(memory_kib: u32) -> Self {
+        Self {
+            cell_count: (memory_kib as usize * 1024 / HASH_LEN).max(1),
+        }
+    }
+}
+
+impl PowHash for Sha3MemoryHard {
+    fn compute(&self, block_hash: &BlockHash) -> BlockHash {
+        thread_local! {
+            static CELLS: RefCell<Vec<BlockHash>> = const { RefCell::new(Vec::new()) };
+        }
+
+        CELLS.with(|cells| {
+            let mut cells = cells.borrow_mut();
+            cells.clear();
+            let mut state = *block_hash;
+            for _ in 0..self.cell_count {
+                cells.push(state);
+                state = sha3(&state);
+            }
+
+            for _ in 0..self.cell_count {
+                let position = u64::from_le_bytes(state[..8].try_into().unwrap());
+                let cell = &cells[(position % self.cell_count as u64) as usize];
+                for (byte, other) in state.iter_mut().zip(cell.iter()) {
+                    *byte ^= other;
+                }
+                state = sha3(&state);
+            }
+            state
+        })
+    }
+}
+
+fn sha3(data: &[u8]) -> BlockHash {
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&Sha3_512::digest(data));
+    hash
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Keeps `max_hash` for `epoch_size` blocks, then scales it by the average block time of
+/// the previous epoch, rounded to an integer factor.
+pub struct EpochRetarget {
+    pub epoch_size: usize,
+    pub target_block_mining_time_seconds: u64,
+}
+
+impl Retarget for EpochRetarget {
+    fn ancestor_count(&self, index: u64) -> usize {
+        if index.is_multiple_of(self.epoch_size as u64) {
+            self.epoch_size
+        } else {
+            1
+        }
+    }
+
+    fn max_ancestor_count(&self) -> usize {
+        self.epoch_size
+    }
+
+    fn max_hash(&self, index: u64, epoch: &[&BlockAttributes]) -> BlockHash {
+        if !index.is_multiple_of(self.epoch_size as u64) {
+            return epoch.last().unwrap().max_hash;
+        }
+
+        let epoch_size = self.epoch_size;
+        assert_eq!(epoch.len(), epoch_size);
+        let epoch_id = epoch[0].index / epoch_size as u64;
+        assert_eq!(epoch[0].index, epoch_id * epoch_size as u64);
+        assert_eq!(
+            epoch.last().unwrap().index,
+            (epoch_id + 1) * epoch_size as u64 - 1
+        );
+
+        let avg_duration = {
+            let mut sum_duration = Duration::zero();
+            for (prev, cur) in epoch.iter().zip(epoch.iter().skip(1)) {
+                assert_eq!(prev.max_hash, cur.max_hash);
+
+                let delta = cur.timestamp - prev.timestamp;
+                assert!(delta > Duration::zero());
+
+                sum_duration = sum_duration
+                    .checked_add(&delta)
+                    .expect("duration add overflow");
+            }
+            sum_duration / (epoch.len() - 1) as i32
+        };
+
+        let old_max_hash = BigUint::from_bytes_be(&epoch[0].max_hash);
+        let factor = (avg_duration.num_seconds() as f64
+            / self.target_block_mining_time_seconds as f64)
+            .clamp(0.001, 1000.);
+
+        let max_hash = if factor > 1. {
+            old_max_hash * factor.round() as u64
+        } else {
+            old_max_hash / (1. / factor).round() as u64
+        };
+        to_hash(max_hash)
+    }
+}
+
+/// Retargets every block: the average `max_hash` of the last `window` blocks, scaled by how
+/// much longer or shorter they took than the target. One step is bounded by a factor of 4.
+pub struct MovingWindowRetarget {
+    pub window: usize,
+    pub target_block_mining_time_seconds: u64,
+}
+
+impl Retarget for MovingWindowRetarget {
+    fn ancestor_count(&self, index: u64) -> usize {
+        self.window.min(index as usize)
+    }
+
+    fn max_ancestor_count(&self) -> usize {
+        self.window
+    }
+
+    fn max_hash(&self, _index: u64, ancestors: &[&BlockAttributes]) -> BlockHash {
+        let parent = ancestors.last().unwrap();
+        if ancestors.len() < 2 {
+            return parent.max_hash;
+        }
+
+        let expected = self.target_block_mining_time_seconds * (ancestors.len() - 1) as u64;
+        let actual = (parent.timestamp - ancestors[0].timestamp)
+            .num_seconds()
+            .max(1) as u64;
+        let actual = actual.clamp(expected.div_ceil(4), expected * 4);
+
+        let sum: BigUint = ancestors
+            .iter()
+            .map(|block| BigUint::from_bytes_be(&block.max_hash))
+            .sum();
+        to_hash(sum * actual / (expected * ancestors.len() as u64))
+    }
+}
+
+/// Saturates at the easiest possible max hash.
+fn to_hash(value: BigUint) -> BlockHash {
+    let bytes = value.to_bytes_be();
+    let prefix_size = bytes.len().saturating_sub(HASH_LEN);
+    let leading_zeros = HASH_LEN.saturating_sub(bytes.len());
+
+    if bytes.iter().take(prefix_size).any(|b| *b > 0) {
+        [255u8; HASH_LEN]
+    } else {
+        let mut result = [0u8; HASH_LEN];
+        for (i, byte) in (leading_zeros..HASH_LEN).zip(bytes.into_iter().skip(prefix_size)) {
+            result[i] = byte;
+        }
+        result
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_forest::BlockForest,
+        chain_params::ChainParams,
+        data::{Block, VerifiedBlock},
+    };
+
+    fn mine(block: &mut Block) {
+        while block.compute_hash() > block.max_hash {
+            block.attrs.nonce += 1;
+        }
+    }
+
+    #[test]
+    fn test_memory_hard() {
+        let pow = Sha3MemoryHard::new(64);
+        let hash = [7u8; HASH_LEN];
+        assert_eq!(pow.compute(&hash), pow.compute(&hash));
+        assert_ne!(pow.compute(&hash), hash);
+        assert_ne!(pow.compute(&hash), pow.compute(&[8u8; HASH_LEN]));
+        assert_ne!(pow.compute(&hash), Sha3MemoryHard::new(32).compute(&hash));
+
+        assert!(PowHashKind::Sha3MemoryHard { memory_kib: 0 }
+            .validate()
+            .is_err());
+        assert!(PowHashKind::Sha3MemoryHard {
+            memory_kib: MAX_POW_MEMORY_KIB + 1
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_moving_window() {
+        let params = ChainParams {
+            target_block_mining_time_seconds: 2,
+            retarget: RetargetKind::MovingWindow { window: 4 },
+            ..ChainParams::regtest()
+        };
+        let mut forest = BlockForest::with_params(params.clone());
+
+        // NB: blocks come twice as fast as the target, so every block gets harder.
+        let mut prev = VerifiedBlock::genesis_for(&params);
+        for index in 1..8 {
+            let mut block = Block::genesis_for(&params);
+            block.attrs.index = index;
+            block.attrs.prev_hash = *prev.hash();
+            block.attrs.timestamp = prev.timestamp + Duration::seconds(1);
+            block.attrs.max_hash = forest.next_max_hash();
+            if index > 1 {
+                assert!(block.max_hash < prev.max_hash);
+            }
+            mine(&mut block);
+
+            let block = block.verified_for(&params).unwrap();
+            forest.add_block(block.clone()).unwrap();
+            assert_eq!(forest.head().hash(), block.hash());
+            prev = block;
+        }
+
+        let mut stale = Block::genesis_for(&params);
+        stale.attrs.index = 8;
+        stale.attrs.prev_hash = *prev.hash();
+        stale.attrs.timestamp = prev.timestamp + Duration::seconds(1);
+        stale.attrs.max_hash = prev.max_hash;
+        mine(&mut stale);
+        assert!(forest
+            .add_block(stale.verified_for(&params).unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::{
+    chain_params::ChainParams,
+    data::{Block, BlockHash, Transaction, VerifiedBlock, VerifiedTransaction},
+};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const BLOCK_LOG_FILE_NAME: &str = "blocks.log";
+const MEMPOOL_FILE_NAME: &str = "mempool.json";
+const RECORD_HEADER_SIZE: usize = 4;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Persistent storage of a chain, used by `BlockForest` to survive restarts.
+///
+/// Blocks are only ever appended; pending transactions are replaced as a whole.
+pub trait ChainStorage: Send {
+    /// Returns all stored blocks in the order they were appended, verified against `params`.
+    fn load_blocks(&mut self, params: &ChainParams) -> Result<Vec<VerifiedBlock>>;
+    fn read_block(
+        &mut self,
+        hash: &BlockHash,
+        params: &ChainParams,
+    ) -> Result<Option<VerifiedBlock>>;
+    fn contains_block(&self, hash: &BlockHash) -> bool;
+    fn append_block(&mut self, block: &VerifiedBlock) -> Result<()>;
+
+    fn load_pending_transactions(&mut self) -> Result<Vec<VerifiedTransaction>>;
+    fn store_pending_transactions(&mut self, transactions: &[VerifiedTransaction]) -> Result<()>;
+
+    /// Makes sure everything stored so far survives a crash.
+    fn flush(&mut self) -> Result<()>;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Stores blocks in an append-only log of length-prefixed JSON records.
+///
+/// Every append is synced to disk before returning. A tail that doesn't parse, like a
+/// record cut short or zero-filled by a crash, is discarded when the log is opened.
+pub struct FileStorage {
+    dir: PathBuf,
+    log: File,
+    log_len: u64,
+    index: HashMap<BlockHash, u64>,
+    /// Blocks read by `open`, handed out by the first `load_blocks`.
+    opened_blocks: Option<Vec<Block>>,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {:?}", dir))?;
+
+        let log_path = dir.join(BLOCK_LOG_FILE_NAME);
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&log_path)
+            .with_context(|| format!("failed to open {:?}", log_path))?;
+
+        let mut index = HashMap::new();
+        let mut blocks = vec![];
+        let mut records = RecordIter::new(&mut log)?;
+        for record in records.by_ref() {
+            match record {
+                Ok((offset, block)) => {
+                    index.insert(block.compute_hash(), offset);
+                    blocks.push(block);
+                }
+                Err(err) => {
+                    warn!("{:#}", err);
+                    break;
+                }
+            }
+        }
+        let log_len = records.offset;
+
+        let file_len = log.metadata()?.len();
+        if file_len > log_len {
+            warn!(
+                "discarding {} bytes of incomplete record at the end of {:?}",
+                file_len - log_len,
+                log_path
+            );
+            log.set_len(log_len)?;
+            log.sync_all()?;
+        }
+
+        Ok(Self {
+            dir,
+            log,
+            log_len,
+            index,
+            opened_blocks: Some(blocks),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn read_record_at(&mut self, offset: u64) -> Result<Block> {
+        self.log.seek(SeekFrom::Start(offset))?;
+        match read_record(&mut self.log)? {
+            Some(block) => Ok(block),
+            None => bail!("block log record at offset {} is incomplete", offset),
+        }
+    }
+}
+
+impl ChainStorage for FileStorage {
+    fn load_blocks(&mut self, params: &ChainParams) -> Result<Vec<VerifiedBlock>> {
+        let blocks = match self.opened_blocks.take() {
+            Some(blocks) => blocks,
+            None => RecordIter::new(&mut self.log)?
+                .map(|record| record.map(|(_, block)| block))
+                .collect::<Result<_>>()?,
+        };
+        blocks
+            .into_iter()
+            .map(|block| {
+                let hash = block.compute_hash();
+                block
+                    .verified_for(params)
+                    .with_context(|| format!("stored block {} is invalid", base64::encode(hash)))
+            })
+            .collect()
+    }
+
+    fn read_block(
+        &mut self,
+        hash: &BlockHash,
+        params: &ChainParams,
+    ) -> Result<Option<VerifiedBlock>> {
+        let Some(&offset) = self.index.get(hash) else {
+            return Ok(None);
+        };
+        let block = self.read_record_at(offset)?;
+        Ok(Some(block.verified_for(params)?))
+    }
+
+    fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    fn append_block(&mut self, block: &VerifiedBlock) -> Result<()> {
+        if self.contains_block(block.hash()) {
+            return Ok(());
+        }
+
+        let data = serde_json::to_vec(&block.to_block()).context("failed to serialize block")?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+        record.write_u32::<LittleEndian>(data.len().try_into()?)?;
+        record.extend_from_slice(&data);
+
+        self.log.seek(SeekFrom::Start(self.log_len))?;
+        self.log
+            .write_all(&record)
+            .context("failed to append to block log")?;
+        self.log.sync_data().context("failed to sync block log")?;
+
+        self.index.insert(*block.hash(), self.log_len);
+        self.log_len += record.len() as u64;
+        Ok(())
+    }
+
+    fn load_pending_transactions(&mut self) -> Result<Vec<VerifiedTransaction>> {
+        let path = self.dir.join(MEMPOOL_FILE_NAME);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {:?}", path)),
+        };
+
+        let transactions: Vec<Transaction> =
+            serde_json::from_slice(&data).with_context(|| format!("failed to parse {:?}", path))?;
+        transactions.into_iter().map(|tx| tx.verified()).collect()
+    }
+
+    fn store_pending_transactions(&mut self, transactions: &[VerifiedTransaction]) -> Result<()> {
+        let transactions = transactions
+            .iter()
+            .map(|tx| tx as &Transaction)
+            .collect::<Vec<_>>();
+        let data = serde_json::to_vec(&transactions).context("failed to serialize mempool")?;
+
+        // NB: write to a temporary file first, so that a crash never leaves a torn mempool.
+        let path = self.dir.join(MEMPOOL_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("failed to replace {:?}", path))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.log.sync_all().context("failed to sync block log")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn read_record(reader: &mut impl Read) -> Result<Option<Block>> {
+    let len = match reader.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    // NB: a garbage length must not turn into a huge allocation, so read what is there.
+    let mut data = vec![];
+    reader.take(len.into()).read_to_end(&mut data)?;
+    if data.len() < len as usize {
+        return Ok(None);
+    }
+
+    let block = serde_json::from_slice(&data).context("failed to deserialize stored block")?;
+    Ok(Some(block))
+}
+
+struct RecordIter<'a> {
+    reader: BufReader<&'a mut File>,
+    offset: u64,
+}
+
+impl<'a> RecordIter<'a> {
+    fn new(file: &'a mut File) -> Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            offset: 0,
+        })
+    }
+}
+
+impl Iterator for RecordIter<'_> {
+    type Item = Result<(u64, Block)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        match read_record(&mut self.reader) {
+            Ok(Some(block)) => {
+                self.offset = match self.reader.stream_position() {
+                    Ok(position) => position,
+                    Err(err) => return Some(Err(err.into())),
+                };
+                Some(Ok((offset, block)))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(
+                err.context(format!("corrupted record at offset {}", offset))
+            )),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_forest::BlockForest, util::parse_pkcs8_private};
+
+    fn test_block() -> VerifiedBlock {
+        let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+        block.verified_for(&ChainParams::mainnet()).unwrap()
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = test_block();
+
+        let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
+        let tx =
+            VerifiedTransaction::sign(&priv_key, block.issuer.clone(), 1, 1, "a".into()).unwrap();
+
+        {
+            let mut storage = FileStorage::open(dir.path()).unwrap();
+            storage.append_block(&block).unwrap();
+            storage.append_block(&block).unwrap();
+            storage
+                .store_pending_transactions(std::slice::from_ref(&tx))
+                .unwrap();
+        }
+
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        assert!(storage.contains_block(block.hash()));
+        assert_eq!(
+            storage.load_blocks(&ChainParams::mainnet()).unwrap(),
+            vec![block.clone()]
+        );
+        assert_eq!(
+            storage
+                .read_block(block.hash(), &ChainParams::mainnet())
+                .unwrap(),
+            Some(block)
+        );
+        assert_eq!(storage.load_pending_transactions().unwrap(), vec![tx]);
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = test_block();
+
+        {
+            let mut storage = FileStorage::open(dir.path()).unwrap();
+            storage.append_block(&block).unwrap();
+        }
+
+        let log_path = dir.path().join(BLOCK_LOG_FILE_NAME);
+        let valid_len = fs::metadata(&log_path).unwrap().len();
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(&[100, 0, 0, 0, b'{']).unwrap();
+        drop(log);
+
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), valid_len);
+        assert_eq!(
+            storage.load_blocks(&ChainParams::mainnet()).unwrap(),
+            vec![block.clone()]
+        );
+
+        let other = VerifiedBlock::genesis();
+        storage.append_block(&other).unwrap();
+        drop(storage);
+
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(
+            storage.load_blocks(&ChainParams::mainnet()).unwrap(),
+            vec![block, other]
+        );
+    }
+
+    #[test]
+    fn test_zeroed_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = test_block();
+
+        {
+            let mut storage = FileStorage::open(dir.path()).unwrap();
+            storage.append_block(&block).unwrap();
+        }
+
+        let log_path = dir.path().join(BLOCK_LOG_FILE_NAME);
+        let valid_len = fs::metadata(&log_path).unwrap().len();
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(&[0; 4096]).unwrap();
+        drop(log);
+
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), valid_len);
+        assert_eq!(
+            storage.load_blocks(&ChainParams::mainnet()).unwrap(),
+            vec![block.clone()]
+        );
+        assert_eq!(
+            storage.load_blocks(&ChainParams::mainnet()).unwrap(),
+            vec![block]
+        );
+    }
+
+    #[test]
+    fn test_block_forest_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = test_block();
+
+        {
+            let storage = FileStorage::open(dir.path()).unwrap();
+            let mut forest =
+                BlockForest::with_storage(ChainParams::mainnet(), Box::new(storage)).unwrap();
+            forest.add_block(block.clone()).unwrap();
+            assert_eq!(forest.head().hash(), block.hash());
+        }
+
+        let storage = FileStorage::open(dir.path()).unwrap();
+        let forest = BlockForest::with_storage(ChainParams::mainnet(), Box::new(storage)).unwrap();
+        assert_eq!(forest.head().hash(), block.hash());
+    }
+}
@@ -1,8 +1,8 @@
 use crate::data::WalletId;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, LocalResult, TimeZone, Utc};
-use rsa::{PublicKeyEncoding, RSAPrivateKey, RSAPublicKey};
+use rsa::{PrivateKeyEncoding, PublicKeyEncoding, RSAPrivateKey, RSAPublicKey};
 use serde::{
     de::{self, Deserializer},
     ser::{self, Serializer},
@@ -32,6 +32,63 @@ pub fn parse_pkcs8_private(raw: &str) -> Result<RSAPrivateKey> {
     RSAPrivateKey::from_pkcs8(&der_bytes).context("failed to decode pkcs8 bytes")
 }
 
+fn encode_pkcs8_plaintext(label: &str, der_bytes: &[u8]) -> String {
+    let encoded = base64::encode(der_bytes);
+    let mut result = format!("-----BEGIN {}-----\n", label);
+    for chunk in encoded.as_bytes().chunks(64) {
+        result.push_str(std::str::from_utf8(chunk).unwrap());
+        result.push('\n');
+    }
+    result.push_str(&format!("-----END {}-----\n", label));
+    result
+}
+
+pub fn format_pkcs8_public(key: &RSAPublicKey) -> Result<String> {
+    let der_bytes = key.to_pkcs8().context("failed to encode key as pkcs8")?;
+    Ok(encode_pkcs8_plaintext("PUBLIC KEY", &der_bytes))
+}
+
+pub fn format_pkcs8_private(key: &RSAPrivateKey) -> Result<String> {
+    let der_bytes = key.to_pkcs8().context("failed to encode key as pkcs8")?;
+    Ok(encode_pkcs8_plaintext("PRIVATE KEY", &der_bytes))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MULTISIG_WALLET_PREFIX: &str = "multisig:";
+
+/// Encodes a wallet id the same way it is represented in json messages and configs:
+/// pkcs8 bytes of the key, or the policy hash with a prefix for multisig wallets.
+pub fn encode_wallet_id(wallet: &WalletId) -> Result<String> {
+    match wallet {
+        WalletId::Key(public_key) => {
+            let bytes = public_key
+                .to_pkcs8()
+                .context("failed to encode key as pkcs8")?;
+            Ok(base64::encode(bytes))
+        }
+        WalletId::Multisig(hash) => Ok(format!(
+            "{}{}",
+            MULTISIG_WALLET_PREFIX,
+            base64::encode(hash)
+        )),
+    }
+}
+
+pub fn decode_wallet_id(raw: &str) -> Result<WalletId> {
+    let raw = raw.trim();
+    if let Some(encoded_hash) = raw.strip_prefix(MULTISIG_WALLET_PREFIX) {
+        let bytes = base64::decode(encoded_hash).context("failed to decode base64")?;
+        let hash = bytes
+            .try_into()
+            .map_err(|_| anyhow!("multisig wallet hash has invalid length"))?;
+        return Ok(WalletId::Multisig(hash));
+    }
+    let bytes = base64::decode(raw).context("failed to decode base64")?;
+    let public_key = RSAPublicKey::from_pkcs8(&bytes).context("failed to decode pkcs8 bytes")?;
+    Ok(WalletId::Key(public_key))
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn serialize_base64<T, S>(array: &T, serializer: S) -> Result<S::Ok, S::Error>
@@ -72,27 +129,106 @@ where
     Ok(array)
 }
 
+pub fn serialize_base64_vec<T, S>(arrays: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    serializer.collect_seq(arrays.iter().map(|array| base64::encode(array.as_ref())))
+}
+
+pub fn serialize_base64_option<T, S>(array: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    match array {
+        Some(array) => serialize_base64(array, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_base64_blobs<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings
+        .into_iter()
+        .map(|string| {
+            base64::decode(&string)
+                .map_err(|err| de::Error::custom(format!("invalid base64: {}", err)))
+        })
+        .collect()
+}
+
+pub fn deserialize_base64_vec<'de, D, const SIZE: usize>(
+    deserializer: D,
+) -> Result<Vec<[u8; SIZE]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings
+        .into_iter()
+        .map(|string| {
+            let bytes = base64::decode(&string)
+                .map_err(|err| de::Error::custom(format!("invalid base64: {}", err)))?;
+            bytes.try_into().map_err(|bytes: Vec<u8>| {
+                de::Error::custom(format!(
+                    "invalid length: expected {}, got {}",
+                    SIZE,
+                    bytes.len()
+                ))
+            })
+        })
+        .collect()
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn serialize_wallet_id<S>(wallet: &WalletId, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let bytes = wallet
-        .public_key
-        .to_pkcs8()
-        .map_err(|err| ser::Error::custom(format!("failed to encode key as PKCS8: {}", err)))?;
-    serialize_base64(&bytes, serializer)
+    let encoded =
+        encode_wallet_id(wallet).map_err(|err| ser::Error::custom(format!("{:#}", err)))?;
+    serializer.serialize_str(&encoded)
 }
 
 pub fn deserialize_wallet_id<'de, D>(deserializer: D) -> Result<WalletId, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let bytes = deserialize_base64(deserializer)?;
-    RSAPublicKey::from_pkcs8(&bytes)
-        .map(|public_key| WalletId { public_key })
-        .map_err(|err| de::Error::custom(format!("invalid PKCS8: {}", err)))
+    let string = String::deserialize(deserializer)?;
+    decode_wallet_id(&string)
+        .map_err(|err| de::Error::custom(format!("invalid wallet id: {:#}", err)))
+}
+
+pub fn serialize_wallet_id_vec<S>(wallets: &[WalletId], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let encoded = wallets
+        .iter()
+        .map(encode_wallet_id)
+        .collect::<Result<Vec<_>>>()
+        .map_err(|err| ser::Error::custom(format!("{:#}", err)))?;
+    serializer.collect_seq(encoded)
+}
+
+pub fn deserialize_wallet_id_vec<'de, D>(deserializer: D) -> Result<Vec<WalletId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings
+        .iter()
+        .map(|string| {
+            decode_wallet_id(string)
+                .map_err(|err| de::Error::custom(format!("invalid wallet id: {:#}", err)))
+        })
+        .collect()
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -1,7 +1,13 @@
 #![allow(dead_code)]
 
+pub mod simulation;
+
 use babencoin::{
-    data::{Block, BlockHash, PeerMessage, HASH_LEN},
+    chain_params::ChainParams,
+    data::{
+        Block, BlockHash, PeerMessage, FEATURE_GOODBYE, FEATURE_PEER_EXCHANGE, HASH_LEN,
+        PROTOCOL_VERSION,
+    },
     node,
 };
 
@@ -16,7 +22,7 @@ use std::{
     io::{self, ErrorKind, Read, Write},
     net::{SocketAddr, TcpStream},
     path::{Path, PathBuf},
-    process::{Child, Command},
+    process::{Child, Command, ExitStatus},
     thread,
     time::{Duration, Instant},
 };
@@ -26,6 +32,7 @@ use std::{
 const NODE_BINARY_PATH: &str = "../target/debug/babencoin";
 const TEST_ARTIFACTS_PATH: &str = "./test_artifacts";
 const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(3);
+const TERMINATION_TIMEOUT: Duration = Duration::from_secs(15);
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -43,6 +50,7 @@ pub struct Env {
     node: Child,
     addr: SocketAddr,
     log_file_path: PathBuf,
+    params: ChainParams,
 }
 
 impl Drop for Env {
@@ -64,6 +72,7 @@ impl Env {
             + ChaCha20Rng::from_entropy().gen_range(0..100);
         let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
         config.peer_app.service.listen_address = Some(addr.to_string());
+        let params = config.chain.params().unwrap();
 
         let dir_suffix = if cfg!(debug_assertions) {
             "debug"
@@ -105,26 +114,72 @@ impl Env {
             node,
             addr,
             log_file_path,
+            params,
         };
 
-        Self::wait_for_liveness(&addr);
+        env.wait_for_liveness();
 
         env
     }
 
-    fn wait_for_liveness(addr: &SocketAddr) {
+    // NB: the node may run another chain than the test process, so this sticks to
+    // its genesis block rather than using `sync`.
+    fn wait_for_liveness(&self) {
         let interval = Duration::from_millis(100);
+        let genesis = Block::genesis_for(&self.params);
         for _ in 0..100 {
             thread::sleep(interval);
-            if let Ok(mut conn) = TcpStream::connect_timeout(&addr, interval) {
-                sync(&mut conn).unwrap();
+            if let Ok(mut conn) = TcpStream::connect_timeout(&self.addr, interval) {
+                handshake_with(&mut conn, hello_for(&self.params)).unwrap();
+                send_message(
+                    &mut conn,
+                    PeerMessage::Request {
+                        block_hash: genesis.compute_hash(),
+                    },
+                )
+                .unwrap();
+                wait_for_message(&mut conn, 3, |msg| match msg {
+                    PeerMessage::Block(block) => **block == genesis,
+                    _ => false,
+                })
+                .unwrap();
                 return;
             }
         }
         panic!("failed to wait for node liveness");
     }
 
+    pub fn address(&self) -> SocketAddr {
+        self.addr
+    }
+
     pub fn connect_to_node(&self) -> io::Result<TcpStream> {
+        let mut conn = self.connect_to_node_raw()?;
+        handshake_with(&mut conn, hello_for(&self.params)).map_err(io::Error::other)?;
+        Ok(conn)
+    }
+
+    /// Sends SIGTERM to the node and waits for it to exit.
+    pub fn terminate(&mut self) -> Result<ExitStatus> {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.node.id().to_string()])
+            .status()?;
+        if !status.success() {
+            bail!("failed to send SIGTERM to the node");
+        }
+
+        let deadline = Instant::now() + TERMINATION_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(status) = self.node.try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        bail!("node hasn't exited in time");
+    }
+
+    /// Connects without a handshake.
+    pub fn connect_to_node_raw(&self) -> io::Result<TcpStream> {
         let conn = TcpStream::connect(&self.addr)?;
         conn.set_read_timeout(Some(DEFAULT_READ_TIMEOUT)).unwrap();
         Ok(conn)
@@ -169,6 +224,33 @@ pub fn recv_message(conn: &mut TcpStream) -> Result<PeerMessage> {
     bail!("stream ended unexpectedly");
 }
 
+pub fn hello() -> PeerMessage {
+    hello_for(&ChainParams::mainnet())
+}
+
+pub fn hello_for(params: &ChainParams) -> PeerMessage {
+    let genesis_hash = Block::genesis_for(params).compute_hash();
+    PeerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        network_id: params.network_id(),
+        head_index: 0,
+        head_hash: genesis_hash,
+        features: FEATURE_PEER_EXCHANGE | FEATURE_GOODBYE,
+    }
+}
+
+pub fn handshake(conn: &mut TcpStream) -> Result<()> {
+    handshake_with(conn, hello())
+}
+
+pub fn handshake_with(conn: &mut TcpStream, hello: PeerMessage) -> Result<()> {
+    send_message(conn, hello)?;
+    match recv_message(conn)? {
+        PeerMessage::Hello { .. } => Ok(()),
+        msg => bail!("expected hello, got {:?}", msg),
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 // Make sure that all the previous messages have been processed by gossip service.
@@ -272,3 +354,66 @@ pub fn generate_private_key() -> RSAPrivateKey {
 pub fn generate_public_key() -> RSAPublicKey {
     generate_private_key().into()
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn rpc_call(
+    addr: &SocketAddr,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let mut conn = (0..30)
+        .find_map(|_| {
+            TcpStream::connect(addr)
+                .map_err(|_| thread::sleep(Duration::from_millis(100)))
+                .ok()
+        })
+        .context("failed to connect to rpc server")?;
+    conn.set_read_timeout(Some(DEFAULT_READ_TIMEOUT)).unwrap();
+
+    let body = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    })
+    .to_string();
+    write!(
+        conn,
+        "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    conn.read_to_string(&mut response)?;
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed http response")?;
+    Ok(serde_json::from_str(body)?)
+}
+
+pub fn http_get(addr: &SocketAddr, path: &str) -> Result<String> {
+    let mut conn = (0..30)
+        .find_map(|_| {
+            TcpStream::connect(addr)
+                .map_err(|_| thread::sleep(Duration::from_millis(100)))
+                .ok()
+        })
+        .context("failed to connect to http server")?;
+    conn.set_read_timeout(Some(DEFAULT_READ_TIMEOUT)).unwrap();
+    write!(conn, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr)?;
+
+    let mut response = String::new();
+    conn.read_to_string(&mut response)?;
+    let (status, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed http response")?;
+    if !status.starts_with("HTTP/1.1 200 ") {
+        bail!(
+            "http request failed: {}",
+            status.lines().next().unwrap_or_default()
+        );
+    }
+    Ok(body.to_string())
+}
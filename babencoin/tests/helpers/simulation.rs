@@ -0,0 +1,467 @@
+//! In-process network of gossip services.
+//!
+//! Every node runs a real `GossipService`, but peers are connected by an in-memory router
+//! instead of `PeerService`, and blocks are mined by the harness itself on demand. Delays
+//! and losses are drawn from a seeded rng, so runs on a paused tokio clock are reproducible
+//! up to the gossip service's own choice of peers for eager requests.
+
+use babencoin::{
+    block_forest::BlockForest,
+    chain_params::ChainParams,
+    data::{
+        Block, BlockAttributes, BlockHash, Transaction, VerifiedBlock, VerifiedPeerMessage,
+        HASH_LEN,
+    },
+    node::{
+        gossip_service::{GossipService, GossipServiceConfig},
+        mining_service::MiningInfo,
+        peer_service::{PeerCommand, PeerCommandKind, PeerEvent, PeerEventKind, SessionId},
+        rpc_service::{RpcRequest, RpcRequestKind, RpcResponse},
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use tokio::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const CHANNEL_SIZE: usize = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type NodeId = usize;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone)]
+pub struct SimulationConfig {
+    pub node_count: usize,
+    pub seed: u64,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Probability for each message to be lost.
+    pub loss_rate: f64,
+    pub eager_requests_interval: Duration,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            node_count: 3,
+            seed: 0,
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            loss_rate: 0.,
+            eager_requests_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Link {
+    peer: NodeId,
+    peer_session_id: SessionId,
+}
+
+struct Network {
+    rng: ChaCha20Rng,
+    min_delay: Duration,
+    max_delay: Duration,
+    loss_rate: f64,
+    /// Nodes with different group ids can't talk to each other.
+    groups: Vec<usize>,
+    links: HashMap<(NodeId, SessionId), Link>,
+    event_senders: Vec<Sender<PeerEvent>>,
+    next_session_id: SessionId,
+}
+
+impl Network {
+    fn is_linked(&self, a: NodeId, b: NodeId) -> bool {
+        self.links
+            .iter()
+            .any(|((node, _), link)| *node == a && link.peer == b)
+    }
+
+    fn connect(&mut self, a: NodeId, b: NodeId) {
+        let a_session_id = self.next_session_id;
+        let b_session_id = self.next_session_id + 1;
+        self.next_session_id += 2;
+
+        self.links.insert(
+            (a, a_session_id),
+            Link {
+                peer: b,
+                peer_session_id: b_session_id,
+            },
+        );
+        self.links.insert(
+            (b, b_session_id),
+            Link {
+                peer: a,
+                peer_session_id: a_session_id,
+            },
+        );
+        self.send_event(a, a_session_id, PeerEventKind::Connected, Duration::ZERO);
+        self.send_event(b, b_session_id, PeerEventKind::Connected, Duration::ZERO);
+    }
+
+    fn disconnect(&mut self, node: NodeId, session_id: SessionId) {
+        let Some(link) = self.links.remove(&(node, session_id)) else {
+            return;
+        };
+        self.links.remove(&(link.peer, link.peer_session_id));
+        self.send_event(
+            node,
+            session_id,
+            PeerEventKind::Disconnected,
+            Duration::ZERO,
+        );
+        self.send_event(
+            link.peer,
+            link.peer_session_id,
+            PeerEventKind::Disconnected,
+            Duration::ZERO,
+        );
+    }
+
+    fn route(&mut self, node: NodeId, session_id: SessionId, message: VerifiedPeerMessage) {
+        let Some(link) = self.links.get(&(node, session_id)) else {
+            return;
+        };
+        let (peer, peer_session_id) = (link.peer, link.peer_session_id);
+
+        if self.rng.gen_bool(self.loss_rate) {
+            return;
+        }
+        let delay = if self.max_delay > self.min_delay {
+            self.rng.gen_range(self.min_delay..self.max_delay)
+        } else {
+            self.min_delay
+        };
+        self.send_event(
+            peer,
+            peer_session_id,
+            PeerEventKind::NewMessage(message),
+            delay,
+        );
+    }
+
+    fn send_event(
+        &self,
+        node: NodeId,
+        session_id: SessionId,
+        event_kind: PeerEventKind,
+        delay: Duration,
+    ) {
+        let sender = self.event_senders[node].clone();
+        tokio::spawn(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let _ = sender
+                .send(PeerEvent {
+                    session_id,
+                    event_kind,
+                })
+                .await;
+        });
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct SimNode {
+    rpc_sender: Sender<RpcRequest>,
+    block_sender: Sender<VerifiedBlock>,
+    mining_info: Arc<Mutex<Option<MiningInfo>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// A network of nodes, fully connected at start. Must be created inside a tokio runtime.
+pub struct Simulation {
+    nodes: Vec<SimNode>,
+    network: Arc<Mutex<Network>>,
+    rng: ChaCha20Rng,
+    timestamps: HashMap<BlockHash, DateTime<Utc>>,
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        for node in self.nodes.iter() {
+            for task in node.tasks.iter() {
+                task.abort();
+            }
+        }
+    }
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig) -> Self {
+        let mut event_receivers = vec![];
+        let mut event_senders = vec![];
+        for _ in 0..config.node_count {
+            let (sender, receiver) = channel(CHANNEL_SIZE);
+            event_senders.push(sender);
+            event_receivers.push(receiver);
+        }
+
+        let network = Arc::new(Mutex::new(Network {
+            rng: ChaCha20Rng::seed_from_u64(config.seed),
+            min_delay: config.min_delay,
+            max_delay: config.max_delay,
+            loss_rate: config.loss_rate,
+            groups: vec![0; config.node_count],
+            links: HashMap::new(),
+            event_senders,
+            next_session_id: 0,
+        }));
+
+        let nodes = event_receivers
+            .into_iter()
+            .enumerate()
+            .map(|(node_id, event_receiver)| {
+                Self::start_node(&config, node_id, event_receiver, network.clone())
+            })
+            .collect();
+
+        let mut timestamps = HashMap::new();
+        let genesis = Block::genesis();
+        timestamps.insert(genesis.compute_hash(), genesis.timestamp);
+
+        let simulation = Self {
+            nodes,
+            network,
+            rng: ChaCha20Rng::seed_from_u64(config.seed.wrapping_add(1)),
+            timestamps,
+        };
+        simulation.heal();
+        simulation
+    }
+
+    fn start_node(
+        config: &SimulationConfig,
+        node_id: NodeId,
+        event_receiver: Receiver<PeerEvent>,
+        network: Arc<Mutex<Network>>,
+    ) -> SimNode {
+        let (command_sender, mut command_receiver) = channel::<PeerCommand>(CHANNEL_SIZE);
+        let (block_sender, block_receiver) = channel(CHANNEL_SIZE);
+        let (mining_info_sender, mut mining_info_receiver) = channel(CHANNEL_SIZE);
+        let (rpc_sender, rpc_receiver) = channel(CHANNEL_SIZE);
+
+        let mut gossip_service = GossipService::new(
+            GossipServiceConfig {
+                eager_requests_interval: config.eager_requests_interval,
+            },
+            BlockForest::new(),
+            event_receiver,
+            command_sender,
+            block_receiver,
+            mining_info_sender,
+            rpc_receiver,
+        );
+        let gossip_task = tokio::spawn(async move {
+            if let Err(err) = gossip_service.run().await {
+                panic!("gossip service of node {} failed: {:#}", node_id, err);
+            }
+        });
+
+        let router_task = tokio::spawn(async move {
+            while let Some(command) = command_receiver.recv().await {
+                let mut network = network.lock().unwrap();
+                match command.command_kind {
+                    PeerCommandKind::SendMessage(message) => {
+                        network.route(node_id, command.session_id, message)
+                    }
+                    PeerCommandKind::Drop => network.disconnect(node_id, command.session_id),
+                }
+            }
+        });
+
+        let mining_info = Arc::new(Mutex::new(None));
+        let mining_info_task = tokio::spawn({
+            let mining_info = mining_info.clone();
+            async move {
+                while let Some(info) = mining_info_receiver.recv().await {
+                    *mining_info.lock().unwrap() = Some(info);
+                }
+            }
+        });
+
+        SimNode {
+            rpc_sender,
+            block_sender,
+            mining_info,
+            tasks: vec![gossip_task, router_task, mining_info_task],
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Splits nodes into groups that can't reach each other. Links between groups are dropped.
+    pub fn partition(&self, groups: &[&[NodeId]]) {
+        let mut network = self.network.lock().unwrap();
+        for (group_id, group) in groups.iter().enumerate() {
+            for node in group.iter() {
+                network.groups[*node] = group_id;
+            }
+        }
+
+        let broken_links = network
+            .links
+            .iter()
+            .filter(|((node, _), link)| network.groups[*node] != network.groups[link.peer])
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for (node, session_id) in broken_links {
+            network.disconnect(node, session_id);
+        }
+    }
+
+    /// Removes all partitions and connects every pair of nodes that is not connected yet.
+    pub fn heal(&self) {
+        let mut network = self.network.lock().unwrap();
+        network.groups.iter_mut().for_each(|group| *group = 0);
+        for a in 0..self.nodes.len() {
+            for b in (a + 1)..self.nodes.len() {
+                if !network.is_linked(a, b) {
+                    network.connect(a, b);
+                }
+            }
+        }
+    }
+
+    pub fn set_loss_rate(&self, loss_rate: f64) {
+        self.network.lock().unwrap().loss_rate = loss_rate;
+    }
+
+    /// Lets the simulation run for `duration` of (possibly virtual) time.
+    pub async fn run_for(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Mines a block on top of the node's head and hands it to the node.
+    pub async fn mine(&mut self, node_id: NodeId) -> Result<BlockHash> {
+        let info = self.nodes[node_id]
+            .mining_info
+            .lock()
+            .unwrap()
+            .clone()
+            .context("node has not published mining info yet")?;
+        let prev_timestamp = *self
+            .timestamps
+            .get(&info.prev_hash)
+            .context("parent block was not mined by the simulation")?;
+
+        let params = ChainParams::mainnet();
+        let mut block = Block {
+            attrs: BlockAttributes {
+                index: info.block_index,
+                reward: params.max_reward,
+                nonce: 0,
+                timestamp: prev_timestamp + chrono::Duration::seconds(1),
+                issuer: params.genesis_issuer.clone(),
+                max_hash: info.max_hash,
+                prev_hash: info.prev_hash,
+                merkle_root: [0; HASH_LEN],
+            },
+            transactions: info
+                .transactions
+                .iter()
+                .map(|tx| (tx as &Transaction).clone())
+                .collect(),
+        };
+        block.update_merkle_root();
+        let pow_hash = params.make_pow_hash();
+        loop {
+            block.nonce = self.rng.gen();
+            if pow_hash.compute(&block.compute_hash()) <= block.max_hash {
+                break;
+            }
+        }
+
+        let block = block.verified_for(&params)?;
+        let hash = *block.hash();
+        self.timestamps.insert(hash, block.timestamp);
+        self.nodes[node_id]
+            .block_sender
+            .send(block)
+            .await
+            .context("node has stopped")?;
+        Ok(hash)
+    }
+
+    pub async fn head(&self, node_id: NodeId) -> Result<(BlockHash, u64)> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.nodes[node_id]
+            .rpc_sender
+            .send(RpcRequest {
+                request_kind: RpcRequestKind::GetHead,
+                response_sender,
+            })
+            .await
+            .context("node has stopped")?;
+        match response_receiver.await?? {
+            RpcResponse::Head { hash, attrs } => Ok((hash, attrs.index)),
+            response => bail!("unexpected response: {:?}", response),
+        }
+    }
+
+    pub async fn heads(&self) -> Result<Vec<(BlockHash, u64)>> {
+        let mut heads = vec![];
+        for node_id in 0..self.nodes.len() {
+            heads.push(self.head(node_id).await?);
+        }
+        Ok(heads)
+    }
+
+    /// Waits until all nodes have the same head and returns it.
+    pub async fn wait_for_convergence(&self, timeout: Duration) -> Result<(BlockHash, u64)> {
+        self.wait_for_heads(timeout, |heads| heads.iter().all(|head| *head == heads[0]))
+            .await
+    }
+
+    /// Waits until all nodes have `hash` as their head.
+    pub async fn wait_for_head(&self, hash: &BlockHash, timeout: Duration) -> Result<u64> {
+        let (_, index) = self
+            .wait_for_heads(timeout, |heads| heads.iter().all(|(head, _)| head == hash))
+            .await?;
+        Ok(index)
+    }
+
+    async fn wait_for_heads(
+        &self,
+        timeout: Duration,
+        pred: impl Fn(&[(BlockHash, u64)]) -> bool,
+    ) -> Result<(BlockHash, u64)> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let heads = self.heads().await?;
+            if pred(&heads) {
+                return Ok(heads[0]);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                let indices = heads.iter().map(|(_, index)| *index).collect::<Vec<_>>();
+                bail!("heads did not converge, head indices: {:?}", indices);
+            }
+            self.run_for(POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,111 @@
+#[allow(unused_macros)]
+mod helpers;
+
+use helpers::random_block;
+
+use babencoin::{
+    chain_params::ChainParams,
+    data::{Block, VerifiedBlock},
+    storage::{ChainStorage, FileStorage},
+    util::encode_wallet_id,
+};
+
+use serde_json::Value;
+
+use std::{fs, path::Path, process::Command};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const EXPLORER_BINARY_PATH: &str = "../target/debug/babencoin-explorer";
+
+fn run_explorer(args: &[&str]) -> (i32, String, String) {
+    let output = Command::new(EXPLORER_BINARY_PATH)
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn write_dump(path: &Path, blocks: &[Block]) {
+    let lines = blocks
+        .iter()
+        .map(|block| serde_json::to_string(block).unwrap() + "\n")
+        .collect::<String>();
+    fs::write(path, lines).unwrap();
+}
+
+fn test_block() -> Block {
+    serde_json::from_str(include_str!("../data/test_block.json")).unwrap()
+}
+
+#[test]
+fn test_audit_dump() {
+    let dir = tempfile::tempdir().unwrap();
+    let dump_path = dir.path().join("chain.jsonl");
+    write_dump(&dump_path, &[test_block()]);
+
+    let (code, stdout, stderr) = run_explorer(&["--dump", dump_path.to_str().unwrap()]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    let blocks = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0]["index"], 1);
+    assert_eq!(
+        blocks[0]["hash"],
+        base64::encode(test_block().compute_hash())
+    );
+    assert_eq!(blocks[0]["transaction_count"], 1);
+    assert_eq!(blocks[1]["index"], 0);
+}
+
+#[test]
+fn test_report_inconsistencies() {
+    let dir = tempfile::tempdir().unwrap();
+    let dump_path = dir.path().join("chain.jsonl");
+
+    let mut bad_max_hash = test_block();
+    bad_max_hash.attrs.max_hash[0] = 0;
+    write_dump(&dump_path, &[random_block(5), bad_max_hash]);
+    fs::write(
+        &dump_path,
+        fs::read_to_string(&dump_path).unwrap() + "{\"index\": 1}\n",
+    )
+    .unwrap();
+
+    let (code, _, stderr) = run_explorer(&["--dump", dump_path.to_str().unwrap(), "-q"]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("line 3"), "{}", stderr);
+    assert!(stderr.contains("is missing"), "{}", stderr);
+    assert!(stderr.contains("3 inconsistencies"), "{}", stderr);
+}
+
+#[test]
+fn test_audit_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        storage
+            .append_block(&test_block().verified_for(&ChainParams::mainnet()).unwrap())
+            .unwrap();
+        storage.append_block(&VerifiedBlock::genesis()).unwrap();
+    }
+
+    let (code, stdout, stderr) = run_explorer(&[
+        "--data-dir",
+        dir.path().to_str().unwrap(),
+        "-q",
+        "--balances",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    let issuer = encode_wallet_id(&test_block().issuer).unwrap();
+    assert!(stdout.lines().any(|line| line == format!("{} 500", issuer)));
+    assert_eq!(stdout.lines().count(), 2);
+}
@@ -9,7 +9,10 @@ use helpers::{
 };
 
 use babencoin::{
-    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction},
+    data::{
+        Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction, HASH_LEN,
+        MAX_HEADERS_PER_MESSAGE,
+    },
     node,
 };
 
@@ -59,6 +62,48 @@ fn test_block_request() {
     .unwrap();
 }
 
+#[test]
+fn test_proof_request() {
+    let env = test_env!("test_proof_request");
+    let mut conn = env.connect_to_node().unwrap();
+
+    let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+    let block_hash = block.compute_hash();
+    let transaction_hash = block.transactions[0].compute_hash();
+    send_message(&mut conn, PeerMessage::Block(Box::new(block))).unwrap();
+    sync(&mut conn).unwrap();
+
+    for (block_hash, transaction_hash) in [
+        (block_hash, [0; HASH_LEN]),
+        (*VerifiedBlock::genesis().hash(), transaction_hash),
+    ] {
+        send_message(
+            &mut conn,
+            PeerMessage::GetProof {
+                block_hash,
+                transaction_hash,
+            },
+        )
+        .unwrap();
+    }
+    ensure_absence(&mut conn, |msg| matches!(msg, PeerMessage::Proof(_))).unwrap();
+
+    send_message(
+        &mut conn,
+        PeerMessage::GetProof {
+            block_hash,
+            transaction_hash,
+        },
+    )
+    .unwrap();
+    let proof = match wait_for_message(&mut conn, 10, |msg| matches!(msg, PeerMessage::Proof(_))) {
+        Ok(PeerMessage::Proof(proof)) => proof,
+        other => panic!("expected proof, got {:?}", other),
+    };
+    assert_eq!(proof.transaction_hash, transaction_hash);
+    proof.verify(&block_hash).unwrap();
+}
+
 #[test]
 fn test_tx_send() {
     let env = test_env!("test_tx_send");
@@ -165,10 +210,12 @@ fn test_head_switch() {
     let mut block_one = random_block(1);
     block_one.attrs.prev_hash = Block::genesis().compute_hash();
     block_one.transactions.push(tx_one.clone().into());
+    block_one.update_merkle_root();
 
     let mut block_two = random_block(1);
     block_two.attrs.prev_hash = Block::genesis().compute_hash();
     block_two.transactions.push(tx_two.clone().into());
+    block_two.update_merkle_root();
 
     let mut conn_one = env.connect_to_node().unwrap();
     send_message(
@@ -287,6 +334,9 @@ fn test_eager_requests() {
         PeerMessage::Block(Box::new(block_two.clone())),
     )
     .unwrap();
+    // NB: the node greets us after the handshake, closing with unread data resets the
+    // connection and may discard the block.
+    sync(&mut conn_one).unwrap();
     drop(conn_one);
 
     let mut conn_two = env.connect_to_node().unwrap();
@@ -313,3 +363,118 @@ fn test_eager_requests() {
     })
     .unwrap();
 }
+
+fn make_chain(len: u64) -> Vec<Block> {
+    let mut chain: Vec<Block> = vec![];
+    for index in 1..=len {
+        let mut block = random_block(index);
+        block.attrs.prev_hash = match chain.last() {
+            Some(prev) => prev.compute_hash(),
+            None => Block::genesis().compute_hash(),
+        };
+        chain.push(block);
+    }
+    chain
+}
+
+#[test]
+fn test_serve_headers() {
+    let env = test_env!("test_serve_headers");
+    let chain = make_chain(40);
+
+    let mut conn_one = env.connect_to_node().unwrap();
+    for block in chain.iter() {
+        send_message(&mut conn_one, PeerMessage::Block(Box::new(block.clone()))).unwrap();
+    }
+    sync(&mut conn_one).unwrap();
+
+    let mut conn_two = env.connect_to_node().unwrap();
+    wait_for_message(&mut conn_two, 10, |msg| match msg {
+        PeerMessage::GetHeaders { locator } => {
+            locator.first() == Some(&chain.last().unwrap().compute_hash())
+                && locator.last() == Some(VerifiedBlock::genesis().hash())
+        }
+        _ => false,
+    })
+    .unwrap();
+
+    send_message(
+        &mut conn_two,
+        PeerMessage::GetHeaders {
+            locator: vec![chain[4].compute_hash(), *VerifiedBlock::genesis().hash()],
+        },
+    )
+    .unwrap();
+    wait_for_message(&mut conn_two, 10, |msg| match msg {
+        PeerMessage::Headers { headers } => {
+            let expected = chain[5..5 + MAX_HEADERS_PER_MESSAGE]
+                .iter()
+                .map(|block| block.attrs.clone())
+                .collect::<Vec<_>>();
+            *headers == expected
+        }
+        _ => false,
+    })
+    .unwrap();
+
+    send_message(
+        &mut conn_two,
+        PeerMessage::GetBlocks {
+            from_hash: chain[37].compute_hash(),
+            count: 5,
+        },
+    )
+    .unwrap();
+    for expected in chain[38..].iter() {
+        wait_for_message(&mut conn_two, 10, |msg| match msg {
+            PeerMessage::Block(block) => **block == *expected,
+            _ => false,
+        })
+        .unwrap();
+    }
+}
+
+#[test]
+fn test_headers_catch_up() {
+    let env = test_env!("test_headers_catch_up");
+    let chain = make_chain(40);
+
+    let mut conn = env.connect_to_node().unwrap();
+    wait_for_message(&mut conn, 10, |msg| {
+        matches!(msg, PeerMessage::GetHeaders { .. })
+    })
+    .unwrap();
+
+    let mut from = 0;
+    while from < chain.len() {
+        let to = (from + MAX_HEADERS_PER_MESSAGE).min(chain.len());
+        let headers = chain[from..to]
+            .iter()
+            .map(|block| block.attrs.clone())
+            .collect::<Vec<_>>();
+        send_message(&mut conn, PeerMessage::Headers { headers }).unwrap();
+
+        let msg = wait_for_message(&mut conn, 10, |msg| {
+            matches!(msg, PeerMessage::GetBlocks { .. })
+        })
+        .unwrap();
+        let PeerMessage::GetBlocks { from_hash, count } = msg else {
+            unreachable!();
+        };
+        assert_eq!(from_hash, chain[from].prev_hash);
+        assert_eq!(count as usize, to - from);
+
+        for block in chain[from..to].iter() {
+            send_message(&mut conn, PeerMessage::Block(Box::new(block.clone()))).unwrap();
+        }
+        from = to;
+    }
+    sync(&mut conn).unwrap();
+
+    let mut conn_two = env.connect_to_node().unwrap();
+    wait_for_message(&mut conn_two, 10, |msg| match msg {
+        PeerMessage::Block(block) => **block == *chain.last().unwrap(),
+        _ => false,
+    })
+    .unwrap();
+}
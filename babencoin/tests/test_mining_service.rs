@@ -1,16 +1,18 @@
 #[macro_use]
 mod helpers;
 
-use std::collections::HashSet;
+use std::{collections::HashSet, net::TcpListener};
 
 use helpers::{
-    generate_private_key, generate_public_key, recv_message, send_message, wait_for_message,
+    generate_private_key, generate_public_key, http_get, recv_message, send_message,
+    wait_for_message,
 };
 
 use babencoin::{
-    block_forest::{BlockForest, EPOCH_SIZE, TARGET_BLOCK_MINING_TIME_SECONDS},
+    block_forest::BlockForest,
+    chain_params::ChainParams,
     data::{Block, BlockAttributes, PeerMessage, VerifiedTransaction, HASH_LEN},
-    node,
+    merkle, node,
 };
 
 use chrono::Duration;
@@ -35,7 +37,7 @@ fn test_genesis_mining() {
     .unwrap();
 
     let mut last_block = match msg {
-        PeerMessage::Block(block) => block.verified().unwrap(),
+        PeerMessage::Block(block) => block.verified_for(&ChainParams::mainnet()).unwrap(),
         _ => unreachable!(),
     };
     block_forest.add_block(last_block.clone()).unwrap();
@@ -51,7 +53,7 @@ fn test_genesis_mining() {
 
         wait_for_message(&mut conn, 10, |msg| match msg {
             PeerMessage::Block(block) => {
-                let verified = block.clone().verified().unwrap();
+                let verified = block.clone().verified_for(&ChainParams::mainnet()).unwrap();
                 if verified.hash() == &last_block.prev_hash {
                     block_forest.add_block(verified.clone()).unwrap();
                     last_block = verified;
@@ -86,13 +88,14 @@ fn test_no_repeated_parents() {
 
 #[test]
 fn test_mining_difficulty() {
+    let params = ChainParams::mainnet();
     let mut blocks = vec![Block::genesis()];
-    for i in 1..3 * EPOCH_SIZE {
+    for i in 1..3 * params.epoch_size {
         let prev_block = blocks.last().unwrap().clone();
-        let time_delta_seconds = if i < 2 * EPOCH_SIZE {
-            TARGET_BLOCK_MINING_TIME_SECONDS
+        let time_delta_seconds = if i < 2 * params.epoch_size {
+            params.target_block_mining_time_seconds
         } else {
-            TARGET_BLOCK_MINING_TIME_SECONDS / 2
+            params.target_block_mining_time_seconds / 2
         };
 
         blocks.push(Block {
@@ -107,6 +110,7 @@ fn test_mining_difficulty() {
                 issuer: generate_public_key().into(),
                 max_hash: [255; HASH_LEN],
                 prev_hash: prev_block.compute_hash(),
+                merkle_root: merkle::compute_root(&[]),
             },
             transactions: vec![],
         });
@@ -132,7 +136,7 @@ fn test_mining_difficulty() {
             if block.prev_hash == expected_prev_hash {
                 assert_eq!(block.index, blocks.len() as u64);
                 assert_eq!(block.max_hash, expected_max_hash);
-                block.clone().verified().unwrap();
+                block.clone().verified_for(&ChainParams::mainnet()).unwrap();
                 true
             } else {
                 false
@@ -177,7 +181,7 @@ fn test_max_tx_per_block() {
     let mut received_transactions = vec![];
     wait_for_message(&mut conn, 15, |msg| match msg {
         PeerMessage::Block(block) => {
-            let verified = block.clone().verified().unwrap();
+            let verified = block.clone().verified_for(&ChainParams::mainnet()).unwrap();
             assert!(verified.transactions().len() <= 2);
             received_transactions.extend(verified.transactions().iter().cloned());
             received_transactions.len() >= transactions.len()
@@ -194,3 +198,40 @@ fn test_max_tx_per_block() {
         assert_eq!(expected_tx, got_tx);
     }
 }
+
+#[test]
+fn test_metrics() {
+    let metrics_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut config = node::Config::default();
+    config.mining_app.service.mining_thread_count = 2;
+    config.mining_app.service.public_key = generate_public_key().into();
+    config.metrics_app.service.listen_address = Some(metrics_addr.to_string());
+
+    let env = test_env!("test_metrics", config);
+    let mut conn = env.connect_to_node().unwrap();
+    wait_for_message(&mut conn, 10, |msg| match msg {
+        PeerMessage::Block(block) => block.index >= 2,
+        _ => false,
+    })
+    .unwrap();
+
+    let text = http_get(&metrics_addr, "/metrics").unwrap();
+    let value = |name: &str| -> f64 {
+        let prefix = format!("babencoin_{} ", name);
+        let line = text.lines().find(|line| line.starts_with(&prefix)).unwrap();
+        line[prefix.len()..].parse().unwrap()
+    };
+    assert_eq!(value("mining_threads"), 2.);
+    assert!(value("mining_hashes_total") > 0.);
+    assert!(value("mining_blocks_mined_total") >= 2.);
+    assert!(value("mining_block_time_seconds_count") >= 2.);
+    assert!(value("mining_work_updates_total") >= 3.);
+    assert!(value("gossip_head_index") >= 2.);
+    assert_eq!(value("peer_sessions"), 1.);
+    assert!(value("peer_messages_sent_total") > 0.);
+
+    assert!(http_get(&metrics_addr, "/").is_err());
+}
@@ -1,17 +1,26 @@
 #[macro_use]
 mod helpers;
 
-use helpers::send_message;
+use helpers::{hello, hello_for, recv_message, send_message, sync, wait_for_message};
 
 use babencoin::{
-    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction, MAX_REWARD},
-    node,
+    chain_params::{ChainConfig, ChainParams, ChainPreset},
+    data::{
+        Block, PeerAddress, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction,
+        PROTOCOL_VERSION,
+    },
+    node::{
+        self,
+        wire::{self, Frame, FrameBuffer, WireFormat},
+    },
     util::parse_pkcs8_private,
 };
 
+use chrono::Utc;
+
 use std::{
     io::{Read, Write},
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     thread::sleep,
     time::Duration,
 };
@@ -56,7 +65,7 @@ fn test_invalid_messages() {
     let invalid_block = {
         let mut block = Block::genesis();
         block.attrs.index = 10;
-        block.attrs.reward = MAX_REWARD + 1;
+        block.attrs.reward = ChainParams::mainnet().max_reward + 1;
         block
     };
 
@@ -69,6 +78,8 @@ fn test_invalid_messages() {
         sender: genesis_key.clone(),
         receiver: genesis_key,
         signature: vec![0; 64],
+        multisig: None,
+        lock_until_index: None,
     };
 
     let cases: &[(&str, String)] = &[
@@ -95,6 +106,35 @@ fn test_invalid_messages() {
     }
 }
 
+#[test]
+fn test_regtest_chain() {
+    let config = node::Config {
+        chain: ChainConfig {
+            preset: ChainPreset::Regtest,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let env = test_env!("test_regtest_chain", config);
+
+    let regtest = ChainParams::regtest();
+    let mut conn = env.connect_to_node_raw().unwrap();
+    send_message(&mut conn, hello_for(&regtest)).unwrap();
+    match recv_message(&mut conn).unwrap() {
+        PeerMessage::Hello { network_id, .. } => {
+            assert_eq!(network_id, Block::genesis_for(&regtest).compute_hash());
+            assert_ne!(network_id, ChainParams::mainnet().network_id());
+        }
+        msg => panic!("expected hello, got {:?}", msg),
+    }
+
+    // NB: mainnet peers are not welcome.
+    let mut conn = env.connect_to_node_raw().unwrap();
+    send_message(&mut conn, hello()).unwrap();
+    let mut buf = vec![];
+    assert!(conn.read_to_end(&mut buf).is_ok());
+}
+
 #[test]
 fn test_huge_message() {
     let env = test_env!("test_huge_message");
@@ -111,6 +151,59 @@ fn test_huge_message() {
     }
 }
 
+fn invalid_block_message() -> PeerMessage {
+    let mut block = Block::genesis();
+    block.attrs.index = 10;
+    block.attrs.reward = ChainParams::mainnet().max_reward + 1;
+    PeerMessage::Block(Box::new(block))
+}
+
+#[test]
+fn test_invalid_message_is_not_fatal() {
+    let env = test_env!("test_invalid_message_is_not_fatal");
+    let mut conn = env.connect_to_node().unwrap();
+
+    send_message(&mut conn, invalid_block_message()).unwrap();
+    send_message(
+        &mut conn,
+        PeerMessage::Request {
+            block_hash: *VerifiedBlock::genesis().hash(),
+        },
+    )
+    .unwrap();
+
+    wait_for_message(&mut conn, 10, |msg| {
+        matches!(msg, PeerMessage::Block(block) if block.compute_hash() == *VerifiedBlock::genesis().hash())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_ban() {
+    let mut config = node::Config::default();
+    config.peer_app.service.scoring.invalid_message_penalty = 30;
+    config.peer_app.service.scoring.ban_threshold = -100;
+    let env = test_env!("test_ban", config);
+
+    let mut conn = env.connect_to_node().unwrap();
+    for _ in 0..4 {
+        if send_message(&mut conn, invalid_block_message()).is_err() {
+            break;
+        }
+    }
+    let mut buf = vec![];
+    if conn.read_to_end(&mut buf).is_err() {
+        panic!("node didn't drop misbehaving peer");
+    }
+
+    let mut conn = env.connect_to_node_raw().unwrap();
+    let mut buf = vec![];
+    match conn.read_to_end(&mut buf) {
+        Ok(_) => assert!(buf.is_empty(), "banned peer received data"),
+        Err(err) => assert_ne!(err.kind(), std::io::ErrorKind::WouldBlock),
+    }
+}
+
 #[test]
 fn test_dial() {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
@@ -123,3 +216,150 @@ fn test_dial() {
         listener.accept().unwrap();
     }
 }
+
+#[test]
+fn test_peer_discovery() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut config = node::Config::default();
+    config.peer_app.service.target_outbound_count = 1;
+    config.peer_app.service.advertised_address = Some("node.example:9090".into());
+    let env = test_env!("test_peer_discovery", config);
+    let mut conn = env.connect_to_node().unwrap();
+
+    wait_for_message(&mut conn, 10, |msg| matches!(msg, PeerMessage::GetPeers)).unwrap();
+    send_message(&mut conn, PeerMessage::GetPeers).unwrap();
+    wait_for_message(&mut conn, 10, |msg| match msg {
+        PeerMessage::Peers { peers } => {
+            peers.iter().any(|peer| peer.address == "node.example:9090")
+        }
+        _ => false,
+    })
+    .unwrap();
+
+    send_message(
+        &mut conn,
+        PeerMessage::Peers {
+            peers: vec![PeerAddress {
+                address: listener.local_addr().unwrap().to_string(),
+                last_seen: Utc::now(),
+            }],
+        },
+    )
+    .unwrap();
+    listener.accept().unwrap();
+}
+
+fn recv_frame(conn: &mut TcpStream, buffer: &mut FrameBuffer) -> Frame {
+    loop {
+        if let Some(frame) = buffer.next_frame().unwrap() {
+            return frame.unwrap();
+        }
+        let mut chunk = [0u8; 4096];
+        let len = conn.read(&mut chunk).unwrap();
+        assert_ne!(len, 0, "node dropped connection");
+        buffer.data_mut().extend_from_slice(&chunk[..len]);
+    }
+}
+
+fn wait_for_genesis(conn: &mut TcpStream, buffer: &mut FrameBuffer) -> Frame {
+    loop {
+        let frame = recv_frame(conn, buffer);
+        if let PeerMessage::Block(block) = &frame.message {
+            if frame.format != WireFormat::Json
+                && block.compute_hash() == *VerifiedBlock::genesis().hash()
+            {
+                return frame;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_binary_wire_format() {
+    let env = test_env!("test_binary_wire_format");
+    let mut conn = env.connect_to_node().unwrap();
+    let mut buffer = FrameBuffer::default();
+
+    let request = PeerMessage::Request {
+        block_hash: *VerifiedBlock::genesis().hash(),
+    };
+
+    // A json frame advertises binary versions, so the reply is binary.
+    conn.write_all(&wire::encode_frame(&request, WireFormat::Json).unwrap())
+        .unwrap();
+    let frame = wait_for_genesis(&mut conn, &mut buffer);
+    assert_eq!(frame.format, WireFormat::Binary(1));
+
+    conn.write_all(&wire::encode_frame(&request, WireFormat::Binary(1)).unwrap())
+        .unwrap();
+    wait_for_genesis(&mut conn, &mut buffer);
+}
+
+#[test]
+fn test_handshake() {
+    let env = test_env!("test_handshake");
+    let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+
+    let mut conn = env.connect_to_node().unwrap();
+    send_message(&mut conn, PeerMessage::Block(Box::new(block.clone()))).unwrap();
+    sync(&mut conn).unwrap();
+
+    let mut conn = env.connect_to_node_raw().unwrap();
+    match recv_message(&mut conn).unwrap() {
+        PeerMessage::Hello {
+            protocol_version,
+            network_id,
+            head_index,
+            head_hash,
+            ..
+        } => {
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+            assert_eq!(network_id, ChainParams::mainnet().network_id());
+            assert_eq!(head_index, 1);
+            assert_eq!(head_hash, block.compute_hash());
+        }
+        msg => panic!("expected hello, got {:?}", msg),
+    }
+}
+
+#[test]
+fn test_handshake_mismatch() {
+    let env = test_env!("test_handshake_mismatch");
+
+    let mut other_network = hello();
+    if let PeerMessage::Hello { network_id, .. } = &mut other_network {
+        network_id[0] ^= 1;
+    }
+    let request = PeerMessage::Request {
+        block_hash: *VerifiedBlock::genesis().hash(),
+    };
+    let cases = [("other_network", other_network), ("no_hello", request)];
+
+    for (name, first_message) in cases {
+        let mut conn = env.connect_to_node_raw().unwrap();
+        send_message(&mut conn, first_message).unwrap();
+        let mut buf = vec![];
+        if conn.read_to_end(&mut buf).is_err() {
+            panic!("node didn't drop connection in case '{}'", name);
+        }
+    }
+}
+
+#[test]
+fn test_graceful_shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = node::Config {
+        data_dir: Some(dir.path().to_owned()),
+        ..Default::default()
+    };
+    let mut env = test_env!("test_graceful_shutdown", config);
+
+    let mut conn = env.connect_to_node().unwrap();
+    sync(&mut conn).unwrap();
+
+    let status = env.terminate().unwrap();
+    assert!(status.success(), "node exited with {}", status);
+    wait_for_message(&mut conn, 10, |msg| matches!(msg, PeerMessage::Goodbye)).unwrap();
+    assert!(dir.path().join("mempool.json").exists());
+}
@@ -0,0 +1,152 @@
+#[macro_use]
+mod helpers;
+
+use helpers::{
+    generate_private_key, generate_public_key, rpc_call, send_message, sync, wait_for_message,
+};
+
+use babencoin::{
+    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction},
+    node,
+    util::{parse_pkcs8_private, serialize_wallet_id},
+};
+
+use serde_json::json;
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn rpc_config() -> (node::Config, SocketAddr) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut config = node::Config::default();
+    config.rpc_app.service.listen_address = Some(addr.to_string());
+    (config, addr)
+}
+
+#[test]
+fn test_chain_queries() {
+    let (mut config, rpc_addr) = rpc_config();
+    config.history_index = true;
+    let env = test_env!("test_rpc_chain_queries", config);
+
+    let reply = rpc_call(&rpc_addr, "get_head", json!(null)).unwrap();
+    assert_eq!(reply["result"]["index"], 0);
+    assert_eq!(
+        reply["result"]["hash"],
+        base64::encode(VerifiedBlock::genesis().hash())
+    );
+
+    let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+    let mut conn = env.connect_to_node().unwrap();
+    send_message(&mut conn, PeerMessage::Block(Box::new(block.clone()))).unwrap();
+    sync(&mut conn).unwrap();
+
+    let hash = base64::encode(block.compute_hash());
+    let reply = rpc_call(&rpc_addr, "get_head", json!(null)).unwrap();
+    assert_eq!(reply["result"]["index"], 1);
+    assert_eq!(reply["result"]["hash"], hash);
+
+    let reply = rpc_call(&rpc_addr, "get_block", json!({ "hash": hash })).unwrap();
+    let recv_block: Block = serde_json::from_value(reply["result"].clone()).unwrap();
+    assert_eq!(recv_block, block);
+
+    let genesis_wallet =
+        serialize_wallet_id(&Block::genesis().issuer, serde_json::value::Serializer).unwrap();
+    let reply = rpc_call(
+        &rpc_addr,
+        "get_balance",
+        json!({ "wallet": genesis_wallet }),
+    )
+    .unwrap();
+    assert_eq!(reply["result"]["balance"], 500);
+
+    let reply = rpc_call(
+        &rpc_addr,
+        "get_history",
+        json!({ "wallet": genesis_wallet }),
+    )
+    .unwrap();
+    let history = reply["result"].as_array().unwrap();
+    let total: i64 = history
+        .iter()
+        .map(|entry| entry["delta"].as_i64().unwrap())
+        .sum();
+    assert_eq!(total, 500);
+    assert!(history
+        .iter()
+        .all(|entry| entry["block_hash"] == hash && entry["confirmations"] == 1));
+
+    let reply = rpc_call(&rpc_addr, "get_block", json!({ "hash": "AAAA" })).unwrap();
+    assert!(reply["error"].is_object());
+}
+
+#[test]
+fn test_submit_transaction() {
+    let (config, rpc_addr) = rpc_config();
+    let env = test_env!("test_rpc_submit_transaction", config);
+    let mut conn = env.connect_to_node().unwrap();
+
+    let tx = VerifiedTransaction::sign(
+        &generate_private_key(),
+        generate_public_key().into(),
+        0,
+        0,
+        "rpc".into(),
+    )
+    .unwrap();
+    let tx_json = serde_json::to_value(&tx as &Transaction).unwrap();
+
+    let reply = rpc_call(&rpc_addr, "submit_transaction", tx_json).unwrap();
+    assert_eq!(reply["result"]["hash"], base64::encode(tx.hash()));
+
+    wait_for_message(&mut conn, 10, |msg| match msg {
+        PeerMessage::Transaction(recv_tx) => **recv_tx == *tx,
+        _ => false,
+    })
+    .unwrap();
+
+    let reply = rpc_call(&rpc_addr, "get_pending_transactions", json!(null)).unwrap();
+    let pending: Vec<Transaction> = serde_json::from_value(reply["result"].clone()).unwrap();
+    assert_eq!(pending, vec![(&tx as &Transaction).clone()]);
+
+    let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
+    let poor_tx =
+        VerifiedTransaction::sign(&priv_key, generate_public_key().into(), 10, 1, "rpc".into())
+            .unwrap();
+    let reply = rpc_call(
+        &rpc_addr,
+        "submit_transaction",
+        serde_json::to_value(&poor_tx as &Transaction).unwrap(),
+    )
+    .unwrap();
+    assert!(reply["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("insufficient funds"));
+}
+
+#[test]
+fn test_oversized_request() {
+    let (config, rpc_addr) = rpc_config();
+    let _env = test_env!("test_rpc_oversized_request", config);
+
+    let mut stream = TcpStream::connect(rpc_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = b"POST /".to_vec();
+    request.resize(1 << 16, b'a');
+    let _ = stream.write_all(&request);
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+}
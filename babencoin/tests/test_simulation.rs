@@ -0,0 +1,86 @@
+#[allow(unused_macros)]
+mod helpers;
+
+use helpers::simulation::{Simulation, SimulationConfig};
+
+use std::time::Duration;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[tokio::test(start_paused = true)]
+async fn test_convergence() {
+    let mut sim = Simulation::new(SimulationConfig {
+        node_count: 4,
+        ..Default::default()
+    });
+    sim.run_for(Duration::from_secs(1)).await;
+
+    for i in 0..8 {
+        sim.mine(i % sim.node_count()).await.unwrap();
+        sim.run_for(Duration::from_millis(200)).await;
+    }
+
+    let (hash, index) = sim.wait_for_convergence(CONVERGENCE_TIMEOUT).await.unwrap();
+    assert_eq!(index, 8);
+    assert_eq!(sim.head(0).await.unwrap().0, hash);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_partition() {
+    let mut sim = Simulation::new(SimulationConfig {
+        node_count: 4,
+        seed: 1,
+        ..Default::default()
+    });
+    sim.run_for(Duration::from_secs(1)).await;
+
+    let hash = sim.mine(0).await.unwrap();
+    sim.wait_for_head(&hash, CONVERGENCE_TIMEOUT).await.unwrap();
+
+    sim.partition(&[&[0, 1], &[2, 3]]);
+    for _ in 0..3 {
+        sim.mine(0).await.unwrap();
+        sim.run_for(Duration::from_millis(200)).await;
+    }
+    for _ in 0..5 {
+        sim.mine(2).await.unwrap();
+        sim.run_for(Duration::from_millis(200)).await;
+    }
+    sim.run_for(Duration::from_secs(1)).await;
+
+    let heads = sim.heads().await.unwrap();
+    assert_eq!(heads[0], heads[1]);
+    assert_eq!(heads[2], heads[3]);
+    assert_eq!((heads[0].1, heads[2].1), (4, 6));
+
+    sim.heal();
+    let (hash, index) = sim.wait_for_convergence(CONVERGENCE_TIMEOUT).await.unwrap();
+    assert_eq!((hash, index), heads[2]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_lossy_network() {
+    let mut sim = Simulation::new(SimulationConfig {
+        node_count: 5,
+        seed: 2,
+        loss_rate: 0.3,
+        min_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(300),
+        ..Default::default()
+    });
+    sim.run_for(Duration::from_secs(1)).await;
+
+    for i in 0..10 {
+        sim.mine((i * 3) % sim.node_count()).await.unwrap();
+        sim.run_for(Duration::from_millis(500)).await;
+    }
+
+    // NB: the last block may be lost for good, so mine one more over a reliable network.
+    sim.set_loss_rate(0.);
+    let (_, max_index) = sim.wait_for_convergence(CONVERGENCE_TIMEOUT).await.unwrap();
+    let hash = sim.mine(0).await.unwrap();
+    let index = sim.wait_for_head(&hash, CONVERGENCE_TIMEOUT).await.unwrap();
+    assert_eq!(index, max_index + 1);
+}
@@ -0,0 +1,127 @@
+#[macro_use]
+mod helpers;
+
+use helpers::wait_for_message;
+
+use babencoin::{
+    data::{Block, MultisigPolicy, PeerMessage},
+    util::{decode_wallet_id, encode_wallet_id, parse_pkcs8_private, parse_pkcs8_public},
+};
+
+use std::{fs, process::Command};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const WALLET_BINARY_PATH: &str = "../target/debug/babencoin-wallet";
+
+fn run_wallet(args: &[&str]) -> String {
+    let output = Command::new(WALLET_BINARY_PATH)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "wallet failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_generate_and_sign() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_path = dir.path().join("key.pem");
+    let key_path = key_path.to_str().unwrap();
+
+    let wallet_id = run_wallet(&["generate", "-o", key_path, "--bits", "1024"]);
+    let key = parse_pkcs8_private(&fs::read_to_string(key_path).unwrap()).unwrap();
+    let public_key =
+        parse_pkcs8_public(&fs::read_to_string(dir.path().join("key.pub")).unwrap()).unwrap();
+    assert_eq!(key.to_public_key(), public_key);
+    assert_eq!(decode_wallet_id(&wallet_id).unwrap(), public_key.into());
+    assert_eq!(run_wallet(&["wallet-id", "-k", key_path]), wallet_id);
+
+    let genesis_wallet = encode_wallet_id(&Block::genesis().issuer).unwrap();
+    let tx_json = run_wallet(&[
+        "transfer",
+        "-k",
+        key_path,
+        "--to",
+        &genesis_wallet,
+        "--amount",
+        "10",
+        "--fee",
+        "2",
+    ]);
+    let tx: babencoin::data::Transaction = serde_json::from_str(&tx_json).unwrap();
+    assert_eq!(tx.amount, 10);
+    assert_eq!(tx.fee, 2);
+    assert_eq!(tx.receiver, Block::genesis().issuer);
+    tx.verified().unwrap();
+
+    let escrow_wallet = run_wallet(&[
+        "escrow",
+        "--threshold",
+        "1",
+        "--key",
+        wallet_id.trim(),
+        "--key",
+        &genesis_wallet,
+    ]);
+    let policy = MultisigPolicy::new(
+        1,
+        vec![key.to_public_key().into(), Block::genesis().attrs.issuer],
+    )
+    .unwrap();
+    assert_eq!(
+        decode_wallet_id(&escrow_wallet).unwrap(),
+        policy.wallet_id()
+    );
+
+    let tx_json = run_wallet(&[
+        "transfer",
+        "-k",
+        key_path,
+        "--to",
+        escrow_wallet.trim(),
+        "--amount",
+        "10",
+        "--lock-until-index",
+        "7",
+    ]);
+    let tx: babencoin::data::Transaction = serde_json::from_str(&tx_json).unwrap();
+    assert_eq!(tx.receiver, policy.wallet_id());
+    assert_eq!(tx.lock_until_index, Some(7));
+    tx.verified().unwrap();
+}
+
+#[test]
+fn test_submit() {
+    let env = test_env!("test_wallet_submit");
+    let mut conn = env.connect_to_node().unwrap();
+
+    let genesis_wallet = encode_wallet_id(&Block::genesis().issuer).unwrap();
+    let output = Command::new(WALLET_BINARY_PATH)
+        .args([
+            "transfer",
+            "-k",
+            "./data/test.pem",
+            "--to",
+            &genesis_wallet,
+            "--amount",
+            "0",
+            "--comment",
+            "wallet",
+            "--submit",
+            &env.address().to_string(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    wait_for_message(&mut conn, 10, |msg| match msg {
+        PeerMessage::Transaction(tx) => tx.comment == "wallet",
+        _ => false,
+    })
+    .unwrap();
+}
@@ -1,13 +1,14 @@
 use proc_macro::TokenStream;
+use quote::ToTokens;
 use syn::{parse_macro_input, Attribute, Data, DeriveInput};
 
-#[proc_macro_derive(Object, attributes(table_name, column_name))]
+#[proc_macro_derive(Object, attributes(table_name, column_name, on_delete, default))]
 pub fn derive_object(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as DeriveInput);
     let struct_name = ast.ident.to_string();
 
-    fn try_get_attr(attrs: &[Attribute]) -> Option<String> {
-        let attr = attrs.iter().next()?;
+    fn try_get_attr(attrs: &[Attribute], name: &str) -> Option<String> {
+        let attr = attrs.iter().find(|attr| attr.path.is_ident(name))?;
         let token = attr.tokens.clone().into_iter().next()?;
         let smth = token.to_string();
         if smth.len() <= 4 {
@@ -16,7 +17,7 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
         Some(String::from(smth.as_str().strip_prefix("(\"")?.strip_suffix("\")")?))
     }
 
-    let table_name = match try_get_attr(&ast.attrs) {
+    let table_name = match try_get_attr(&ast.attrs, "table_name") {
         Some(s) => s,
         None => struct_name.clone(),
     };
@@ -48,17 +49,121 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
     let mut keys = Vec::new();
     let mut types = Vec::new();
     let mut columns = Vec::new();
+    let mut references = Vec::new();
+    let mut defaults = Vec::new();
     for field in fields.named.iter() {
         let name = field.ident.as_ref().unwrap().to_string();
-        columns.push(match try_get_attr(&field.attrs) {
+        columns.push(match try_get_attr(&field.attrs, "column_name") {
             None => name.clone(),
             Some(s) => s,
         });
-        keys.push(name);
-        let syn::Type::Path(path) = &field.ty else {
+        let (ty, nullable) = match option_arg(&field.ty) {
+            Some(ty) => (ty, true),
+            None => (&field.ty, false),
+        };
+        let syn::Type::Path(path) = ty else {
             panic!();
         };
-        types.push(path.path.clone().segments.into_iter().next().unwrap().ident.to_string());
+        let segment = path.path.segments.last().unwrap();
+        let type_name = segment.ident.to_string();
+        let data_type = parse_type(&type_name);
+        references.push(if type_name == "Ref" {
+            let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+                panic!("{} must be Ref<T>", name);
+            };
+            let on_delete = match field.attrs.iter().find(|attr| attr.path.is_ident("on_delete")) {
+                None => "Restrict".to_string(),
+                Some(attr) => match attr.parse_args::<syn::Ident>().unwrap().to_string().as_str() {
+                    "cascade" => "Cascade".to_string(),
+                    "restrict" => "Restrict".to_string(),
+                    other => panic!("unknown on_delete for {}: {}", name, other),
+                },
+            };
+            format!(
+                "Some(orm::object::Reference {{
+                    target: orm::object::schema_of::<{0}>,
+                    on_delete: orm::object::OnDelete::{1},
+                }})",
+                args.args.to_token_stream(),
+                on_delete
+            )
+        } else {
+            "None".to_string()
+        });
+        defaults.push(match field.attrs.iter().find(|attr| attr.path.is_ident("default")) {
+            None => "None".to_string(),
+            Some(attr) => match default_value(&name, data_type, attr) {
+                Ok(value) => format!("Some(orm::data::Value::{0}({1}))", data_type, value),
+                Err(err) => return err.to_compile_error().into(),
+            },
+        });
+        keys.push(name);
+        types.push(if nullable {
+            format!("Nullable(&orm::data::DataType::{})", data_type)
+        } else {
+            data_type.to_string()
+        });
+    }
+    /// The literal of `#[default(...)]` as an argument of `Value::<data_type>`. Integers
+    /// are taken for floats too, anything else must match the field type.
+    fn default_value(name: &str, data_type: &str, attr: &Attribute) -> syn::Result<String> {
+        let expr = attr.parse_args::<syn::Expr>()?;
+        let (sign, lit) = match &expr {
+            syn::Expr::Lit(lit) => ("", Some(&lit.lit)),
+            syn::Expr::Unary(syn::ExprUnary { op: syn::UnOp::Neg(_), expr: inner, .. }) => {
+                match inner.as_ref() {
+                    syn::Expr::Lit(lit) => ("-", Some(&lit.lit)),
+                    _ => ("-", None),
+                }
+            }
+            _ => ("", None),
+        };
+        let value = match (data_type, sign, lit) {
+            ("String", "", Some(syn::Lit::Str(s))) => {
+                format!("std::borrow::Cow::Borrowed({})", s.to_token_stream())
+            }
+            ("Bytes", "", Some(syn::Lit::ByteStr(s))) => {
+                format!("std::borrow::Cow::Borrowed({} as &[u8])", s.to_token_stream())
+            }
+            ("Int64", _, Some(syn::Lit::Int(int))) => format!("{}{}", sign, int.base10_digits()),
+            ("Float64", _, Some(syn::Lit::Int(int))) => {
+                format!("{}{}.0", sign, int.base10_digits())
+            }
+            ("Float64", _, Some(syn::Lit::Float(float))) => {
+                format!("{}{}", sign, float.base10_digits())
+            }
+            ("Bool", "", Some(syn::Lit::Bool(b))) => b.value.to_string(),
+            _ => {
+                let expected = match data_type {
+                    "String" => "a string",
+                    "Bytes" => "a byte string",
+                    "Int64" => "an integer",
+                    "Float64" => "a number",
+                    _ => "a bool",
+                };
+                return Err(syn::Error::new_spanned(
+                    expr,
+                    format!("default of {} must be {} literal", name, expected),
+                ));
+            }
+        };
+        Ok(value)
+    }
+    fn option_arg(ty: &syn::Type) -> Option<&syn::Type> {
+        let syn::Type::Path(path) = ty else {
+            return None;
+        };
+        let segment = path.path.segments.last()?;
+        if segment.ident != "Option" {
+            return None;
+        }
+        let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+            return None;
+        };
+        match args.args.first()? {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }
     }
     fn parse_type(t: &str) -> &'static str {
         match t {
@@ -67,6 +172,7 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
             "i64" => "Int64",
             "f64" => "Float64",
             "bool" => "Bool",
+            "Ref" => "Int64",
             _ => panic!("shit"),
         }
     }
@@ -97,16 +203,20 @@ impl Object for {0} {{
     struct_name,
     struct_name,
     table_name,
-    keys.iter().zip(types.iter().map(|x| parse_type(x))).zip(columns.iter()).map(|((name, t), column)| {
+    keys.iter().zip(types.iter()).zip(columns.iter()).zip(references.iter()).zip(defaults.iter()).map(|((((name, t), column), reference), default)| {
         format!("
             orm::object::FieldInfo {{
                 column_name: \"{0}\",
                 attr_name: \"{1}\",
                 data_type: orm::data::DataType::{2},
+                reference: {3},
+                default: {4},
             }},",
         column,
         name,
-        t
+        t,
+        reference,
+        default
         )
     }).collect::<Vec<String>>().join(""),
     keys.iter().map(|name| {
@@ -1,35 +1,36 @@
-use crate::{storage::StorageTransaction, Result, Transaction};
+use crate::{kv::KvStore, storage::StorageConnection, Result, Transaction};
 
 use std::path::Path;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-trait StorageConnection {
-    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>>;
-}
-
-impl StorageConnection for rusqlite::Connection {
-    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
-        Ok(Box::new(self.transaction()?))
-    }
-}
-
-////////////////////////////////////////////////////////////////////////////////
-
 pub struct Connection {
     inner: Box<dyn StorageConnection>,
 }
 
 impl Connection {
+    pub fn new(storage: Box<dyn StorageConnection>) -> Self {
+        Self { inner: storage }
+    }
+
     pub fn open_sqlite_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(Self {
-            inner: Box::new(rusqlite::Connection::open(path)?),
-        })
+        Self::from_sqlite(rusqlite::Connection::open(path)?)
     }
 
     pub fn open_in_memory() -> Result<Self> {
+        Self::from_sqlite(rusqlite::Connection::open_in_memory()?)
+    }
+
+    /// Opens a fresh in-process key-value store, see `KvStore`.
+    pub fn open_kv() -> Self {
+        Self::new(Box::new(KvStore::new()))
+    }
+
+    fn from_sqlite(conn: rusqlite::Connection) -> Result<Self> {
+        // NB: sqlite doesn't enforce foreign keys, which back `Ref` fields, unless asked to.
+        conn.pragma_update(None, "foreign_keys", true)?;
         Ok(Self {
-            inner: Box::new(rusqlite::Connection::open_in_memory()?),
+            inner: Box::new(conn),
         })
     }
 
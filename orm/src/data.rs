@@ -34,10 +34,27 @@ pub enum DataType {
     Int64,
     Float64,
     Bool,
+    /// Also admits NULL, which `Option` fields map to.
+    Nullable(&'static DataType),
+}
+
+impl DataType {
+    pub fn is_nullable(self) -> bool {
+        matches!(self, DataType::Nullable(_))
+    }
+
+    /// The type of non-null values.
+    pub fn non_null(self) -> DataType {
+        match self {
+            DataType::Nullable(data_type) => *data_type,
+            data_type => data_type,
+        }
+    }
 }
 
 pub fn datatype_to_sql(datatype: DataType) -> &'static str {
     match datatype {
+        DataType::Nullable(datatype) => datatype_to_sql(*datatype),
         DataType::String => "TEXT",
         DataType::Bytes => "BLOB",
         DataType::Int64 => "BIGINT",
@@ -46,15 +63,59 @@ pub fn datatype_to_sql(datatype: DataType) -> &'static str {
     }
 }
 
+pub fn sql_to_datatype(sql: &str) -> Option<DataType> {
+    match sql.to_ascii_uppercase().as_str() {
+        "TEXT" => Some(DataType::String),
+        "BLOB" => Some(DataType::Bytes),
+        "BIGINT" => Some(DataType::Int64),
+        "REAL" => Some(DataType::Float64),
+        "TINYINT" => Some(DataType::Bool),
+        _ => None,
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value<'a> {
     String(Cow<'a, str>),
     Bytes(Cow<'a, [u8]>),
     Int64(i64),
     Float64(f64),
     Bool(bool),
+    Null,
+}
+
+impl Value<'_> {
+    /// `None` for `Null`, which fits any nullable type.
+    pub fn data_type(&self) -> Option<DataType> {
+        match self {
+            Value::String(_) => Some(DataType::String),
+            Value::Bytes(_) => Some(DataType::Bytes),
+            Value::Int64(_) => Some(DataType::Int64),
+            Value::Float64(_) => Some(DataType::Float64),
+            Value::Bool(_) => Some(DataType::Bool),
+            Value::Null => None,
+        }
+    }
+
+    pub fn fits(&self, data_type: DataType) -> bool {
+        match self.data_type() {
+            Some(value_type) => value_type == data_type.non_null(),
+            None => data_type.is_nullable(),
+        }
+    }
+
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::String(s) => Value::String(Cow::Owned(s.into_owned())),
+            Value::Bytes(b) => Value::Bytes(Cow::Owned(b.into_owned())),
+            Value::Int64(i) => Value::Int64(i),
+            Value::Float64(f) => Value::Float64(f),
+            Value::Bool(b) => Value::Bool(b),
+            Value::Null => Value::Null,
+        }
+    }
 }
 
 impl ToSql for Value<'_> {
@@ -65,6 +126,7 @@ impl ToSql for Value<'_> {
             Value::Int64(i) => i.to_sql(),
             Value::Float64(f) => f.to_sql(),
             Value::Bool(b) => b.to_sql(),
+            Value::Null => rusqlite::types::Null.to_sql(),
         }
     }
 }
@@ -99,6 +161,54 @@ impl From<&bool> for Value<'_> {
     }
 }
 
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(s: &'a str) -> Self {
+        Value::String(Cow::from(s))
+    }
+}
+
+impl From<String> for Value<'_> {
+    fn from(s: String) -> Self {
+        Value::String(Cow::from(s))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value<'a> {
+    fn from(b: &'a [u8]) -> Self {
+        Value::Bytes(Cow::from(b))
+    }
+}
+
+impl From<Vec<u8>> for Value<'_> {
+    fn from(v: Vec<u8>) -> Self {
+        Value::Bytes(Cow::from(v))
+    }
+}
+
+impl From<i64> for Value<'_> {
+    fn from(v: i64) -> Self {
+        Value::Int64(v)
+    }
+}
+
+impl From<f64> for Value<'_> {
+    fn from(v: f64) -> Self {
+        Value::Float64(v)
+    }
+}
+
+impl From<bool> for Value<'_> {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+
+impl From<ObjectId> for Value<'_> {
+    fn from(id: ObjectId) -> Self {
+        Value::Int64(id.0)
+    }
+}
+
 impl From<&Value<'_>> for String {
     fn from(value: &Value<'_>) -> String {
         let Value::String(s) = value else {
@@ -143,3 +253,25 @@ impl From<&Value<'_>> for bool {
         *b
     }
 }
+
+/// Maps `None` to `Null` and back for fields of a nullable type.
+macro_rules! impl_nullable {
+    ($($t:ty),*) => {$(
+        impl<'a> From<&'a Option<$t>> for Value<'a> {
+            fn from(v: &'a Option<$t>) -> Self {
+                v.as_ref().map_or(Value::Null, Value::from)
+            }
+        }
+
+        impl From<&Value<'_>> for Option<$t> {
+            fn from(value: &Value<'_>) -> Option<$t> {
+                match value {
+                    Value::Null => None,
+                    value => Some(value.into()),
+                }
+            }
+        }
+    )*};
+}
+
+impl_nullable!(String, Vec<u8>, i64, f64, bool);
@@ -17,6 +17,16 @@ pub enum Error {
     UnexpectedType(Box<UnexpectedTypeError>),
     #[error(transparent)]
     MissingColumn(Box<MissingColumnError>),
+    #[error(transparent)]
+    UnknownField(Box<UnknownFieldError>),
+    #[error(transparent)]
+    FieldTypeMismatch(Box<FieldTypeMismatchError>),
+    #[error(transparent)]
+    NotAReference(Box<NotAReferenceError>),
+    #[error(transparent)]
+    Restricted(Box<RestrictedError>),
+    #[error(transparent)]
+    UnorderedMigrations(Box<UnorderedMigrationsError>),
     #[error("database is locked")]
     LockConflict,
     #[error("storage error: {0}")]
@@ -53,9 +63,13 @@ impl From<rusqlite::Error> for Error {
                 if let rusqlite::ErrorCode::DatabaseBusy = code.code {
                     return Error::LockConflict;
                 }
-                let msg = msg.unwrap();
-                let column_name = parse_column_name(&msg).unwrap();
-                let field = schema.find_field(column_name).unwrap();
+                let Some(field) = msg
+                    .as_deref()
+                    .and_then(parse_column_name)
+                    .and_then(|column_name| schema.find_field(column_name))
+                else {
+                    return Error::Storage(Box::new(rusqlite::Error::SqliteFailure(code, msg)));
+                };
                 Error::MissingColumn(Box::new(MissingColumnError {
                     type_name: schema.struct_name,
                     attr_name: field.attr_name,
@@ -97,7 +111,8 @@ pub struct NotFoundError {
 #[derive(Error, Debug)]
 #[error(
     "invalid type for {type_name}::{attr_name}: expected equivalent of {expected_type:?}, \
-    got {got_type} (table: {table_name}, column: {column_name})"
+    got {got_type}{} (table: {table_name}, column: {column_name})",
+    if .got_type == "Null" { ", but the field is not an Option" } else { "" }
 )]
 pub struct UnexpectedTypeError {
     pub type_name: &'static str,
@@ -124,4 +139,56 @@ pub struct MissingColumnError {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Error, Debug)]
+#[error("{type_name} has no field {attr_name}")]
+pub struct UnknownFieldError {
+    pub type_name: &'static str,
+    pub attr_name: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "cannot compare {type_name}::{attr_name} of type {expected_type:?} with {}",
+    .got_type.map_or("NULL".to_string(), |got_type| format!("a value of type {:?}", got_type))
+)]
+pub struct FieldTypeMismatchError {
+    pub type_name: &'static str,
+    pub attr_name: &'static str,
+    pub expected_type: DataType,
+    /// `None` for NULL.
+    pub got_type: Option<DataType>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("{type_name}::{attr_name} does not refer to {target_type}")]
+pub struct NotAReferenceError {
+    pub type_name: &'static str,
+    pub attr_name: &'static str,
+    pub target_type: &'static str,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("cannot delete object of type '{type_name}', id {object_id}: other objects refer to it")]
+pub struct RestrictedError {
+    pub object_id: ObjectId,
+    pub type_name: &'static str,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("migration versions must ascend: {version} comes after {previous_version}")]
+pub struct UnorderedMigrationsError {
+    pub previous_version: i64,
+    pub version: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub type Result<T> = std::result::Result<T, Error>;
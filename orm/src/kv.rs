@@ -0,0 +1,576 @@
+use crate::{
+    data::{DataType, ObjectId, Value},
+    error::*,
+    object::{FieldInfo, OnDelete, Reference, Schema},
+    query::{CompareOp, Selection},
+    storage::{ColumnInfo, Row, RowSlice, StorageConnection, StorageTransaction},
+};
+
+use std::{
+    cell::{Cell, RefCell, RefMut},
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use thiserror::Error;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct KvError(String);
+
+fn kv_error(msg: String) -> Error {
+    Error::Storage(Box::new(KvError(msg)))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug)]
+struct Column {
+    name: String,
+    data_type: DataType,
+    default: Option<Value<'static>>,
+    /// The target table and what happens when its row is deleted.
+    reference: Option<(String, OnDelete)>,
+}
+
+fn column_reference(reference: &Reference) -> (String, OnDelete) {
+    (
+        (reference.target)().table_name.to_string(),
+        reference.on_delete,
+    )
+}
+
+#[derive(Clone, Debug)]
+struct Table {
+    columns: Vec<Column>,
+    rows: BTreeMap<i64, Row<'static>>,
+    /// Ids are never reused, like with sqlite's AUTOINCREMENT.
+    last_id: i64,
+}
+
+impl Table {
+    fn column_index(&self, column_name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name == column_name)
+    }
+
+    fn field_index(&self, schema: &Schema, field: &'static FieldInfo) -> Result<usize> {
+        self.column_index(field.column_name).ok_or_else(|| {
+            Error::MissingColumn(Box::new(MissingColumnError {
+                type_name: schema.struct_name,
+                attr_name: field.attr_name,
+                table_name: schema.table_name,
+                column_name: field.column_name,
+            }))
+        })
+    }
+
+    /// Positions of the schema fields among the columns.
+    fn field_indices(&self, schema: &Schema) -> Result<Vec<usize>> {
+        schema
+            .fields
+            .iter()
+            .map(|field| self.field_index(schema, field))
+            .collect()
+    }
+
+    fn read_row(
+        &self,
+        schema: &Schema,
+        indices: &[usize],
+        values: &[Value<'static>],
+    ) -> Result<Row<'static>> {
+        schema
+            .fields
+            .iter()
+            .zip(indices)
+            .map(|(field, index)| {
+                let value = &values[*index];
+                if !value.fits(field.data_type) {
+                    return Err(Error::UnexpectedType(Box::new(UnexpectedTypeError {
+                        type_name: schema.struct_name,
+                        attr_name: field.attr_name,
+                        table_name: schema.table_name,
+                        column_name: field.column_name,
+                        expected_type: field.data_type,
+                        got_type: type_name(value).to_string(),
+                    })));
+                }
+                Ok(value.clone())
+            })
+            .collect()
+    }
+}
+
+/// Cloning is cheap: tables are shared until one of the clones changes them.
+#[derive(Clone, Debug, Default)]
+struct Database {
+    tables: BTreeMap<String, Arc<Table>>,
+}
+
+impl Database {
+    fn table(&self, table: &str) -> Result<&Table> {
+        self.tables
+            .get(table)
+            .map(Arc::as_ref)
+            .ok_or_else(|| kv_error(format!("no such table: {}", table)))
+    }
+
+    fn table_mut(&mut self, table: &str) -> Result<&mut Table> {
+        self.tables
+            .get_mut(table)
+            .map(Arc::make_mut)
+            .ok_or_else(|| kv_error(format!("no such table: {}", table)))
+    }
+
+    fn check_references(&self, table: &Table, values: &RowSlice) -> Result<()> {
+        for (column, value) in table.columns.iter().zip(values) {
+            let (Some((target, _)), Value::Int64(id)) = (&column.reference, value) else {
+                continue;
+            };
+            if !self.table(target)?.rows.contains_key(id) {
+                return Err(kv_error(format!(
+                    "{} refers to a missing row {} of {}",
+                    column.name, id, target
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// An in-process store. Every table is a map from ids to rows.
+///
+/// Clones are connections to the same data, which lives as long as any of them does.
+/// A transaction works on its own copy of the data, which replaces the original on commit.
+/// Only the tables it changes are actually copied.
+///
+/// Like with sqlite, one transaction at a time may write, and only if nothing was committed
+/// since it started. Others get `Error::LockConflict`.
+#[derive(Clone, Default)]
+pub struct KvStore {
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    database: Database,
+    /// Number of commits so far.
+    version: u64,
+    /// Whether a transaction has started writing.
+    writing: bool,
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().expect("kv store lock is poisoned")
+}
+
+impl StorageConnection for KvStore {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let shared = lock(&self.shared);
+        Ok(Box::new(KvTransaction {
+            shared: self.shared.clone(),
+            working: RefCell::new(shared.database.clone()),
+            version: shared.version,
+            writing: Cell::new(false),
+        }))
+    }
+}
+
+struct KvTransaction {
+    shared: Arc<Mutex<Shared>>,
+    working: RefCell<Database>,
+    /// The version the working copy was taken at.
+    version: u64,
+    writing: Cell<bool>,
+}
+
+impl KvTransaction {
+    /// The working copy, to be changed. Takes the write lock on the first change.
+    fn write(&self) -> Result<RefMut<'_, Database>> {
+        if !self.writing.get() {
+            let mut shared = lock(&self.shared);
+            if shared.writing || shared.version != self.version {
+                return Err(Error::LockConflict);
+            }
+            shared.writing = true;
+            self.writing.set(true);
+        }
+        Ok(self.working.borrow_mut())
+    }
+
+    fn release(&self) {
+        if self.writing.replace(false) {
+            lock(&self.shared).writing = false;
+        }
+    }
+}
+
+impl Drop for KvTransaction {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl StorageTransaction for KvTransaction {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        Ok(self.working.borrow().tables.contains_key(table))
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let mut db = self.write()?;
+        if db.tables.contains_key(schema.table_name) {
+            return Err(kv_error(format!(
+                "table {} already exists",
+                schema.table_name
+            )));
+        }
+        let columns = schema
+            .fields
+            .iter()
+            .map(|field| Column {
+                name: field.column_name.to_string(),
+                data_type: field.data_type,
+                default: field.default.clone(),
+                reference: field.reference.as_ref().map(column_reference),
+            })
+            .collect();
+        db.tables.insert(
+            schema.table_name.to_string(),
+            Arc::new(Table {
+                columns,
+                rows: BTreeMap::new(),
+                last_id: 0,
+            }),
+        );
+        Ok(())
+    }
+
+    fn table_columns(&self, table: &str) -> Result<Vec<ColumnInfo>> {
+        Ok(self
+            .working
+            .borrow()
+            .table(table)?
+            .columns
+            .iter()
+            .map(|column| ColumnInfo {
+                name: column.name.clone(),
+                data_type: Some(column.data_type.non_null()),
+            })
+            .collect())
+    }
+
+    fn add_column(
+        &self,
+        table: &str,
+        column: &str,
+        data_type: DataType,
+        reference: Option<&Reference>,
+        default: Option<&Value>,
+    ) -> Result<()> {
+        let mut db = self.write()?;
+        let table = db.table_mut(table)?;
+        if table.column_index(column).is_some() {
+            return Err(kv_error(format!("duplicate column name: {}", column)));
+        }
+        let default = default.map(|default| default.clone().into_owned());
+        for values in table.rows.values_mut() {
+            values.push(default.clone().unwrap_or(Value::Null));
+        }
+        table.columns.push(Column {
+            name: column.to_string(),
+            data_type,
+            default,
+            reference: reference.map(column_reference),
+        });
+        Ok(())
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        let mut db = self.write()?;
+        let table = db.table_mut(table)?;
+        if table.column_index(to).is_some() {
+            return Err(kv_error(format!("duplicate column name: {}", to)));
+        }
+        let index = table
+            .column_index(from)
+            .ok_or_else(|| kv_error(format!("no such column: {}", from)))?;
+        table.columns[index].name = to.to_string();
+        Ok(())
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        let mut db = self.write()?;
+        let table = db.table_mut(table)?;
+        let index = table
+            .column_index(column)
+            .ok_or_else(|| kv_error(format!("no such column: {}", column)))?;
+        table.columns.remove(index);
+        for values in table.rows.values_mut() {
+            values.remove(index);
+        }
+        Ok(())
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        let mut db = self.write()?;
+        if db.tables.contains_key(to) {
+            return Err(kv_error(format!("table {} already exists", to)));
+        }
+        let table = db
+            .tables
+            .remove(from)
+            .ok_or_else(|| kv_error(format!("no such table: {}", from)))?;
+        db.tables.insert(to.to_string(), table);
+        for table in db.tables.values_mut() {
+            let refers =
+                |column: &Column| matches!(&column.reference, Some((target, _)) if target == from);
+            if !table.columns.iter().any(refers) {
+                continue;
+            }
+            for column in Arc::make_mut(table).columns.iter_mut() {
+                if let Some((target, _)) = column.reference.as_mut() {
+                    if target == from {
+                        *target = to.to_string();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        let mut db = self.write()?;
+        let table = db.table(schema.table_name)?;
+        let indices = table.field_indices(schema)?;
+        let mut values: Row<'static> = table
+            .columns
+            .iter()
+            .map(|column| column.default.clone().unwrap_or(Value::Null))
+            .collect();
+        for (index, value) in indices.iter().zip(row) {
+            values[*index] = value.clone().into_owned();
+        }
+        db.check_references(table, &values)?;
+
+        let table = db.table_mut(schema.table_name)?;
+        table.last_id += 1;
+        table.rows.insert(table.last_id, values);
+        Ok(ObjectId(table.last_id))
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        let mut db = self.write()?;
+        let table = db.table(schema.table_name)?;
+        let indices = table.field_indices(schema)?;
+        let Some(mut values) = table.rows.get(&id.0).cloned() else {
+            return Ok(());
+        };
+        for (index, value) in indices.iter().zip(row) {
+            values[*index] = value.clone().into_owned();
+        }
+        db.check_references(table, &values)?;
+
+        db.table_mut(schema.table_name)?.rows.insert(id.0, values);
+        Ok(())
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let db = self.working.borrow();
+        let table = db.table(schema.table_name)?;
+        let indices = table.field_indices(schema)?;
+        let values = table.rows.get(&id.0).ok_or_else(|| {
+            Error::NotFound(Box::new(NotFoundError {
+                object_id: id,
+                type_name: schema.struct_name,
+            }))
+        })?;
+        table.read_row(schema, &indices, values)
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        selection: &Selection,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let db = self.working.borrow();
+        let table = db.table(schema.table_name)?;
+        let indices = table.field_indices(schema)?;
+        let filters = selection
+            .filters
+            .iter()
+            .map(|(field, op, value)| Ok((table.field_index(schema, field)?, *op, value)))
+            .collect::<Result<Vec<_>>>()?;
+        let order = selection
+            .order
+            .iter()
+            .map(|(field, descending)| Ok((table.field_index(schema, field)?, *descending)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut rows: Vec<_> = table
+            .rows
+            .iter()
+            .filter(|(_, values)| {
+                filters
+                    .iter()
+                    .all(|(index, op, value)| satisfies(&values[*index], *op, value))
+            })
+            .collect();
+        // NB: the sort is stable, so equal rows stay in the order of ids.
+        rows.sort_by(|(_, lhs), (_, rhs)| {
+            order
+                .iter()
+                .map(|(index, descending)| {
+                    let ordering = order_values(&lhs[*index], &rhs[*index]);
+                    if *descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+
+        rows.into_iter()
+            .skip(usize::try_from(selection.offset).unwrap_or(usize::MAX))
+            .take(selection.limit.map_or(usize::MAX, |limit| {
+                usize::try_from(limit).unwrap_or(usize::MAX)
+            }))
+            .map(|(id, values)| Ok((ObjectId(*id), table.read_row(schema, &indices, values)?)))
+            .collect()
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        let mut db = self.write()?;
+        db.table(schema.table_name)?;
+
+        // NB: references may form cycles, so every row is queued at most once.
+        let mut queued = BTreeSet::from([(schema.table_name.to_string(), id.0)]);
+        let mut deleted = BTreeSet::new();
+        let mut restricting = vec![];
+        let mut pending = vec![(schema.table_name.to_string(), id.0)];
+        while let Some((target, target_id)) = pending.pop() {
+            for (table_name, table) in db.tables.iter() {
+                for (index, column) in table.columns.iter().enumerate() {
+                    let Some((column_target, on_delete)) = &column.reference else {
+                        continue;
+                    };
+                    if *column_target != target {
+                        continue;
+                    }
+                    for (row_id, values) in table.rows.iter() {
+                        if values[index] != Value::Int64(target_id) {
+                            continue;
+                        }
+                        let key = (table_name.clone(), *row_id);
+                        match on_delete {
+                            OnDelete::Cascade => {
+                                if queued.insert(key.clone()) {
+                                    pending.push(key);
+                                }
+                            }
+                            OnDelete::Restrict => restricting.push(key),
+                        }
+                    }
+                }
+            }
+            deleted.insert((target, target_id));
+        }
+        if restricting.iter().any(|key| !deleted.contains(key)) {
+            return Err(Error::Restricted(Box::new(RestrictedError {
+                object_id: id,
+                type_name: schema.struct_name,
+            })));
+        }
+
+        for (table_name, row_id) in deleted {
+            db.table_mut(&table_name)?.rows.remove(&row_id);
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        if self.writing.replace(false) {
+            let mut shared = lock(&self.shared);
+            shared.database = self.working.take();
+            shared.version += 1;
+            shared.writing = false;
+        }
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.release();
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Names as sqlite reports them in `UnexpectedTypeError`.
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "Text",
+        Value::Bytes(_) => "Blob",
+        Value::Int64(_) | Value::Bool(_) => "Integer",
+        Value::Float64(_) => "Real",
+        Value::Null => "Null",
+    }
+}
+
+/// Bools are integers to sqlite.
+fn as_integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int64(value) => Some(*value),
+        Value::Bool(value) => Some(*value as i64),
+        _ => None,
+    }
+}
+
+/// Compares values like sqlite does, numbers by value whatever their type. `None` for
+/// NULL or values of different kinds.
+fn compare_values(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Value::String(lhs), Value::String(rhs)) => Some(lhs.cmp(rhs)),
+        (Value::Bytes(lhs), Value::Bytes(rhs)) => Some(lhs.cmp(rhs)),
+        (Value::Float64(lhs), Value::Float64(rhs)) => lhs.partial_cmp(rhs),
+        (Value::Float64(lhs), rhs) => lhs.partial_cmp(&(as_integer(rhs)? as f64)),
+        (lhs, Value::Float64(rhs)) => (as_integer(lhs)? as f64).partial_cmp(rhs),
+        _ => Some(as_integer(lhs)?.cmp(&as_integer(rhs)?)),
+    }
+}
+
+/// Same as sqlite's `IS`, `IS NOT` and comparisons, see `CompareOp::to_sql`.
+fn satisfies(lhs: &Value, op: CompareOp, rhs: &Value) -> bool {
+    let ordering = compare_values(lhs, rhs);
+    match op {
+        CompareOp::Eq => ordering.is_some_and(Ordering::is_eq) || lhs == rhs,
+        CompareOp::Ne => !(ordering.is_some_and(Ordering::is_eq) || lhs == rhs),
+        CompareOp::Lt => ordering.is_some_and(Ordering::is_lt),
+        CompareOp::Le => ordering.is_some_and(Ordering::is_le),
+        CompareOp::Gt => ordering.is_some_and(Ordering::is_gt),
+        CompareOp::Ge => ordering.is_some_and(Ordering::is_ge),
+    }
+}
+
+/// NULL goes first, like in sqlite.
+fn order_values(lhs: &Value, rhs: &Value) -> Ordering {
+    match (lhs, rhs) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        _ => compare_values(lhs, rhs).unwrap_or(Ordering::Equal),
+    }
+}
@@ -2,16 +2,22 @@
 
 mod connection;
 mod error;
+mod reference;
 mod transaction;
 
 pub mod data;
+pub mod kv;
+pub mod migration;
 pub mod object;
+pub mod query;
 pub mod storage;
 
 pub use connection::Connection;
 pub use data::ObjectId;
 pub use error::{Error, Result};
 pub use object::Object;
+pub use query::{field, Query};
+pub use reference::Ref;
 pub use transaction::{ObjectState, Transaction, Tx};
 
 pub use orm_derive::Object;
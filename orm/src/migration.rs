@@ -0,0 +1,128 @@
+use crate::{
+    data::{DataType, Value},
+    object::{FieldInfo, Object, Reference, Schema},
+    storage::{ColumnInfo, Row, RowSlice},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// How a live table differs from a schema, see `Transaction::schema_diff`.
+#[derive(Debug, Default)]
+pub struct SchemaDiff {
+    /// Fields without a column.
+    pub missing: Vec<&'static FieldInfo>,
+    /// Fields whose column is of another type.
+    pub mismatched: Vec<(&'static FieldInfo, ColumnInfo)>,
+    /// Columns without a field. These are left alone, reading and writing objects ignores them.
+    pub extra: Vec<ColumnInfo>,
+}
+
+impl SchemaDiff {
+    pub fn new(schema: &Schema, columns: Vec<ColumnInfo>) -> Self {
+        let mut diff = Self::default();
+        for field in schema.fields {
+            match columns
+                .iter()
+                .find(|column| column.name == field.column_name)
+            {
+                None => diff.missing.push(field),
+                Some(column) if column.data_type != Some(field.data_type.non_null()) => {
+                    diff.mismatched.push((field, column.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        diff.extra = columns
+            .into_iter()
+            .filter(|column| schema.find_field(&column.name).is_none())
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.extra.is_empty()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A change of a live table.
+#[derive(Debug)]
+pub enum Step {
+    AddColumn {
+        table_name: &'static str,
+        column_name: &'static str,
+        data_type: DataType,
+        /// Makes the column refer to another table, like the column of a `Ref` field.
+        reference: Option<Reference>,
+        /// Fills the existing rows, and becomes the column default.
+        default: Option<Value<'static>>,
+    },
+    RenameColumn {
+        table_name: &'static str,
+        from: &'static str,
+        to: &'static str,
+    },
+    DropColumn {
+        table_name: &'static str,
+        column_name: &'static str,
+    },
+    RenameTable {
+        from: &'static str,
+        to: &'static str,
+    },
+}
+
+/// Changes that can't be made automatically, see `Transaction::migrate`.
+///
+/// Missing columns of nullable fields and fields with a default are added as soon as
+/// a transaction touches their table, so migrations are only needed for anything else.
+#[derive(Debug)]
+pub struct Migration {
+    /// Migrations are applied in the order of versions, each at most once.
+    pub version: i64,
+    pub description: &'static str,
+    pub steps: Vec<Step>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A row of the metadata table, which records the applied migrations.
+pub(crate) struct AppliedMigration {
+    pub version: i64,
+    pub description: String,
+}
+
+impl Object for AppliedMigration {
+    const SCHEMA: Schema = Schema {
+        struct_name: "AppliedMigration",
+        table_name: "orm_migrations",
+        fields: &[
+            FieldInfo {
+                column_name: "version",
+                attr_name: "version",
+                data_type: DataType::Int64,
+                reference: None,
+                default: None,
+            },
+            FieldInfo {
+                column_name: "description",
+                attr_name: "description",
+                data_type: DataType::String,
+                reference: None,
+                default: None,
+            },
+        ],
+    };
+
+    fn to_row(&self) -> Row<'_> {
+        vec![Value::from(&self.version), Value::from(&self.description)]
+    }
+
+    fn from_row(row: &RowSlice) -> Self {
+        Self {
+            version: (&row[0]).into(),
+            description: (&row[1]).into(),
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    data::{DataType, ObjectId},
+    data::{DataType, ObjectId, Value},
     storage::Row,
     storage::RowSlice,
 };
@@ -23,6 +23,28 @@ pub struct FieldInfo {
     pub column_name: &'static str,
     pub attr_name: &'static str,
     pub data_type: DataType,
+    /// Set for `Ref` fields, which are stored as ids of the target objects.
+    pub reference: Option<Reference>,
+    /// Given to rows created without this column: by the column default of the table,
+    /// to the existing rows when the column is added to a live table, and by
+    /// `Transaction::create` in place of `None`.
+    pub default: Option<Value<'static>>,
+}
+
+/// What happens to an object when the object it refers to is deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDelete {
+    /// Delete the referring object as well.
+    Cascade,
+    /// Fail to delete the target while anything refers to it.
+    Restrict,
+}
+
+#[derive(Debug)]
+pub struct Reference {
+    /// A function rather than the schema itself, since an object may refer to its own type.
+    pub target: fn() -> &'static Schema,
+    pub on_delete: OnDelete,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -38,6 +60,16 @@ impl Schema {
             .iter()
             .find(|&field| field.column_name == column_name)
     }
+
+    pub fn find_attr(&self, attr_name: &str) -> Option<&'static FieldInfo> {
+        self.fields
+            .iter()
+            .find(|&field| field.attr_name == attr_name)
+    }
+}
+
+pub fn schema_of<T: Object>() -> &'static Schema {
+    &T::SCHEMA
 }
 
 thread_local!(
@@ -0,0 +1,212 @@
+use crate::{
+    data::Value,
+    error::{Error, FieldTypeMismatchError, NotAReferenceError, Result, UnknownFieldError},
+    object::{FieldInfo, Object, Schema},
+    transaction::{Transaction, Tx},
+};
+
+use std::marker::PhantomData;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Names a field of an object by its attribute name, not by its column name.
+#[derive(Clone, Copy, Debug)]
+pub struct Field(&'static str);
+
+pub fn field(attr_name: &'static str) -> Field {
+    Field(attr_name)
+}
+
+impl Field {
+    pub fn eq<'v>(self, value: impl Into<Value<'v>>) -> Condition {
+        self.compare(CompareOp::Eq, value.into())
+    }
+
+    pub fn ne<'v>(self, value: impl Into<Value<'v>>) -> Condition {
+        self.compare(CompareOp::Ne, value.into())
+    }
+
+    pub fn lt<'v>(self, value: impl Into<Value<'v>>) -> Condition {
+        self.compare(CompareOp::Lt, value.into())
+    }
+
+    pub fn le<'v>(self, value: impl Into<Value<'v>>) -> Condition {
+        self.compare(CompareOp::Le, value.into())
+    }
+
+    pub fn gt<'v>(self, value: impl Into<Value<'v>>) -> Condition {
+        self.compare(CompareOp::Gt, value.into())
+    }
+
+    pub fn ge<'v>(self, value: impl Into<Value<'v>>) -> Condition {
+        self.compare(CompareOp::Ge, value.into())
+    }
+
+    /// Holds for objects whose `Ref` field points at `target`.
+    pub fn refers_to<U: Object>(self, target: &Tx<'_, U>) -> Condition {
+        Condition {
+            target: Some(&U::SCHEMA),
+            ..self.eq(target.id())
+        }
+    }
+
+    fn compare(self, op: CompareOp, value: Value<'_>) -> Condition {
+        Condition {
+            attr_name: self.0,
+            op,
+            value: value.into_owned(),
+            target: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    pub fn to_sql(self) -> &'static str {
+        match self {
+            // NB: unlike `=` and `<>`, these treat NULL as a value, just like `Option` does.
+            // Other backends must compare the same way.
+            CompareOp::Eq => "IS",
+            CompareOp::Ne => "IS NOT",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Condition {
+    attr_name: &'static str,
+    op: CompareOp,
+    value: Value<'static>,
+    /// The type the field must refer to, if any.
+    target: Option<&'static Schema>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A query checked against the schema, as passed to the storage.
+#[derive(Debug)]
+pub struct Selection {
+    pub filters: Vec<(&'static FieldInfo, CompareOp, Value<'static>)>,
+    /// Fields to order by, with `true` for the descending order.
+    pub order: Vec<(&'static FieldInfo, bool)>,
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+/// Selects objects of type `T` within a transaction, see `Transaction::query`.
+///
+/// All conditions must hold for an object to be selected. Names and value types are
+/// checked against `T::SCHEMA` once the query is fetched.
+pub struct Query<'t, 'a, T> {
+    tx: &'t Transaction<'a>,
+    conditions: Vec<Condition>,
+    order: Vec<(Field, bool)>,
+    limit: Option<u64>,
+    offset: u64,
+    holder: PhantomData<T>,
+}
+
+impl<'t, 'a, T: Object> Query<'t, 'a, T> {
+    pub(crate) fn new(tx: &'t Transaction<'a>) -> Self {
+        Self {
+            tx,
+            conditions: vec![],
+            order: vec![],
+            limit: None,
+            offset: 0,
+            holder: PhantomData,
+        }
+    }
+
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Orders by `field` ascending. Later calls break ties of the earlier ones.
+    pub fn order_by(mut self, field: Field) -> Self {
+        self.order.push((field, false));
+        self
+    }
+
+    pub fn order_by_desc(mut self, field: Field) -> Self {
+        self.order.push((field, true));
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Returns the selected objects. Objects already loaded into the transaction are
+    /// shared with it, including changes not yet committed.
+    pub fn fetch(self) -> Result<Vec<Tx<'t, T>>> {
+        let schema = &T::SCHEMA;
+        let mut filters = vec![];
+        for condition in self.conditions {
+            let field = find_attr(schema, condition.attr_name)?;
+            if let Some(target) = condition.target {
+                let refers = field
+                    .reference
+                    .as_ref()
+                    .is_some_and(|reference| (reference.target)().table_name == target.table_name);
+                if !refers {
+                    return Err(Error::NotAReference(Box::new(NotAReferenceError {
+                        type_name: schema.struct_name,
+                        attr_name: field.attr_name,
+                        target_type: target.struct_name,
+                    })));
+                }
+            }
+            if !condition.value.fits(field.data_type) {
+                return Err(Error::FieldTypeMismatch(Box::new(FieldTypeMismatchError {
+                    type_name: schema.struct_name,
+                    attr_name: field.attr_name,
+                    expected_type: field.data_type,
+                    got_type: condition.value.data_type(),
+                })));
+            }
+            filters.push((field, condition.op, condition.value));
+        }
+        let order = self
+            .order
+            .into_iter()
+            .map(|(field, descending)| Ok((find_attr(schema, field.0)?, descending)))
+            .collect::<Result<_>>()?;
+
+        self.tx.select(&Selection {
+            filters,
+            order,
+            limit: self.limit,
+            offset: self.offset,
+        })
+    }
+}
+
+fn find_attr(schema: &Schema, attr_name: &str) -> Result<&'static FieldInfo> {
+    schema.find_attr(attr_name).ok_or_else(|| {
+        Error::UnknownField(Box::new(UnknownFieldError {
+            type_name: schema.struct_name,
+            attr_name: attr_name.to_string(),
+        }))
+    })
+}
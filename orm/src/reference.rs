@@ -0,0 +1,93 @@
+use crate::{
+    data::{ObjectId, Value},
+    error::Result,
+    object::Object,
+    transaction::{Transaction, Tx},
+};
+
+use std::{fmt, marker::PhantomData};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A field referring to an object of type `T`, stored as a foreign key column.
+///
+/// The target is not loaded along with the referring object, see `get`.
+pub struct Ref<T> {
+    id: ObjectId,
+    holder: PhantomData<fn() -> T>,
+}
+
+impl<T> Ref<T> {
+    pub fn new(id: ObjectId) -> Self {
+        Self {
+            id,
+            holder: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> ObjectId {
+        self.id
+    }
+}
+
+impl<T: Object> Ref<T> {
+    /// Loads the target within `tx`, sharing it with the rest of the transaction.
+    pub fn get<'t>(&self, tx: &'t Transaction<'_>) -> Result<Tx<'t, T>> {
+        tx.get(self.id)
+    }
+}
+
+impl<T> Clone for Ref<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Ref<T> {}
+
+impl<T> PartialEq for Ref<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for Ref<T> {}
+
+impl<T> fmt::Debug for Ref<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ref{}", self.id)
+    }
+}
+
+impl<T: Object> From<&Tx<'_, T>> for Ref<T> {
+    fn from(tx: &Tx<'_, T>) -> Self {
+        Self::new(tx.id())
+    }
+}
+
+impl<T> From<&Ref<T>> for Value<'_> {
+    fn from(r: &Ref<T>) -> Self {
+        Value::Int64(r.id.0)
+    }
+}
+
+impl<T> From<&Value<'_>> for Ref<T> {
+    fn from(value: &Value<'_>) -> Self {
+        Self::new(ObjectId(i64::from(value)))
+    }
+}
+
+impl<T> From<&Option<Ref<T>>> for Value<'_> {
+    fn from(r: &Option<Ref<T>>) -> Self {
+        r.as_ref().map_or(Value::Null, Value::from)
+    }
+}
+
+impl<T> From<&Value<'_>> for Option<Ref<T>> {
+    fn from(value: &Value<'_>) -> Self {
+        match value {
+            Value::Null => None,
+            value => Some(value.into()),
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use crate::{
-    data::{datatype_to_sql, DataType, Value},
-    error::Result,
-    object::Schema,
+    data::{datatype_to_sql, sql_to_datatype, DataType, Value},
+    error::{Error, RestrictedError, Result},
+    object::{OnDelete, Reference, Schema},
+    query::Selection,
     ObjectId,
 };
 
 use rusqlite::params;
+use rusqlite::types::ValueRef;
 use rusqlite::ToSql;
 
 use std::borrow::Cow;
@@ -21,19 +23,72 @@ pub fn row_to_params<'a>(row: &'a RowSlice<'a>) -> Vec<&'a dyn ToSql> {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub(crate) trait StorageTransaction {
+/// A column of a live table, as opposed to a field of a schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// `None` if the column type doesn't correspond to any `DataType`.
+    pub data_type: Option<DataType>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A backend of `Connection`, see `Connection::new`.
+pub trait StorageConnection {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>>;
+}
+
+/// A transaction of a backend. Changes are visible within the transaction right away,
+/// and to anyone else once committed. A transaction dropped without commit is rolled back.
+///
+/// Backends report the same errors as sqlite does through `Error`:
+/// - `NotFound` from `select_row` for a missing id;
+/// - `MissingColumn` for a schema field the table has no column for;
+/// - `UnexpectedType` for a value that doesn't fit the field type, NULL included;
+/// - `Restricted` from `delete_row` while `OnDelete::Restrict` references remain.
+///
+/// Anything else, a dangling reference for one, is `Storage`.
+pub trait StorageTransaction {
     fn table_exists(&self, table: &str) -> Result<bool>;
     fn create_table(&self, schema: &Schema) -> Result<()>;
+    /// Columns besides the id, in their order in the table.
+    fn table_columns(&self, table: &str) -> Result<Vec<ColumnInfo>>;
+
+    /// Adds a column, which existing rows and rows inserted without it get `default` for.
+    fn add_column(
+        &self,
+        table: &str,
+        column: &str,
+        data_type: DataType,
+        reference: Option<&Reference>,
+        default: Option<&Value>,
+    ) -> Result<()>;
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()>;
+    fn drop_column(&self, table: &str, column: &str) -> Result<()>;
+    fn rename_table(&self, from: &str, to: &str) -> Result<()>;
 
     fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId>;
     fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()>;
     fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>>;
+    /// Rows in the order of `selection`, and in any order among equal ones.
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        selection: &Selection,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>>;
+    /// Deletes the row along with the ones referring to it by `OnDelete::Cascade`.
     fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()>;
 
     fn commit(&self) -> Result<()>;
     fn rollback(&self) -> Result<()>;
 }
 
+impl StorageConnection for rusqlite::Connection {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(self.transaction()?))
+    }
+}
+
 impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
     fn table_exists(&self, table: &str) -> Result<bool> {
         let mut stmt = self.prepare("SELECT 1 FROM sqlite_master WHERE name == (?1)")?;
@@ -55,9 +110,19 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
                 .iter()
                 .map(|field| {
                     format!(
-                        ", {0} {1}",
+                        ", {0} {1}{2}{3}",
                         field.column_name,
-                        datatype_to_sql(field.data_type)
+                        datatype_to_sql(field.data_type),
+                        field
+                            .reference
+                            .as_ref()
+                            .map(reference_to_sql)
+                            .unwrap_or_default(),
+                        field
+                            .default
+                            .as_ref()
+                            .map(|default| format!(" DEFAULT {}", value_to_sql(default)))
+                            .unwrap_or_default()
                     )
                 })
                 .collect::<String>()
@@ -66,6 +131,60 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
         Ok(())
     }
 
+    fn table_columns(&self, table: &str) -> Result<Vec<ColumnInfo>> {
+        let mut stmt =
+            self.prepare("SELECT name, type FROM pragma_table_info(?1) WHERE name <> 'id'")?;
+        let columns = stmt.query_map(params![table], |row| {
+            let data_type: String = row.get(1)?;
+            Ok(ColumnInfo {
+                name: row.get(0)?,
+                data_type: sql_to_datatype(&data_type),
+            })
+        })?;
+        Ok(columns.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn add_column(
+        &self,
+        table: &str,
+        column: &str,
+        data_type: DataType,
+        reference: Option<&Reference>,
+        default: Option<&Value>,
+    ) -> Result<()> {
+        // NB: existing rows read the column default, so there is nothing to update.
+        let query = format!(
+            "ALTER TABLE {0} ADD COLUMN {1} {2}{3}{4}",
+            table,
+            column,
+            datatype_to_sql(data_type),
+            reference.map(reference_to_sql).unwrap_or_default(),
+            default
+                .map(|default| format!(" DEFAULT {}", value_to_sql(default)))
+                .unwrap_or_default()
+        );
+        self.execute(&query, params![])?;
+        Ok(())
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        let query = format!("ALTER TABLE {0} RENAME COLUMN {1} TO {2}", table, from, to);
+        self.execute(&query, params![])?;
+        Ok(())
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        let query = format!("ALTER TABLE {0} DROP COLUMN {1}", table, column);
+        self.execute(&query, params![])?;
+        Ok(())
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        let query = format!("ALTER TABLE {0} RENAME TO {1}", from, to);
+        self.execute(&query, params![])?;
+        Ok(())
+    }
+
     fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
         let query = match schema.fields.len() {
             0 => format!("INSERT INTO {0} DEFAULT VALUES", schema.table_name),
@@ -124,36 +243,86 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
             ),
         };
         let mut stmt = self.prepare(&query)?;
-        let pull = stmt.query_row(
-            params![],
-            |row: &rusqlite::Row<'_>| -> rusqlite::Result<Row<'static>> {
-                schema
-                    .fields
-                    .iter()
-                    .enumerate()
-                    .map(|(i, field)| match field.data_type {
-                        DataType::String => {
-                            let s: String = row.get(i)?;
-                            Ok(Value::String(Cow::from(s)))
-                        }
-                        DataType::Bytes => {
-                            let v: Vec<u8> = row.get(i)?;
-                            Ok(Value::Bytes(Cow::from(v)))
-                        }
-                        DataType::Int64 => Ok(Value::Int64(row.get(i)?)),
-                        DataType::Float64 => Ok(Value::Float64(row.get(i)?)),
-                        DataType::Bool => Ok(Value::Bool(row.get(i)?)),
-                    })
-                    .collect()
-            },
-        );
+        let pull = stmt.query_row(params![], |row| read_row(schema, row, 0));
         Ok(pull?)
     }
 
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        selection: &Selection,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let mut query = format!(
+            "SELECT id{0} FROM {1}",
+            schema
+                .fields
+                .iter()
+                .map(|field| format!(", {0}", field.column_name))
+                .collect::<String>(),
+            schema.table_name
+        );
+        if !selection.filters.is_empty() {
+            query += " WHERE ";
+            query += &selection
+                .filters
+                .iter()
+                .map(|(field, op, _)| format!("{0} {1} ?", field.column_name, op.to_sql()))
+                .collect::<Vec<String>>()
+                .join(" AND ");
+        }
+        if !selection.order.is_empty() {
+            query += " ORDER BY ";
+            query += &selection
+                .order
+                .iter()
+                .map(|(field, descending)| {
+                    format!(
+                        "{0} {1}",
+                        field.column_name,
+                        if *descending { "DESC" } else { "ASC" }
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+        }
+        // NB: sqlite allows OFFSET only after LIMIT, where -1 means no limit.
+        query += &format!(
+            " LIMIT {0} OFFSET {1}",
+            selection
+                .limit
+                .map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX)),
+            i64::try_from(selection.offset).unwrap_or(i64::MAX)
+        );
+
+        let mut stmt = self.prepare(&query)?;
+        let content: Vec<&dyn ToSql> = selection
+            .filters
+            .iter()
+            .map(|(_, _, value)| value as &dyn ToSql)
+            .collect();
+        let rows = stmt.query_map(&*content, |row| {
+            Ok((ObjectId(row.get(0)?), read_row(schema, row, 1)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
     fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
         let query = format!("DELETE FROM {0} WHERE id == {1}", schema.table_name, id.0);
-        self.execute(&query, params![])?;
-        Ok(())
+        match self.execute(&query, params![]) {
+            // NB: foreign keys are the only constraints a deletion can violate.
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(Error::Restricted(Box::new(RestrictedError {
+                    object_id: id,
+                    type_name: schema.struct_name,
+                })))
+            }
+            res => {
+                res?;
+                Ok(())
+            }
+        }
     }
 
     fn commit(&self) -> Result<()> {
@@ -166,3 +335,69 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
         Ok(())
     }
 }
+
+fn reference_to_sql(reference: &Reference) -> String {
+    format!(
+        " REFERENCES {0}(id) ON DELETE {1}",
+        (reference.target)().table_name,
+        match reference.on_delete {
+            OnDelete::Cascade => "CASCADE",
+            OnDelete::Restrict => "RESTRICT",
+        }
+    )
+}
+
+/// Reads the fields of `schema` from the columns starting at `first`.
+fn read_row(
+    schema: &Schema,
+    row: &rusqlite::Row<'_>,
+    first: usize,
+) -> rusqlite::Result<Row<'static>> {
+    schema
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| read_value(row, first + i, field.data_type))
+        .collect()
+}
+
+fn read_value(
+    row: &rusqlite::Row<'_>,
+    index: usize,
+    data_type: DataType,
+) -> rusqlite::Result<Value<'static>> {
+    match data_type {
+        DataType::String => {
+            let s: String = row.get(index)?;
+            Ok(Value::String(Cow::from(s)))
+        }
+        DataType::Bytes => {
+            let v: Vec<u8> = row.get(index)?;
+            Ok(Value::Bytes(Cow::from(v)))
+        }
+        DataType::Int64 => Ok(Value::Int64(row.get(index)?)),
+        DataType::Float64 => Ok(Value::Float64(row.get(index)?)),
+        DataType::Bool => Ok(Value::Bool(row.get(index)?)),
+        DataType::Nullable(data_type) => match row.get_ref(index)? {
+            ValueRef::Null => Ok(Value::Null),
+            _ => read_value(row, index, *data_type),
+        },
+    }
+}
+
+/// Renders a constant for DDL, where parameters aren't allowed.
+fn value_to_sql(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Bytes(b) => format!(
+            "X'{}'",
+            b.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        ),
+        Value::Int64(i) => i.to_string(),
+        Value::Float64(f) => format!("{:?}", f),
+        Value::Bool(b) => (*b as i64).to_string(),
+        Value::Null => "NULL".to_string(),
+    }
+}
@@ -1,14 +1,16 @@
 use crate::{
-    data::ObjectId,
+    data::{ObjectId, Value},
     error::*,
-    object::{fetch_id, fetch_schema, Object, Schema, Store},
-    storage::StorageTransaction,
+    migration::{AppliedMigration, Migration, SchemaDiff, Step},
+    object::{fetch_id, fetch_schema, Object, OnDelete, Schema, Store},
+    query::{field, Query, Selection},
+    storage::{Row, StorageTransaction},
 };
 
 use std::{
     any::Any,
     cell::{Ref, RefCell, RefMut},
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     marker::PhantomData,
     ops::Deref,
     ops::DerefMut,
@@ -23,6 +25,8 @@ type ObjectWrapper = (ObjectState, Box<dyn Store>);
 pub struct Transaction<'a> {
     inner: Box<dyn StorageTransaction + 'a>,
     content: RefCell<HashMap<Key, Rc<RefCell<ObjectWrapper>>>>,
+    /// Tables already checked against their schemas, see `sync_table`.
+    synced: RefCell<HashSet<&'static str>>,
 }
 
 impl<'a> Transaction<'a> {
@@ -30,16 +34,28 @@ impl<'a> Transaction<'a> {
         Self {
             inner,
             content: RefCell::new(HashMap::new()),
+            synced: RefCell::new(HashSet::new()),
         }
     }
 
+    /// Stores a new object. A field with a default that is `None` gets the default, just
+    /// like a row inserted without the column, and so does the returned object.
     pub fn create<T: Object>(&self, obj: T) -> Result<Tx<'_, T>> {
-        let schema: &Schema = &T::SCHEMA;
+        let schema: &'static Schema = &T::SCHEMA;
+        self.ensure_table(schema)?;
         fetch_schema(schema);
-        if !self.inner.table_exists(schema.table_name)? {
-            self.inner.create_table(schema)?;
+        let mut row = obj.to_row();
+        let mut defaulted = false;
+        for (value, field) in row.iter_mut().zip(schema.fields) {
+            if let (Value::Null, Some(default)) = (&*value, &field.default) {
+                *value = default.clone();
+                defaulted = true;
+            }
         }
-        let id = self.inner.insert_row(schema, &obj.to_row())?;
+        let id = self.inner.insert_row(schema, &row)?;
+        let defaulted_obj = defaulted.then(|| T::from_row(&row));
+        drop(row);
+        let obj = defaulted_obj.unwrap_or(obj);
         let rc = self
             .content
             .borrow_mut()
@@ -55,13 +71,14 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get<T: Object>(&self, id: ObjectId) -> Result<Tx<'_, T>> {
-        let schema: &Schema = &T::SCHEMA;
+        let schema: &'static Schema = &T::SCHEMA;
         if !self.inner.table_exists(schema.table_name)? {
             return Err(Error::NotFound(Box::new(NotFoundError {
                 object_id: id,
                 type_name: schema.struct_name,
             })));
         }
+        self.sync_table(schema)?;
         fetch_schema(schema);
         fetch_id(id);
         if let Some(rc) = self.content.borrow().get(&(schema.table_name, id)) {
@@ -93,20 +110,269 @@ impl<'a> Transaction<'a> {
         })
     }
 
-    pub fn commit(self) -> Result<()> {
+    /// Starts a query over objects of type `T`, see `Query`.
+    pub fn query<T: Object>(&self) -> Query<'_, 'a, T> {
+        Query::new(self)
+    }
+
+    pub(crate) fn select<T: Object>(&self, selection: &Selection) -> Result<Vec<Tx<'_, T>>> {
+        let schema: &'static Schema = &T::SCHEMA;
+        fetch_schema(schema);
+        if !self.inner.table_exists(schema.table_name)? {
+            return Ok(vec![]);
+        }
+        self.sync_table(schema)?;
+        self.flush(false)?;
+        fetch_schema(schema);
+        let rows = self.inner.select_rows(schema, selection)?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, row)| self.load(id, row))
+            .collect())
+    }
+
+    /// Objects of type `T` whose `attr_name` field refers to `target`.
+    pub fn referrers<T: Object, U: Object>(
+        &self,
+        target: &Tx<'_, U>,
+        attr_name: &'static str,
+    ) -> Result<Vec<Tx<'_, T>>> {
+        self.query::<T>()
+            .filter(field(attr_name).refers_to(target))
+            .fetch()
+    }
+
+    /// Compares the live table of `T` with `T::SCHEMA`. A table that doesn't exist yet
+    /// misses every field.
+    pub fn schema_diff<T: Object>(&self) -> Result<SchemaDiff> {
+        let schema: &'static Schema = &T::SCHEMA;
+        fetch_schema(schema);
+        if !self.inner.table_exists(schema.table_name)? {
+            return Ok(SchemaDiff::new(schema, vec![]));
+        }
+        Ok(SchemaDiff::new(
+            schema,
+            self.inner.table_columns(schema.table_name)?,
+        ))
+    }
+
+    /// Applies the migrations newer than the last applied one, in the order of versions,
+    /// and records them in the metadata table. Like everything else, this takes effect
+    /// on commit.
+    pub fn migrate(&self, migrations: &[Migration]) -> Result<()> {
+        if let Some(pair) = migrations.windows(2).find(|w| w[0].version >= w[1].version) {
+            return Err(Error::UnorderedMigrations(Box::new(
+                UnorderedMigrationsError {
+                    previous_version: pair[0].version,
+                    version: pair[1].version,
+                },
+            )));
+        }
+        let current = self.migration_version()?;
+        for migration in migrations
+            .iter()
+            .filter(|migration| Some(migration.version) > current)
+        {
+            for step in migration.steps.iter() {
+                self.apply_step(step)?;
+            }
+            self.create(AppliedMigration {
+                version: migration.version,
+                description: migration.description.to_string(),
+            })?;
+        }
+        // NB: migrations may have changed tables that were checked before.
+        self.synced.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// The version of the last applied migration, if any.
+    pub fn migration_version(&self) -> Result<Option<i64>> {
+        let last = self
+            .query::<AppliedMigration>()
+            .order_by_desc(field("version"))
+            .limit(1)
+            .fetch()?;
+        Ok(last.first().map(|migration| migration.borrow().version))
+    }
+
+    fn apply_step(&self, step: &Step) -> Result<()> {
+        match step {
+            Step::AddColumn {
+                table_name,
+                column_name,
+                data_type,
+                reference,
+                default,
+            } => self.inner.add_column(
+                table_name,
+                column_name,
+                *data_type,
+                reference.as_ref(),
+                default.as_ref(),
+            ),
+            Step::RenameColumn {
+                table_name,
+                from,
+                to,
+            } => self.inner.rename_column(table_name, from, to),
+            Step::DropColumn {
+                table_name,
+                column_name,
+            } => self.inner.drop_column(table_name, column_name),
+            Step::RenameTable { from, to } => self.inner.rename_table(from, to),
+        }
+    }
+
+    /// Adds the columns missing from the live table, once per transaction. Columns that
+    /// existing rows can't be filled for are left to migrations, reads fail without them.
+    fn sync_table(&self, schema: &'static Schema) -> Result<()> {
+        if !self.synced.borrow_mut().insert(schema.table_name) {
+            return Ok(());
+        }
+        fetch_schema(schema);
+        let columns = self.inner.table_columns(schema.table_name)?;
+        for field in SchemaDiff::new(schema, columns).missing {
+            if field.default.is_some() || field.data_type.is_nullable() {
+                self.inner.add_column(
+                    schema.table_name,
+                    field.column_name,
+                    field.data_type,
+                    field.reference.as_ref(),
+                    field.default.as_ref(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the table along with the tables it refers to, unless they exist.
+    fn ensure_table(&self, schema: &'static Schema) -> Result<()> {
+        fetch_schema(schema);
+        if self.inner.table_exists(schema.table_name)? {
+            return self.sync_table(schema);
+        }
+        self.inner.create_table(schema)?;
+        self.synced.borrow_mut().insert(schema.table_name);
+        for field in schema.fields {
+            if let Some(reference) = &field.reference {
+                self.ensure_table((reference.target)())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes pending changes to the storage. The objects stay dirty until commit, so
+    /// flushing again is harmless. Objects borrowed mutably at the moment are left for the
+    /// next flush, which is on commit at the latest, when nothing can be borrowed anymore.
+    ///
+    /// An object may be restricted from deletion by another one removed later in the same
+    /// pass, so deletions are retried while any of them succeeds. The ones still restricted
+    /// fail the flush if `strict`, or are left for the next flush otherwise.
+    fn flush(&self, strict: bool) -> Result<()> {
+        let mut removed = vec![];
         for ((_, id), rc) in self.content.borrow().iter() {
-            let schema: &'static Schema = rc.borrow().1.get_schema();
+            let Ok(wrapper) = rc.try_borrow() else {
+                continue;
+            };
+            let schema: &'static Schema = wrapper.1.get_schema();
             fetch_id(*id);
-            match rc.borrow().0 {
+            match wrapper.0 {
                 ObjectState::Clean => continue,
-                ObjectState::Removed => self.inner.delete_row(*id, schema)?,
+                ObjectState::Removed => removed.push((*id, schema)),
                 ObjectState::Modified => {
-                    let store = &rc.borrow().1;
-                    let row = store.get_row();
-                    self.inner.update_row(*id, schema, &row)?;
+                    self.inner.update_row(*id, schema, &wrapper.1.get_row())?
+                }
+            }
+        }
+
+        loop {
+            let count = removed.len();
+            let mut restricted = vec![];
+            let mut last_err = None;
+            for (id, schema) in removed {
+                fetch_schema(schema);
+                fetch_id(id);
+                match self.inner.delete_row(id, schema) {
+                    Ok(()) => self.cascade(schema.table_name, id),
+                    Err(Error::Restricted(err)) => {
+                        restricted.push((id, schema));
+                        last_err = Some(err);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            match last_err {
+                None => return Ok(()),
+                Some(err) if restricted.len() == count => {
+                    return if strict {
+                        Err(Error::Restricted(err))
+                    } else {
+                        Ok(())
+                    };
+                }
+                Some(_) => removed = restricted,
+            }
+        }
+    }
+
+    /// Marks the loaded objects that the storage deletes along with the given one as removed.
+    /// Objects borrowed at the moment are left as they are.
+    fn cascade(&self, table_name: &'static str, id: ObjectId) {
+        let mut deleted = vec![(table_name, id)];
+        while let Some((table_name, id)) = deleted.pop() {
+            for (key, rc) in self.content.borrow().iter() {
+                let refers = {
+                    let Ok(wrapper) = rc.try_borrow() else {
+                        continue;
+                    };
+                    if let ObjectState::Removed = wrapper.0 {
+                        continue;
+                    }
+                    let schema = wrapper.1.get_schema();
+                    let row = wrapper.1.get_row();
+                    schema.fields.iter().zip(row.iter()).any(|(field, value)| {
+                        matches!(
+                            &field.reference,
+                            Some(reference) if reference.on_delete == OnDelete::Cascade
+                                && (reference.target)().table_name == table_name
+                        ) && matches!(value, Value::Int64(value) if *value == id.0)
+                    })
+                };
+                if refers {
+                    if let Ok(mut wrapper) = rc.try_borrow_mut() {
+                        wrapper.0 = ObjectState::Removed;
+                    }
+                    deleted.push(*key);
                 }
             }
         }
+    }
+
+    /// Returns the object already in the transaction, if any, or adds the one from `row`.
+    fn load<T: Object>(&self, id: ObjectId, row: Row) -> Tx<'_, T> {
+        let schema: &Schema = &T::SCHEMA;
+        let rc = self
+            .content
+            .borrow_mut()
+            .entry((schema.table_name, id))
+            .or_insert_with(|| {
+                Rc::new(RefCell::new((
+                    ObjectState::Clean,
+                    Box::new(T::from_row(&row)),
+                )))
+            })
+            .clone();
+        Tx {
+            inner: rc,
+            id,
+            lifetime: PhantomData::<&'_ Transaction>,
+            holder: PhantomData::<&'_ T>,
+        }
+    }
+
+    pub fn commit(self) -> Result<()> {
+        self.flush(true)?;
         self.inner.commit()?;
         Ok(())
     }
@@ -155,7 +421,7 @@ impl<'a, T: Any> Tx<'a, T> {
 
     pub fn borrow_mut(&self) -> RefWrapperMut<'_, T> {
         if let ObjectState::Removed = self.inner.borrow().0 {
-            panic!();
+            panic!("cannot borrow a removed object");
         }
         self.inner.borrow_mut().0 = ObjectState::Modified;
         RefWrapperMut {
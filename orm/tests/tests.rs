@@ -1,12 +1,18 @@
-use orm::{data::DataType, Connection, Object, ObjectId, ObjectState, Result, Tx};
-
-use rusqlite::params;
-use tempfile::NamedTempFile;
+use orm::{
+    data::{DataType, Value},
+    field,
+    kv::KvStore,
+    migration::{Migration, Step},
+    object::{FieldInfo, OnDelete, Reference, Schema},
+    storage::{Row, RowSlice, StorageConnection},
+    Connection, Object, ObjectId, ObjectState, Ref, Result, Tx,
+};
+
+use tempfile::{NamedTempFile, TempPath};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-//#[derive(Object, PartialEq, Clone, Debug)]
-#[derive(PartialEq, Clone, Debug)]
+#[derive(Object, PartialEq, Clone, Debug)]
 struct User {
     pub name: String,
     pub picture: Vec<u8>,
@@ -15,44 +21,130 @@ struct User {
     is_admin: bool,
 }
 
-use orm::object::{Schema, FieldInfo};
-
-impl Object for User {
-    fn get_schema() -> Schema {
-        Schema {
-            struct_name: "User",
-            table_name: "User",
-            fields: vec![
-                FieldInfo {
-                    column_name: "name",
-                    attr_name: "name",
-                    data_type: DataType::String,
-                },
-                FieldInfo {
-                    column_name: "picture",
-                    attr_name: "picture",
-                    data_type: DataType::Bytes,
-                },
-                FieldInfo {
-                    column_name: "visits",
-                    attr_name: "visits",
-                    data_type: DataType::Int64,
-                },
-                FieldInfo {
-                    column_name: "balance",
-                    attr_name: "balance",
-                    data_type: DataType::Float64,
-                },
-                FieldInfo {
-                    column_name: "is_admin",
-                    attr_name: "is_admin",
-                    data_type: DataType::Bool,
-                },
-            ],
+#[derive(Object, Debug)]
+struct Owner {
+    name: String,
+}
+
+#[derive(Object, Debug)]
+struct Pet {
+    name: String,
+    #[on_delete(cascade)]
+    owner: Ref<Owner>,
+}
+
+#[derive(Object, Debug)]
+struct Vet {
+    name: String,
+    patient: Ref<Pet>,
+}
+
+#[derive(Object, Debug)]
+#[table_name("account")]
+struct AccountV1 {
+    login: String,
+}
+
+struct AccountV2 {
+    login: String,
+    karma: i64,
+}
+
+impl Object for AccountV2 {
+    const SCHEMA: Schema = Schema {
+        struct_name: "AccountV2",
+        table_name: "account",
+        fields: &[
+            FieldInfo {
+                column_name: "login",
+                attr_name: "login",
+                data_type: DataType::String,
+                reference: None,
+                default: None,
+            },
+            FieldInfo {
+                column_name: "karma",
+                attr_name: "karma",
+                data_type: DataType::Int64,
+                reference: None,
+                default: Some(Value::Int64(10)),
+            },
+        ],
+    };
+
+    fn to_row(&self) -> Row<'_> {
+        vec![Value::from(&self.login), Value::from(&self.karma)]
+    }
+
+    fn from_row(row: &RowSlice) -> Self {
+        Self {
+            login: (&row[0]).into(),
+            karma: (&row[1]).into(),
         }
     }
 }
 
+#[derive(Object, Debug)]
+#[table_name("account")]
+struct AccountV3 {
+    name: String,
+    karma: i64,
+    verified: bool,
+}
+
+#[derive(Object, Debug)]
+#[table_name("account")]
+struct AccountV4 {
+    name: String,
+    karma: i64,
+    verified: bool,
+    #[on_delete(cascade)]
+    owner: Option<Ref<Owner>>,
+}
+
+#[derive(Object, Debug)]
+#[table_name("member")]
+struct Member {
+    name: String,
+    karma: i64,
+}
+
+#[derive(Object, Debug)]
+#[table_name("keeper")]
+struct Keeper {
+    name: String,
+}
+
+#[derive(Object, Debug)]
+struct Node {
+    name: String,
+    #[on_delete(cascade)]
+    parent: Option<Ref<Node>>,
+}
+
+#[derive(Object, Debug)]
+#[table_name("profile")]
+struct ProfileV1 {
+    login: String,
+}
+
+#[derive(Object, PartialEq, Clone, Debug)]
+#[table_name("profile")]
+struct ProfileV2 {
+    login: String,
+    nickname: Option<String>,
+    age: Option<i64>,
+    #[default(1)]
+    level: i64,
+    #[default("en")]
+    locale: String,
+    mentor: Option<Ref<Owner>>,
+    #[default(1)]
+    rating: f64,
+    #[default("guest")]
+    title: Option<String>,
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 fn assert_not_found<'a>(
@@ -77,13 +169,122 @@ fn fmt_res<T>(res: &Result<T>) -> String {
     }
 }
 
+/// A database to open connections to, on one of the backends.
+enum TestDb {
+    Sqlite(TempPath),
+    Kv(KvStore),
+}
+
+impl TestDb {
+    fn connect(&self) -> Connection {
+        match self {
+            Self::Sqlite(path) => Connection::open_sqlite_file(path).unwrap(),
+            Self::Kv(store) => Connection::new(Box::new(store.clone())),
+        }
+    }
+
+    /// A connection to the storage itself, bypassing the orm.
+    fn connect_storage(&self) -> Box<dyn StorageConnection> {
+        match self {
+            Self::Sqlite(path) => Box::new(rusqlite::Connection::open(path).unwrap()),
+            Self::Kv(store) => Box::new(store.clone()),
+        }
+    }
+
+    /// Writes rows that the orm wouldn't, creating their table unless it exists.
+    fn insert_raw(&self, schema: &Schema, rows: &[Row]) -> Vec<ObjectId> {
+        let mut storage = self.connect_storage();
+        let tx = storage.new_transaction().unwrap();
+        if !tx.table_exists(schema.table_name).unwrap() {
+            tx.create_table(schema).unwrap();
+        }
+        let ids = rows
+            .iter()
+            .map(|row| tx.insert_row(schema, row).unwrap())
+            .collect();
+        tx.commit().unwrap();
+        ids
+    }
+}
+
+/// A column of a table written by `TestDb::insert_raw`.
+const fn raw_field(column_name: &'static str, data_type: DataType) -> FieldInfo {
+    FieldInfo {
+        column_name,
+        attr_name: column_name,
+        data_type,
+        reference: None,
+        default: None,
+    }
+}
+
+/// Runs each of the tests against every backend, which must behave the same.
+macro_rules! backend_tests {
+    ($($(#[$attr:meta])* $name:ident),* $(,)?) => {
+        mod sqlite {
+            $(
+                #[test]
+                $(#[$attr])*
+                fn $name() {
+                    let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+                    super::$name(super::TestDb::Sqlite(path));
+                }
+            )*
+        }
+
+        mod kv {
+            $(
+                #[test]
+                $(#[$attr])*
+                fn $name() {
+                    super::$name(super::TestDb::Kv(orm::kv::KvStore::new()));
+                }
+            )*
+        }
+    };
+}
+
+backend_tests!(
+    test_create,
+    test_update,
+    test_delete,
+    test_create_delete,
+    #[should_panic(expected = "already borrowed")]
+    test_double_borrow,
+    #[should_panic(expected = "cannot borrow a removed object")]
+    test_borrow_created_deleted,
+    #[should_panic(expected = "cannot borrow a removed object")]
+    test_borrow_deleted,
+    #[should_panic(expected = "cannot delete a borrowed object")]
+    test_delete_borrowed,
+    test_missing_column,
+    test_unexpected_type,
+    test_null_value,
+    test_conflict,
+    test_empty_struct,
+    test_sql_injection,
+    test_table_column_names,
+    test_not_found,
+    test_unexpected_type_renamed,
+    test_missing_column_renamed,
+    #[cfg(feature = "test_lifetimes_create")]
+    test_lifetimes_create,
+    #[cfg(feature = "test_lifetimes_get")]
+    test_lifetimes_get,
+    test_query,
+    test_relations,
+    test_migrations,
+    test_schema_steps,
+    test_reference_cycle,
+    test_optional_fields,
+);
+
 ////////////////////////////////////////////////////////////////////////////////
 
-#[test]
-fn test_create() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_create(db: TestDb) {
+    let mut conn = db.connect();
     let tx = conn.new_transaction().unwrap();
-    let schema = User::get_schema();
+    let schema = User::SCHEMA;
     println!("{:?}", schema);
     let user = User {
         name: "John".into(),
@@ -92,7 +293,6 @@ fn test_create() {
         balance: 100.,
         is_admin: true,
     };
-    /*
     let tx_user = tx.create(user.clone()).unwrap();
     assert_eq!(*tx_user.borrow(), user);
 
@@ -102,12 +302,603 @@ fn test_create() {
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx.get::<User>(user_id).unwrap();
     assert_eq!(*tx_user.borrow(), user);
-    */
 }
-/*
+
+fn test_query(db: TestDb) {
+    let mut conn = db.connect();
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.query::<User>().fetch().unwrap().is_empty());
+
+    let mut ids = vec![];
+    for (name, visits) in [("Ann", 5), ("Bob", 3), ("Carl", 8), ("Dave", 1)] {
+        let user = tx
+            .create(User {
+                name: name.into(),
+                picture: vec![],
+                visits,
+                balance: visits as f64 * 10.,
+                is_admin: name == "Carl",
+            })
+            .unwrap();
+        ids.push(user.id());
+    }
+
+    let names = |users: Vec<Tx<'_, User>>| -> Vec<String> {
+        users
+            .iter()
+            .map(|user| user.borrow().name.clone())
+            .collect()
+    };
+    let users = tx
+        .query::<User>()
+        .filter(field("visits").gt(2))
+        .order_by(field("visits"))
+        .fetch()
+        .unwrap();
+    assert_eq!(names(users), ["Bob", "Ann", "Carl"]);
+    let users = tx
+        .query::<User>()
+        .order_by_desc(field("balance"))
+        .offset(1)
+        .limit(2)
+        .fetch()
+        .unwrap();
+    assert_eq!(names(users), ["Ann", "Bob"]);
+    let users = tx
+        .query::<User>()
+        .order_by_desc(field("balance"))
+        .offset(1)
+        .limit(u64::MAX)
+        .fetch()
+        .unwrap();
+    assert_eq!(names(users), ["Ann", "Bob", "Dave"]);
+    let users = tx
+        .query::<User>()
+        .filter(field("is_admin").eq(false))
+        .filter(field("name").ne("Ann"))
+        .order_by(field("name"))
+        .fetch()
+        .unwrap();
+    assert_eq!(names(users), ["Bob", "Dave"]);
+
+    // Objects are shared with the transaction, uncommitted changes included.
+    let bob = tx.get::<User>(ids[1]).unwrap();
+    bob.borrow_mut().visits = 10;
+    tx.get::<User>(ids[3]).unwrap().delete();
+    let users = tx
+        .query::<User>()
+        .order_by_desc(field("visits"))
+        .fetch()
+        .unwrap();
+    assert_eq!(users.len(), 3);
+    assert_eq!(users[0].id(), bob.id());
+    users[0].borrow_mut().balance = 0.;
+    assert_eq!(bob.borrow().balance, 0.);
+    assert!(matches!(bob.state(), ObjectState::Modified));
+
+    // An object borrowed at the moment is written by a later flush, it doesn't stop a query.
+    let mut carl = users[1].borrow_mut();
+    carl.visits = 9;
+    let admins = tx
+        .query::<User>()
+        .filter(field("is_admin").eq(true))
+        .fetch()
+        .unwrap();
+    assert_eq!(admins.len(), 1);
+    drop(carl);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let users = tx
+        .query::<User>()
+        .filter(field("balance").le(0.))
+        .fetch()
+        .unwrap();
+    assert_eq!(names(users), ["Bob"]);
+    let users = tx
+        .query::<User>()
+        .filter(field("visits").eq(9))
+        .fetch()
+        .unwrap();
+    assert_eq!(names(users), ["Carl"]);
+
+    match tx.query::<User>().filter(field("age").eq(1)).fetch() {
+        Err(orm::Error::UnknownField(err)) => {
+            assert_eq!(err.type_name, "User");
+            assert_eq!(err.attr_name, "age");
+        }
+        res => panic!("expected UnknownField error, got {}", fmt_res(&res)),
+    }
+    match tx.query::<User>().order_by(field("age")).fetch() {
+        Err(orm::Error::UnknownField(_)) => {}
+        res => panic!("expected UnknownField error, got {}", fmt_res(&res)),
+    }
+    match tx
+        .query::<User>()
+        .filter(field("visits").eq("many"))
+        .fetch()
+    {
+        Err(orm::Error::FieldTypeMismatch(err)) => {
+            assert_eq!(err.attr_name, "visits");
+            assert_eq!(err.expected_type, DataType::Int64);
+            assert_eq!(err.got_type, Some(DataType::String));
+        }
+        res => panic!("expected FieldTypeMismatch error, got {}", fmt_res(&res)),
+    }
+}
+
+fn test_relations(db: TestDb) {
+    let mut conn = db.connect();
+    let tx = conn.new_transaction().unwrap();
+    let ann = tx.create(Owner { name: "Ann".into() }).unwrap();
+    let rex = tx
+        .create(Pet {
+            name: "Rex".into(),
+            owner: Ref::from(&ann),
+        })
+        .unwrap();
+    tx.create(Pet {
+        name: "Tom".into(),
+        owner: Ref::from(&ann),
+    })
+    .unwrap();
+    tx.create(Vet {
+        name: "Bob".into(),
+        patient: Ref::from(&rex),
+    })
+    .unwrap();
+    let dangling = tx.create(Pet {
+        name: "Max".into(),
+        owner: Ref::new(ObjectId(100)),
+    });
+    assert!(matches!(dangling, Err(orm::Error::Storage(_))));
+    let (ann_id, rex_id) = (ann.id(), rex.id());
+    tx.commit().unwrap();
+
+    // Targets are loaded lazily and shared with the transaction.
+    let tx = conn.new_transaction().unwrap();
+    let rex = tx.get::<Pet>(rex_id).unwrap();
+    let owner = rex.borrow().owner.get(&tx).unwrap();
+    owner.borrow_mut().name = "Anna".into();
+    assert_eq!(tx.get::<Owner>(ann_id).unwrap().borrow().name, "Anna");
+
+    let mut names: Vec<String> = tx
+        .referrers::<Pet, _>(&owner, "owner")
+        .unwrap()
+        .iter()
+        .map(|pet| pet.borrow().name.clone())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["Rex", "Tom"]);
+    let pets = tx
+        .query::<Pet>()
+        .filter(field("owner").refers_to(&owner))
+        .filter(field("name").ne("Rex"))
+        .fetch()
+        .unwrap();
+    assert_eq!(pets.len(), 1);
+    match tx.referrers::<Vet, _>(&owner, "patient") {
+        Err(orm::Error::NotAReference(err)) => {
+            assert_eq!(err.type_name, "Vet");
+            assert_eq!(err.attr_name, "patient");
+            assert_eq!(err.target_type, "Owner");
+        }
+        res => panic!("expected NotAReference error, got {}", fmt_res(&res)),
+    }
+    assert!(matches!(
+        tx.referrers::<Pet, _>(&owner, "name"),
+        Err(orm::Error::NotAReference(_))
+    ));
+    tx.commit().unwrap();
+
+    // The vet restricts deleting its patient, unless it goes away too.
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Pet>(rex_id).unwrap().delete();
+    match tx.commit() {
+        Err(orm::Error::Restricted(err)) => {
+            assert_eq!(err.object_id, rex_id);
+            assert_eq!(err.type_name, "Pet");
+        }
+        res => panic!("expected Restricted error, got {}", fmt_res(&res)),
+    }
+    let tx = conn.new_transaction().unwrap();
+    let vet = tx.query::<Vet>().fetch().unwrap().pop().unwrap();
+    vet.delete();
+    tx.get::<Pet>(rex_id).unwrap().delete();
+    tx.commit().unwrap();
+
+    // Pets are deleted with their owner, including the ones already loaded. Objects
+    // borrowed at the moment don't get in the way.
+    let tx = conn.new_transaction().unwrap();
+    let pets = tx.query::<Pet>().fetch().unwrap();
+    assert_eq!(pets.len(), 1);
+    let bea = tx.create(Owner { name: "Bea".into() }).unwrap();
+    let mut bea_mut = bea.borrow_mut();
+    bea_mut.name = "Beatrice".into();
+    tx.get::<Owner>(ann_id).unwrap().delete();
+    assert!(tx.query::<Pet>().fetch().unwrap().is_empty());
+    assert!(matches!(pets[0].state(), ObjectState::Removed));
+    drop(bea_mut);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.query::<Pet>().fetch().unwrap().is_empty());
+    assert!(matches!(
+        tx.get::<Owner>(ann_id),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+fn test_migrations(db: TestDb) {
+    let mut conn = db.connect();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.schema_diff::<AccountV1>().unwrap().missing.len(), 1);
+    let id = tx
+        .create(AccountV1 {
+            login: "ann".into(),
+        })
+        .unwrap()
+        .id();
+    assert!(tx.schema_diff::<AccountV1>().unwrap().is_empty());
+    tx.commit().unwrap();
+
+    // A field with a default is added on the fly.
+    let tx = conn.new_transaction().unwrap();
+    let diff = tx.schema_diff::<AccountV2>().unwrap();
+    assert_eq!(diff.missing.len(), 1);
+    assert_eq!(diff.missing[0].attr_name, "karma");
+    let account = tx.get::<AccountV2>(id).unwrap();
+    assert_eq!(account.borrow().login, "ann");
+    assert_eq!(account.borrow().karma, 10);
+    assert!(tx.schema_diff::<AccountV2>().unwrap().is_empty());
+    tx.commit().unwrap();
+
+    // The rest needs a migration.
+    let tx = conn.new_transaction().unwrap();
+    match tx.get::<AccountV3>(id) {
+        Err(orm::Error::MissingColumn(err)) => assert_eq!(err.attr_name, "name"),
+        res => panic!("expected MissingColumn error, got {}", fmt_res(&res)),
+    }
+    assert_eq!(tx.migration_version().unwrap(), None);
+    let mut migrations = vec![Migration {
+        version: 1,
+        description: "rename login, add verified",
+        steps: vec![
+            Step::RenameColumn {
+                table_name: "account",
+                from: "login",
+                to: "name",
+            },
+            Step::AddColumn {
+                table_name: "account",
+                column_name: "verified",
+                data_type: DataType::Bool,
+                reference: None,
+                default: Some(Value::Bool(false)),
+            },
+        ],
+    }];
+    tx.migrate(&migrations).unwrap();
+    let account = tx.get::<AccountV3>(id).unwrap();
+    assert_eq!(account.borrow().name, "ann");
+    assert_eq!(account.borrow().karma, 10);
+    assert!(!account.borrow().verified);
+    tx.commit().unwrap();
+
+    // Applied migrations are skipped.
+    let tx = conn.new_transaction().unwrap();
+    migrations.push(Migration {
+        version: 2,
+        description: "add note",
+        steps: vec![Step::AddColumn {
+            table_name: "account",
+            column_name: "note",
+            data_type: DataType::String,
+            reference: None,
+            default: None,
+        }],
+    });
+    tx.migrate(&migrations).unwrap();
+    assert_eq!(tx.migration_version().unwrap(), Some(2));
+    let diff = tx.schema_diff::<AccountV3>().unwrap();
+    assert!(diff.missing.is_empty() && diff.mismatched.is_empty());
+    assert_eq!(diff.extra.len(), 1);
+    assert_eq!(diff.extra[0].name, "note");
+    tx.create(AccountV3 {
+        name: "bob".into(),
+        karma: 0,
+        verified: true,
+    })
+    .unwrap();
+    tx.commit().unwrap();
+
+    // Versions out of order are rejected before anything is applied.
+    let tx = conn.new_transaction().unwrap();
+    let mut unordered = vec![Migration {
+        version: 3,
+        description: "add owner",
+        steps: vec![Step::AddColumn {
+            table_name: "account",
+            column_name: "owner",
+            data_type: DataType::Nullable(&DataType::Int64),
+            reference: Some(Reference {
+                target: || &Owner::SCHEMA,
+                on_delete: OnDelete::Cascade,
+            }),
+            default: None,
+        }],
+    }];
+    unordered.append(&mut migrations);
+    match tx.migrate(&unordered) {
+        Err(orm::Error::UnorderedMigrations(err)) => {
+            assert_eq!(err.previous_version, 3);
+            assert_eq!(err.version, 1);
+        }
+        res => panic!("expected UnorderedMigrations error, got {}", fmt_res(&res)),
+    }
+    assert_eq!(tx.migration_version().unwrap(), Some(2));
+
+    // An added column can refer to another table.
+    unordered.rotate_left(1);
+    tx.migrate(&unordered).unwrap();
+    let owner = tx.create(Owner { name: "Ann".into() }).unwrap();
+    let owner_id = owner.id();
+    let id = tx
+        .create(AccountV4 {
+            name: "carl".into(),
+            karma: 0,
+            verified: false,
+            owner: Some(Ref::from(&owner)),
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Owner>(owner_id).unwrap().delete();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert!(matches!(
+        tx.get::<AccountV4>(id),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+fn test_optional_fields(db: TestDb) {
+    let mut conn = db.connect();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(ProfileV1 {
+            login: "ann".into(),
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    // Nullable and defaulted columns are added on the fly.
+    let tx = conn.new_transaction().unwrap();
+    let ann = tx.get::<ProfileV2>(id).unwrap();
+    assert_eq!(
+        *ann.borrow(),
+        ProfileV2 {
+            login: "ann".into(),
+            nickname: None,
+            age: None,
+            level: 1,
+            locale: "en".into(),
+            mentor: None,
+            rating: 1.,
+            title: Some("guest".into()),
+        }
+    );
+    let mentor = tx.create(Owner { name: "Bob".into() }).unwrap();
+    let mentor_id = mentor.id();
+    let mut bob = ProfileV2 {
+        login: "bob".into(),
+        nickname: Some("bobby".into()),
+        age: Some(30),
+        level: 5,
+        locale: "de".into(),
+        mentor: Some(Ref::from(&mentor)),
+        rating: 2.5,
+        title: None,
+    };
+    let tx_bob = tx.create(bob.clone()).unwrap();
+    bob.title = Some("guest".into());
+    assert_eq!(*tx_bob.borrow(), bob);
+    let bob_id = tx_bob.id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<ProfileV2>(bob_id).unwrap().borrow(), bob);
+    let profiles = tx
+        .query::<ProfileV2>()
+        .filter(field("nickname").eq(Value::Null))
+        .fetch()
+        .unwrap();
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(profiles[0].id(), id);
+    let profiles = tx
+        .query::<ProfileV2>()
+        .filter(field("nickname").ne("bob"))
+        .fetch()
+        .unwrap();
+    assert_eq!(profiles.len(), 2);
+    match tx
+        .query::<ProfileV2>()
+        .filter(field("login").eq(Value::Null))
+        .fetch()
+    {
+        Err(orm::Error::FieldTypeMismatch(err)) => {
+            assert_eq!(err.attr_name, "login");
+            assert_eq!(err.got_type, None);
+        }
+        res => panic!("expected FieldTypeMismatch error, got {}", fmt_res(&res)),
+    }
+    tx.commit().unwrap();
+
+    // The column added on the fly keeps the reference of the field.
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Owner>(mentor_id).unwrap().delete();
+    assert!(matches!(tx.commit(), Err(orm::Error::Restricted(_))));
+}
+
+fn test_schema_steps(db: TestDb) {
+    let mut conn = db.connect();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(AccountV3 {
+            name: "ann".into(),
+            karma: 5,
+            verified: true,
+        })
+        .unwrap()
+        .id();
+    let owner = tx.create(Owner { name: "Ann".into() }).unwrap();
+    let owner_id = owner.id();
+    let pet_id = tx
+        .create(Pet {
+            name: "Rex".into(),
+            owner: Ref::from(&owner),
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.migrate(&[Migration {
+        version: 1,
+        description: "account is a member now",
+        steps: vec![
+            Step::DropColumn {
+                table_name: "account",
+                column_name: "verified",
+            },
+            Step::RenameTable {
+                from: "account",
+                to: "member",
+            },
+            Step::RenameTable {
+                from: "Owner",
+                to: "keeper",
+            },
+        ],
+    }])
+    .unwrap();
+    assert!(matches!(
+        tx.get::<AccountV3>(id),
+        Err(orm::Error::NotFound(_))
+    ));
+    let member = tx.get::<Member>(id).unwrap();
+    assert_eq!(member.borrow().name, "ann");
+    assert_eq!(member.borrow().karma, 5);
+    assert!(tx.schema_diff::<Member>().unwrap().is_empty());
+    tx.commit().unwrap();
+
+    // References follow the renamed table.
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Keeper>(owner_id).unwrap().borrow().name, "Ann");
+    tx.get::<Keeper>(owner_id).unwrap().delete();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert!(matches!(
+        tx.get::<Pet>(pet_id),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+fn test_reference_cycle(db: TestDb) {
+    let mut conn = db.connect();
+    let tx = conn.new_transaction().unwrap();
+    let root = tx
+        .create(Node {
+            name: "root".into(),
+            parent: None,
+        })
+        .unwrap();
+    let child = tx
+        .create(Node {
+            name: "child".into(),
+            parent: Some(Ref::from(&root)),
+        })
+        .unwrap();
+    root.borrow_mut().parent = Some(Ref::from(&child));
+    let lone = tx
+        .create(Node {
+            name: "lone".into(),
+            parent: None,
+        })
+        .unwrap();
+    lone.borrow_mut().parent = Some(Ref::from(&lone));
+    let (root_id, lone_id) = (root.id(), lone.id());
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Node>(root_id).unwrap().delete();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let mut nodes = tx.query::<Node>().fetch().unwrap();
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0].id(), lone_id);
+    nodes.remove(0).delete();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.query::<Node>().fetch().unwrap().is_empty());
+}
+
+/// Rows written bypassing the orm, which only sqlite allows.
 #[test]
-fn test_update() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_external_rows() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = orm_conn.new_transaction().unwrap();
+    tx.create(ProfileV1 {
+        login: "ann".into(),
+    })
+    .unwrap();
+    tx.commit().unwrap();
+    let tx = orm_conn.new_transaction().unwrap();
+    tx.query::<ProfileV2>().fetch().unwrap();
+    tx.commit().unwrap();
+
+    // Defaults apply to rows created elsewhere too, but NULL only fits an Option.
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    sqlite_conn
+        .execute("INSERT INTO profile(id, login) VALUES (100, 'carl')", [])
+        .unwrap();
+    sqlite_conn
+        .execute("INSERT INTO profile(id) VALUES (101)", [])
+        .unwrap();
+    // NB: the column added on the fly keeps the reference of the field.
+    let references: Vec<(String, String)> = sqlite_conn
+        .prepare("SELECT \"from\", \"table\" FROM pragma_foreign_key_list('profile')")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap();
+    assert_eq!(references, [("mentor".to_string(), "Owner".to_string())]);
+    sqlite_conn.close().unwrap();
+
+    let tx = orm_conn.new_transaction().unwrap();
+    let carl = tx.get::<ProfileV2>(ObjectId(100)).unwrap();
+    assert_eq!(carl.borrow().level, 1);
+    assert_eq!(carl.borrow().locale, "en");
+    assert_eq!(carl.borrow().age, None);
+    match tx.get::<ProfileV2>(ObjectId(101)) {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.attr_name, "login");
+            assert_eq!(err.expected_type, DataType::String);
+            assert_eq!(err.got_type, "Null");
+            assert!(err.to_string().contains("not an Option"));
+        }
+        res => panic!("expected UnexpectedType error, got {}", fmt_res(&res)),
+    }
+}
+
+fn test_update(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -134,9 +925,8 @@ fn test_update() {
     assert_eq!(tx_user.borrow().balance, 400.);
 }
 
-#[test]
-fn test_delete() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_delete(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -168,9 +958,8 @@ fn test_delete() {
     assert_not_found(res, user_id, "User");
 }
 
-#[test]
-fn test_create_delete() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_create_delete(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -198,10 +987,8 @@ fn test_create_delete() {
     assert_not_found(res, user_id, "User");
 }
 
-#[test]
-#[should_panic(expected = "already borrowed")]
-fn test_double_borrow() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_double_borrow(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -219,10 +1006,8 @@ fn test_double_borrow() {
     let _r2 = tx_user_2.borrow_mut();
 }
 
-#[test]
-#[should_panic(expected = "cannot borrow a removed object")]
-fn test_borrow_created_deleted() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_borrow_created_deleted(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -240,10 +1025,8 @@ fn test_borrow_created_deleted() {
     tx_user_2.borrow();
 }
 
-#[test]
-#[should_panic(expected = "cannot borrow a removed object")]
-fn test_borrow_deleted() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_borrow_deleted(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -266,10 +1049,8 @@ fn test_borrow_deleted() {
     tx_user_2.borrow();
 }
 
-#[test]
-#[should_panic(expected = "cannot delete a borrowed object")]
-fn test_delete_borrowed() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_delete_borrowed(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let tx_user = tx
@@ -287,15 +1068,13 @@ fn test_delete_borrowed() {
     tx_user_2.delete();
 }
 
-#[test]
-fn test_missing_column() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .execute("CREATE TABLE \"User\" (foo INTEGER)", [])
-        .unwrap();
-    sqlite_conn.close().unwrap();
+fn test_missing_column(db: TestDb) {
+    const LEGACY_USER: Schema = Schema {
+        struct_name: "LegacyUser",
+        table_name: "User",
+        fields: &[raw_field("foo", DataType::Int64)],
+    };
+    db.insert_raw(&LEGACY_USER, &[]);
 
     fn check_missing_column<T>(res: &Result<T>) -> bool {
         let columns = ["id", "name", "picture", "visits", "balance", "is_admin"];
@@ -311,7 +1090,7 @@ fn test_missing_column() {
         }
     }
 
-    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+    let mut orm_conn = db.connect();
     let tx = orm_conn.new_transaction().unwrap();
 
     let res_get = tx.get::<User>(1.into());
@@ -342,36 +1121,33 @@ fn test_missing_column() {
     }
 }
 
-#[test]
-fn test_unexpected_type() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .execute(
-            "CREATE TABLE User (\
-                id INTEGER PRIMARY KEY AUTOINCREMENT,\
-                name TEXT,\
-                picture BLOB,\
-                visits BIGINT,\
-                balance REAL,\
-                is_admin TEXT\
-            )",
-            [],
-        )
-        .unwrap();
-    sqlite_conn
-        .execute(
-            "INSERT INTO \"User\" VALUES (?, ?, ?, ?, ?, ?)",
-            params![1, "Bill", &b"binary"[..], 20, 34.2, "true"],
-        )
-        .unwrap();
-    sqlite_conn.close().unwrap();
+fn test_unexpected_type(db: TestDb) {
+    const LEGACY_USER: Schema = Schema {
+        struct_name: "LegacyUser",
+        table_name: "User",
+        fields: &[
+            raw_field("name", DataType::String),
+            raw_field("picture", DataType::Bytes),
+            raw_field("visits", DataType::Int64),
+            raw_field("balance", DataType::Float64),
+            raw_field("is_admin", DataType::String),
+        ],
+    };
+    let ids = db.insert_raw(
+        &LEGACY_USER,
+        &[vec![
+            Value::from("Bill"),
+            Value::from(&b"binary"[..]),
+            Value::from(20),
+            Value::from(34.2),
+            Value::from("true"),
+        ]],
+    );
 
-    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+    let mut orm_conn = db.connect();
     let tx = orm_conn.new_transaction().unwrap();
 
-    match tx.get::<User>(1i64.into()) {
+    match tx.get::<User>(ids[0]) {
         Err(orm::Error::UnexpectedType(err)) => {
             assert_eq!(err.type_name, "User");
             assert_eq!(err.table_name, "User");
@@ -387,11 +1163,8 @@ fn test_unexpected_type() {
     }
 }
 
-#[test]
-fn test_null_value() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+fn test_null_value(db: TestDb) {
+    let mut orm_conn = db.connect();
     let tx = orm_conn.new_transaction().unwrap();
 
     tx.create(User {
@@ -405,26 +1178,20 @@ fn test_null_value() {
 
     tx.commit().unwrap();
 
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .execute(
-            "INSERT INTO User(id, name, picture, visits, balance, is_admin) \
-            VALUES (?, ?, ?, ?, ?, ?)",
-            params![
-                100,
-                "Jill",
-                rusqlite::types::Value::Null,
-                45,
-                12415.31,
-                false
-            ],
-        )
-        .unwrap();
-    sqlite_conn.close().unwrap();
+    let ids = db.insert_raw(
+        &User::SCHEMA,
+        &[vec![
+            Value::from("Jill"),
+            Value::Null,
+            Value::from(45),
+            Value::from(12415.31),
+            Value::from(false),
+        ]],
+    );
 
     let tx = orm_conn.new_transaction().unwrap();
 
-    match tx.get::<User>(100i64.into()) {
+    match tx.get::<User>(ids[0]) {
         Err(orm::Error::UnexpectedType(err)) => {
             assert_eq!(err.type_name, "User");
             assert_eq!(err.attr_name, "picture");
@@ -439,11 +1206,8 @@ fn test_null_value() {
     }
 }
 
-#[test]
-fn test_conflict() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let mut conn_one = Connection::open_sqlite_file(&path).unwrap();
+fn test_conflict(db: TestDb) {
+    let mut conn_one = db.connect();
     let tx_one = conn_one.new_transaction().unwrap();
 
     tx_one
@@ -456,7 +1220,7 @@ fn test_conflict() {
         })
         .unwrap();
 
-    let mut conn_two = Connection::open_sqlite_file(&path).unwrap();
+    let mut conn_two = db.connect();
     let tx_two = conn_two.new_transaction().unwrap();
 
     let res_create = tx_two.create(User {
@@ -472,15 +1236,14 @@ fn test_conflict() {
     }
 }
 
-#[test]
-fn test_empty_struct() {
+fn test_empty_struct(db: TestDb) {
     #[derive(Object)]
     struct Empty {}
 
     #[derive(Object)]
     struct Void;
 
-    let mut conn = Connection::open_in_memory().unwrap();
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     let empty_id = tx.create::<Empty>(Empty {}).unwrap().id();
@@ -507,11 +1270,10 @@ fn test_empty_struct() {
     ));
 }
 
-#[test]
-fn test_sql_injection() {
+fn test_sql_injection(db: TestDb) {
     let names = ["\"; DROP TABLE user --", "'; DROP TABLE user --"];
 
-    let mut conn = Connection::open_in_memory().unwrap();
+    let mut conn = db.connect();
 
     for &name in names.iter() {
         let tx = conn.new_transaction().unwrap();
@@ -550,27 +1312,27 @@ struct Order {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[test]
-fn test_table_column_names() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+fn test_table_column_names(db: TestDb) {
+    let mut orm_conn = db.connect();
     let tx = orm_conn.new_transaction().unwrap();
 
-    let order_id = tx.create(Order { is_tall: true }).unwrap().id().into_i64();
+    let order_id = tx.create(Order { is_tall: true }).unwrap().id();
     tx.commit().unwrap();
 
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .prepare("SELECT IsTall FROM order_table WHERE id = ?")
+    let mut storage = db.connect_storage();
+    let tx = storage.new_transaction().unwrap();
+    let columns: Vec<_> = tx
+        .table_columns("order_table")
         .unwrap()
-        .query_row([order_id], |_| Ok(()))
-        .unwrap();
+        .into_iter()
+        .map(|column| column.name)
+        .collect();
+    assert_eq!(columns, ["IsTall"]);
+    tx.select_row(order_id, &Order::SCHEMA).unwrap();
 }
 
-#[test]
-fn test_not_found() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_not_found(db: TestDb) {
+    let mut conn = db.connect();
 
     let tx = conn.new_transaction().unwrap();
     match tx.get::<Order>(3523.into()) {
@@ -582,29 +1344,18 @@ fn test_not_found() {
     }
 }
 
-#[test]
-fn test_unexpected_type_renamed() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .execute(
-            "CREATE TABLE order_table (\
-                id INTEGER PRIMARY KEY AUTOINCREMENT,\
-                IsTall TEXT\
-            )",
-            [],
-        )
-        .unwrap();
-    sqlite_conn
-        .execute("INSERT INTO order_table VALUES (?, ?)", params![1, "FALSE"])
-        .unwrap();
-    sqlite_conn.close().unwrap();
+fn test_unexpected_type_renamed(db: TestDb) {
+    const LEGACY_ORDER: Schema = Schema {
+        struct_name: "LegacyOrder",
+        table_name: "order_table",
+        fields: &[raw_field("IsTall", DataType::String)],
+    };
+    let ids = db.insert_raw(&LEGACY_ORDER, &[vec![Value::from("FALSE")]]);
 
-    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+    let mut orm_conn = db.connect();
     let tx = orm_conn.new_transaction().unwrap();
 
-    match tx.get::<Order>(1.into()) {
+    match tx.get::<Order>(ids[0]) {
         Err(orm::Error::UnexpectedType(err)) => {
             assert_eq!(err.type_name, "Order");
             assert_eq!(err.table_name, "order_table");
@@ -620,21 +1371,13 @@ fn test_unexpected_type_renamed() {
     }
 }
 
-#[test]
-fn test_missing_column_renamed() {
-    let path = NamedTempFile::new().unwrap().into_temp_path();
-
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .execute(
-            "CREATE TABLE order_table (id INTEGER PRIMARY KEY AUTOINCREMENT)",
-            [],
-        )
-        .unwrap();
-    sqlite_conn
-        .execute("INSERT INTO order_table VALUES (?)", [1])
-        .unwrap();
-    sqlite_conn.close().unwrap();
+fn test_missing_column_renamed(db: TestDb) {
+    const LEGACY_ORDER: Schema = Schema {
+        struct_name: "LegacyOrder",
+        table_name: "order_table",
+        fields: &[],
+    };
+    let ids = db.insert_raw(&LEGACY_ORDER, &[vec![]]);
 
     fn check_missing_column<T>(res: &Result<T>) -> bool {
         match res {
@@ -649,10 +1392,10 @@ fn test_missing_column_renamed() {
         }
     }
 
-    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+    let mut orm_conn = db.connect();
     let tx = orm_conn.new_transaction().unwrap();
 
-    let res_get = tx.get::<Order>(1.into());
+    let res_get = tx.get::<Order>(ids[0]);
     assert!(
         check_missing_column(&res_get),
         "expected Error::MissingColumn at get(), got {}",
@@ -675,9 +1418,8 @@ fn test_missing_column_renamed() {
 }
 
 #[cfg(feature = "test_lifetimes_create")]
-#[test]
-fn test_lifetimes_create() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_lifetimes_create(db: TestDb) {
+    let mut conn = db.connect();
     let tx = conn.new_transaction().unwrap();
 
     let order = tx.create(Order { is_tall: false }).unwrap();
@@ -687,9 +1429,8 @@ fn test_lifetimes_create() {
 }
 
 #[cfg(feature = "test_lifetimes_get")]
-#[test]
-fn test_lifetimes_create() {
-    let mut conn = Connection::open_in_memory().unwrap();
+fn test_lifetimes_get(db: TestDb) {
+    let mut conn = db.connect();
     let tx = conn.new_transaction().unwrap();
 
     let order_id = tx.create(Order { is_tall: false }).unwrap().id();
@@ -701,4 +1442,3 @@ fn test_lifetimes_create() {
 
     eprintln!("is_tall: {}", order.borrow().is_tall);
 }
-*/